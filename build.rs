@@ -0,0 +1,27 @@
+// Generates the gRPC service and messages from proto/electrs.proto (requires `protoc`)
+#[cfg(feature = "grpc")]
+fn generate_grpc() {
+    use std::fs;
+    use std::path::Path;
+
+    println!("cargo:rerun-if-changed=proto/electrs.proto");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    protoc_grpcio::compile_grpc_protos(&["electrs.proto"], &["proto"], &out_dir, None)
+        .expect("failed to compile gRPC definitions");
+
+    // the generated files start with inner attributes, which can't be include!()-ed
+    for name in &["electrs.rs", "electrs_grpc.rs"] {
+        let path = Path::new(&out_dir).join(name);
+        let code = fs::read_to_string(&path).unwrap();
+        let code: Vec<&str> = code
+            .lines()
+            .filter(|line| !line.starts_with("#!"))
+            .collect();
+        fs::write(&path, code.join("\n")).unwrap();
+    }
+}
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    generate_grpc();
+}
@@ -0,0 +1,141 @@
+use bincode;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::new_index::db::{DBFlush, DBRow, DB};
+#[cfg(not(feature = "liquid"))]
+use crate::util::xpub::XpubScriptType;
+use crate::util::{Bytes, FullHash};
+
+// Named groups of scripts (e.g. all the addresses of a wallet) whose stats, history and UTXOs are
+// queried together. They're persisted in their own db:
+//      A{name} → {account}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub members: Vec<AccountMember>,
+    // whose used addresses are members too, scanned for on each query
+    #[cfg(not(feature = "liquid"))]
+    pub xpubs: Vec<AccountXpub>,
+}
+
+// The accounts saved by older versions, without xpubs
+#[cfg(not(feature = "liquid"))]
+#[derive(Deserialize)]
+struct AccountWithoutXpubs {
+    members: Vec<AccountMember>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountMember {
+    pub member: String, // the address or scripthash, as given
+    pub scripthash: FullHash,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountXpub {
+    pub xpub: String, // as given
+    pub script_type: XpubScriptType,
+    pub gap_limit: u32,
+}
+
+impl Account {
+    pub fn scripthashes(&self) -> Vec<FullHash> {
+        self.members.iter().map(|m| m.scripthash).collect()
+    }
+}
+
+pub struct Accounts {
+    db: DB,
+    accounts: RwLock<HashMap<String, Account>>,
+}
+
+fn account_key(name: &str) -> Bytes {
+    [&b"A"[..], name.as_bytes()].concat()
+}
+
+#[cfg(not(feature = "liquid"))]
+fn parse_account(value: &[u8]) -> bincode::Result<Account> {
+    bincode::deserialize(value).or_else(|_| {
+        let account: AccountWithoutXpubs = bincode::deserialize(value)?;
+        Ok(Account {
+            members: account.members,
+            xpubs: vec![],
+        })
+    })
+}
+
+#[cfg(feature = "liquid")]
+fn parse_account(value: &[u8]) -> bincode::Result<Account> {
+    bincode::deserialize(value)
+}
+
+impl Accounts {
+    pub fn open(path: &Path) -> Self {
+        let db = DB::open(path);
+        db.enable_auto_compaction();
+        let accounts: HashMap<String, Account> = db
+            .iter_scan(b"A")
+            .map(|row| {
+                let name = String::from_utf8(row.key[1..].to_vec()).expect("invalid account name");
+                let account = parse_account(&row.value).expect("failed to parse account");
+                (name, account)
+            })
+            .collect();
+        info!("loaded {} accounts", accounts.len());
+        Accounts {
+            db,
+            accounts: RwLock::new(accounts),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Account> {
+        self.accounts.read().unwrap().get(name).cloned()
+    }
+
+    // Creates or replaces the account
+    pub fn put(&self, name: &str, account: Account) {
+        self.db.write(
+            vec![DBRow {
+                key: account_key(name),
+                value: bincode::serialize(&account).unwrap(),
+            }],
+            DBFlush::Enable,
+        );
+        self.accounts
+            .write()
+            .unwrap()
+            .insert(name.to_string(), account);
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.db
+            .write_with_deletes(vec![], vec![account_key(name)], DBFlush::Enable);
+        self.accounts.write().unwrap().remove(name).is_some()
+    }
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::{parse_account, AccountMember};
+
+    #[derive(Serialize)]
+    struct SavedAccount {
+        members: Vec<AccountMember>,
+    }
+
+    #[test]
+    fn test_parse_account_without_xpubs() {
+        let saved = SavedAccount {
+            members: vec![AccountMember {
+                member: "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".to_string(),
+                scripthash: [1; 32],
+            }],
+        };
+        let account = parse_account(&bincode::serialize(&saved).unwrap()).unwrap();
+        assert_eq!(account.members.len(), 1);
+        assert!(account.xpubs.is_empty());
+    }
+}
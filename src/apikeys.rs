@@ -0,0 +1,207 @@
+// API keys for the REST server, granting permissions and rate limits to the clients that send
+// them as an `Authorization: Bearer <key>` header. They're loaded from a JSON file (re-read on
+// SIGHUP) of the form:
+//
+//   {
+//     "anonymous": ["read", "broadcast"],
+//     "keys": {
+//       "<key>": { "name": "partner", "permissions": ["read", "broadcast"], "rate_limit": 600 }
+//     }
+//   }
+//
+// where the rate limits are in requests per minute (unlimited if unset), and the anonymous
+// permissions apply to the requests without a key.
+
+use crypto::util::fixed_time_eq;
+use serde_json;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use crate::errors::*;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Read,
+    Broadcast,
+    Watch, // managing the watch list and the notifications
+    Admin,
+}
+
+// What the requests without a key may do, unless the file says otherwise
+pub const ANONYMOUS_PERMISSIONS: &[Permission] = &[Permission::Read, Permission::Broadcast];
+
+// The client of a request, as identified by its key
+pub struct Access {
+    pub name: Option<String>,
+    permissions: Vec<Permission>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Access {
+    pub fn anonymous(permissions: &[Permission]) -> Self {
+        Access {
+            name: None,
+            permissions: permissions.to_vec(),
+            limiter: None,
+        }
+    }
+
+    // The admin token grants everything
+    pub fn admin() -> Self {
+        Access {
+            name: Some("admin".to_string()),
+            permissions: vec![
+                Permission::Read,
+                Permission::Broadcast,
+                Permission::Watch,
+                Permission::Admin,
+            ],
+            limiter: None,
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    // Counts a request against the rate limit, returning false if it's exceeded
+    pub fn acquire(&self) -> bool {
+        self.limiter
+            .as_ref()
+            .map_or(true, |limiter| limiter.acquire())
+    }
+}
+
+// A token bucket refilled at `per_minute` tokens per minute, allowing bursts of as many
+pub struct RateLimiter {
+    per_minute: u32,
+    state: Mutex<(f64, Instant)>, // the available tokens, as of the last request
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            state: Mutex::new((per_minute as f64, Instant::now())),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1);
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let tokens =
+            (state.0 + elapsed_secs * self.per_minute as f64 / 60.0).min(self.per_minute as f64);
+        if tokens < 1.0 {
+            *state = (tokens, now);
+            return false;
+        }
+        *state = (tokens - 1.0, now);
+        true
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeysFile {
+    anonymous: Option<Vec<Permission>>,
+    #[serde(default)]
+    keys: HashMap<String, ApiKeyEntry>,
+}
+
+#[derive(Deserialize)]
+struct ApiKeyEntry {
+    name: String,
+    permissions: Vec<Permission>,
+    rate_limit: Option<u32>,
+}
+
+struct ApiKey {
+    key: String,
+    name: String,
+    permissions: Vec<Permission>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+struct Keys {
+    anonymous: Vec<Permission>,
+    keys: Vec<ApiKey>,
+}
+
+pub struct ApiKeys {
+    path: PathBuf,
+    keys: RwLock<Keys>,
+}
+
+impl ApiKeys {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(ApiKeys {
+            path: path.to_path_buf(),
+            keys: RwLock::new(load(path)?),
+        })
+    }
+
+    // The rate limits start over
+    pub fn reload(&self) -> Result<()> {
+        let keys = load(&self.path)?;
+        info!("reloaded {} API keys", keys.keys.len());
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    pub fn anonymous(&self) -> Access {
+        Access::anonymous(&self.keys.read().unwrap().anonymous)
+    }
+
+    // Compared in constant time, so that the keys can't be guessed from the response times
+    pub fn authenticate(&self, key: &[u8]) -> Option<Access> {
+        let keys = self.keys.read().unwrap();
+        let mut found = None;
+        for api_key in &keys.keys {
+            if fixed_time_eq(api_key.key.as_bytes(), key) {
+                found = Some(api_key);
+            }
+        }
+        found.map(|api_key| Access {
+            name: Some(api_key.name.clone()),
+            permissions: api_key.permissions.clone(),
+            limiter: api_key.limiter.clone(),
+        })
+    }
+}
+
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ApiKeys({:?})", self.path)
+    }
+}
+
+fn load(path: &Path) -> Result<Keys> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read API keys from {:?}", path))?;
+    let file: ApiKeysFile = serde_json::from_str(&contents)
+        .chain_err(|| format!("invalid API keys file at {:?}", path))?;
+    Ok(Keys {
+        anonymous: file
+            .anonymous
+            .unwrap_or_else(|| ANONYMOUS_PERMISSIONS.to_vec()),
+        keys: file
+            .keys
+            .into_iter()
+            .map(|(key, entry)| ApiKey {
+                key,
+                name: entry.name,
+                permissions: entry.permissions,
+                limiter: entry
+                    .rate_limit
+                    .map(|limit| Arc::new(RateLimiter::new(limit))),
+            })
+            .collect(),
+    })
+}
@@ -1,26 +1,38 @@
 extern crate bitcoin;
-extern crate error_chain;
 #[macro_use]
 extern crate log;
 
 extern crate electrs;
 
-use error_chain::ChainedError;
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use electrs::{
+    accounts::Accounts,
     config::Config,
-    daemon::Daemon,
+    consensus::{header_sources, ConsensusMonitor},
+    daemon::{Daemon, RpcOptions},
     electrum::RPC as ElectrumRPC,
     errors::*,
     metrics::Metrics,
-    new_index::{precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
+    new_index::{
+        precache,
+        snapshot::{create_snapshot, restore_snapshot},
+        ChainQuery, FetchFrom, Indexer, Mempool, Query, Store,
+    },
     rest,
     signal::Waiter,
+    tor, traces,
 };
 
+#[cfg(not(feature = "liquid"))]
+use electrs::{new_index::miners::MinerTags, prices::PriceFeed, watch::WatchList};
+
+#[cfg(all(feature = "grpc", not(feature = "liquid")))]
+use electrs::grpc::GrpcServer;
+
 #[cfg(feature = "liquid")]
 use electrs::elements::AssetRegistry;
 
@@ -37,32 +49,61 @@ fn fetch_from(config: &Config, store: &Store) -> FetchFrom {
 }
 
 fn run_server(config: Arc<Config>) -> Result<()> {
+    traces::init(&config)?;
     let signal = Waiter::new();
     let metrics = Metrics::new(config.monitoring_addr);
     metrics.start();
 
     let daemon = Arc::new(Daemon::new(
-        &config.daemon_dir,
-        config.daemon_rpc_addr,
+        &config.blocks_dir,
+        &config.daemon_rpc_addrs,
+        RpcOptions::new(&config),
         config.cookie_getter(),
         config.network_type,
         signal.clone(),
         &metrics,
     )?);
-    let store = Arc::new(Store::open(&config.db_path.join("newindex")));
-    let mut indexer = Indexer::open(Arc::clone(&store), fetch_from(&config, &store), &metrics);
-    let mut tip = indexer.update(&daemon)?;
-
-    let chain = Arc::new(ChainQuery::new(Arc::clone(&store), &metrics));
+    if let Some(ref snapshot_dir) = config.restore_from {
+        restore_snapshot(
+            snapshot_dir,
+            &config.db_path.join("newindex"),
+            config.network_type,
+        )?;
+    }
+    let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
+    let mut indexer = Indexer::open(
+        Arc::clone(&store),
+        fetch_from(&config, &store),
+        &config,
+        &metrics,
+    );
 
-    if let Some(ref precache_file) = config.precache_scripts {
-        let precache_scripthashes = precache::scripthashes_from_file(precache_file.to_string())
-            .expect("cannot load scripts to precache");
-        precache::precache(&chain, precache_scripthashes);
+    #[cfg(not(feature = "liquid"))]
+    {
+        if config.index_miners {
+            indexer.set_miner_tags(MinerTags::load(
+                config.miner_tags.as_ref().map(|path| path.as_path()),
+            )?);
+        }
     }
 
+    let chain = Arc::new(ChainQuery::new(Arc::clone(&store), &metrics));
     let mempool = Arc::new(RwLock::new(Mempool::new(Arc::clone(&chain), &metrics)));
-    mempool.write().unwrap().update(&daemon)?;
+
+    // the watch list is managed through the admin endpoints, so it needs an admin token
+    #[cfg(not(feature = "liquid"))]
+    let watchlist = match config.admin_token {
+        Some(_) => {
+            let webhook_url = match config.watch_webhook_url {
+                Some(ref url) => Some(url.parse().chain_err(|| "invalid watch webhook url")?),
+                None => None,
+            };
+            let watchlist = Arc::new(WatchList::open(&config.db_path.join("watch"), webhook_url));
+            indexer.set_watchlist(Arc::clone(&watchlist));
+            Some(watchlist)
+        }
+        None => None,
+    };
 
     #[cfg(feature = "liquid")]
     let asset_db = config
@@ -70,45 +111,205 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         .as_ref()
         .map(|dir| AssetRegistry::new(dir.clone()));
 
+    let accounts = Arc::new(Accounts::open(&config.db_path.join("accounts")));
+
+    let header_sources = header_sources(&config)?;
+    let consensus = if header_sources.is_empty() {
+        None
+    } else {
+        Some(ConsensusMonitor::start(
+            header_sources,
+            Arc::clone(&chain),
+            &config,
+            &metrics,
+        ))
+    };
+
+    #[cfg(not(feature = "liquid"))]
+    let prices = match config.price_feed_url {
+        Some(ref url) => {
+            let prices = Arc::new(PriceFeed::open(&config.db_path.join("prices")));
+            PriceFeed::start(
+                &prices,
+                url,
+                Duration::from_secs(config.price_feed_interval),
+            )?;
+            Some(prices)
+        }
+        None => None,
+    };
+
     let query = Arc::new(Query::new(
         Arc::clone(&chain),
         Arc::clone(&mempool),
         Arc::clone(&daemon),
+        accounts,
+        consensus,
+        #[cfg(not(feature = "liquid"))]
+        watchlist.clone(),
+        #[cfg(not(feature = "liquid"))]
+        prices,
         #[cfg(feature = "liquid")]
         asset_db,
     ));
 
     // TODO: configuration for which servers to start
+    // the REST server is started early to report the initial indexing progress
     let rest_server = rest::run_server(Arc::clone(&config), Arc::clone(&query));
+    // removed by Tor once dropped
+    let _onion_service = match config.tor_control_addr {
+        Some(_) => {
+            let onion_service = tor::publish_onion_service(&config)?;
+            info!("REST server published at http://{}", onion_service.hostname);
+            Some(onion_service)
+        }
+        None => None,
+    };
+
+    store.migrate()?;
+    store.repair();
+    let mut tip = indexer.update(&daemon)?;
+
+    if let Some(ref precache_file) = config.precache_scripts {
+        let precache_scripthashes = precache::scripthashes_from_file(precache_file.to_string())
+            .expect("cannot load scripts to precache");
+        precache::precache(&chain, precache_scripthashes);
+    }
+
+    let mempool_snapshot = config.db_path.join("mempool.snapshot");
+    if let Err(err) = mempool.write().unwrap().load_snapshot(&mempool_snapshot) {
+        warn!("failed to restore mempool: {}", err.display_chain());
+    }
+    // notify about new mempool transactions only, not about those restored from the snapshot
+    #[cfg(not(feature = "liquid"))]
+    {
+        if let Some(ref watchlist) = watchlist {
+            mempool
+                .write()
+                .unwrap()
+                .set_watchlist(Arc::clone(watchlist));
+        }
+    }
+    mempool.write().unwrap().update(&daemon)?;
+
     let electrum_server =
         ElectrumRPC::start(config.electrum_rpc_addr, Arc::clone(&query), &metrics);
 
+    #[cfg(all(feature = "grpc", not(feature = "liquid")))]
+    let grpc_server = match config.grpc_addr {
+        Some(addr) => Some(GrpcServer::start(
+            addr,
+            config.network_type,
+            Arc::clone(&query),
+        )?),
+        None => None,
+    };
+
+    // the pending rescans of watched scripts go on without waiting for the next block
+    #[allow(unused_mut)]
+    let mut rescanning = false;
     loop {
-        if let Err(err) = signal.wait(Duration::from_secs(5)) {
+        let wait = if rescanning { 0 } else { 5 };
+        if let Err(err) = signal.wait(Duration::from_secs(wait)) {
             info!("stopping server: {}", err);
+            // waits for in-flight requests to complete
             rest_server.stop();
+            drop(electrum_server);
+            #[cfg(all(feature = "grpc", not(feature = "liquid")))]
+            drop(grpc_server);
+            if let Err(err) = mempool.read().unwrap().save_snapshot(&mempool_snapshot) {
+                warn!("failed to persist mempool: {}", err.display_chain());
+            }
+            store.flush();
             break;
         }
 
+        if signal.take_reload() {
+            if let Err(err) = config.reload() {
+                warn!("failed to reload config: {}", err.display_chain());
+            }
+        }
+
         // Index new blocks
         let current_tip = daemon.getbestblockhash()?;
         if current_tip != tip {
             indexer.update(&daemon)?;
             tip = current_tip;
+
+            #[cfg(not(feature = "liquid"))]
+            {
+                if let Some(ref watchlist) = watchlist {
+                    watchlist.check_confirmations(&chain);
+                }
+            }
         };
 
+        // Index the history of newly watched scripts
+        #[cfg(not(feature = "liquid"))]
+        {
+            rescanning = indexer.rescan_watched();
+        }
+
         // Update mempool
         mempool.write().unwrap().update(&daemon)?;
 
         // Update subscribed clients
         electrum_server.notify();
+        rest_server.notify();
+        #[cfg(all(feature = "grpc", not(feature = "liquid")))]
+        {
+            if let Some(ref grpc_server) = grpc_server {
+                grpc_server.notify();
+            }
+        }
     }
     info!("server stopped");
     Ok(())
 }
 
+fn run_snapshot(config: &Config, out: &Path) -> Result<()> {
+    let store = Store::open(&config.db_path.join("newindex"), config);
+    let manifest = create_snapshot(&store, config.network_type, out)?;
+    info!(
+        "created a snapshot of block {} at height {} in {:?}",
+        manifest.tip_hash, manifest.tip_height, out
+    );
+    Ok(())
+}
+
+fn run_reindex(config: &Config, from_height: usize) -> Result<()> {
+    if config.index_richlist {
+        return Err(
+            "the rich list index can't be reindexed from a height, it has to be rebuilt".into(),
+        );
+    }
+    let store = Store::open(&config.db_path.join("newindex"), config);
+    store.migrate()?;
+    store.repair();
+    let blocks = store.unindex_from(from_height);
+    info!(
+        "{} blocks from height {} will be indexed again on the next start",
+        blocks, from_height
+    );
+    Ok(())
+}
+
 fn main() {
     let config = Arc::new(Config::from_args());
+    if let Some(from_height) = config.reindex_from_height {
+        if let Err(e) = run_reindex(&config, from_height) {
+            error!("reindex failed: {}", e.display_chain());
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(ref out) = config.snapshot_out {
+        if let Err(e) = run_snapshot(&config, out) {
+            error!("snapshot failed: {}", e.display_chain());
+            process::exit(1);
+        }
+        return;
+    }
     if let Err(e) = run_server(config) {
         error!("server failed: {}", e.display_chain());
         process::exit(1);
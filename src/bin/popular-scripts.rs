@@ -8,24 +8,15 @@ use electrs::{
 
 fn main() {
     let config = Config::from_args();
-    let store = Store::open(&config.db_path.join("newindex"));
-
-    let mut iter = store.history_db().raw_iterator();
-    iter.seek(b"H");
+    let store = Store::open(&config.db_path.join("newindex"), &config);
 
     let mut curr_scripthash = [0u8; 32];
     let mut total_entries = 0;
 
-    while iter.valid() {
-        let key = iter.key().unwrap();
-
-        if !key.starts_with(b"H") {
-            break;
-        }
-
+    for row in store.history_db().iter_scan(b"H") {
         let entry: TxHistoryKey = bincode::config()
             .big_endian()
-            .deserialize(&key)
+            .deserialize(&row.key)
             .expect("failed to deserialize TxHistoryKey");
 
         if curr_scripthash != entry.hash {
@@ -38,8 +29,6 @@ fn main() {
         }
 
         total_entries = total_entries + 1;
-
-        iter.next();
     }
 
     if total_entries > 100 {
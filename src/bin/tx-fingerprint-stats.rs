@@ -12,7 +12,7 @@ fn main() {
     use electrs::{
         chain::Transaction,
         config::Config,
-        daemon::Daemon,
+        daemon::{Daemon, RpcOptions},
         metrics::Metrics,
         new_index::{ChainQuery, FetchFrom, Indexer, Store},
         signal::Waiter,
@@ -21,7 +21,7 @@ fn main() {
 
     let signal = Waiter::new();
     let config = Config::from_args();
-    let store = Arc::new(Store::open(&config.db_path.join("newindex")));
+    let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
 
     let metrics = Metrics::new(config.monitoring_addr);
     metrics.start();
@@ -30,8 +30,9 @@ fn main() {
 
     let daemon = Arc::new(
         Daemon::new(
-            &config.daemon_dir,
-            config.daemon_rpc_addr,
+            &config.blocks_dir,
+            &config.daemon_rpc_addrs,
+            RpcOptions::new(&config),
             config.cookie_getter(),
             config.network_type,
             signal.clone(),
@@ -40,26 +41,14 @@ fn main() {
         .unwrap(),
     );
 
-    let mut indexer = Indexer::open(Arc::clone(&store), FetchFrom::Bitcoind, &metrics);
+    let mut indexer = Indexer::open(Arc::clone(&store), FetchFrom::Bitcoind, &config, &metrics);
     indexer.update(&daemon).unwrap();
 
-    let mut iter = store.txstore_db().raw_iterator();
-    iter.seek(b"T");
-
     let mut total = 0;
     let mut uih_totals = vec![0, 0, 0];
 
-    while iter.valid() {
-        let key = iter.key().unwrap();
-        let value = iter.value().unwrap();
-
-        if !key.starts_with(b"T") {
-            break;
-        }
-
-        iter.next();
-
-        let tx: Transaction = deserialize(&value).expect("failed to parse Transaction");
+    for row in store.txstore_db().iter_scan(b"T") {
+        let tx: Transaction = deserialize(&row.value).expect("failed to parse Transaction");
         let txid = tx.txid();
 
         // only consider transactions of exactly two outputs
@@ -13,6 +13,8 @@ use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::network::constants::Network as BNetwork;
 use bitcoin::util::hash::BitcoinHash;
 
+use std::sync::RwLock;
+
 #[cfg(not(feature = "liquid"))]
 pub type Value = u64;
 #[cfg(feature = "liquid")]
@@ -23,6 +25,8 @@ pub enum Network {
     Bitcoin,
     Testnet,
     Regtest,
+    Signet,
+    Custom, // regtest-like, with the parameters overridden by NetworkParams
 
     #[cfg(feature = "liquid")]
     Liquid,
@@ -30,9 +34,44 @@ pub enum Network {
     LiquidRegtest,
 }
 
+// Overrides for the signet (with a custom challenge) and custom networks' parameters
+#[derive(Debug, Clone, Default)]
+pub struct NetworkParams {
+    pub magic: Option<u32>,
+    pub genesis_hash: Option<Sha256dHash>,
+    pub address_network: Option<BNetwork>, // the address prefixes to use
+}
+
+lazy_static! {
+    static ref NETWORK_PARAMS: RwLock<NetworkParams> = RwLock::new(NetworkParams::default());
+}
+
+// Set once on startup, from the command-line options
+pub fn set_network_params(params: NetworkParams) {
+    *NETWORK_PARAMS.write().unwrap() = params;
+}
+
+fn network_params() -> NetworkParams {
+    NETWORK_PARAMS.read().unwrap().clone()
+}
+
 impl Network {
     pub fn genesis_hash(&self) -> Sha256dHash {
-        let block = genesis_block(BNetwork::from(self));
+        match self {
+            Network::Signet | Network::Custom => {
+                if let Some(hash) = network_params().genesis_hash {
+                    return hash;
+                }
+            }
+            _ => (),
+        }
+        let mut block = genesis_block(BNetwork::from(self));
+        if *self == Network::Signet {
+            // the default signet's genesis block only differs from regtest's by its header
+            block.header.time = 1598918400;
+            block.header.bits = 0x1e0377ae;
+            block.header.nonce = 52613770;
+        }
         block.bitcoin_hash()
     }
 
@@ -41,6 +80,8 @@ impl Network {
             Network::Bitcoin => 0xD9B4BEF9,
             Network::Testnet => 0x0709110B,
             Network::Regtest => 0xDAB5BFFA,
+            Network::Signet => network_params().magic.unwrap_or(0x40CF030A),
+            Network::Custom => network_params().magic.unwrap_or(0xDAB5BFFA),
 
             #[cfg(feature = "liquid")]
             Network::Liquid => 0xDAB5BFFA,
@@ -59,12 +100,23 @@ impl Network {
         }
     }
 
+    // Whether addresses parsed as `addr_network` belong to this network. Regtest's base58
+    // addresses can't be told apart from testnet's, and are parsed as such.
+    #[cfg(not(feature = "liquid"))]
+    pub fn is_address_network(&self, addr_network: BNetwork) -> bool {
+        let network = BNetwork::from(self);
+        addr_network == network
+            || (addr_network == BNetwork::Testnet && network == BNetwork::Regtest)
+    }
+
     pub fn names() -> Vec<String> {
         #[cfg(not(feature = "liquid"))]
         return vec![
             "mainnet".to_string(),
             "testnet".to_string(),
             "regtest".to_string(),
+            "signet".to_string(),
+            "custom".to_string(),
         ];
 
         #[cfg(feature = "liquid")]
@@ -72,6 +124,8 @@ impl Network {
             "mainnet".to_string(),
             "testnet".to_string(),
             "regtest".to_string(),
+            "signet".to_string(),
+            "custom".to_string(),
             "liquid".to_string(),
             "liquidregtest".to_string(),
         ];
@@ -84,6 +138,8 @@ impl From<&str> for Network {
             "mainnet" => Network::Bitcoin,
             "testnet" => Network::Testnet,
             "regtest" => Network::Regtest,
+            "signet" => Network::Signet,
+            "custom" => Network::Custom,
 
             #[cfg(feature = "liquid")]
             "liquid" => Network::Liquid,
@@ -101,6 +157,13 @@ impl From<&Network> for BNetwork {
             Network::Bitcoin => BNetwork::Bitcoin,
             Network::Testnet => BNetwork::Testnet,
             Network::Regtest => BNetwork::Regtest,
+            // signet uses testnet's address prefixes
+            Network::Signet => network_params()
+                .address_network
+                .unwrap_or(BNetwork::Testnet),
+            Network::Custom => network_params()
+                .address_network
+                .unwrap_or(BNetwork::Regtest),
 
             #[cfg(feature = "liquid")]
             Network::Liquid => BNetwork::Bitcoin, // @FIXME
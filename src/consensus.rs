@@ -0,0 +1,335 @@
+// Compares the tips of secondary header sources with the local headers, as an early warning that
+// our node follows a minority chain (or a stalled one). The sources are other bitcoind nodes
+// (--header-source-rpc, with the same syntax as --daemon-rpc-addr) and Esplora-compatible HTTP
+// APIs (--header-source-http, such as another electrs' REST server), which are polled every
+// --header-source-interval. Each source ends up:
+//  - in_sync, with the same tip as ours
+//  - behind, with its tip in our chain
+//  - ahead, with our tip in its chain
+//  - diverged, on another branch since the `fork_height` block
+//  - unreachable
+// and is reported by GET /internal/consensus and the `consensus_*` metrics.
+
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use hyper::Uri;
+use serde_json::Value;
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::daemon::{call_once, CookieGetter, DaemonAddr, RpcOptions};
+use crate::errors::*;
+use crate::metrics::{GaugeVec, MetricOpts, Metrics};
+use crate::new_index::ChainQuery;
+use crate::util::http::http_get;
+use crate::util::spawn_thread;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum HeaderSource {
+    Rpc(DaemonAddr),
+    Http(String), // the base URL, without a trailing slash
+}
+
+impl fmt::Display for HeaderSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderSource::Rpc(addr) => write!(f, "rpc:{}", addr),
+            HeaderSource::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+struct SourceClient<'a> {
+    source: &'a HeaderSource,
+    cookie_getter: &'a Arc<CookieGetter>,
+    options: &'a RpcOptions,
+}
+
+impl<'a> SourceClient<'a> {
+    fn rpc(&self, addr: &DaemonAddr, method: &str, params: Value) -> Result<Value> {
+        call_once(addr, self.cookie_getter, self.options, method, params)
+    }
+
+    fn get(&self, url: &str, path: &str) -> Result<String> {
+        let url: Uri = format!("{}{}", url, path)
+            .parse()
+            .chain_err(|| format!("invalid url {}{}", url, path))?;
+        Ok(http_get(&url, HTTP_TIMEOUT)?.trim().to_string())
+    }
+
+    fn tip(&self) -> Result<(usize, Sha256dHash)> {
+        match self.source {
+            HeaderSource::Rpc(addr) => {
+                let info = self.rpc(addr, "getblockchaininfo", json!([]))?;
+                let height = info["blocks"].as_u64().chain_err(|| "missing blocks")?;
+                let hash = info["bestblockhash"]
+                    .as_str()
+                    .chain_err(|| "missing bestblockhash")?;
+                Ok((height as usize, parse_hash(hash)?))
+            }
+            HeaderSource::Http(url) => {
+                let height = self.get(url, "/blocks/tip/height")?;
+                let hash = self.get(url, "/blocks/tip/hash")?;
+                Ok((
+                    height
+                        .parse()
+                        .chain_err(|| format!("invalid height {:?}", height))?,
+                    parse_hash(&hash)?,
+                ))
+            }
+        }
+    }
+
+    fn hash_at(&self, height: usize) -> Result<Sha256dHash> {
+        match self.source {
+            HeaderSource::Rpc(addr) => {
+                let hash = self.rpc(addr, "getblockhash", json!([height]))?;
+                parse_hash(hash.as_str().chain_err(|| "non-string block hash")?)
+            }
+            HeaderSource::Http(url) => {
+                parse_hash(&self.get(url, &format!("/block-height/{}", height))?)
+            }
+        }
+    }
+}
+
+fn parse_hash(hash: &str) -> Result<Sha256dHash> {
+    Sha256dHash::from_hex(hash).chain_err(|| format!("invalid block hash {:?}", hash))
+}
+
+#[derive(Serialize, Clone)]
+pub struct SourceStatus {
+    pub source: String,
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tip_height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tip_hash: Option<Sha256dHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fork_height: Option<usize>, // the last block in common, if diverged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub checked_at: u64, // unix time
+}
+
+pub struct ConsensusMonitor {
+    statuses: RwLock<Vec<SourceStatus>>,
+    local_height: RwLock<usize>, // as of the last check
+}
+
+struct ConsensusMetrics {
+    diverged: GaugeVec,
+    fork_depth: GaugeVec,
+    tip_lag: GaugeVec,
+    reachable: GaugeVec,
+}
+
+impl ConsensusMetrics {
+    fn new(metrics: &Metrics) -> Self {
+        ConsensusMetrics {
+            diverged: metrics.gauge_vec(
+                MetricOpts::new(
+                    "consensus_diverged",
+                    "Whether each header source is on another branch than ours",
+                ),
+                &["source"],
+            ),
+            fork_depth: metrics.gauge_vec(
+                MetricOpts::new(
+                    "consensus_fork_depth",
+                    "Number of our blocks since the last one in common with each diverged header source",
+                ),
+                &["source"],
+            ),
+            tip_lag: metrics.gauge_vec(
+                MetricOpts::new(
+                    "consensus_tip_lag",
+                    "Height of each header source's tip minus ours",
+                ),
+                &["source"],
+            ),
+            reachable: metrics.gauge_vec(
+                MetricOpts::new(
+                    "consensus_reachable",
+                    "Whether each header source answered the last check",
+                ),
+                &["source"],
+            ),
+        }
+    }
+
+    fn observe(&self, status: &SourceStatus, local_height: usize) {
+        let labels = [status.source.as_str()];
+        let reachable = status.status != "unreachable";
+        self.reachable
+            .with_label_values(&labels)
+            .set(if reachable { 1.0 } else { 0.0 });
+        if !reachable {
+            return;
+        }
+        self.diverged
+            .with_label_values(&labels)
+            .set(if status.status == "diverged" {
+                1.0
+            } else {
+                0.0
+            });
+        self.fork_depth.with_label_values(&labels).set(
+            status.fork_height.map_or(0.0, |fork_height| {
+                local_height.saturating_sub(fork_height) as f64
+            }),
+        );
+        if let Some(tip_height) = status.tip_height {
+            self.tip_lag
+                .with_label_values(&labels)
+                .set(tip_height as f64 - local_height as f64);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// The highest block in common, between `low` (in common) and `high` (not in common)
+fn find_fork(
+    client: &SourceClient,
+    chain: &ChainQuery,
+    mut low: usize,
+    mut high: usize,
+) -> Result<usize> {
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if Some(client.hash_at(mid)?) == chain.hash_by_height(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+fn check_source(client: &SourceClient, chain: &ChainQuery) -> Result<SourceStatus> {
+    let local_height = chain.best_height();
+    let (tip_height, tip_hash) = client.tip()?;
+    let mut fork_height = None;
+    let status = if chain.height_by_hash(&tip_hash) == Some(tip_height) {
+        if tip_height == local_height {
+            "in_sync"
+        } else {
+            "behind"
+        }
+    } else if tip_height > local_height
+        && Some(client.hash_at(local_height)?) == chain.hash_by_height(local_height)
+    {
+        "ahead"
+    } else {
+        // the genesis block is in common, and the source's block at the lowest of both tips isn't
+        fork_height = Some(find_fork(client, chain, 0, tip_height.min(local_height))?);
+        "diverged"
+    };
+    Ok(SourceStatus {
+        source: client.source.to_string(),
+        status,
+        tip_height: Some(tip_height),
+        tip_hash: Some(tip_hash),
+        fork_height,
+        error: None,
+        checked_at: now(),
+    })
+}
+
+impl ConsensusMonitor {
+    pub fn start(
+        sources: Vec<HeaderSource>,
+        chain: Arc<ChainQuery>,
+        config: &Config,
+        metrics: &Metrics,
+    ) -> Arc<ConsensusMonitor> {
+        let monitor = Arc::new(ConsensusMonitor {
+            statuses: RwLock::new(vec![]),
+            local_height: RwLock::new(0),
+        });
+        let consensus_metrics = ConsensusMetrics::new(metrics);
+        let cookie_getter = config.cookie_getter();
+        let options = RpcOptions::new(config);
+        let interval = Duration::from_secs(config.header_source_interval);
+        let monitor_ref = Arc::clone(&monitor);
+        spawn_thread("consensus", move || loop {
+            let statuses: Vec<SourceStatus> = sources
+                .iter()
+                .map(|source| {
+                    let client = SourceClient {
+                        source,
+                        cookie_getter: &cookie_getter,
+                        options: &options,
+                    };
+                    check_source(&client, &chain).unwrap_or_else(|e| SourceStatus {
+                        source: source.to_string(),
+                        status: "unreachable",
+                        tip_height: None,
+                        tip_hash: None,
+                        fork_height: None,
+                        error: Some(e.to_string()),
+                        checked_at: now(),
+                    })
+                })
+                .collect();
+            let local_height = chain.best_height();
+            for status in &statuses {
+                consensus_metrics.observe(status, local_height);
+                match status.status {
+                    "diverged" => warn!(
+                        "header source {} diverged from our chain after block {} (its tip is at {}, ours at {})",
+                        status.source,
+                        status.fork_height.unwrap(),
+                        status.tip_height.unwrap(),
+                        local_height
+                    ),
+                    "unreachable" => debug!(
+                        "header source {} is unreachable: {}",
+                        status.source,
+                        status.error.as_ref().unwrap()
+                    ),
+                    _ => (),
+                }
+            }
+            *monitor_ref.local_height.write().unwrap() = local_height;
+            *monitor_ref.statuses.write().unwrap() = statuses;
+            thread::sleep(interval);
+        });
+        monitor
+    }
+
+    // The statuses of the sources as of the last check, along with our height then
+    pub fn statuses(&self) -> (usize, Vec<SourceStatus>) {
+        (
+            *self.local_height.read().unwrap(),
+            self.statuses.read().unwrap().clone(),
+        )
+    }
+}
+
+pub fn header_sources(config: &Config) -> Result<Vec<HeaderSource>> {
+    let mut sources: Vec<HeaderSource> = config
+        .header_sources_rpc
+        .iter()
+        .cloned()
+        .map(HeaderSource::Rpc)
+        .collect();
+    for url in &config.header_sources_http {
+        if !url.starts_with("http://") {
+            bail!("invalid header source url {} (only http is supported)", url);
+        }
+        sources.push(HeaderSource::Http(url.trim_end_matches('/').to_string()));
+    }
+    Ok(sources)
+}
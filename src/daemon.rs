@@ -1,8 +1,12 @@
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Lines, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::fmt;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 use std::time::Duration;
 
 use base64;
@@ -11,6 +15,7 @@ use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::util::hash::BitcoinHash;
 use glob;
 use hex;
+use rayon::prelude::*;
 use serde_json::{from_str, from_value, Value};
 
 #[cfg(not(feature = "liquid"))]
@@ -18,10 +23,11 @@ use bitcoin::consensus::encode::{deserialize, serialize};
 #[cfg(feature = "liquid")]
 use elements::encode::{deserialize, serialize};
 
-use crate::chain::{Block, BlockHeader, Network, Transaction};
-use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
+use crate::chain::{Block, BlockHeader, Network, OutPoint, Transaction};
+use crate::config::Config;
+use crate::metrics::{Gauge, GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::signal::Waiter;
-use crate::util::HeaderList;
+use crate::util::{spawn_thread, HeaderList};
 
 use crate::errors::*;
 
@@ -67,9 +73,14 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
         if let Some(err) = reply_obj.get("error") {
             if !err.is_null() {
                 if let Some(code) = parse_error_code(&err) {
+                    let message = err["message"].as_str().unwrap_or_default().to_string();
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
-                        -28 => bail!(ErrorKind::Connection(err.to_string())),
+                        -28 => bail!(ErrorKind::DaemonUnreachable(err.to_string())),
+                        // RPC_INVALID_ADDRESS_OR_KEY, for unknown blocks and transactions
+                        -5 => bail!(ErrorKind::NotFound(message)),
+                        // RPC_INVALID_PARAMETER
+                        -8 => bail!(ErrorKind::InvalidInput(message)),
                         _ => (),
                     }
                 }
@@ -145,34 +156,159 @@ pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
 }
 
-struct Connection {
-    tx: TcpStream,
-    rx: Lines<BufReader<TcpStream>>,
-    cookie_getter: Arc<CookieGetter>,
-    addr: SocketAddr,
-    signal: Waiter,
+// A bitcoind JSONRPC endpoint, given as 'host:port' or 'http://[user:password@]host:port'
+#[derive(Clone, PartialEq)]
+pub struct DaemonAddr {
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<String>, // 'USER:PASSWORD' from the URL, instead of the cookie
 }
 
-fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
-    loop {
-        match TcpStream::connect(addr) {
-            Ok(conn) => return Ok(conn),
-            Err(err) => {
-                warn!("failed to connect daemon at {}: {}", addr, err);
-                signal.wait(Duration::from_secs(3))?;
-                continue;
+impl FromStr for DaemonAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (auth, host_port) = match s.find("://") {
+            Some(pos) => {
+                if &s[..pos] != "http" {
+                    bail!("unsupported scheme in {}", s);
+                }
+                let rest = s[pos + 3..].trim_end_matches('/');
+                match rest.rfind('@') {
+                    Some(at) => (Some(rest[..at].to_string()), &rest[at + 1..]),
+                    None => (None, rest),
+                }
             }
+            None => (None, s),
+        };
+        let colon = host_port
+            .rfind(':')
+            .chain_err(|| format!("missing port in {}", s))?;
+        Ok(DaemonAddr {
+            host: host_port[..colon]
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: host_port[colon + 1..]
+                .parse()
+                .chain_err(|| format!("invalid port in {}", s))?,
+            auth,
+        })
+    }
+}
+
+// Without the credentials, for logging
+impl fmt::Display for DaemonAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
         }
     }
 }
 
+impl fmt::Debug for DaemonAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+fn tcp_connect(addr: &DaemonAddr, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = None;
+    for socket_addr in (addr.host.as_str(), addr.port)
+        .to_socket_addrs()
+        .chain_err(|| format!("failed to resolve {}", addr))?
+    {
+        match TcpStream::connect_timeout(&socket_addr, timeout) {
+            Ok(conn) => return Ok(conn),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    match last_err {
+        Some(err) => Err(err).chain_err(|| format!("failed to connect to {}", addr)),
+        None => bail!("no address found for {}", addr),
+    }
+}
+
+// Connect through a SOCKS5 proxy (e.g. Tor's), which resolves the host name itself
+fn socks5_connect(proxy: SocketAddr, addr: &DaemonAddr, timeout: Duration) -> Result<TcpStream> {
+    if addr.host.len() > 255 {
+        bail!("host name too long for SOCKS5: {}", addr.host);
+    }
+    let mut conn = TcpStream::connect_timeout(&proxy, timeout)
+        .chain_err(|| format!("failed to connect to SOCKS5 proxy at {}", proxy))?;
+    conn.set_read_timeout(Some(timeout))
+        .chain_err(|| "failed to set timeout")?;
+
+    // version 5, with a single authentication method: none
+    conn.write_all(&[5, 1, 0])
+        .chain_err(|| "failed to send SOCKS5 greeting")?;
+    let mut reply = [0u8; 2];
+    conn.read_exact(&mut reply)
+        .chain_err(|| "failed to read SOCKS5 greeting")?;
+    if reply != [5, 0] {
+        bail!("SOCKS5 proxy at {} requires authentication", proxy);
+    }
+
+    // CONNECT to a domain name
+    let mut request = vec![5, 1, 0, 3, addr.host.len() as u8];
+    request.extend_from_slice(addr.host.as_bytes());
+    request.extend_from_slice(&addr.port.to_be_bytes());
+    conn.write_all(&request)
+        .chain_err(|| "failed to send SOCKS5 request")?;
+    let mut reply = [0u8; 4];
+    conn.read_exact(&mut reply)
+        .chain_err(|| "failed to read SOCKS5 reply")?;
+    if reply[1] != 0 {
+        bail!(
+            "SOCKS5 proxy failed to connect to {} (error {})",
+            addr,
+            reply[1]
+        );
+    }
+    // skip the bound address and port
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            conn.read_exact(&mut len)
+                .chain_err(|| "failed to read SOCKS5 reply")?;
+            len[0] as usize
+        }
+        atyp => bail!("invalid SOCKS5 address type {}", atyp),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    conn.read_exact(&mut bound)
+        .chain_err(|| "failed to read SOCKS5 reply")?;
+    Ok(conn)
+}
+
+struct Connection {
+    tx: TcpStream,
+    rx: Lines<BufReader<TcpStream>>,
+    cookie_getter: Arc<CookieGetter>,
+    auth: Option<String>,
+}
+
 impl Connection {
+    // A single attempt, the callers handle the retries (and failing over to other endpoints)
     fn new(
-        addr: SocketAddr,
+        addr: &DaemonAddr,
         cookie_getter: Arc<CookieGetter>,
-        signal: Waiter,
+        options: &RpcOptions,
     ) -> Result<Connection> {
-        let conn = tcp_connect(addr, &signal)?;
+        let conn = match options.proxy {
+            Some(proxy) => socks5_connect(proxy, addr, options.timeout),
+            None => tcp_connect(addr, options.timeout),
+        }
+        .chain_err(|| {
+            ErrorKind::DaemonUnreachable(format!("failed to connect daemon at {}", addr))
+        })?;
+        conn.set_read_timeout(Some(options.timeout))
+            .and_then(|_| conn.set_write_timeout(Some(options.timeout)))
+            .chain_err(|| "failed to set timeouts")?;
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone {:?}", conn))?,
@@ -181,25 +317,30 @@ impl Connection {
             tx: conn,
             rx: reader.lines(),
             cookie_getter,
-            addr,
-            signal,
+            auth: addr.auth.clone(),
         })
     }
 
-    fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+    // Pooled connections are reused for other requests, so it's set again before each of them
+    fn set_read_timeout(&self, timeout: Duration) -> Result<()> {
+        self.tx
+            .set_read_timeout(Some(timeout))
+            .chain_err(|| "failed to set timeouts")
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
-        let cookie = &self.cookie_getter.get()?;
+        let cookie = match self.auth {
+            Some(ref auth) => auth.as_bytes().to_vec(),
+            None => self.cookie_getter.get()?,
+        };
         let msg = format!(
             "POST / HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
-            base64::encode(cookie),
+            base64::encode(&cookie),
             request.len(),
             request,
         );
         self.tx.write_all(msg.as_bytes()).chain_err(|| {
-            ErrorKind::Connection("disconnected from daemon while sending".to_owned())
+            ErrorKind::DaemonUnreachable("disconnected from daemon while sending".to_owned())
         })
     }
 
@@ -211,12 +352,13 @@ impl Connection {
         let status = iter
             .next()
             .chain_err(|| {
-                ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
+                ErrorKind::DaemonUnreachable("disconnected from daemon while receiving".to_owned())
             })?
-            .chain_err(|| "failed to read status")?;
+            .chain_err(|| ErrorKind::DaemonUnreachable("failed to read status".to_owned()))?;
         let mut headers = HashMap::new();
         for line in iter {
-            let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
+            let line =
+                line.chain_err(|| ErrorKind::DaemonUnreachable("failed to read".to_owned()))?;
             if line.is_empty() {
                 in_header = false; // next line should contain the actual response.
             } else if in_header {
@@ -232,8 +374,8 @@ impl Connection {
             }
         }
 
-        let contents =
-            contents.chain_err(|| ErrorKind::Connection("no reply from daemon".to_owned()))?;
+        let contents = contents
+            .chain_err(|| ErrorKind::DaemonUnreachable("no reply from daemon".to_owned()))?;
         let contents_length: &str = headers
             .get("Content-Length")
             .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
@@ -243,7 +385,7 @@ impl Connection {
 
         let expected_length = contents_length - 1; // trailing EOL is skipped
         if expected_length != contents.len() {
-            bail!(ErrorKind::Connection(format!(
+            bail!(ErrorKind::DaemonUnreachable(format!(
                 "expected {} bytes, got {}",
                 expected_length,
                 contents.len()
@@ -255,6 +397,11 @@ impl Connection {
         } else if status == "HTTP/1.1 500 Internal Server Error" {
             warn!("HTTP status: {}", status);
             contents // the contents should have a JSONRPC error field
+        } else if status == "HTTP/1.1 401 Unauthorized" {
+            // retried (with the re-read cookie, if it was rotated) or failed over
+            bail!(ErrorKind::DaemonUnreachable(
+                "unauthorized by daemon".to_owned()
+            ));
         } else {
             bail!(
                 "request failed {:?}: {:?} = {:?}",
@@ -266,6 +413,70 @@ impl Connection {
     }
 }
 
+struct PoolState {
+    idle: Vec<Connection>,
+    in_use: usize, // including those being connected
+}
+
+// The persistent connections to an endpoint, opened as needed up to --daemon-rpc-connections
+// and shared by all the (reconnected) copies of a Daemon. The calls beyond that wait in turn.
+struct ConnectionPool {
+    size: usize,
+    state: Mutex<PoolState>,
+    released: Condvar,
+    idle_gauge: Gauge,
+    in_use_gauge: Gauge,
+    waiting_gauge: Gauge,
+}
+
+impl ConnectionPool {
+    fn new(size: usize, addr: &DaemonAddr, connections: &GaugeVec, waiting: &GaugeVec) -> Self {
+        let addr = addr.to_string();
+        ConnectionPool {
+            size,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                in_use: 0,
+            }),
+            released: Condvar::new(),
+            idle_gauge: connections.with_label_values(&[&addr, "idle"]),
+            in_use_gauge: connections.with_label_values(&[&addr, "in_use"]),
+            waiting_gauge: waiting.with_label_values(&[&addr]),
+        }
+    }
+
+    fn observe(&self, state: &PoolState) {
+        self.idle_gauge.set(state.idle.len() as f64);
+        self.in_use_gauge.set(state.in_use as f64);
+    }
+
+    // An idle connection, or None if a new one should be opened (and released once done with)
+    fn take(&self) -> Option<Connection> {
+        let mut state = self.state.lock().unwrap();
+        if state.idle.is_empty() && state.in_use >= self.size {
+            self.waiting_gauge.inc();
+            while state.idle.is_empty() && state.in_use >= self.size {
+                state = self.released.wait(state).unwrap();
+            }
+            self.waiting_gauge.dec();
+        }
+        state.in_use += 1;
+        let conn = state.idle.pop();
+        self.observe(&state);
+        conn
+    }
+
+    // The failed connections are dropped rather than released, as they may be left in an
+    // inconsistent state
+    fn release(&self, conn: Option<Connection>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use -= 1;
+        state.idle.extend(conn);
+        self.observe(&state);
+        self.released.notify_one();
+    }
+}
+
 struct Counter {
     value: Mutex<u64>,
 }
@@ -284,35 +495,179 @@ impl Counter {
     }
 }
 
+// The read-only methods that get spread over the endpoints with --daemon-rpc-balance
+const BALANCED_METHODS: &[&str] = &[
+    "getblock",
+    "getblockhash",
+    "getblockheader",
+    "getrawtransaction",
+    "estimatesmartfee",
+];
+
+// Endpoints further behind the best one are considered down, so that they don't serve stale data
+const MAX_ENDPOINT_LAG: u64 = 2;
+
+#[derive(Clone)]
+pub struct RpcOptions {
+    pub timeout: Duration,
+    pub connections: usize, // per endpoint
+    pub batch_size: usize,  // calls per JSONRPC batch request
+    pub balance_reads: bool,
+    pub health_check_interval: Duration,
+    pub proxy: Option<SocketAddr>,
+}
+
+impl RpcOptions {
+    pub fn new(config: &Config) -> Self {
+        RpcOptions {
+            timeout: Duration::from_secs(config.daemon_rpc_timeout),
+            connections: config.daemon_rpc_connections,
+            batch_size: config.daemon_rpc_batch_size,
+            balance_reads: config.daemon_rpc_balance,
+            health_check_interval: Duration::from_secs(10),
+            proxy: config.daemon_rpc_proxy,
+        }
+    }
+}
+
+// Shared by all the (reconnected) copies of a Daemon
+struct Endpoint {
+    addr: DaemonAddr,
+    up: AtomicBool,
+    pool: ConnectionPool,
+}
+
+fn set_endpoint_up(endpoint: &Endpoint, up: bool, gauge: &GaugeVec) {
+    if endpoint.up.swap(up, Ordering::SeqCst) != up {
+        if up {
+            info!("bitcoind at {} is back up", endpoint.addr);
+        } else {
+            warn!("bitcoind at {} is down", endpoint.addr);
+        }
+    }
+    gauge
+        .with_label_values(&[&endpoint.addr.to_string()])
+        .set(if up { 1.0 } else { 0.0 });
+}
+
+// A single call over a new connection, outside of a Daemon (for the health checks, and to query
+// other nodes than ours)
+pub fn call_once(
+    addr: &DaemonAddr,
+    cookie_getter: &Arc<CookieGetter>,
+    options: &RpcOptions,
+    method: &str,
+    params: Value,
+) -> Result<Value> {
+    let mut conn = Connection::new(addr, Arc::clone(cookie_getter), options)?;
+    let request = json!({"method": method, "params": params, "id": 0});
+    conn.send(&request.to_string())?;
+    let reply = from_str(&conn.recv()?).chain_err(|| "invalid JSON")?;
+    parse_jsonrpc_reply(reply, method, 0)
+}
+
+fn getblockcount(
+    addr: &DaemonAddr,
+    cookie_getter: &Arc<CookieGetter>,
+    options: &RpcOptions,
+) -> Result<u64> {
+    call_once(addr, cookie_getter, options, "getblockcount", json!([]))?
+        .as_u64()
+        .chain_err(|| "non-numeric block count")
+}
+
+// Periodically probe all the endpoints (including those that are down, so that they're used
+// again once they recover), and mark those lagging behind as down.
+fn start_health_checks(
+    endpoints: Arc<Vec<Endpoint>>,
+    cookie_getter: Arc<CookieGetter>,
+    options: RpcOptions,
+    gauge: GaugeVec,
+) {
+    spawn_thread("daemon_health", move || loop {
+        let heights: Vec<Option<u64>> = endpoints
+            .iter()
+            .map(|endpoint| {
+                getblockcount(&endpoint.addr, &cookie_getter, &options)
+                    .map_err(|e| debug!("health check of {} failed: {}", endpoint.addr, e))
+                    .ok()
+            })
+            .collect();
+        let best = heights.iter().filter_map(|height| *height).max();
+        for (endpoint, height) in endpoints.iter().zip(heights) {
+            let up = match (height, best) {
+                (Some(height), Some(best)) => height + MAX_ENDPOINT_LAG >= best,
+                _ => false,
+            };
+            set_endpoint_up(endpoint, up, &gauge);
+        }
+        thread::sleep(options.health_check_interval);
+    });
+}
+
 pub struct Daemon {
-    daemon_dir: PathBuf,
+    blocks_dir: PathBuf,
     network: Network,
-    conn: Mutex<Connection>,
-    message_id: Counter, // for monotonic JSONRPC 'id'
+    endpoints: Arc<Vec<Endpoint>>,
+    cookie_getter: Arc<CookieGetter>,
+    options: RpcOptions,
+    batch_workers: Arc<rayon::ThreadPool>, // sending the chunks of the large batches
+    next_endpoint: Arc<AtomicUsize>,       // for balancing the read-only calls
+    message_id: Counter,                   // for monotonic JSONRPC 'id'
     signal: Waiter,
 
     // monitoring
     latency: HistogramVec,
     size: HistogramVec,
+    endpoint_up: GaugeVec,
 }
 
 impl Daemon {
     pub fn new(
-        daemon_dir: &PathBuf,
-        daemon_rpc_addr: SocketAddr,
+        blocks_dir: &PathBuf,
+        daemon_rpc_addrs: &[DaemonAddr],
+        options: RpcOptions,
         cookie_getter: Arc<CookieGetter>,
         network: Network,
         signal: Waiter,
         metrics: &Metrics,
     ) -> Result<Daemon> {
+        assert!(!daemon_rpc_addrs.is_empty(), "no daemon RPC address");
+        let connections = metrics.gauge_vec(
+            MetricOpts::new(
+                "daemon_rpc_connections",
+                "Number of persistent connections to each bitcoind endpoint, by state",
+            ),
+            &["addr", "state"],
+        );
+        let waiting = metrics.gauge_vec(
+            MetricOpts::new(
+                "daemon_rpc_waiting",
+                "Number of RPC calls waiting for a connection to each bitcoind endpoint",
+            ),
+            &["addr"],
+        );
+        let endpoints: Vec<Endpoint> = daemon_rpc_addrs
+            .iter()
+            .map(|addr| Endpoint {
+                addr: addr.clone(),
+                up: AtomicBool::new(true),
+                pool: ConnectionPool::new(options.connections, addr, &connections, &waiting),
+            })
+            .collect();
+        let batch_workers = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.connections)
+            .thread_name(|i| format!("daemon-batch-{}", i))
+            .build()
+            .chain_err(|| "failed to create the daemon batch workers")?;
         let daemon = Daemon {
-            daemon_dir: daemon_dir.clone(),
+            blocks_dir: blocks_dir.clone(),
             network,
-            conn: Mutex::new(Connection::new(
-                daemon_rpc_addr,
-                cookie_getter,
-                signal.clone(),
-            )?),
+            endpoints: Arc::new(endpoints),
+            cookie_getter,
+            options,
+            batch_workers: Arc::new(batch_workers),
+            next_endpoint: Arc::new(AtomicUsize::new(0)),
             message_id: Counter::new(),
             signal: signal.clone(),
             latency: metrics.histogram_vec(
@@ -323,7 +678,14 @@ impl Daemon {
                 HistogramOpts::new("daemon_bytes", "Bitcoind RPC size (in bytes)"),
                 &["method", "dir"],
             ),
+            endpoint_up: metrics.gauge_vec(
+                MetricOpts::new("daemon_up", "Whether each bitcoind endpoint is up"),
+                &["addr"],
+            ),
         };
+        for endpoint in daemon.endpoints.iter() {
+            set_endpoint_up(endpoint, true, &daemon.endpoint_up);
+        }
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
         if network_info.version < 00_16_00_00 {
@@ -332,6 +694,18 @@ impl Daemon {
                 network_info.subversion,
             )
         }
+        // the network's magic and address prefixes depend on it
+        #[cfg(not(feature = "liquid"))]
+        {
+            let genesis_hash = parse_hash(&daemon.request("getblockhash", json!([0]))?)?;
+            if genesis_hash != network.genesis_hash() {
+                bail!(
+                    "bitcoind isn't on the {:?} network (its genesis block is {})",
+                    network,
+                    genesis_hash
+                );
+            }
+        }
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
         if blockchain_info.pruned == true {
@@ -352,24 +726,36 @@ impl Daemon {
             );
             signal.wait(Duration::from_secs(5))?;
         }
+        if daemon.endpoints.len() > 1 {
+            start_health_checks(
+                Arc::clone(&daemon.endpoints),
+                Arc::clone(&daemon.cookie_getter),
+                daemon.options.clone(),
+                daemon.endpoint_up.clone(),
+            );
+        }
         Ok(daemon)
     }
 
     pub fn reconnect(&self) -> Result<Daemon> {
         Ok(Daemon {
-            daemon_dir: self.daemon_dir.clone(),
+            blocks_dir: self.blocks_dir.clone(),
             network: self.network,
-            conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
+            endpoints: Arc::clone(&self.endpoints),
+            cookie_getter: Arc::clone(&self.cookie_getter),
+            options: self.options.clone(),
+            batch_workers: Arc::clone(&self.batch_workers),
+            next_endpoint: Arc::clone(&self.next_endpoint),
             message_id: Counter::new(),
             signal: self.signal.clone(),
             latency: self.latency.clone(),
             size: self.size.clone(),
+            endpoint_up: self.endpoint_up.clone(),
         })
     }
 
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
-        let mut path = self.daemon_dir.clone();
-        path.push("blocks");
+        let mut path = self.blocks_dir.clone();
         path.push("blk*.dat");
         debug!("listing block files at {:?}", path);
         let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
@@ -384,15 +770,53 @@ impl Daemon {
         self.network.magic()
     }
 
-    fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
-        let mut conn = self.conn.lock().unwrap();
+    fn call_jsonrpc(
+        &self,
+        index: usize,
+        method: &str,
+        request: &Value,
+        timeout: Duration,
+    ) -> Result<Value> {
+        let _span = tracing::info_span!(
+            "daemon_rpc",
+            method,
+            endpoint = %self.endpoints[index].addr
+        )
+        .entered();
+        let endpoint = &self.endpoints[index];
+        let mut conn = match endpoint.pool.take() {
+            Some(conn) => conn,
+            None => match Connection::new(
+                &endpoint.addr,
+                Arc::clone(&self.cookie_getter),
+                &self.options,
+            ) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    endpoint.pool.release(None);
+                    return Err(e);
+                }
+            },
+        };
         let timer = self.latency.with_label_values(&[method]).start_timer();
         let request = request.to_string();
-        conn.send(&request)?;
+        let response = match conn
+            .set_read_timeout(timeout)
+            .and_then(|_| conn.send(&request))
+            .and_then(|_| conn.recv())
+        {
+            Ok(response) => {
+                endpoint.pool.release(Some(conn));
+                response
+            }
+            Err(e) => {
+                endpoint.pool.release(None);
+                return Err(e);
+            }
+        };
         self.size
             .with_label_values(&[method, "send"])
             .observe(request.len() as f64);
-        let response = conn.recv()?;
         let result: Value = from_str(&response).chain_err(|| "invalid JSON")?;
         timer.observe_duration();
         self.size
@@ -401,14 +825,24 @@ impl Daemon {
         Ok(result)
     }
 
-    fn handle_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+    fn send_request_batch(
+        &self,
+        index: usize,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Value>> {
         let id = self.message_id.next();
         let reqs = params_list
             .iter()
             .map(|params| json!({"method": method, "params": params, "id": id}))
             .collect();
+        // The daemon replies to a block batch only once it has read all the blocks from disk
+        let timeout = match method {
+            "getblock" => self.options.timeout * params_list.len().max(1) as u32,
+            _ => self.options.timeout,
+        };
         let mut results = vec![];
-        let mut replies = self.call_jsonrpc(method, &reqs)?;
+        let mut replies = self.call_jsonrpc(index, method, &reqs, timeout)?;
         if let Some(replies_vec) = replies.as_array_mut() {
             for reply in replies_vec {
                 results.push(parse_jsonrpc_reply(reply.take(), method, id)?)
@@ -418,18 +852,67 @@ impl Daemon {
         bail!("non-array replies: {:?}", replies);
     }
 
+    // The batches larger than --daemon-rpc-batch-size are split into chunks, sent concurrently
+    // over the endpoint's connections
+    fn handle_request_batch(
+        &self,
+        index: usize,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Value>> {
+        if params_list.len() <= self.options.batch_size {
+            return self.send_request_batch(index, method, params_list);
+        }
+        let span = tracing::Span::current();
+        let chunks: Vec<&[Value]> = params_list.chunks(self.options.batch_size).collect();
+        let results: Vec<Vec<Value>> = self.batch_workers.install(|| {
+            chunks
+                .par_iter()
+                .map(|chunk| span.in_scope(|| self.send_request_batch(index, method, chunk)))
+                .collect::<Result<_>>()
+        })?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    // The endpoints to try, in order: starting from the first one (or the next one in turn for
+    // balanced calls), with those that are down only tried as a last resort.
+    fn endpoints_order(&self, method: &str) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let start = if self.options.balance_reads && BALANCED_METHODS.contains(&method) {
+            self.next_endpoint.fetch_add(1, Ordering::Relaxed) % count
+        } else {
+            0
+        };
+        let mut order: Vec<usize> = (0..count).map(|i| (start + i) % count).collect();
+        order.sort_by_key(|&index| !self.endpoints[index].up.load(Ordering::SeqCst));
+        order
+    }
+
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
         loop {
-            match self.handle_request_batch(method, params_list) {
-                Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3))?;
-                    let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
-                    continue;
+            for index in self.endpoints_order(method) {
+                match self.handle_request_batch(index, method, params_list) {
+                    Err(e) => match e.kind() {
+                        ErrorKind::DaemonUnreachable(msg) => {
+                            warn!(
+                                "{} failed at {}: {}",
+                                method, self.endpoints[index].addr, msg
+                            );
+                            set_endpoint_up(&self.endpoints[index], false, &self.endpoint_up);
+                        }
+                        _ => return Err(e),
+                    },
+                    result => return result,
                 }
-                result => return result,
             }
+            // broadcasts are made on behalf of clients, which shouldn't be kept waiting
+            if method == "sendrawtransaction" {
+                bail!(ErrorKind::DaemonUnreachable(
+                    "no bitcoind is reachable".to_owned()
+                ));
+            }
+            warn!("no bitcoind is reachable, reconnecting");
+            self.signal.wait(Duration::from_secs(3))?;
         }
     }
 
@@ -520,6 +1003,25 @@ impl Daemon {
         Ok(txs)
     }
 
+    // Whether each output is in bitcoind's UTXO set (as of its tip, ignoring the mempool)
+    pub fn gettxouts(&self, outpoints: &[OutPoint]) -> Result<Vec<bool>> {
+        let params_list: Vec<Value> = outpoints
+            .iter()
+            .map(|outpoint| {
+                json!([
+                    outpoint.txid.to_hex(),
+                    outpoint.vout,
+                    /*include_mempool=*/ false
+                ])
+            })
+            .collect();
+        Ok(self
+            .requests("gettxout", &params_list)?
+            .iter()
+            .map(|value| !value.is_null())
+            .collect())
+    }
+
     pub fn getmempooltx(&self, txhash: &Sha256dHash) -> Result<Transaction> {
         let value = self.request(
             "getrawtransaction",
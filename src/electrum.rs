@@ -9,7 +9,6 @@ use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use error_chain::ChainedError;
 use hex;
 use serde_json::{from_str, Value};
 
@@ -20,7 +19,7 @@ use elements::encode::serialize;
 
 use crate::errors::*;
 use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::new_index::Query;
+use crate::new_index::{Query, QueryBudget};
 use crate::util::{
     full_hash, get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof, spawn_thread,
     BlockId, Channel, FullHash, HeaderEntry, SyncChannel,
@@ -62,7 +61,7 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
 
 // FIXME: implement caching and delta updates
 // FIXME: ensure stable ordering
-fn get_status_hash(txs: Vec<(Sha256dHash, Option<BlockId>)>) -> Option<FullHash> {
+pub fn get_status_hash(txs: Vec<(Sha256dHash, Option<BlockId>)>) -> Option<FullHash> {
     if txs.is_empty() {
         None
     } else {
@@ -217,7 +216,9 @@ impl Connection {
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
 
-        let history_txids = self.query.history_txids(&script_hash[..]);
+        let history_txids = self
+            .query
+            .history_txids(&script_hash[..], &QueryBudget::unlimited())?;
         let status_hash = get_status_hash(history_txids)
             .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
 
@@ -228,7 +229,9 @@ impl Connection {
     #[cfg(not(feature = "liquid"))]
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let (chain_stats, mempool_stats) = self.query.stats(&script_hash[..]);
+        let (chain_stats, mempool_stats) = self
+            .query
+            .stats(&script_hash[..], &QueryBudget::unlimited())?;
 
         Ok(
             json!({ "confirmed": chain_stats.funded_txo_sum - chain_stats.spent_txo_sum, "unconfirmed": mempool_stats.funded_txo_sum - mempool_stats.spent_txo_sum}),
@@ -237,7 +240,9 @@ impl Connection {
 
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let history_txids = self.query.history_txids(&script_hash[..]);
+        let history_txids = self
+            .query
+            .history_txids(&script_hash[..], &QueryBudget::unlimited())?;
         Ok(json!(Value::Array(
             history_txids
                 .into_iter()
@@ -248,7 +253,9 @@ impl Connection {
 
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let utxos = self.query.utxo(&script_hash[..]);
+        let utxos = self
+            .query
+            .utxo(&script_hash[..], &QueryBudget::unlimited())?;
         Ok(json!(Value::Array(
             utxos
                 .into_iter()
@@ -397,7 +404,9 @@ impl Connection {
             }
         }
         for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let history_txids = self.query.history_txids(&script_hash[..]);
+            let history_txids = self
+                .query
+                .history_txids(&script_hash[..], &QueryBudget::unlimited())?;
             let new_status_hash = get_status_hash(history_txids)
                 .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
             if new_status_hash == *status_hash {
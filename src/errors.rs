@@ -1,19 +1,172 @@
 use chan_signal::Signal;
 
-error_chain! {
-    types {
-        Error, ErrorKind, ResultExt, Result;
+use std::error::Error as StdError;
+use std::fmt;
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+// Mapped to distinct HTTP statuses and error codes by the REST API, when they're the error's own
+// kind (rather than the cause of another error)
+#[derive(Debug, thiserror::Error)]
+pub enum ErrorKind {
+    #[error("Daemon unreachable: {0}")]
+    DaemonUnreachable(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    InvalidInput(String),
+
+    #[error("Block {0} is no longer in the best chain")]
+    ReorgDetected(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
+    #[error("Query timed out after scanning {0} rows")]
+    QueryTimeout(usize),
+
+    #[error("Query exceeded the row limit after scanning {0} rows")]
+    QueryTooLarge(usize),
+
+    #[error("Iterrupted by SIG{0:?}")]
+    Interrupt(Signal),
+
+    // any other error, described by its message
+    #[error("{0}")]
+    Msg(String),
+}
+
+// An error of the given kind, along with the error that caused it (see ResultExt::chain_err)
+#[derive(Debug, thiserror::Error)]
+#[error("{kind}")]
+pub struct Error {
+    kind: ErrorKind,
+    #[source]
+    cause: Option<Box<dyn StdError + Send + 'static>>,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    // The error followed by its causes, one per line
+    pub fn display_chain(&self) -> DisplayChain<'_> {
+        DisplayChain(self)
     }
+}
+
+pub struct DisplayChain<'a>(&'a Error);
 
-    errors {
-        Connection(msg: String) {
-            description("Connection error")
-            display("Connection error: {}", msg)
+impl<'a> fmt::Display for DisplayChain<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Error: {}", self.0)?;
+        let mut cause = self.0.source();
+        while let Some(err) = cause {
+            writeln!(f, "Caused by: {}", err)?;
+            cause = err.source();
         }
+        Ok(())
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error { kind, cause: None }
+    }
+}
+
+impl From<String> for ErrorKind {
+    fn from(msg: String) -> Self {
+        ErrorKind::Msg(msg)
+    }
+}
+
+impl<'a> From<&'a str> for ErrorKind {
+    fn from(msg: &'a str) -> Self {
+        ErrorKind::Msg(msg.to_string())
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::from(ErrorKind::from(msg))
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(msg: &'a str) -> Self {
+        Error::from(ErrorKind::from(msg))
+    }
+}
+
+pub trait ResultExt<T> {
+    // Wraps the error (or the missing value) into an error of the kind returned by `callback`
+    fn chain_err<F, K>(self, callback: F) -> Result<T>
+    where
+        F: FnOnce() -> K,
+        K: Into<ErrorKind>;
+}
+
+impl<T, E> ResultExt<T> for ::std::result::Result<T, E>
+where
+    E: StdError + Send + 'static,
+{
+    fn chain_err<F, K>(self, callback: F) -> Result<T>
+    where
+        F: FnOnce() -> K,
+        K: Into<ErrorKind>,
+    {
+        self.map_err(|e| Error {
+            kind: callback().into(),
+            cause: Some(Box::new(e)),
+        })
+    }
+}
+
+impl<T> ResultExt<T> for Option<T> {
+    fn chain_err<F, K>(self, callback: F) -> Result<T>
+    where
+        F: FnOnce() -> K,
+        K: Into<ErrorKind>,
+    {
+        self.ok_or_else(|| Error::from(callback().into()))
+    }
+}
+
+// Returns early with an error built from a value or from a format string and its arguments
+macro_rules! bail {
+    ($e:expr) => {
+        return Err($e.into());
+    };
+    ($fmt:expr, $($arg:tt)+) => {
+        return Err(format!($fmt, $($arg)+).into());
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorKind, ResultExt};
 
-        Interrupt(signal: Signal) {
-            description("Interruption by external signal")
-            display("Iterrupted by SIG{:?}", signal)
+    #[test]
+    fn test_display_chain() {
+        let err = "full"
+            .parse::<u32>()
+            .chain_err(|| ErrorKind::StorageError("failed to write".to_string()))
+            .chain_err(|| "failed to index")
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::Msg(msg) => assert_eq!(msg, "failed to index"),
+            kind => panic!("unexpected kind {:?}", kind),
         }
+        assert_eq!(
+            err.display_chain().to_string(),
+            "Error: failed to index\n\
+             Caused by: Storage error: failed to write\n\
+             Caused by: invalid digit found in string\n"
+        );
+        assert_eq!(Error::from("oops").to_string(), "oops");
     }
 }
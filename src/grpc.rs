@@ -0,0 +1,380 @@
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::Hash;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use grpcio::{
+    Environment, RpcContext, RpcStatus, RpcStatusCode, Server, ServerBuilder, ServerStreamingSink,
+    UnarySink, WriteFlags,
+};
+use protobuf::RepeatedField;
+
+use std::collections::VecDeque;
+use std::mem;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::chain::{address, Network};
+use crate::errors::*;
+use crate::new_index::{compute_script_hash, Query, QueryBudget, ScriptStats};
+use crate::util::{full_hash, FullHash, HeaderEntry, TransactionStatus};
+
+use self::proto::electrs as pb;
+use self::proto::electrs_grpc::{create_electrs, Electrs};
+
+const MAX_REORG_DEPTH: usize = 100;
+
+#[allow(renamed_and_removed_lints, clippy::all)]
+mod proto {
+    pub mod electrs {
+        include!(concat!(env!("OUT_DIR"), "/electrs.rs"));
+    }
+    pub mod electrs_grpc {
+        include!(concat!(env!("OUT_DIR"), "/electrs_grpc.rs"));
+    }
+}
+
+// Hashes are exchanged in their display (reversed) byte order, like their hex representation
+fn hash_to_bytes(hash: &Sha256dHash) -> Vec<u8> {
+    hash.iter().rev().cloned().collect()
+}
+
+fn hash_from_bytes(bytes: &[u8]) -> Result<Sha256dHash, RpcStatus> {
+    let reversed: Vec<u8> = bytes.iter().rev().cloned().collect();
+    Sha256dHash::from_slice(&reversed).map_err(|_| invalid_argument("invalid hash"))
+}
+
+fn invalid_argument(msg: &str) -> RpcStatus {
+    RpcStatus::new(RpcStatusCode::InvalidArgument, Some(msg.to_string()))
+}
+
+fn not_found(msg: &str) -> RpcStatus {
+    RpcStatus::new(RpcStatusCode::NotFound, Some(msg.to_string()))
+}
+
+fn reply<T>(ctx: &RpcContext, sink: UnarySink<T>, result: Result<T, RpcStatus>) {
+    let f = match result {
+        Ok(resp) => sink.success(resp),
+        Err(status) => sink.fail(status),
+    };
+    ctx.spawn(f.map_err(|e| warn!("failed to reply to gRPC request: {:?}", e)));
+}
+
+fn block_message(query: &Query, header: &HeaderEntry) -> pb::Block {
+    let mut block = pb::Block::new();
+    block.set_hash(hash_to_bytes(header.hash()));
+    block.set_height(header.height() as u32);
+    block.set_header(serialize(header.header()));
+    if let Some(meta) = query.chain().get_block_meta(header.hash()) {
+        block.set_tx_count(meta.tx_count);
+        block.set_size(meta.size);
+        block.set_weight(meta.weight);
+    }
+    block
+}
+
+fn status_message(status: TransactionStatus) -> pb::TransactionStatus {
+    let mut msg = pb::TransactionStatus::new();
+    msg.set_confirmed(status.confirmed);
+    if let Some(height) = status.block_height {
+        msg.set_block_height(height as u32);
+    }
+    if let Some(hash) = status.block_hash {
+        msg.set_block_hash(hash_to_bytes(&hash));
+    }
+    if let Some(time) = status.block_time {
+        msg.set_block_time(time);
+    }
+    msg
+}
+
+fn stats_message(stats: &ScriptStats) -> pb::ScriptStats {
+    let mut msg = pb::ScriptStats::new();
+    msg.set_tx_count(stats.tx_count as u64);
+    msg.set_funded_txo_count(stats.funded_txo_count as u64);
+    msg.set_funded_txo_sum(stats.funded_txo_sum);
+    msg.set_spent_txo_count(stats.spent_txo_count as u64);
+    msg.set_spent_txo_sum(stats.spent_txo_sum);
+    if let (Some(height), Some(time)) = (stats.first_seen_height, stats.first_seen_time) {
+        msg.set_first_seen_height(height as u32);
+        msg.set_first_seen_time(time);
+    }
+    if let (Some(height), Some(time)) = (stats.last_seen_height, stats.last_seen_time) {
+        msg.set_last_seen_height(height as u32);
+        msg.set_last_seen_time(time);
+    }
+    msg
+}
+
+fn address_stats(query: &Query, scripthash: &FullHash) -> pb::AddressStats {
+    let (chain_stats, mempool_stats) = query
+        .stats(&scripthash[..], &QueryBudget::unlimited())
+        .expect("unlimited budget");
+    let mut msg = pb::AddressStats::new();
+    msg.set_scripthash(scripthash.to_vec());
+    msg.set_chain_stats(stats_message(&chain_stats));
+    msg.set_mempool_stats(stats_message(&mempool_stats));
+    msg
+}
+
+fn parse_script(req: &pb::ScriptRequest, network: Network) -> Result<FullHash, RpcStatus> {
+    if req.has_scripthash() {
+        let scripthash = req.get_scripthash();
+        if scripthash.len() != 32 {
+            return Err(invalid_argument("invalid scripthash"));
+        }
+        Ok(full_hash(scripthash))
+    } else if req.has_address() {
+        let addr = address::Address::from_str(req.get_address())
+            .map_err(|_| invalid_argument("invalid address"))?;
+        if !network.is_address_network(addr.network) {
+            return Err(invalid_argument("address on invalid network"));
+        }
+        Ok(compute_script_hash(&addr.script_pubkey()))
+    } else {
+        Err(invalid_argument("missing scripthash or address"))
+    }
+}
+
+// Subscribers are notified from the main loop, through `GrpcServer::notify()`
+#[derive(Default)]
+struct Subscriptions {
+    blocks: Vec<mpsc::UnboundedSender<pb::Block>>,
+    scripts: Vec<(
+        FullHash,
+        pb::AddressStats,
+        mpsc::UnboundedSender<pb::AddressStats>,
+    )>,
+}
+
+#[derive(Clone)]
+struct ElectrsService {
+    query: Arc<Query>,
+    network: Network,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+}
+
+impl ElectrsService {
+    fn stream<T: Send + 'static>(
+        ctx: &RpcContext,
+        sink: ServerStreamingSink<T>,
+        receiver: mpsc::UnboundedReceiver<T>,
+    ) {
+        let messages = receiver
+            .map(|msg| (msg, WriteFlags::default()))
+            .map_err(|_| grpcio::Error::RemoteStopped);
+        ctx.spawn(
+            sink.send_all(messages)
+                .map(|_| ())
+                .map_err(|e| debug!("gRPC subscription closed: {:?}", e)),
+        );
+    }
+}
+
+impl Electrs for ElectrsService {
+    fn get_block(&mut self, ctx: RpcContext, req: pb::BlockRequest, sink: UnarySink<pb::Block>) {
+        let chain = self.query.chain();
+        let result = if req.has_hash() {
+            hash_from_bytes(req.get_hash()).and_then(|hash| {
+                chain
+                    .height_by_hash(&hash)
+                    .and_then(|height| chain.header_by_height(height))
+                    .ok_or_else(|| not_found("block not found"))
+            })
+        } else {
+            chain
+                .header_by_height(req.get_height() as usize)
+                .ok_or_else(|| not_found("block not found"))
+        };
+        let result = result.map(|header| block_message(&self.query, &header));
+        reply(&ctx, sink, result)
+    }
+
+    fn get_transaction(
+        &mut self,
+        ctx: RpcContext,
+        req: pb::TransactionRequest,
+        sink: UnarySink<pb::Transaction>,
+    ) {
+        let result = hash_from_bytes(req.get_txid()).and_then(|txid| {
+            let raw = self
+                .query
+                .lookup_raw_txn(&txid)
+                .ok_or_else(|| not_found("transaction not found"))?;
+            let mut tx = pb::Transaction::new();
+            tx.set_txid(hash_to_bytes(&txid));
+            tx.set_raw(raw);
+            tx.set_status(status_message(self.query.get_tx_status(&txid)));
+            Ok(tx)
+        });
+        reply(&ctx, sink, result)
+    }
+
+    fn get_address_stats(
+        &mut self,
+        ctx: RpcContext,
+        req: pb::ScriptRequest,
+        sink: UnarySink<pb::AddressStats>,
+    ) {
+        let result = parse_script(&req, self.network)
+            .map(|scripthash| address_stats(&self.query, &scripthash));
+        reply(&ctx, sink, result)
+    }
+
+    fn get_utxos(
+        &mut self,
+        ctx: RpcContext,
+        req: pb::ScriptRequest,
+        sink: UnarySink<pb::UtxoList>,
+    ) {
+        let result = parse_script(&req, self.network).map(|scripthash| {
+            let utxos = self
+                .query
+                .utxo(&scripthash[..], &QueryBudget::unlimited())
+                .expect("unlimited budget")
+                .into_iter()
+                .map(|utxo| {
+                    let mut msg = pb::Utxo::new();
+                    msg.set_txid(hash_to_bytes(&utxo.txid));
+                    msg.set_vout(utxo.vout);
+                    msg.set_value(utxo.value);
+                    msg.set_status(status_message(TransactionStatus::from(utxo.confirmed)));
+                    msg
+                })
+                .collect();
+            let mut list = pb::UtxoList::new();
+            list.set_utxos(RepeatedField::from_vec(utxos));
+            list
+        });
+        reply(&ctx, sink, result)
+    }
+
+    fn subscribe_blocks(
+        &mut self,
+        ctx: RpcContext,
+        _req: pb::SubscribeBlocksRequest,
+        sink: ServerStreamingSink<pb::Block>,
+    ) {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscriptions.lock().unwrap().blocks.push(sender);
+        ElectrsService::stream(&ctx, sink, receiver);
+    }
+
+    fn subscribe_address(
+        &mut self,
+        ctx: RpcContext,
+        req: pb::ScriptRequest,
+        sink: ServerStreamingSink<pb::AddressStats>,
+    ) {
+        let scripthash = match parse_script(&req, self.network) {
+            Ok(scripthash) => scripthash,
+            Err(status) => {
+                ctx.spawn(sink.fail(status).map_err(|_| ()));
+                return;
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded();
+        let stats = address_stats(&self.query, &scripthash);
+        if sender.unbounded_send(stats.clone()).is_ok() {
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .scripts
+                .push((scripthash, stats, sender));
+        }
+        ElectrsService::stream(&ctx, sink, receiver);
+    }
+}
+
+pub struct GrpcServer {
+    server: Server,
+    query: Arc<Query>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    recent: Mutex<VecDeque<HeaderEntry>>, // the most recently notified blocks, to detect reorgs
+}
+
+impl GrpcServer {
+    pub fn start(addr: SocketAddr, network: Network, query: Arc<Query>) -> Result<GrpcServer> {
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let service = create_electrs(ElectrsService {
+            query: Arc::clone(&query),
+            network,
+            subscriptions: Arc::clone(&subscriptions),
+        });
+        let env = Arc::new(Environment::new(1));
+        let mut server = ServerBuilder::new(env)
+            .register_service(service)
+            .bind(addr.ip().to_string(), addr.port())
+            .build()
+            .chain_err(|| format!("failed to start gRPC server on {}", addr))?;
+        server.start();
+        info!("gRPC server running on {}", addr);
+        let recent = Mutex::new(vec![query.chain().best_header()].into_iter().collect());
+        Ok(GrpcServer {
+            server,
+            query,
+            subscriptions,
+            recent,
+        })
+    }
+
+    // Push the new blocks and changed address stats to the subscribers
+    pub fn notify(&self) {
+        let chain = self.query.chain();
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+
+        let best = chain.best_header();
+        let mut recent = self.recent.lock().unwrap();
+        if recent.back().map(HeaderEntry::hash) != Some(best.hash()) {
+            // drop the blocks that were reorged out, the new blocks start above the fork point
+            while let Some(header) = recent.pop_back() {
+                if chain.height_by_hash(header.hash()).is_some() {
+                    recent.push_back(header);
+                    break;
+                }
+            }
+            let from = recent
+                .back()
+                .map_or(best.height(), |header| header.height() + 1);
+            let headers: Vec<HeaderEntry> = (from..=best.height())
+                .filter_map(|height| chain.header_by_height(height))
+                .collect();
+            let blocks: Vec<pb::Block> = headers
+                .iter()
+                .map(|header| block_message(&self.query, header))
+                .collect();
+            subscriptions.blocks.retain(|sender| {
+                blocks
+                    .iter()
+                    .all(|block| sender.unbounded_send(block.clone()).is_ok())
+            });
+            recent.extend(headers);
+            while recent.len() > MAX_REORG_DEPTH {
+                recent.pop_front();
+            }
+        }
+
+        let scripts = mem::replace(&mut subscriptions.scripts, vec![]);
+        subscriptions.scripts = scripts
+            .into_iter()
+            .filter_map(|(scripthash, last, sender)| {
+                let stats = address_stats(&self.query, &scripthash);
+                if stats == last {
+                    Some((scripthash, last, sender))
+                } else if sender.unbounded_send(stats.clone()).is_ok() {
+                    Some((scripthash, stats, sender))
+                } else {
+                    None // unsubscribed
+                }
+            })
+            .collect();
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        let _ = self.server.shutdown().wait();
+        trace!("gRPC server is stopped");
+    }
+}
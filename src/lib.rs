@@ -6,7 +6,10 @@ extern crate bitcoin;
 extern crate chan_signal;
 extern crate crypto;
 extern crate dirs;
+extern crate flate2;
 extern crate glob;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
 extern crate hex;
 extern crate hyper;
 extern crate itertools;
@@ -16,13 +19,20 @@ extern crate lru_cache;
 extern crate num_cpus;
 extern crate page_size;
 extern crate prometheus;
+#[cfg(feature = "grpc")]
+extern crate protobuf;
 extern crate rayon;
 extern crate rocksdb;
 extern crate serde;
-extern crate stderrlog;
+extern crate serde_cbor;
+#[cfg(feature = "sled")]
+extern crate sled;
 extern crate sysconf;
+extern crate thiserror;
 extern crate time;
 extern crate tiny_http;
+extern crate tracing;
+extern crate tracing_subscriber;
 extern crate url;
 
 #[macro_use]
@@ -32,28 +42,42 @@ extern crate clap;
 #[macro_use]
 extern crate arrayref;
 #[macro_use]
-extern crate error_chain;
-#[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
-#[cfg(feature = "liquid")]
 #[macro_use]
 extern crate lazy_static;
 
+// first, so that its bail! macro is available to the other modules
+#[macro_use]
+pub mod errors;
+
+pub mod accounts;
+pub mod apikeys;
 pub mod chain;
 pub mod config;
+pub mod consensus;
 pub mod daemon;
 pub mod electrum;
-pub mod errors;
+#[cfg(all(feature = "grpc", not(feature = "liquid")))]
+pub mod grpc;
+pub mod logger;
 pub mod metrics;
 pub mod new_index;
+#[cfg(not(feature = "liquid"))]
+pub mod prices;
 pub mod rest;
 pub mod signal;
+pub mod tor;
+pub mod traces;
+#[cfg(feature = "ui")]
+pub mod ui;
 pub mod util;
+#[cfg(not(feature = "liquid"))]
+pub mod watch;
 
 #[cfg(feature = "liquid")]
 pub mod elements;
@@ -0,0 +1,317 @@
+// The log output, to stderr or to --log-file. The file is rotated once it reaches
+// --log-rotate-size or has been written to for --log-rotate-interval, into <file>.1 (the newest)
+// up to <file>.<n> for the --log-keep last ones. Lines are plain text, or JSON objects with
+// --log-format json:
+//
+//   {"timestamp": "...", "level": "INFO", "target": "electrs::rest", "thread": "...", "message": "...", "request_id": "..."}
+//
+// where the request id is the trace id of the HTTP request being handled (see traces.rs), also
+// returned as its X-Request-Id header. The level follows -v (and the runtime config), and can be
+// overridden per module with --log-filter, e.g. `electrs::rest=debug,hyper=warn`.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use time;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::traces;
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+// The level of the modules without a filter, which can change at runtime
+static LEVEL: AtomicUsize = AtomicUsize::new(0);
+// The highest level of the filters, for log::set_max_level()
+static MAX_FILTER_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("invalid log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub timestamp: bool, // of the text lines, the JSON ones always have one
+    pub file: Option<PathBuf>,
+    pub rotate_size: u64, // in bytes, 0 for no limit
+    pub rotate_interval: Option<Duration>,
+    pub keep: usize,
+    pub filters: Vec<(String, LevelFilter)>, // by module path
+}
+
+// Parses `<module>=<level>` pairs separated by commas
+pub fn parse_filters(filters: &str) -> std::result::Result<Vec<(String, LevelFilter)>, String> {
+    filters
+        .split(',')
+        .map(str::trim)
+        .filter(|filter| !filter.is_empty())
+        .map(|filter| {
+            let mut parts = filter.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(module), Some(level)) if !module.is_empty() => {
+                    let level = LevelFilter::from_str(level)
+                        .map_err(|_| format!("invalid log level in {}", filter))?;
+                    Ok((module.to_string(), level))
+                }
+                _ => Err(format!(
+                    "invalid log filter {} (expected <module>=<level>)",
+                    filter
+                )),
+            }
+        })
+        .collect()
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    rotate_size: u64,
+    rotate_interval: Option<Duration>,
+    keep: usize,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    fn open(config: &LogConfig, path: &Path) -> io::Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            opened: Instant::now(),
+            rotate_size: config.rotate_size,
+            rotate_interval: config.rotate_interval,
+            keep: config.keep,
+        })
+    }
+
+    fn rotation_due(&self) -> bool {
+        (self.rotate_size > 0 && self.size >= self.rotate_size)
+            || self
+                .rotate_interval
+                .map_or(false, |interval| self.opened.elapsed() >= interval)
+    }
+
+    // <file>.<keep> is dropped and the other rotated files shift by one
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.rotation_due() {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+struct Logger {
+    format: LogFormat,
+    timestamp: bool,
+    filters: Vec<(String, LevelFilter)>,
+    output: Mutex<Output>,
+}
+
+fn level_filter(index: usize) -> LevelFilter {
+    LEVELS[index.min(LEVELS.len() - 1)]
+}
+
+fn timestamp() -> String {
+    let now = time::now_utc();
+    format!(
+        "{}.{:03}Z",
+        time::strftime("%Y-%m-%dT%H:%M:%S", &now).unwrap(),
+        now.tm_nsec / 1_000_000
+    )
+}
+
+impl Logger {
+    // The filter of the longest matching module path, if any
+    fn level(&self, target: &str) -> LevelFilter {
+        self.filters
+            .iter()
+            .filter(|(module, _)| {
+                target.starts_with(module.as_str())
+                    && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or_else(
+                || level_filter(LEVEL.load(Ordering::Relaxed)),
+                |(_, level)| *level,
+            )
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let request_id = traces::current_trace_id();
+        match self.format {
+            LogFormat::Json => {
+                let mut line = json!({
+                    "timestamp": timestamp(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "thread": thread::current().name().unwrap_or_default(),
+                    "message": record.args().to_string(),
+                });
+                if let Some(request_id) = request_id {
+                    line["request_id"] = json!(request_id);
+                }
+                format!("{}\n", line)
+            }
+            LogFormat::Text => {
+                let mut line = String::new();
+                if self.timestamp {
+                    line.push_str(&timestamp());
+                    line.push_str(" - ");
+                }
+                line.push_str(&format!("{} - ", record.level()));
+                if let Some(request_id) = request_id {
+                    line.push_str(&format!("[{}] ", request_id));
+                }
+                line.push_str(&format!("{}\n", record.args()));
+                line
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        let mut output = self.output.lock().unwrap();
+        let result = match *output {
+            Output::Stderr => io::stderr().write_all(line.as_bytes()),
+            Output::File(ref mut file) => file.write_line(line.as_bytes()),
+        };
+        if let Err(e) = result {
+            // there's nowhere else to report it
+            eprintln!("failed to write log: {}", e);
+        }
+    }
+
+    fn flush(&self) {
+        let mut output = self.output.lock().unwrap();
+        let _ = match *output {
+            Output::Stderr => io::stderr().flush(),
+            Output::File(ref mut file) => file.file.flush(),
+        };
+    }
+}
+
+pub fn init(config: &LogConfig) -> Result<()> {
+    let output = match config.file {
+        Some(ref path) => Output::File(
+            RotatingFile::open(config, path)
+                .chain_err(|| format!("failed to open the log file {:?}", path))?,
+        ),
+        None => Output::Stderr,
+    };
+    let max_filter_level = config
+        .filters
+        .iter()
+        .map(|(_, level)| *level)
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    MAX_FILTER_LEVEL.store(max_filter_level as usize, Ordering::Relaxed);
+    let logger = Logger {
+        format: config.format,
+        timestamp: config.timestamp,
+        filters: config.filters.clone(),
+        output: Mutex::new(output),
+    };
+    log::set_logger(Box::leak(Box::new(logger))).map_err(|_| Error::from("a logger is already set"))
+}
+
+// Sets the level of the modules without a filter
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level.max(level_filter(MAX_FILTER_LEVEL.load(Ordering::Relaxed))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_filters;
+    use log::LevelFilter;
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(
+            parse_filters("electrs::rest=debug, hyper=warn").unwrap(),
+            vec![
+                ("electrs::rest".to_string(), LevelFilter::Debug),
+                ("hyper".to_string(), LevelFilter::Warn)
+            ]
+        );
+        assert_eq!(parse_filters("").unwrap(), vec![]);
+        assert!(parse_filters("electrs::rest").is_err());
+        assert!(parse_filters("electrs=loud").is_err());
+        assert!(parse_filters("=debug").is_err());
+    }
+}
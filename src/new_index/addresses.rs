@@ -0,0 +1,56 @@
+// An index of the addresses that received outputs, by their textual form, written to the history
+// db as blocks are indexed so that they can be searched by prefix (e.g. for autocompletion):
+//
+//   L{address} → {scripthash}
+//
+// Bech32 addresses are stored (and searched) in lowercase. Addresses from orphaned blocks are
+// kept, as they were used nonetheless.
+
+use crate::chain::Network;
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::{compute_script_hash, ChainQuery};
+use crate::util::script_to_address;
+
+// The human-readable parts of the bech32 addresses (of bitcoin and elements), which may also be
+// written in uppercase
+const BECH32_HRPS: &[&str] = &["bc1", "tb1", "bcrt1", "ex1", "lq1", "ert1", "el1"];
+
+fn address_key(address: &str) -> Vec<u8> {
+    [&b"L"[..], address.as_bytes()].concat()
+}
+
+pub fn index_addresses(block_entries: &[BlockEntry], network: &Network) -> Vec<DBRow> {
+    let mut rows = vec![];
+    for b in block_entries {
+        for tx in &b.block.txdata {
+            for txo in &tx.output {
+                let script = &txo.script_pubkey;
+                if let Some(address) = script_to_address(script, network) {
+                    rows.push(DBRow {
+                        key: address_key(&address),
+                        value: compute_script_hash(script).to_vec(),
+                    });
+                }
+            }
+        }
+    }
+    rows
+}
+
+// The known addresses starting with `prefix`, in lexicographic order
+pub fn search_addresses(chain: &ChainQuery, prefix: &str, limit: usize) -> Vec<String> {
+    let lowercase = prefix.to_lowercase();
+    let prefix = if BECH32_HRPS.iter().any(|hrp| lowercase.starts_with(hrp)) {
+        &lowercase
+    } else {
+        prefix
+    };
+    chain
+        .store()
+        .history_db()
+        .iter_scan(&address_key(prefix))
+        .take(limit)
+        .map(|row| String::from_utf8(row.key[1..].to_vec()).expect("invalid address"))
+        .collect()
+}
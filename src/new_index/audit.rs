@@ -0,0 +1,187 @@
+// Cross-checks the index against bitcoind, for operators suspecting index corruption: the tip,
+// the transactions of randomly sampled blocks, and whether randomly sampled outputs of these
+// blocks are unspent according to both.
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::util::hash::BitcoinHash;
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chain::OutPoint;
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::new_index::ChainQuery;
+use crate::util::is_spendable;
+
+const MAX_LISTED_TXIDS: usize = 10; // per discrepancy
+
+#[derive(Serialize)]
+pub struct AuditReport {
+    pub index_tip: Sha256dHash,
+    pub daemon_tip: Sha256dHash,
+    pub blocks_checked: usize,
+    pub outpoints_checked: usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Discrepancy {
+    // also expected briefly when bitcoind has a block the index hasn't caught up with yet
+    TipMismatch {
+        index: Sha256dHash,
+        daemon: Sha256dHash,
+    },
+    BlockHashMismatch {
+        height: usize,
+        index: Sha256dHash,
+        daemon: Sha256dHash,
+    },
+    BlockTxsMissing {
+        height: usize,
+        hash: Sha256dHash,
+    },
+    BlockTxids {
+        height: usize,
+        hash: Sha256dHash,
+        missing_count: usize, // from the index
+        unexpected_count: usize,
+        missing: Vec<Sha256dHash>,
+        unexpected: Vec<Sha256dHash>,
+    },
+    UtxoMismatch {
+        txid: Sha256dHash,
+        vout: u32,
+        index_unspent: bool,
+        daemon_unspent: bool,
+    },
+}
+
+// xorshift64*, as the samples only need to be spread out
+struct Sampler(u64);
+
+impl Sampler {
+    fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        Sampler(u64::from(nanos) | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n as u64) as usize
+    }
+}
+
+pub fn audit(
+    chain: &ChainQuery,
+    daemon: &Daemon,
+    blocks: usize,
+    outpoints: usize,
+) -> Result<AuditReport> {
+    let mut discrepancies = vec![];
+    let index_tip = chain.best_hash();
+    let daemon_tip = daemon.getbestblockhash()?;
+    if index_tip != daemon_tip {
+        discrepancies.push(Discrepancy::TipMismatch {
+            index: index_tip,
+            daemon: daemon_tip,
+        });
+    }
+
+    let mut sampler = Sampler::new();
+    let best_height = chain.best_height();
+    let heights: Vec<usize> = (0..blocks)
+        .map(|_| sampler.below(best_height + 1))
+        .collect::<HashSet<usize>>()
+        .into_iter()
+        .collect();
+    let daemon_hashes: Vec<Sha256dHash> = daemon
+        .getblockheaders(&heights)?
+        .iter()
+        .map(|header| header.bitcoin_hash())
+        .collect();
+
+    let mut candidates = vec![]; // the spendable outputs of the sampled blocks
+    for (height, daemon_hash) in heights.iter().zip(daemon_hashes) {
+        let index_hash = chain
+            .hash_by_height(*height)
+            .chain_err(|| "missing header")?;
+        if index_hash != daemon_hash {
+            discrepancies.push(Discrepancy::BlockHashMismatch {
+                height: *height,
+                index: index_hash,
+                daemon: daemon_hash,
+            });
+            continue;
+        }
+        let index_txids: HashSet<Sha256dHash> = match chain.get_block_txids(&index_hash) {
+            Some(txids) => txids.into_iter().collect(),
+            None => {
+                discrepancies.push(Discrepancy::BlockTxsMissing {
+                    height: *height,
+                    hash: index_hash,
+                });
+                continue;
+            }
+        };
+        let block = daemon.getblock(&daemon_hash)?;
+        let daemon_txids: HashSet<Sha256dHash> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        if index_txids != daemon_txids {
+            let missing: Vec<Sha256dHash> =
+                daemon_txids.difference(&index_txids).cloned().collect();
+            let unexpected: Vec<Sha256dHash> =
+                index_txids.difference(&daemon_txids).cloned().collect();
+            discrepancies.push(Discrepancy::BlockTxids {
+                height: *height,
+                hash: index_hash,
+                missing_count: missing.len(),
+                unexpected_count: unexpected.len(),
+                missing: missing.into_iter().take(MAX_LISTED_TXIDS).collect(),
+                unexpected: unexpected.into_iter().take(MAX_LISTED_TXIDS).collect(),
+            });
+        }
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                if is_spendable(txout) {
+                    candidates.push(OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut sampled = vec![];
+    while sampled.len() < outpoints && !candidates.is_empty() {
+        let outpoint = candidates.swap_remove(sampler.below(candidates.len()));
+        sampled.push(outpoint);
+    }
+    let daemon_unspent = daemon.gettxouts(&sampled)?;
+    for (outpoint, daemon_unspent) in sampled.iter().zip(daemon_unspent) {
+        let index_unspent = chain.lookup_spend(outpoint).is_none();
+        if index_unspent != daemon_unspent {
+            discrepancies.push(Discrepancy::UtxoMismatch {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                index_unspent,
+                daemon_unspent,
+            });
+        }
+    }
+
+    Ok(AuditReport {
+        index_tip,
+        daemon_tip,
+        blocks_checked: heights.len(),
+        outpoints_checked: sampled.len(),
+        discrepancies,
+    })
+}
@@ -0,0 +1,135 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::errors::*;
+
+// The deadline is only checked every so many rows, to keep the overhead low
+const DEADLINE_CHECK_INTERVAL: usize = 1000;
+
+#[derive(Clone, Copy)]
+enum Exceeded {
+    Timeout,
+    Rows,
+}
+
+// Limits the work done by the history scans of a single request, so that a script with a huge
+// history can't keep a worker thread busy for minutes. The scans stop once the budget is
+// exhausted, and check() then fails.
+//
+// The history scans can also be truncated to a number of rows instead, returning partial results
+// (see truncated()) rather than failing.
+pub struct QueryBudget {
+    deadline: Option<Instant>,
+    max_rows: Option<usize>,
+    scanned_rows: Cell<usize>,
+    exceeded: Cell<Option<Exceeded>>,
+    max_history_rows: Option<usize>,
+    truncated: Cell<bool>,
+}
+
+impl QueryBudget {
+    pub fn new(config: &Config) -> Self {
+        QueryBudget {
+            deadline: match config.query_timeout_ms {
+                0 => None,
+                timeout => Some(Instant::now() + Duration::from_millis(timeout)),
+            },
+            max_rows: match config.query_max_rows {
+                0 => None,
+                max_rows => Some(max_rows),
+            },
+            scanned_rows: Cell::new(0),
+            exceeded: Cell::new(None),
+            max_history_rows: None,
+            truncated: Cell::new(false),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        QueryBudget {
+            deadline: None,
+            max_rows: None,
+            scanned_rows: Cell::new(0),
+            exceeded: Cell::new(None),
+            max_history_rows: None,
+            truncated: Cell::new(false),
+        }
+    }
+
+    // Truncate each history scan to `max_rows` rows (0 for no limit)
+    pub fn truncate_history(mut self, max_rows: usize) -> Self {
+        self.max_history_rows = match max_rows {
+            0 => None,
+            max_rows => Some(max_rows),
+        };
+        self
+    }
+
+    // Stops the history scan `iter` at the truncation limit, on top of the budget
+    pub fn limit_history<'a, I>(&'a self, iter: I) -> impl Iterator<Item = I::Item> + 'a
+    where
+        I: Iterator + 'a,
+    {
+        let max_rows = self.max_history_rows.unwrap_or(usize::max_value());
+        let mut rows = 0;
+        self.limit(iter).take_while(move |_| {
+            rows += 1;
+            if rows > max_rows {
+                self.truncated.set(true);
+            }
+            rows <= max_rows
+        })
+    }
+
+    // Whether a history scan was truncated, in which case its results only cover part of it
+    pub fn truncated(&self) -> bool {
+        self.truncated.get()
+    }
+
+    // Stops `iter` once the budget is exhausted, counting each item as a scanned row
+    pub fn limit<'a, I>(&'a self, iter: I) -> impl Iterator<Item = I::Item> + 'a
+    where
+        I: Iterator + 'a,
+    {
+        iter.take_while(move |_| self.charge())
+    }
+
+    fn charge(&self) -> bool {
+        if self.exceeded.get().is_some() {
+            return false;
+        }
+        let scanned_rows = self.scanned_rows.get() + 1;
+        self.scanned_rows.set(scanned_rows);
+        if self
+            .max_rows
+            .map_or(false, |max_rows| scanned_rows > max_rows)
+        {
+            self.exceeded.set(Some(Exceeded::Rows));
+        } else if scanned_rows % DEADLINE_CHECK_INTERVAL == 0
+            && self
+                .deadline
+                .map_or(false, |deadline| Instant::now() > deadline)
+        {
+            self.exceeded.set(Some(Exceeded::Timeout));
+        }
+        self.exceeded.get().is_none()
+    }
+
+    // Fails if the budget was exhausted, in which case the scanned results are incomplete
+    pub fn check(&self) -> Result<()> {
+        let scanned_rows = self.scanned_rows.get();
+        match self.exceeded.get() {
+            None => Ok(()),
+            Some(Exceeded::Timeout) => bail!(ErrorKind::QueryTimeout(scanned_rows)),
+            Some(Exceeded::Rows) => bail!(ErrorKind::QueryTooLarge(scanned_rows)),
+        }
+    }
+}
+
+// Reports the scanned rows to the span of the request (if any) once it's handled
+impl Drop for QueryBudget {
+    fn drop(&mut self) {
+        tracing::Span::current().record("rows", &(self.scanned_rows.get() as u64));
+    }
+}
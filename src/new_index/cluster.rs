@@ -0,0 +1,256 @@
+// Common-input-ownership clustering: scripts that are spent together as inputs of the same
+// transaction are assumed to be controlled by the same entity.
+//
+// Clusters are kept in the history db as a disjoint-set forest (union by size, without path
+// compression, so that rows are never rewritten except for the root sizes):
+//
+//   P{scripthash} → {parent scripthash}    (absent for roots, including singleton scripts)
+//   K{root scripthash} → {member count}    (absent for singleton scripts)
+//   N{scripthash}{child scripthash} → ""   (to enumerate the members of a cluster)
+//
+// Since unions are commutative, blocks can be processed in any order. Links are never removed,
+// so transactions from blocks that were later orphaned still contribute to the clusters.
+
+use bincode;
+use hex;
+
+#[cfg(not(feature = "liquid"))]
+use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::chain::{OutPoint, TxOut};
+use crate::new_index::db::{DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::{compute_script_hash, ChainQuery};
+use crate::util::{full_hash, has_prevout, Bytes, FullHash};
+
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::QueryBudget;
+
+// don't compute the aggregate balance of clusters larger than this (one stats lookup per member)
+#[cfg(not(feature = "liquid"))]
+const MAX_BALANCE_MEMBERS: u64 = 5000;
+
+#[derive(Serialize)]
+pub struct ClusterInfo {
+    pub cluster_id: String, // the root script of the cluster, may change as clusters merge
+    pub member_count: u64,
+    #[cfg(not(feature = "liquid"))]
+    pub balance: Option<u64>, // confirmed, omitted for large clusters
+}
+
+fn parent_key(scripthash: &FullHash) -> Bytes {
+    [&b"P"[..], &scripthash[..]].concat()
+}
+
+fn size_key(root: &FullHash) -> Bytes {
+    [&b"K"[..], &root[..]].concat()
+}
+
+fn child_key(parent: &FullHash, child: &FullHash) -> Bytes {
+    [&b"N"[..], &parent[..], &child[..]].concat()
+}
+
+fn get_parent(db: &DB, scripthash: &FullHash) -> Option<FullHash> {
+    db.get(&parent_key(scripthash)).map(|val| full_hash(&val))
+}
+
+fn get_size(db: &DB, root: &FullHash) -> u64 {
+    db.get(&size_key(root)).map_or(1, |val| {
+        bincode::deserialize(&val).expect("failed to parse cluster size")
+    })
+}
+
+// Pending cluster updates for a batch of blocks, on top of the state stored in the db
+struct ClusterBatch<'a> {
+    db: &'a DB,
+    parents: HashMap<FullHash, FullHash>,
+    sizes: HashMap<FullHash, u64>,
+    children: Vec<(FullHash, FullHash)>,
+}
+
+impl<'a> ClusterBatch<'a> {
+    fn find(&self, scripthash: &FullHash) -> FullHash {
+        let mut node = *scripthash;
+        loop {
+            let parent = match self.parents.get(&node) {
+                Some(parent) => Some(*parent),
+                None => get_parent(self.db, &node),
+            };
+            match parent {
+                Some(parent) => node = parent,
+                None => return node,
+            }
+        }
+    }
+
+    fn size(&self, root: &FullHash) -> u64 {
+        match self.sizes.get(root) {
+            Some(size) => *size,
+            None => get_size(self.db, root),
+        }
+    }
+
+    fn union(&mut self, a: &FullHash, b: &FullHash) {
+        let (root_a, root_b) = (self.find(a), self.find(b));
+        if root_a == root_b {
+            return;
+        }
+        let (size_a, size_b) = (self.size(&root_a), self.size(&root_b));
+        let (root, child) = if size_a >= size_b {
+            (root_a, root_b)
+        } else {
+            (root_b, root_a)
+        };
+        self.parents.insert(child, root);
+        self.sizes.remove(&child);
+        self.sizes.insert(root, size_a + size_b);
+        self.children.push((root, child));
+    }
+
+    fn into_rows(self) -> Vec<DBRow> {
+        let parents = self.parents.into_iter().map(|(scripthash, parent)| DBRow {
+            key: parent_key(&scripthash),
+            value: parent.to_vec(),
+        });
+        let sizes = self.sizes.into_iter().map(|(root, size)| DBRow {
+            key: size_key(&root),
+            value: bincode::serialize(&size).unwrap(),
+        });
+        let children = self.children.into_iter().map(|(parent, child)| DBRow {
+            key: child_key(&parent, &child),
+            value: vec![],
+        });
+        parents.chain(sizes).chain(children).collect()
+    }
+}
+
+// Link the input scripts of every transaction, returning the rows to write alongside the history
+// rows of the same blocks. Must not run concurrently with itself (the Indexer is single-threaded).
+pub fn index_clusters(
+    db: &DB,
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Vec<DBRow> {
+    let mut batch = ClusterBatch {
+        db,
+        parents: HashMap::new(),
+        sizes: HashMap::new(),
+        children: vec![],
+    };
+    for tx in block_entries.iter().flat_map(|b| b.block.txdata.iter()) {
+        let scripthashes: BTreeSet<FullHash> = tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| {
+                let prevout = previous_txos_map
+                    .get(&txin.previous_output)
+                    .expect(&format!("missing outpoint {:?}", txin.previous_output));
+                compute_script_hash(&prevout.script_pubkey)
+            })
+            .collect();
+        let mut scripthashes = scripthashes.iter();
+        if let Some(first) = scripthashes.next() {
+            for other in scripthashes {
+                batch.union(first, other);
+            }
+        }
+    }
+    batch.into_rows()
+}
+
+pub fn cluster_info(chain: &ChainQuery, scripthash: &FullHash) -> ClusterInfo {
+    let db = chain.store().history_db();
+    let mut root = *scripthash;
+    while let Some(parent) = get_parent(db, &root) {
+        root = parent;
+    }
+    let member_count = get_size(db, &root);
+
+    #[cfg(not(feature = "liquid"))]
+    let balance = if member_count <= MAX_BALANCE_MEMBERS {
+        Some(
+            cluster_members(db, &root)
+                .iter()
+                .map(|member| {
+                    let stats = chain
+                        .stats(&member[..], &QueryBudget::unlimited())
+                        .expect("unlimited budget");
+                    // spends may outweigh the indexed funding when pruning (see --prune-below)
+                    stats.funded_txo_sum.saturating_sub(stats.spent_txo_sum)
+                })
+                .sum(),
+        )
+    } else {
+        None
+    };
+
+    ClusterInfo {
+        cluster_id: hex::encode(&root),
+        member_count,
+        #[cfg(not(feature = "liquid"))]
+        balance,
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+fn cluster_members(db: &DB, root: &FullHash) -> HashSet<FullHash> {
+    let mut members = HashSet::new();
+    let mut pending = vec![*root];
+    while let Some(node) = pending.pop() {
+        if members.insert(node) {
+            pending.extend(
+                db.iter_scan(&[&b"N"[..], &node[..]].concat())
+                    .map(|row| full_hash(&row.key[33..])),
+            );
+        }
+    }
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_size, ClusterBatch};
+    use crate::new_index::db::{DBFlush, DB};
+    use std::collections::HashMap;
+    use tempfile;
+
+    fn new_batch(db: &DB) -> ClusterBatch {
+        ClusterBatch {
+            db,
+            parents: HashMap::new(),
+            sizes: HashMap::new(),
+            children: vec![],
+        }
+    }
+
+    #[test]
+    fn test_cluster_union_across_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path());
+        let (a, b, c, d, e) = ([1u8; 32], [2u8; 32], [3u8; 32], [4u8; 32], [5u8; 32]);
+
+        let mut batch = new_batch(&db);
+        batch.union(&a, &b);
+        batch.union(&a, &b);
+        db.write(batch.into_rows(), DBFlush::Enable);
+
+        let mut batch = new_batch(&db);
+        batch.union(&c, &b);
+        batch.union(&d, &e);
+        db.write(batch.into_rows(), DBFlush::Enable);
+
+        let batch = new_batch(&db);
+        let root = batch.find(&a);
+        assert_eq!(root, batch.find(&b));
+        assert_eq!(root, batch.find(&c));
+        assert_eq!(get_size(&db, &root), 3);
+        assert_ne!(root, batch.find(&d));
+        assert_eq!(batch.find(&d), batch.find(&e));
+        assert_eq!(get_size(&db, &batch.find(&d)), 2);
+
+        #[cfg(not(feature = "liquid"))]
+        assert_eq!(super::cluster_members(&db, &root).len(), 3);
+    }
+}
@@ -0,0 +1,113 @@
+// Chain-wide statistics bucketed per (UTC) day of the block timestamp. Each indexed block adds
+// its own contribution row to the history db:
+//
+//   W{day (big-endian u32)}{blockhash} → {contribution}
+//
+// The contributions are summed up at query time, skipping blocks that are not part of the best
+// chain, so that reorgs are accounted for. Blocks indexed by older versions have no rows.
+
+use bincode;
+use itertools::Itertools;
+
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::{parse_hash, ChainQuery};
+use crate::util::{full_hash, BlockStats, Bytes};
+
+const DAY_SECS: u32 = 86400;
+
+#[derive(Serialize, Deserialize)]
+struct BlockContribution {
+    tx_count: u32,
+    total_fees: u64,
+    size: u32,
+    weight: u32,
+    output_count: u32,
+}
+
+#[derive(Serialize)]
+pub struct DailyStats {
+    pub timestamp: u32, // start of the day
+    pub block_count: u32,
+    pub tx_count: u64,
+    pub total_fees: u64,
+    pub avg_block_size: u32,
+    pub avg_block_weight: u32,
+    pub new_outputs: u64,
+}
+
+impl DailyStats {
+    fn new(day: u32) -> Self {
+        DailyStats {
+            timestamp: day * DAY_SECS,
+            block_count: 0,
+            tx_count: 0,
+            total_fees: 0,
+            avg_block_size: 0,
+            avg_block_weight: 0,
+            new_outputs: 0,
+        }
+    }
+}
+
+pub fn day_of(timestamp: u32) -> u32 {
+    timestamp / DAY_SECS
+}
+
+fn day_filter(day: u32) -> Bytes {
+    [&b"W"[..], &day.to_be_bytes()[..]].concat()
+}
+
+pub fn block_row(block_entry: &BlockEntry, stats: &BlockStats) -> DBRow {
+    let block = &block_entry.block;
+    let contribution = BlockContribution {
+        tx_count: block.txdata.len() as u32,
+        total_fees: stats.total_fees,
+        size: block_entry.size,
+        weight: block.txdata.iter().map(|tx| tx.get_weight() as u32).sum(),
+        output_count: stats.output_count,
+    };
+    DBRow {
+        key: [
+            &day_filter(day_of(block.header.time))[..],
+            &block_entry.entry.hash()[..],
+        ]
+        .concat(),
+        value: bincode::serialize(&contribution).unwrap(),
+    }
+}
+
+// Get the statistics of each day in the given (inclusive) range that has blocks
+pub fn daily_stats(chain: &ChainQuery, from_day: u32, to_day: u32) -> Vec<DailyStats> {
+    let rows = chain
+        .store()
+        .history_db()
+        .iter_scan_from(b"W", &day_filter(from_day))
+        .map(|row| (u32::from_be_bytes(*array_ref![row.key, 1, 4]), row))
+        .take_while(|(day, _)| *day <= to_day)
+        // skip blocks that are not part of the best chain
+        .filter(|(_, row)| {
+            let blockhash = parse_hash(&full_hash(&row.key[5..]));
+            chain.height_by_hash(&blockhash).is_some()
+        });
+
+    let mut result = vec![];
+    for (day, rows) in &rows.group_by(|(day, _)| *day) {
+        let mut stats = DailyStats::new(day);
+        let (mut total_size, mut total_weight) = (0u64, 0u64);
+        for (_, row) in rows {
+            let contribution: BlockContribution =
+                bincode::deserialize(&row.value).expect("failed to parse daily stats row");
+            stats.block_count += 1;
+            stats.tx_count += contribution.tx_count as u64;
+            stats.total_fees += contribution.total_fees;
+            stats.new_outputs += contribution.output_count as u64;
+            total_size += contribution.size as u64;
+            total_weight += contribution.weight as u64;
+        }
+        stats.avg_block_size = (total_size / stats.block_count as u64) as u32;
+        stats.avg_block_weight = (total_weight / stats.block_count as u64) as u32;
+        result.push(stats);
+    }
+    result
+}
@@ -1,10 +1,18 @@
 use rocksdb;
 
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
 
+use crate::config::Config;
+use crate::errors::*;
+use crate::new_index::memdb::MemoryStore;
+#[cfg(feature = "sled")]
+use crate::new_index::sleddb::SledStore;
 use crate::util::Bytes;
 
-static DB_VERSION: u32 = 1;
+// Bumped whenever the rows format changes (see migrations.rs)
+pub const DB_VERSION: u32 = 2;
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct DBRow {
@@ -12,9 +20,51 @@ pub struct DBRow {
     pub value: Vec<u8>,
 }
 
+// The key-value operations used by the index, implemented by each storage backend
+pub trait KVStore: fmt::Debug + Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<Bytes>;
+
+    fn put(&self, key: &[u8], value: &[u8]);
+
+    // Atomically delete `deletes`, then write `rows` (sorted by key)
+    fn write(&self, rows: Vec<DBRow>, deletes: Vec<Bytes>, flush: DBFlush);
+
+    // Iterate over the rows in ascending key order, starting at `start_at`
+    fn iter_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a>;
+
+    // Iterate over the rows in descending key order, starting at the last key <= `start_at`
+    fn iter_reverse_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a>;
+
+    // Whether any row starts with each of the `prefixes`
+    fn has_prefixes(&self, prefixes: &[Bytes]) -> Vec<bool> {
+        prefixes
+            .iter()
+            .map(|prefix| {
+                self.iter_from(prefix)
+                    .next()
+                    .map_or(false, |row| row.key.starts_with(prefix))
+            })
+            .collect()
+    }
+
+    fn compact_range(&self, _start: &[u8], _end: Option<&[u8]>) {}
+
+    fn enable_auto_compaction(&self) {}
+
+    // Persist the in-memory write buffers to disk
+    fn flush(&self) {}
+
+    fn stats(&self) -> DBStats;
+
+    // Create a consistent copy of the database at `path`
+    fn checkpoint(&self, _path: &Path) -> Result<()> {
+        bail!("checkpoints are not supported by {:?}", self)
+    }
+}
+
 pub struct ScanIterator<'a> {
     prefix: Vec<u8>,
-    iter: rocksdb::DBIterator<'a>,
+    iter: Box<dyn Iterator<Item = DBRow> + 'a>,
     done: bool,
 }
 
@@ -25,21 +75,18 @@ impl<'a> Iterator for ScanIterator<'a> {
         if self.done {
             return None;
         }
-        let (key, value) = self.iter.next()?;
-        if !key.starts_with(&self.prefix) {
+        let row = self.iter.next()?;
+        if !row.key.starts_with(&self.prefix) {
             self.done = true;
             return None;
         }
-        Some(DBRow {
-            key: key.to_vec(),
-            value: value.to_vec(),
-        })
+        Some(row)
     }
 }
 
 pub struct ReverseScanIterator<'a> {
     prefix: Vec<u8>,
-    iter: rocksdb::DBRawIterator<'a>,
+    iter: Box<dyn Iterator<Item = DBRow> + 'a>,
     done: bool,
 }
 
@@ -47,28 +94,113 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
     type Item = DBRow;
 
     fn next(&mut self) -> Option<DBRow> {
-        if self.done || !self.iter.valid() {
+        if self.done {
             return None;
         }
-
-        let key = self.iter.key().unwrap();
-        if !key.starts_with(&self.prefix) {
+        let row = self.iter.next()?;
+        if !row.key.starts_with(&self.prefix) {
             self.done = true;
             return None;
         }
-        let value = self.iter.value().unwrap();
+        Some(row)
+    }
+}
 
-        self.iter.prev();
+pub struct DB {
+    backend: Box<dyn KVStore>,
+}
 
-        Some(DBRow { key, value })
+impl fmt::Debug for DB {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.backend)
     }
 }
 
-#[derive(Debug)]
-pub struct DB {
-    db: rocksdb::DB,
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DBBackend {
+    RocksDB,
+    #[cfg(feature = "sled")]
+    Sled,
+    Memory, // nothing is persisted, mostly useful for tests
 }
 
+impl DBBackend {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "rocksdb" => DBBackend::RocksDB,
+            #[cfg(feature = "sled")]
+            "sled" => DBBackend::Sled,
+            "memory" => DBBackend::Memory,
+            _ => return None,
+        })
+    }
+}
+
+// Storage backend and RocksDB tuning, set through the --db-* options
+#[derive(Clone, Debug)]
+pub struct DBOptions {
+    pub backend: DBBackend,
+    pub block_cache_size: usize, // in bytes, 0 for RocksDB's default
+    pub write_buffer_size: usize,
+    pub compression: rocksdb::DBCompressionType,
+    pub background_jobs: i32,
+}
+
+impl Default for DBOptions {
+    fn default() -> Self {
+        DBOptions {
+            backend: DBBackend::RocksDB,
+            block_cache_size: 0,
+            write_buffer_size: 256 << 20,
+            compression: rocksdb::DBCompressionType::Snappy,
+            background_jobs: 2,
+        }
+    }
+}
+
+impl DBOptions {
+    // The options for the `name` database
+    pub fn new(config: &Config, name: &str) -> Self {
+        let compression = config
+            .db_compression
+            .get(name)
+            .or_else(|| config.db_compression.get("*"))
+            .map_or("snappy", |c| c.as_str());
+        DBOptions {
+            backend: config.db_backend,
+            block_cache_size: config.db_block_cache_mb << 20,
+            write_buffer_size: config.db_write_buffer_mb << 20,
+            compression: compression_type(compression).expect("invalid compression type"),
+            background_jobs: config.db_background_jobs as i32,
+        }
+    }
+}
+
+pub fn compression_type(name: &str) -> Option<rocksdb::DBCompressionType> {
+    Some(match name {
+        "none" => rocksdb::DBCompressionType::None,
+        "snappy" => rocksdb::DBCompressionType::Snappy,
+        "zlib" => rocksdb::DBCompressionType::Zlib,
+        "bz2" => rocksdb::DBCompressionType::Bz2,
+        "lz4" => rocksdb::DBCompressionType::Lz4,
+        "lz4hc" => rocksdb::DBCompressionType::Lz4hc,
+        "zstd" => rocksdb::DBCompressionType::Zstd,
+        _ => return None,
+    })
+}
+
+// Not all of the stats are available with every backend
+#[derive(Serialize, Debug, Default)]
+pub struct DBStats {
+    pub size: Option<u64>, // on-disk size of the live data files
+    pub estimated_keys: Option<u64>,
+    pub memtables_size: Option<u64>,
+    pub block_cache_usage: Option<u64>,
+    pub block_cache_hit_ratio: Option<f64>,
+}
+
+pub const COMPACTION_STEPS: usize = 256;
+
 #[derive(Copy, Clone, Debug)]
 pub enum DBFlush {
     Disable,
@@ -77,86 +209,221 @@ pub enum DBFlush {
 
 impl DB {
     pub fn open(path: &Path) -> DB {
-        debug!("opening DB at {:?}", path);
-        let mut db_opts = rocksdb::Options::default();
-        db_opts.create_if_missing(true);
-        db_opts.set_max_open_files(-1); // TODO: make sure to `ulimit -n` this process correctly
-        db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
-        db_opts.set_target_file_size_base(256 << 20);
-        db_opts.set_write_buffer_size(256 << 20);
-        db_opts.set_disable_auto_compactions(true); // for initial bulk load
-
-        // db_opts.set_advise_random_on_open(???);
-        db_opts.set_compaction_readahead_size(1 << 20);
-        db_opts.increase_parallelism(2);
-
-        // let mut block_opts = rocksdb::BlockBasedOptions::default();
-        // block_opts.set_block_size(???);
+        DB::open_with(path, &DBOptions::default())
+    }
 
-        let db = DB {
-            db: rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB"),
+    pub fn open_with(path: &Path, options: &DBOptions) -> DB {
+        debug!("opening DB at {:?} with {:?}", path, options);
+        let backend: Box<dyn KVStore> = match options.backend {
+            DBBackend::RocksDB => Box::new(RocksDBStore::open(path, options)),
+            #[cfg(feature = "sled")]
+            DBBackend::Sled => Box::new(SledStore::open(path)),
+            DBBackend::Memory => Box::new(MemoryStore::default()),
         };
+        let db = DB { backend };
         db.verify_compatibility();
         db
     }
 
-    pub fn full_compaction(&self) {
+    pub fn in_memory() -> DB {
+        DB::open_with(
+            Path::new(":memory:"),
+            &DBOptions {
+                backend: DBBackend::Memory,
+                ..DBOptions::default()
+            },
+        )
+    }
+
+    // Compacts the key space one leading byte at a time, so that progress can be reported
+    // and each step keeps a bounded amount of data in flight.
+    pub fn full_compaction<F>(&self, progress: F)
+    where
+        F: Fn(usize),
+    {
         // TODO: make sure this doesn't fail silently
-        debug!("starting full compaction on {:?}", self.db);
-        self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
-        debug!("finished full compaction on {:?}", self.db);
+        debug!("starting full compaction on {:?}", self.backend);
+        for prefix in 0..=255u8 {
+            let next = prefix.checked_add(1).map(|next| [next]);
+            self.backend
+                .compact_range(&[prefix], next.as_ref().map(|next| &next[..]));
+            progress(1);
+        }
+        debug!("finished full compaction on {:?}", self.backend);
+    }
+
+    // Estimated on-disk size of the live data files (in bytes)
+    pub fn estimate_size(&self) -> Option<u64> {
+        self.backend.stats().size
+    }
+
+    pub fn stats(&self) -> DBStats {
+        self.backend.stats()
     }
 
     pub fn enable_auto_compaction(&self) {
-        let opts = [("disable_auto_compactions", "false")];
-        self.db.set_options(&opts).unwrap();
+        self.backend.enable_auto_compaction();
     }
 
-    pub fn raw_iterator(&self) -> rocksdb::DBRawIterator {
-        self.db.raw_iterator()
+    pub fn checkpoint(&self, path: &Path) -> Result<()> {
+        self.backend.checkpoint(path)
     }
 
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
-        ScanIterator {
-            prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
-            done: false,
-        }
+        self.iter_scan_from(prefix, prefix)
     }
 
     pub fn iter_scan_from(&self, prefix: &[u8], start_at: &[u8]) -> ScanIterator {
-        let iter = self.db.iterator(rocksdb::IteratorMode::From(
-            start_at,
-            rocksdb::Direction::Forward,
-        ));
         ScanIterator {
             prefix: prefix.to_vec(),
-            iter,
+            iter: self.backend.iter_from(start_at),
             done: false,
         }
     }
 
     pub fn iter_scan_reverse(&self, prefix: &[u8], prefix_max: &[u8]) -> ReverseScanIterator {
-        let mut iter = self.db.raw_iterator();
-        iter.seek_for_prev(prefix_max);
-
         ReverseScanIterator {
             prefix: prefix.to_vec(),
-            iter,
+            iter: self.backend.iter_reverse_from(prefix_max),
             done: false,
         }
     }
 
-    pub fn write(&self, mut rows: Vec<DBRow>, flush: DBFlush) {
+    pub fn write(&self, rows: Vec<DBRow>, flush: DBFlush) {
+        self.write_with_deletes(rows, vec![], flush)
+    }
+
+    // Atomically delete `deletes`, then write `rows`
+    pub fn write_with_deletes(&self, mut rows: Vec<DBRow>, deletes: Vec<Bytes>, flush: DBFlush) {
         debug!(
-            "writing {} rows to {:?}, flush={:?}",
+            "writing {} rows ({} deletes) to {:?}, flush={:?}",
             rows.len(),
-            self.db,
+            deletes.len(),
+            self.backend,
             flush
         );
+        // in a single batch, sorted by key to speed up the inserts of the bulk writes
         rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        self.backend.write(rows, deletes, flush);
+    }
+
+    // Persist the in-memory write buffers (which may not be covered by the WAL) to disk
+    pub fn flush(&self) {
+        self.backend.flush();
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) {
+        self.backend.put(key, value);
+    }
+
+    pub fn has_prefixes(&self, prefixes: &[Bytes]) -> Vec<bool> {
+        self.backend.has_prefixes(prefixes)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.backend.get(key)
+    }
+
+    // The version of the rows format, set when the database is created
+    pub fn version(&self) -> u32 {
+        let value = self.get(b"V").expect("missing DB version");
+        bincode::deserialize(&value).expect("failed to parse DB version")
+    }
+
+    pub fn version_row(version: u32) -> DBRow {
+        DBRow {
+            key: b"V".to_vec(),
+            value: bincode::serialize(&version).unwrap(),
+        }
+    }
+
+    // Older databases are upgraded by the migrations, when the store is opened
+    fn verify_compatibility(&self) {
+        match self.get(b"V") {
+            None => {
+                let row = DB::version_row(DB_VERSION);
+                self.put(&row.key, &row.value)
+            }
+            Some(_) if self.version() > DB_VERSION => {
+                panic!("Incompatible database found (created by a newer version). Please reindex.")
+            }
+            Some(_) => (),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RocksDBStore {
+    db: rocksdb::DB,
+}
+
+impl RocksDBStore {
+    fn open(path: &Path, options: &DBOptions) -> Self {
+        let mut db_opts = rocksdb::Options::default();
+        db_opts.create_if_missing(true);
+        db_opts.set_max_open_files(-1); // TODO: make sure to `ulimit -n` this process correctly
+        db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
+        db_opts.set_compression_type(options.compression);
+        db_opts.set_target_file_size_base(256 << 20);
+        db_opts.set_write_buffer_size(options.write_buffer_size);
+        db_opts.set_disable_auto_compactions(true); // for initial bulk load
+        db_opts.enable_statistics();
+
+        // db_opts.set_advise_random_on_open(???);
+        db_opts.set_compaction_readahead_size(1 << 20);
+        db_opts.increase_parallelism(options.background_jobs);
+
+        if options.block_cache_size > 0 {
+            let mut block_opts = rocksdb::BlockBasedOptions::default();
+            block_opts.set_lru_cache(options.block_cache_size);
+            db_opts.set_block_based_table_factory(&block_opts);
+        }
+
+        RocksDBStore {
+            db: rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB"),
+        }
+    }
+
+    fn property(&self, name: &str) -> Option<u64> {
+        self.db
+            .property_int_value(name)
+            .ok()
+            .and_then(|value| value)
+    }
+}
+
+struct RocksDBReverseIterator<'a> {
+    iter: rocksdb::DBRawIterator<'a>,
+}
+
+impl<'a> Iterator for RocksDBReverseIterator<'a> {
+    type Item = DBRow;
+
+    fn next(&mut self) -> Option<DBRow> {
+        if !self.iter.valid() {
+            return None;
+        }
+        let key = self.iter.key().unwrap();
+        let value = self.iter.value().unwrap();
+        self.iter.prev();
+        Some(DBRow { key, value })
+    }
+}
+
+impl KVStore for RocksDBStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.db.get(key).unwrap().map(|v| v.to_vec())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.db.put(key, value).unwrap();
+    }
+
+    fn write(&self, rows: Vec<DBRow>, deletes: Vec<Bytes>, flush: DBFlush) {
         let mut batch = rocksdb::WriteBatch::default();
+        for key in deletes {
+            batch.delete(&key).unwrap();
+        }
         for row in rows {
             batch.put(&row.key, &row.value).unwrap();
         }
@@ -170,23 +437,95 @@ impl DB {
         self.db.write_opt(batch, &opts).unwrap();
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) {
-        self.db.put(key, value).unwrap();
+    fn iter_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a> {
+        let iter = self.db.iterator(rocksdb::IteratorMode::From(
+            start_at,
+            rocksdb::Direction::Forward,
+        ));
+        Box::new(iter.map(|(key, value)| DBRow {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }))
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+    fn iter_reverse_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a> {
+        let mut iter = self.db.raw_iterator();
+        iter.seek_for_prev(start_at);
+        Box::new(RocksDBReverseIterator { iter })
     }
 
-    fn verify_compatibility(&self) {
-        let compatibility_bytes = bincode::serialize(&DB_VERSION).unwrap();
+    // A single iterator seeking through the prefixes in key order, rather than one per prefix
+    fn has_prefixes(&self, prefixes: &[Bytes]) -> Vec<bool> {
+        let mut order: Vec<usize> = (0..prefixes.len()).collect();
+        order.sort_unstable_by(|a, b| prefixes[*a].cmp(&prefixes[*b]));
+        let mut found = vec![false; prefixes.len()];
+        let mut iter = self.db.raw_iterator();
+        for index in order {
+            iter.seek(&prefixes[index]);
+            found[index] = iter.valid()
+                && iter
+                    .key()
+                    .map_or(false, |key| key.starts_with(&prefixes[index]));
+        }
+        found
+    }
 
-        match self.get(b"V") {
-            None => self.put(b"V", &compatibility_bytes),
-            Some(ref x) if x != &compatibility_bytes => {
-                panic!("Incompatible database found. Please reindex.")
-            }
-            Some(_) => (),
+    fn compact_range(&self, start: &[u8], end: Option<&[u8]>) {
+        self.db.compact_range(Some(start), end);
+    }
+
+    fn enable_auto_compaction(&self) {
+        let opts = [("disable_auto_compactions", "false")];
+        self.db.set_options(&opts).unwrap();
+    }
+
+    // Made of hard links to the live data files (when on the same filesystem)
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        rocksdb::checkpoint::Checkpoint::new(&self.db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(path))
+            .chain_err(|| {
+                ErrorKind::StorageError(format!("failed to create a checkpoint at {:?}", path))
+            })
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.db.flush() {
+            warn!("failed to flush {:?}: {}", self.db, e);
+        }
+    }
+
+    fn stats(&self) -> DBStats {
+        // the statistics are dumped as "rocksdb.block.cache.hit COUNT : 123" lines
+        let tickers: HashMap<String, u64> = self
+            .db
+            .property_value("rocksdb.options-statistics")
+            .ok()
+            .and_then(|stats| stats)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(name), Some("COUNT"), Some(":"), Some(count)) => {
+                        Some((name.to_string(), count.parse().ok()?))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        let block_cache_hit_ratio = match (
+            tickers.get("rocksdb.block.cache.hit"),
+            tickers.get("rocksdb.block.cache.miss"),
+        ) {
+            (Some(hit), Some(miss)) if hit + miss > 0 => Some(*hit as f64 / (hit + miss) as f64),
+            _ => None,
+        };
+        DBStats {
+            size: self.property("rocksdb.total-sst-files-size"),
+            estimated_keys: self.property("rocksdb.estimate-num-keys"),
+            memtables_size: self.property("rocksdb.cur-size-all-mem-tables"),
+            block_cache_usage: self.property("rocksdb.block-cache-usage"),
+            block_cache_hit_ratio,
         }
     }
 }
@@ -194,14 +533,11 @@ impl DB {
 #[cfg(test)]
 mod tests {
 
-    use crate::new_index::db::{DBRow, DB};
+    use crate::new_index::db::{DBFlush, DBRow, DB};
     use tempfile;
 
-    #[test]
-    fn test_db_iterator() {
-        let dir = tempfile::tempdir().unwrap();
-        let db = DB::open(dir.path());
-        let rows = vec![
+    fn test_rows() -> Vec<DBRow> {
+        vec![
             DBRow {
                 key: b"X11".to_vec(),
                 value: b"a".to_vec(),
@@ -214,11 +550,38 @@ mod tests {
                 key: b"X33".to_vec(),
                 value: b"c".to_vec(),
             },
-        ];
+        ]
+    }
+
+    #[test]
+    fn test_db_iterator() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path());
+        let rows = test_rows();
         for row in &rows {
             db.put(&row.key, &row.value);
         }
         let actual: Vec<DBRow> = db.iter_scan(b"X").collect();
         assert_eq!(rows, actual);
     }
+
+    #[test]
+    fn test_memory_db_iterator() {
+        let db = DB::in_memory();
+        db.write(test_rows(), DBFlush::Disable);
+        db.put(b"Y11", b"d");
+
+        let actual: Vec<DBRow> = db.iter_scan(b"X").collect();
+        assert_eq!(test_rows(), actual);
+
+        let actual: Vec<Vec<u8>> = db.iter_scan_from(b"X", b"X2").map(|r| r.key).collect();
+        assert_eq!(actual, vec![b"X22".to_vec(), b"X33".to_vec()]);
+
+        let actual: Vec<Vec<u8>> = db.iter_scan_reverse(b"X", b"X30").map(|r| r.key).collect();
+        assert_eq!(actual, vec![b"X22".to_vec(), b"X11".to_vec()]);
+
+        db.write_with_deletes(vec![], vec![b"X22".to_vec()], DBFlush::Enable);
+        assert_eq!(db.get(b"X22"), None);
+        assert_eq!(db.get(b"X33"), Some(b"c".to_vec()));
+    }
 }
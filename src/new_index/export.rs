@@ -0,0 +1,284 @@
+// Paginated dumps of the history index (its `H` rows, one per funded or spent output of a script),
+// for analytics pipelines extracting it into their own storage. The first page of an export
+// checkpoints the history database into <db-dir>/exports/<id> (see KVStore::checkpoint), and all
+// of its pages are read from that checkpoint in key order, so that they're consistent with each
+// other regardless of the ongoing indexing. The export covers the blocks up to the tip at its start
+// (only those above `since_height` for incremental extracts), skipping the rows of stale blocks.
+//
+// Each page returns a cursor for the next one, `<id>-<last scanned key in hex>`, which keeps
+// returning the same page until the export expires after EXPORT_IDLE_TIMEOUT without requests.
+// The checkpoints are removed as their exports expire (and on the first export after a restart).
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use hex;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::new_index::db::{DBBackend, DBOptions, DB};
+use crate::new_index::schema::{TxHistoryInfo, TxHistoryRow};
+use crate::new_index::{parse_hash, ChainQuery};
+use crate::util::Bytes;
+
+const EXPORT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const EXPORT_MAX_SESSIONS: usize = 4;
+// pages with few matching rows (such as incremental ones) end early rather than scan the whole index
+const PAGE_MAX_SCANNED_ROWS: usize = 100_000;
+
+// A checkpoint of the history database, removed once the last page reading it is done
+struct Checkpoint {
+    db: Option<DB>,
+    path: PathBuf,
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        self.db.take(); // closed before its files are removed
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("failed to remove the export at {:?}: {}", self.path, e);
+        }
+    }
+}
+
+struct Export {
+    checkpoint: Arc<Checkpoint>,
+    blockhashes: Arc<Vec<Sha256dHash>>, // the best chain by height, as of the export's start
+    tip_height: u32,
+    tip_hash: Sha256dHash,
+    since_height: Option<u32>,
+    last_used: Instant,
+}
+
+#[derive(Serialize)]
+pub struct HistoryExportRow {
+    #[serde(rename = "type")]
+    pub kind: &'static str, // funding or spending
+    pub scripthash: String,
+    pub height: u32,
+    pub txid: Sha256dHash,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vout: Option<u16>, // of the funding rows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vin: Option<u16>, // of the spending rows, along with the spent output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_txid: Option<Sha256dHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_vout: Option<u16>,
+    pub value: u64,
+}
+
+#[derive(Serialize)]
+pub struct ExportPage {
+    pub tip_height: u32,
+    pub tip_hash: Sha256dHash,
+    pub since_height: Option<u32>,
+    pub rows: Vec<HistoryExportRow>,
+    pub next_cursor: Option<String>, // null once the export is complete
+}
+
+#[derive(Default)]
+pub struct HistoryExports {
+    exports: Mutex<HashMap<u64, Export>>,
+    removed_leftovers: AtomicBool, // of the previous runs
+}
+
+fn export_row(row: TxHistoryRow) -> HistoryExportRow {
+    let key = row.key;
+    let (kind, txid, vout, vin, prev, value) = match key.txinfo {
+        TxHistoryInfo::Funding(info) => (
+            "funding",
+            info.txid,
+            Some(info.vout),
+            None,
+            None,
+            info.value,
+        ),
+        TxHistoryInfo::Spending(info) => (
+            "spending",
+            info.txid,
+            None,
+            Some(info.vin),
+            Some((parse_hash(&info.prev_txid), info.prev_vout)),
+            info.value,
+        ),
+    };
+    HistoryExportRow {
+        kind,
+        scripthash: hex::encode(key.hash),
+        height: key.confirmed_height,
+        txid: parse_hash(&txid),
+        vout,
+        vin,
+        prev_txid: prev.map(|(txid, _)| txid),
+        prev_vout: prev.map(|(_, vout)| vout),
+        value,
+    }
+}
+
+fn parse_cursor(cursor: &str) -> Result<(u64, Bytes)> {
+    let invalid = || Error::from(ErrorKind::InvalidInput("Invalid cursor".to_string()));
+    let mut parts = cursor.splitn(2, '-');
+    let id = parts.next().ok_or_else(invalid)?;
+    let key = parts.next().ok_or_else(invalid)?;
+    Ok((
+        u64::from_str_radix(id, 16).map_err(|_| invalid())?,
+        hex::decode(key).map_err(|_| invalid())?,
+    ))
+}
+
+impl HistoryExports {
+    fn prune(exports: &mut HashMap<u64, Export>) {
+        exports.retain(|id, export| {
+            let expired = export.last_used.elapsed() >= EXPORT_IDLE_TIMEOUT;
+            if expired {
+                debug!("history export {:x} expired", id);
+            }
+            !expired
+        });
+    }
+
+    // Whether another export can be started
+    pub fn available(&self) -> bool {
+        let mut exports = self.exports.lock().unwrap();
+        HistoryExports::prune(&mut exports);
+        exports.len() < EXPORT_MAX_SESSIONS
+    }
+
+    // Checkpoints the history database into `dir` and returns the first page
+    pub fn start(
+        &self,
+        chain: &ChainQuery,
+        dir: &Path,
+        since_height: Option<u32>,
+        limit: usize,
+    ) -> Result<ExportPage> {
+        let mut exports = self.exports.lock().unwrap();
+        HistoryExports::prune(&mut exports);
+        if !self.removed_leftovers.swap(true, Ordering::SeqCst) && dir.exists() {
+            fs::remove_dir_all(dir).chain_err(|| format!("failed to remove {:?}", dir))?;
+        }
+        fs::create_dir_all(dir).chain_err(|| format!("failed to create {:?}", dir))?;
+
+        // the history rows are written before the headers they're for are added, and the rows of
+        // blocks orphaned during the export are told apart from the ones still in its chain
+        let blockhashes = chain.best_chain_hashes();
+        let tip_height = blockhashes.len() - 1;
+        let tip_hash = blockhashes[tip_height];
+        let mut id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        while exports.contains_key(&id) {
+            id += 1;
+        }
+        let path = dir.join(format!("{:x}", id));
+        chain.store().history_db().checkpoint(&path)?;
+        let db = DB::open_with(
+            &path,
+            &DBOptions {
+                backend: DBBackend::RocksDB,
+                ..DBOptions::default()
+            },
+        );
+        info!(
+            "started history export {:x} at block {} (height {})",
+            id, tip_hash, tip_height
+        );
+        exports.insert(
+            id,
+            Export {
+                checkpoint: Arc::new(Checkpoint { db: Some(db), path }),
+                blockhashes: Arc::new(blockhashes),
+                tip_height: tip_height as u32,
+                tip_hash,
+                since_height,
+                last_used: Instant::now(),
+            },
+        );
+        drop(exports);
+        self.page(chain, &format!("{:x}-", id), limit)
+    }
+
+    pub fn page(&self, chain: &ChainQuery, cursor: &str, limit: usize) -> Result<ExportPage> {
+        let (id, last_key) = parse_cursor(cursor)?;
+        let (checkpoint, blockhashes, tip_height, tip_hash, since_height) = {
+            let mut exports = self.exports.lock().unwrap();
+            HistoryExports::prune(&mut exports);
+            let export = exports.get_mut(&id).ok_or_else(|| {
+                Error::from(ErrorKind::NotFound(
+                    "Unknown or expired export cursor".to_string(),
+                ))
+            })?;
+            export.last_used = Instant::now();
+            (
+                Arc::clone(&export.checkpoint),
+                Arc::clone(&export.blockhashes),
+                export.tip_height,
+                export.tip_hash,
+                export.since_height,
+            )
+        };
+        let db = checkpoint.db.as_ref().unwrap();
+
+        // the first key after the last one
+        let start_at = if last_key.is_empty() {
+            b"H".to_vec()
+        } else {
+            [&last_key[..], &[0]].concat()
+        };
+        let mut rows = vec![];
+        let mut next_key = None;
+        for (scanned, row) in db.iter_scan_from(b"H", &start_at).enumerate() {
+            if rows.len() >= limit || scanned >= PAGE_MAX_SCANNED_ROWS {
+                break;
+            }
+            let key = row.key.clone();
+            let row = TxHistoryRow::from_row(row);
+            let height = row.key.confirmed_height;
+            next_key = Some(key);
+            if height > tip_height || since_height.map_or(false, |since| height <= since) {
+                continue;
+            }
+            let confirmed = chain.tx_confirmed_in(&row.get_txid(), &blockhashes[height as usize]);
+            if confirmed {
+                rows.push(export_row(row));
+            }
+        }
+        // the scan ended at the last row if it wasn't cut short by the limits
+        let complete = next_key.as_ref().map_or(true, |key| {
+            db.iter_scan_from(b"H", &[&key[..], &[0]].concat())
+                .next()
+                .is_none()
+        });
+        Ok(ExportPage {
+            tip_height,
+            tip_hash,
+            since_height,
+            rows,
+            next_cursor: if complete {
+                None
+            } else {
+                next_key.map(|key| format!("{:x}-{}", id, hex::encode(key)))
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cursor;
+
+    #[test]
+    fn test_parse_cursor() {
+        assert_eq!(parse_cursor("1f-").unwrap(), (0x1f, vec![]));
+        assert_eq!(parse_cursor("1f-48ab").unwrap(), (0x1f, vec![0x48, 0xab]));
+        assert!(parse_cursor("1f").is_err());
+        assert!(parse_cursor("xyz-48").is_err());
+        assert!(parse_cursor("1f-4").is_err());
+    }
+}
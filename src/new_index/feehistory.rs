@@ -0,0 +1,170 @@
+// Fee rate percentiles of each block, for charting the fee market over time. They're computed
+// while indexing the blocks (as this requires their previous txos), and written to the history db:
+//
+//   Q{block time (big-endian u32)}{blockhash} → {fee rates}
+//
+// Blocks that are not part of the best chain are skipped at query time. Blocks indexed by older
+// versions and blocks without transactions besides the coinbase have no rows.
+
+use bincode;
+
+use std::collections::BTreeMap;
+
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::{parse_hash, ChainQuery};
+use crate::util::{full_hash, median, Bytes};
+
+// In sat/vbyte, over the non-coinbase transactions
+#[derive(Serialize, Deserialize)]
+struct BlockFeeRates {
+    min: f32,
+    median: f32,
+    p90: f32,
+}
+
+#[derive(Serialize)]
+pub struct FeeHistoryPoint {
+    pub timestamp: u32, // the block time, or the start of the interval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_height: Option<usize>, // without an interval
+    pub block_count: u32,
+    pub min_feerate: f32,
+    // with an interval, the medians of the blocks' median and 90th percentile fee rates
+    pub median_feerate: f32,
+    pub p90_feerate: f32,
+}
+
+fn time_filter(time: u32) -> Bytes {
+    [&b"Q"[..], &time.to_be_bytes()[..]].concat()
+}
+
+// The nearest-rank percentile of the sorted fee rates
+fn percentile(sorted: &[f32], p: f32) -> f32 {
+    sorted[((sorted.len() - 1) as f32 * p).round() as usize]
+}
+
+// `feerates` should be sorted
+pub fn block_row(block_entry: &BlockEntry, feerates: &[f32]) -> Option<DBRow> {
+    if feerates.is_empty() {
+        return None;
+    }
+    let feerates = BlockFeeRates {
+        min: feerates[0],
+        median: median(feerates),
+        p90: percentile(feerates, 0.9),
+    };
+    Some(DBRow {
+        key: [
+            &time_filter(block_entry.block.header.time)[..],
+            &block_entry.entry.hash()[..],
+        ]
+        .concat(),
+        value: bincode::serialize(&feerates).unwrap(),
+    })
+}
+
+fn sorted(mut values: Vec<f32>) -> Vec<f32> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    values
+}
+
+// The fee rates of the blocks with a time in the given (inclusive) range, one point per block or
+// per `interval` seconds
+pub fn fee_history(
+    chain: &ChainQuery,
+    from: u32,
+    to: u32,
+    interval: Option<u32>,
+) -> Vec<FeeHistoryPoint> {
+    let blocks = chain
+        .store()
+        .history_db()
+        .iter_scan_from(b"Q", &time_filter(from))
+        .map(|row| (u32::from_be_bytes(*array_ref![row.key, 1, 4]), row))
+        .take_while(|(time, _)| *time <= to)
+        .filter_map(|(time, row)| {
+            // skip blocks that are not part of the best chain
+            let blockhash = parse_hash(&full_hash(&row.key[5..]));
+            let height = chain.height_by_hash(&blockhash)?;
+            let feerates: BlockFeeRates =
+                bincode::deserialize(&row.value).expect("failed to parse fee history row");
+            Some((time, height, feerates))
+        });
+
+    let interval = match interval {
+        Some(interval) => interval,
+        None => {
+            return blocks
+                .map(|(time, height, feerates)| FeeHistoryPoint {
+                    timestamp: time,
+                    block_height: Some(height),
+                    block_count: 1,
+                    min_feerate: feerates.min,
+                    median_feerate: feerates.median,
+                    p90_feerate: feerates.p90,
+                })
+                .collect()
+        }
+    };
+    let mut buckets: BTreeMap<u32, Vec<BlockFeeRates>> = BTreeMap::new();
+    for (time, _, feerates) in blocks {
+        buckets
+            .entry(time / interval * interval)
+            .or_default()
+            .push(feerates);
+    }
+    buckets
+        .into_iter()
+        .map(|(timestamp, blocks)| FeeHistoryPoint {
+            timestamp,
+            block_height: None,
+            block_count: blocks.len() as u32,
+            min_feerate: blocks
+                .iter()
+                .map(|b| b.min)
+                .fold(std::f32::INFINITY, f32::min),
+            median_feerate: median(&sorted(blocks.iter().map(|b| b.median).collect())),
+            p90_feerate: median(&sorted(blocks.iter().map(|b| b.p90).collect())),
+        })
+        .collect()
+}
+
+// Parses an interval of `<n>h`, `<n>d` or `<n>w` into seconds
+pub fn parse_interval(interval: &str) -> Option<u32> {
+    if interval.len() < 2 || !interval.is_char_boundary(interval.len() - 1) {
+        return None;
+    }
+    let (count, unit) = interval.split_at(interval.len() - 1);
+    let secs = match unit {
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => return None,
+    };
+    Some(count.parse::<u32>().ok()?.checked_mul(secs)?).filter(|secs| *secs > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_interval, percentile};
+
+    #[test]
+    fn test_percentile() {
+        let feerates: Vec<f32> = (1..=10).map(|n| n as f32).collect();
+        assert_eq!(percentile(&feerates, 0.0), 1.0);
+        assert_eq!(percentile(&feerates, 0.9), 9.0);
+        assert_eq!(percentile(&feerates, 1.0), 10.0);
+        assert_eq!(percentile(&[2.5], 0.9), 2.5);
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("1h"), Some(3600));
+        assert_eq!(parse_interval("2d"), Some(2 * 86_400));
+        assert_eq!(parse_interval("1w"), Some(7 * 86_400));
+        assert_eq!(parse_interval("0d"), None);
+        assert_eq!(parse_interval("h"), None);
+        assert_eq!(parse_interval("1m"), None);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::chain::Block;
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
-use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::hash::{bitcoin_merkle_root, BitcoinHash};
 use rayon::prelude::*;
 
 #[cfg(not(feature = "liquid"))]
@@ -16,6 +16,7 @@ use std::path::PathBuf;
 use std::sync::mpsc::Receiver;
 use std::thread;
 
+use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::util::{spawn_thread, HeaderEntry, SyncChannel};
@@ -26,16 +27,35 @@ pub enum FetchFrom {
     BlkFiles,
 }
 
+// Tuning of the indexing pipeline
+#[derive(Clone, Copy, Debug)]
+pub struct FetchOptions {
+    pub batch_size: usize, // blocks per JSONRPC request
+    pub workers: usize,    // concurrent JSONRPC fetchers
+    pub queue_size: usize, // batches buffered between the pipeline stages
+}
+
+impl FetchOptions {
+    pub fn new(config: &Config) -> Self {
+        FetchOptions {
+            batch_size: config.index_batch_size,
+            workers: config.fetch_workers,
+            queue_size: config.index_queue_size,
+        }
+    }
+}
+
 pub fn start_fetcher(
     from: FetchFrom,
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
+    options: &FetchOptions,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
     let fetcher = match from {
         FetchFrom::Bitcoind => bitcoind_fetcher,
         FetchFrom::BlkFiles => blkfiles_fetcher,
     };
-    fetcher(daemon, new_headers)
+    fetcher(daemon, new_headers, options)
 }
 
 pub struct BlockEntry {
@@ -65,53 +85,129 @@ impl<T> Fetcher<T> {
         }
         self.thread.join().expect("fetcher thread panicked")
     }
+
+    // Process the items with `func` in a separate thread, as another stage of the pipeline
+    pub fn then<U, F>(self, name: &str, queue_size: usize, func: F) -> Fetcher<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        F: Fn(T) -> U + Send + 'static,
+    {
+        let chan = SyncChannel::new(queue_size);
+        let sender = chan.sender();
+        Fetcher::from(
+            chan.into_receiver(),
+            spawn_thread(name, move || -> () {
+                self.map(|item| {
+                    sender
+                        .send(func(item))
+                        .expect("failed to send processed items")
+                });
+            }),
+        )
+    }
 }
 
+// Fetches the batches of blocks using `options.workers` concurrent JSONRPC connections, which
+// are merged back in order
 fn bitcoind_fetcher(
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
+    options: &FetchOptions,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
     new_headers.last().map(|tip| {
         debug!("{:?} ({} new blocks to fetch)", tip, new_headers.len());
     });
-    let daemon = daemon.reconnect()?;
-    let chan = SyncChannel::new(1);
+    let batches: Vec<Vec<HeaderEntry>> = new_headers
+        .chunks(options.batch_size.max(1))
+        .map(|entries| entries.to_vec())
+        .collect();
+    let workers = options.workers.max(1);
+    let fetchers = (0..workers)
+        .map(|worker| {
+            // the worker fetches every `workers`-th batch, starting at its index
+            let batches: Vec<Vec<HeaderEntry>> = batches
+                .iter()
+                .skip(worker)
+                .step_by(workers)
+                .cloned()
+                .collect();
+            bitcoind_worker(daemon.reconnect()?, batches, options.queue_size)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let chan = SyncChannel::new(options.queue_size);
+    let sender = chan.sender();
+    Ok(Fetcher::from(
+        chan.into_receiver(),
+        spawn_thread("bitcoind_merger", move || -> () {
+            'merge: loop {
+                for fetcher in &fetchers {
+                    // the first worker to run out of batches means there are none left
+                    match fetcher.receiver.recv() {
+                        Ok(block_entries) => sender
+                            .send(block_entries)
+                            .expect("failed to send fetched blocks"),
+                        Err(_) => break 'merge,
+                    }
+                }
+            }
+            // drain the other workers in case one failed, and propagate its panic
+            for fetcher in fetchers {
+                fetcher.map(|_| ());
+            }
+        }),
+    ))
+}
+
+fn bitcoind_worker(
+    daemon: Daemon,
+    batches: Vec<Vec<HeaderEntry>>,
+    queue_size: usize,
+) -> Result<Fetcher<Vec<BlockEntry>>> {
+    let chan = SyncChannel::new(queue_size);
     let sender = chan.sender();
     Ok(Fetcher::from(
         chan.into_receiver(),
         spawn_thread("bitcoind_fetcher", move || -> () {
-            for entries in new_headers.chunks(100) {
-                let blockhashes: Vec<Sha256dHash> = entries.iter().map(|e| *e.hash()).collect();
-                let blocks = daemon
-                    .getblocks(&blockhashes)
-                    .expect("failed to get blocks from bitcoind");
-                assert_eq!(blocks.len(), entries.len());
-                let block_entries: Vec<BlockEntry> = blocks
-                    .into_iter()
-                    .zip(entries)
-                    .map(|(block, entry)| BlockEntry {
-                        entry: entry.clone(),                 // TODO: remove this clone()
-                        size: serialize(&block).len() as u32, // TODO: avoid re-serializing
-                        block,
-                    })
-                    .collect();
-                assert_eq!(block_entries.len(), entries.len());
+            for entries in batches {
                 sender
-                    .send(block_entries)
+                    .send(fetch_blocks(&daemon, entries))
                     .expect("failed to send fetched blocks");
             }
         }),
     ))
 }
 
+fn fetch_blocks(daemon: &Daemon, entries: Vec<HeaderEntry>) -> Vec<BlockEntry> {
+    let blockhashes: Vec<Sha256dHash> = entries.iter().map(|e| *e.hash()).collect();
+    let blocks = daemon
+        .getblocks(&blockhashes)
+        .expect("failed to get blocks from bitcoind");
+    // the blocks are zipped with their entries, so they must match one to one
+    assert_eq!(blocks.len(), entries.len());
+    blocks
+        .into_iter()
+        .zip(entries)
+        .map(|(block, entry)| BlockEntry {
+            entry,
+            size: serialize(&block).len() as u32, // TODO: avoid re-serializing
+            block,
+        })
+        .collect()
+}
+
 fn blkfiles_fetcher(
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
+    options: &FetchOptions,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
     let magic = daemon.magic();
     let blk_files = daemon.list_blk_files()?;
+    let rpc = daemon.reconnect()?;
+    let batch_size = options.batch_size.max(1);
 
-    let chan = SyncChannel::new(1);
+    let chan = SyncChannel::new(options.queue_size);
     let sender = chan.sender();
 
     let mut entry_map: HashMap<Sha256dHash, HeaderEntry> =
@@ -140,11 +236,19 @@ fn blkfiles_fetcher(
                     .send(block_entries)
                     .expect("failed to send blocks entries from blk*.dat files");
             });
+            // the most recent blocks may not be flushed to the blk*.dat files yet
             if !entry_map.is_empty() {
-                panic!(
-                    "failed to index {} blocks from blk*.dat files",
+                info!(
+                    "fetching {} blocks missing from blk*.dat files over JSONRPC",
                     entry_map.len()
-                )
+                );
+                let mut entries: Vec<HeaderEntry> = entry_map.drain().map(|(_, e)| e).collect();
+                entries.sort_by_key(|e| e.height());
+                for entries in entries.chunks(batch_size) {
+                    sender
+                        .send(fetch_blocks(&rpc, entries.to_vec()))
+                        .expect("failed to send fetched blocks");
+                }
             }
         }),
     ))
@@ -208,6 +312,10 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
             .seek(SeekFrom::Current(block_size as i64))
             .chain_err(|| format!("seek {} failed", block_size))?;
         let end = cursor.position() as usize;
+        if end > blob.len() {
+            warn!("skipping a truncated block of {} bytes", block_size);
+            break;
+        }
 
         // If Core's WriteBlockToDisk ftell fails, only the magic byte and size will be written
         // and the block body will be unwritten data. skip that's data.
@@ -233,7 +341,22 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
     Ok(pool.install(|| {
         slices
             .into_par_iter()
-            .map(|(slice, size)| (deserialize(slice).expect("failed to parse Block"), size))
+            // skip corrupted blocks (these get fetched over JSONRPC instead)
+            .filter_map(|(slice, size)| match deserialize(slice) {
+                Ok(block) => Some((block, size)),
+                Err(e) => {
+                    warn!("skipping an unparsable block of {} bytes: {}", size, e);
+                    None
+                }
+            })
+            .filter(|(block, _): &SizedBlock| {
+                let txids = block.txdata.iter().map(|tx| tx.txid()).collect();
+                let valid = bitcoin_merkle_root(txids) == block.header.merkle_root;
+                if !valid {
+                    warn!("invalid merkle root for block {}", block.bitcoin_hash());
+                }
+                valid
+            })
             .collect()
     }))
 }
@@ -0,0 +1,120 @@
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use lru_cache::LruCache;
+
+use std::hash::Hash;
+use std::mem;
+use std::sync::Mutex;
+
+use crate::chain::Transaction;
+use crate::util::BlockMeta;
+
+// Deserialized transactions take up about twice their serialized size
+const TX_SIZE_FACTOR: usize = 2;
+
+// An LRU map bounded by the (estimated) size of its values rather than by their count
+struct SizedLru<K: Hash + Eq, V> {
+    entries: LruCache<K, (V, usize)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl<K: Hash + Eq, V: Clone> SizedLru<K, V> {
+    fn new(max_size: usize) -> Self {
+        SizedLru {
+            entries: LruCache::new(usize::max_value()),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.entries.get_mut(key).map(|(value, _)| value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V, size: usize) {
+        if size > self.max_size {
+            return;
+        }
+        if let Some((_, old_size)) = self.entries.insert(key, (value, size)) {
+            self.size -= old_size;
+        }
+        self.size += size;
+        while self.size > self.max_size {
+            match self.entries.remove_lru() {
+                Some((_, (_, evicted_size))) => self.size -= evicted_size,
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, size) = self.entries.remove(key)?;
+        self.size -= size;
+        Some(value)
+    }
+}
+
+// Keeps the most recently looked up transactions and blocks in memory, so that popular ones don't
+// have to be read from the database and deserialized on every request. The entries are keyed by
+// hash and so never go stale, but the ones of orphaned blocks are dropped on reorgs.
+pub struct LookupCache {
+    txs: Mutex<SizedLru<Sha256dHash, Transaction>>,
+    block_txids: Mutex<SizedLru<Sha256dHash, Vec<Sha256dHash>>>,
+    block_metas: Mutex<SizedLru<Sha256dHash, BlockMeta>>,
+}
+
+impl LookupCache {
+    // Most of the budget goes to the transactions, the block metas are tiny
+    pub fn new(max_size: usize) -> Self {
+        LookupCache {
+            txs: Mutex::new(SizedLru::new(max_size / 10 * 7)),
+            block_txids: Mutex::new(SizedLru::new(max_size / 10 * 3)),
+            block_metas: Mutex::new(SizedLru::new(max_size / 100)),
+        }
+    }
+
+    pub fn get_tx(&self, txid: &Sha256dHash) -> Option<Transaction> {
+        self.txs.lock().unwrap().get(txid)
+    }
+
+    pub fn put_tx(&self, txid: Sha256dHash, tx: Transaction, raw_size: usize) {
+        self.txs
+            .lock()
+            .unwrap()
+            .insert(txid, tx, raw_size * TX_SIZE_FACTOR)
+    }
+
+    pub fn get_block_txids(&self, blockhash: &Sha256dHash) -> Option<Vec<Sha256dHash>> {
+        self.block_txids.lock().unwrap().get(blockhash)
+    }
+
+    pub fn put_block_txids(&self, blockhash: Sha256dHash, txids: Vec<Sha256dHash>) {
+        let size = txids.len() * mem::size_of::<Sha256dHash>();
+        self.block_txids
+            .lock()
+            .unwrap()
+            .insert(blockhash, txids, size)
+    }
+
+    pub fn get_block_meta(&self, blockhash: &Sha256dHash) -> Option<BlockMeta> {
+        self.block_metas.lock().unwrap().get(blockhash)
+    }
+
+    pub fn put_block_meta(&self, blockhash: Sha256dHash, meta: BlockMeta) {
+        self.block_metas
+            .lock()
+            .unwrap()
+            .insert(blockhash, meta, mem::size_of::<BlockMeta>())
+    }
+
+    // Drop an orphaned block, along with its transactions
+    pub fn evict_block(&self, blockhash: &Sha256dHash) {
+        self.block_metas.lock().unwrap().remove(blockhash);
+        if let Some(txids) = self.block_txids.lock().unwrap().remove(blockhash) {
+            let mut txs = self.txs.lock().unwrap();
+            for txid in &txids {
+                txs.remove(txid);
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::RwLock;
+
+use crate::new_index::db::{DBFlush, DBRow, DBStats, KVStore};
+use crate::util::Bytes;
+
+// An in-memory backend, nothing is persisted
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    rows: RwLock<BTreeMap<Bytes, Bytes>>,
+}
+
+// Looks up the next row on each step, so that the lock isn't held by the iterator
+struct MemoryIterator<'a> {
+    rows: &'a RwLock<BTreeMap<Bytes, Bytes>>,
+    bound: Bound<Bytes>,
+    reverse: bool,
+}
+
+impl<'a> Iterator for MemoryIterator<'a> {
+    type Item = DBRow;
+
+    fn next(&mut self) -> Option<DBRow> {
+        let rows = self.rows.read().unwrap();
+        let (key, value) = if self.reverse {
+            rows.range::<Bytes, _>((Bound::Unbounded, self.bound.clone()))
+                .next_back()?
+        } else {
+            rows.range::<Bytes, _>((self.bound.clone(), Bound::Unbounded))
+                .next()?
+        };
+        self.bound = Bound::Excluded(key.clone());
+        Some(DBRow {
+            key: key.clone(),
+            value: value.clone(),
+        })
+    }
+}
+
+impl KVStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.rows.read().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.rows
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+    }
+
+    fn write(&self, rows: Vec<DBRow>, deletes: Vec<Bytes>, _flush: DBFlush) {
+        let mut stored = self.rows.write().unwrap();
+        for key in deletes {
+            stored.remove(&key);
+        }
+        for row in rows {
+            stored.insert(row.key, row.value);
+        }
+    }
+
+    fn iter_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a> {
+        Box::new(MemoryIterator {
+            rows: &self.rows,
+            bound: Bound::Included(start_at.to_vec()),
+            reverse: false,
+        })
+    }
+
+    fn iter_reverse_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a> {
+        Box::new(MemoryIterator {
+            rows: &self.rows,
+            bound: Bound::Included(start_at.to_vec()),
+            reverse: true,
+        })
+    }
+
+    fn stats(&self) -> DBStats {
+        let rows = self.rows.read().unwrap();
+        DBStats {
+            estimated_keys: Some(rows.len() as u64),
+            ..DBStats::default()
+        }
+    }
+}
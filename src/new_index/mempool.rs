@@ -1,16 +1,19 @@
 use arraydeque::{ArrayDeque, Wrapping};
+use bincode;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use itertools::Itertools;
 
 #[cfg(not(feature = "liquid"))]
-use bitcoin::consensus::encode::serialize;
+use bitcoin::consensus::encode::{deserialize, serialize};
 #[cfg(feature = "liquid")]
-use elements::encode::serialize;
+use elements::encode::{deserialize, serialize};
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
 use std::iter::FromIterator;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::chain::{OutPoint, Transaction, TxOut};
 use crate::daemon::Daemon;
@@ -20,14 +23,23 @@ use crate::new_index::{
     compute_script_hash, parse_hash, schema::FullHash, ChainQuery, FundingInfo, ScriptStats,
     SpendingInfo, SpendingInput, TxHistoryInfo, Utxo,
 };
-use crate::util::fees::{make_fee_histogram, TxFeeInfo};
+use crate::util::fees::{
+    fee_for, feerate_for_blocks, make_fee_histogram, TxFeeInfo, BLOCK_VSIZE,
+    INCREMENTAL_RELAY_FEERATE, MIN_RELAY_FEERATE,
+};
 use crate::util::{full_hash, has_prevout, is_spendable, Bytes};
 
+#[cfg(not(feature = "liquid"))]
+use crate::watch::WatchList;
+
 #[cfg(feature = "liquid")]
 use crate::elements::asset;
 
 const RECENT_TXS_SIZE: usize = 10;
 const BACKLOG_STATS_TTL: u64 = 10;
+const PROJECTED_BLOCKS: usize = 8; // the last one holding all the remaining transactions
+const EVICTED_TXS_TTL: u64 = 1800;
+const EVICTED_TXS_MAX: usize = 10_000;
 
 pub struct Mempool {
     chain: Arc<ChainQuery>,
@@ -37,6 +49,12 @@ pub struct Mempool {
     edges: HashMap<OutPoint, (Sha256dHash, u32)>,   // OutPoint -> (spending_txid, spending_vin)
     recent: ArrayDeque<[TxOverview; RECENT_TXS_SIZE], Wrapping>, // The N most recent txs to enter the mempool
     backlog_stats: (BacklogStats, Instant),
+    projected_blocks: Vec<ProjectedBlock>, // updated along with the backlog stats
+    evicted: HashMap<Sha256dHash, EvictedTx>,
+    evicted_history: HashMap<FullHash, Vec<Sha256dHash>>, // ScriptHash -> {evicted_txids}
+    evicted_order: VecDeque<Sha256dHash>,                 // oldest evictions first
+    #[cfg(not(feature = "liquid"))]
+    watchlist: Option<Arc<WatchList>>,
 
     // monitoring
     latency: HistogramVec, // mempool requests latency
@@ -50,6 +68,81 @@ pub struct Mempool {
     pub asset_issuance: HashMap<Sha256dHash, asset::AssetRow>, // asset_id -> {history_entries}
 }
 
+// The on-disk mempool snapshot. Only the raw transactions are stored, the fee info,
+// history entries and spend edges are re-derived from them when the snapshot is loaded.
+#[derive(Serialize, Deserialize)]
+struct MempoolSnapshot {
+    txs: Vec<Bytes>,
+}
+
+// The unconfirmed ancestors and descendants of a mempool transaction. As with bitcoind's
+// getmempoolentry, the counts, sizes and fees include the transaction itself.
+#[derive(Serialize)]
+pub struct PackageInfo {
+    pub ancestor_count: usize,
+    pub ancestor_vsize: u64,
+    pub ancestor_fees: u64,
+    pub descendant_count: usize,
+    pub descendant_vsize: u64,
+    pub descendant_fees: u64,
+    pub effective_feerate: f32, // in sat/vbyte
+}
+
+// The fees needed for an unconfirmed transaction to reach a confirmation target, either by
+// replacing it (RBF) or by spending one of its outputs (CPFP)
+#[derive(Serialize)]
+pub struct FeeBump {
+    pub target_feerate: f32, // in sat/vbyte
+    pub effective_feerate: f32,
+    pub on_target: bool,
+    pub rbf: RbfBump,
+    pub cpfp: Option<CpfpBump>, // None if all of its outputs are spent or unspendable
+}
+
+#[derive(Serialize)]
+pub struct RbfBump {
+    pub signaled: bool, // by the transaction or one of its unconfirmed ancestors (BIP125)
+    pub fee: u64,       // of the replacement
+    pub additional_fee: u64,
+}
+
+#[derive(Serialize)]
+pub struct CpfpBump {
+    pub vouts: Vec<u32>, // the unspent outputs the child could spend
+    pub child_vsize: u32,
+    pub child_fee: u64,
+}
+
+// One of the next blocks, as a miner would likely fill it from the current mempool. The fee rates
+// are those of the packages (a transaction along with its unconfirmed ancestors) included in it.
+#[derive(Serialize)]
+pub struct ProjectedBlock {
+    pub tx_count: usize,
+    pub vsize: u64,
+    pub total_fees: u64,
+    pub min_feerate: f32, // in sat/vbyte
+    pub median_feerate: f32,
+    pub max_feerate: f32,
+}
+
+// A transaction that left the mempool without confirming, kept for EVICTED_TXS_TTL seconds so that
+// the wallets tracking its scripts can tell it apart from a pending one
+pub struct EvictedTx {
+    pub tx: Transaction,
+    pub fee: u64,
+    pub reason: EvictionReason,
+    pub conflicting_txid: Option<Sha256dHash>, // the replacement or the conflicting block tx
+    pub time: u64,                             // unix timestamp of the eviction
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    Replaced,   // by a mempool transaction spending the same outputs
+    Conflicted, // by a block transaction spending the same outputs
+    Dropped,    // expired, evicted by bitcoind's mempool limits or along with an ancestor
+}
+
 // A simplified transaction view used for the list of most recent transactions
 #[derive(Serialize)]
 pub struct TxOverview {
@@ -73,6 +166,12 @@ impl Mempool {
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
             ),
+            projected_blocks: vec![],
+            evicted: HashMap::new(),
+            evicted_history: HashMap::new(),
+            evicted_order: VecDeque::new(),
+            #[cfg(not(feature = "liquid"))]
+            watchlist: None,
             latency: metrics.histogram_vec(
                 HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
                 &["part"],
@@ -93,6 +192,11 @@ impl Mempool {
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn set_watchlist(&mut self, watchlist: Arc<WatchList>) {
+        self.watchlist = Some(watchlist);
+    }
+
     pub fn lookup_txn(&self, txid: &Sha256dHash) -> Option<Transaction> {
         self.txstore.get(txid).map(|item| item.clone())
     }
@@ -109,10 +213,192 @@ impl Mempool {
         })
     }
 
+    // The recently evicted transactions that spent the outpoint, as (vin, evicted tx)
+    pub fn evicted_spends(&self, outpoint: &OutPoint) -> Vec<(u32, &EvictedTx)> {
+        self.evicted_order
+            .iter()
+            .rev()
+            .filter_map(|txid| self.evicted.get(txid))
+            .filter_map(|evicted_tx| {
+                let vin = evicted_tx
+                    .tx
+                    .input
+                    .iter()
+                    .position(|txin| txin.previous_output == *outpoint)?;
+                Some((vin as u32, evicted_tx))
+            })
+            .collect()
+    }
+
     pub fn has_spend(&self, outpoint: &OutPoint) -> bool {
         self.edges.contains_key(outpoint)
     }
 
+    // The unconfirmed transactions spent by `txid`, directly or through other ones
+    pub fn ancestors(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        self.walk(*txid, |txid| match self.txstore.get(txid) {
+            Some(tx) => tx
+                .input
+                .iter()
+                .map(|txin| txin.previous_output.txid)
+                .filter(|prev_txid| self.txstore.contains_key(prev_txid))
+                .collect(),
+            None => vec![],
+        })
+    }
+
+    // The unconfirmed transactions spending `tx` (which may be confirmed), directly or through
+    // other ones
+    pub fn descendants(&self, tx: &Transaction) -> Vec<Sha256dHash> {
+        let txid = tx.txid();
+        self.walk(txid, |parent_txid| {
+            let outputs = if *parent_txid == txid {
+                tx.output.len()
+            } else {
+                self.txstore[parent_txid].output.len()
+            };
+            (0..outputs as u32)
+                .filter_map(|vout| {
+                    self.edges.get(&OutPoint {
+                        txid: *parent_txid,
+                        vout,
+                    })
+                })
+                .map(|(spending_txid, _)| *spending_txid)
+                .collect()
+        })
+    }
+
+    fn walk<F>(&self, txid: Sha256dHash, related: F) -> Vec<Sha256dHash>
+    where
+        F: Fn(&Sha256dHash) -> Vec<Sha256dHash>,
+    {
+        let mut seen = HashSet::new();
+        let mut queue = vec![txid];
+        while let Some(txid) = queue.pop() {
+            for related_txid in related(&txid) {
+                if seen.insert(related_txid) {
+                    queue.push(related_txid);
+                }
+            }
+        }
+        seen.into_iter().collect()
+    }
+
+    // The total fees and vsize of the transactions
+    fn package_totals(&self, txids: &[Sha256dHash]) -> (u64, u64) {
+        txids
+            .iter()
+            .filter_map(|txid| self.feeinfo.get(txid))
+            .fold((0, 0), |(fees, vsize), feeinfo| {
+                (fees + feeinfo.fee, vsize + feeinfo.vsize as u64)
+            })
+    }
+
+    fn ancestor_feerate(&self, txid: &Sha256dHash) -> f32 {
+        let mut package = self.ancestors(txid);
+        package.push(*txid);
+        let (fees, vsize) = self.package_totals(&package);
+        fees as f32 / vsize as f32
+    }
+
+    pub fn package_info(&self, txid: &Sha256dHash) -> Option<PackageInfo> {
+        let _timer = self.latency.with_label_values(&["package"]).start_timer();
+        let tx = self.txstore.get(txid)?;
+        let feeinfo = self.feeinfo.get(txid)?;
+        let mut ancestors = self.ancestors(txid);
+        let descendants = self.descendants(tx);
+
+        // as selected by miners sorting by ancestor fee rate: held back by low-paying ancestors,
+        // but pulled in by a descendant whose package pays more (CPFP)
+        let effective_feerate = descendants
+            .iter()
+            .map(|descendant| self.ancestor_feerate(descendant))
+            .fold(
+                feeinfo.fee_per_vbyte.min(self.ancestor_feerate(txid)),
+                f32::max,
+            );
+
+        ancestors.push(*txid);
+        let (ancestor_fees, ancestor_vsize) = self.package_totals(&ancestors);
+        let (descendant_fees, descendant_vsize) = self.package_totals(&descendants);
+        Some(PackageInfo {
+            ancestor_count: ancestors.len(),
+            ancestor_vsize,
+            ancestor_fees,
+            descendant_count: descendants.len() + 1,
+            descendant_vsize: descendant_vsize + feeinfo.vsize as u64,
+            descendant_fees: descendant_fees + feeinfo.fee,
+            effective_feerate,
+        })
+    }
+
+    pub fn fee_bump(
+        &self,
+        txid: &Sha256dHash,
+        target_blocks: u16,
+        child_vsize: u32,
+    ) -> Option<FeeBump> {
+        let tx = self.txstore.get(txid)?;
+        let feeinfo = self.feeinfo.get(txid)?;
+        let package = self.package_info(txid)?;
+        let target_feerate = feerate_for_blocks(&self.backlog_stats.0.fee_histogram, target_blocks);
+
+        // the replacement has to get its unconfirmed ancestors to the target too, and BIP125 also
+        // requires it to pay for the transactions it evicts and for relaying itself
+        let ancestors_fees = package.ancestor_fees - feeinfo.fee;
+        let rbf_fee = fee_for(target_feerate, package.ancestor_vsize)
+            .saturating_sub(ancestors_fees)
+            .max(
+                package.descendant_fees + fee_for(INCREMENTAL_RELAY_FEERATE, feeinfo.vsize as u64),
+            );
+        let signaled = self
+            .ancestors(txid)
+            .iter()
+            .chain(Some(txid))
+            .filter_map(|txid| self.txstore.get(txid))
+            .any(|tx| tx.input.iter().any(|txin| txin.sequence < 0xffff_fffe));
+
+        let vouts: Vec<u32> = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(vout, txo)| (vout as u32, txo))
+            .filter(|(vout, txo)| {
+                is_spendable(txo)
+                    && !self.edges.contains_key(&OutPoint {
+                        txid: *txid,
+                        vout: *vout,
+                    })
+            })
+            .map(|(vout, _)| vout)
+            .collect();
+        let cpfp = if vouts.is_empty() {
+            None
+        } else {
+            let package_vsize = package.ancestor_vsize + child_vsize as u64;
+            Some(CpfpBump {
+                vouts,
+                child_vsize,
+                child_fee: fee_for(target_feerate, package_vsize)
+                    .saturating_sub(package.ancestor_fees)
+                    .max(fee_for(MIN_RELAY_FEERATE, child_vsize as u64)),
+            })
+        };
+
+        Some(FeeBump {
+            target_feerate,
+            effective_feerate: package.effective_feerate,
+            on_target: package.effective_feerate >= target_feerate,
+            rbf: RbfBump {
+                signaled,
+                fee: rbf_fee,
+                additional_fee: rbf_fee - feeinfo.fee,
+            },
+            cpfp,
+        })
+    }
+
     // XXX return as Vec<(Transaction,Option<BlockId>)>?
     pub fn history(&self, scripthash: &[u8], limit: usize) -> Vec<Transaction> {
         self.history
@@ -132,6 +418,10 @@ impl Mempool {
             .collect()
     }
 
+    pub fn has_history(&self, scripthash: &[u8]) -> bool {
+        self.history.contains_key(scripthash)
+    }
+
     pub fn history_txids(&self, scripthash: &[u8]) -> Vec<Sha256dHash> {
         let _timer = self
             .latency
@@ -143,6 +433,21 @@ impl Mempool {
         }
     }
 
+    // The recently evicted transactions of the script, the most recent first (unless they've
+    // re-entered the mempool since)
+    pub fn evicted_history(&self, scripthash: &[u8]) -> Vec<&EvictedTx> {
+        self.evicted_history
+            .get(scripthash)
+            .map_or(vec![], |txids| {
+                txids
+                    .iter()
+                    .rev()
+                    .filter(|txid| !self.txstore.contains_key(txid))
+                    .filter_map(|txid| self.evicted.get(txid))
+                    .collect()
+            })
+    }
+
     pub fn utxo(&self, scripthash: &[u8]) -> Vec<Utxo> {
         let _timer = self.latency.with_label_values(&["utxo"]).start_timer();
         let entries = match self.history.get(scripthash) {
@@ -237,6 +542,54 @@ impl Mempool {
         &self.backlog_stats.0
     }
 
+    pub fn projected_blocks(&self) -> &[ProjectedBlock] {
+        &self.projected_blocks
+    }
+
+    // Fills the blocks greedily by order of ancestor fee rate, including each transaction with its
+    // ancestors that aren't in yet. Unlike bitcoind, the ancestor fee rates aren't updated as the
+    // ancestors get included.
+    fn project_blocks(&self) -> Vec<ProjectedBlock> {
+        let mut candidates: Vec<(f32, &Sha256dHash)> = self
+            .feeinfo
+            .keys()
+            .map(|txid| (self.ancestor_feerate(txid), txid))
+            .collect();
+        candidates.sort_unstable_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap());
+
+        let mut included = HashSet::new();
+        let mut blocks = vec![];
+        let mut packages = vec![]; // of the current block, as (feerate, tx count, fees, vsize)
+        let mut block_vsize = 0;
+        for (_, txid) in candidates {
+            if included.contains(txid) {
+                continue;
+            }
+            let mut package: Vec<Sha256dHash> = self
+                .ancestors(txid)
+                .into_iter()
+                .filter(|ancestor| !included.contains(ancestor))
+                .collect();
+            package.push(*txid);
+            let (fees, vsize) = self.package_totals(&package);
+            if block_vsize + vsize > BLOCK_VSIZE
+                && !packages.is_empty()
+                && blocks.len() < PROJECTED_BLOCKS - 1
+            {
+                blocks.push(ProjectedBlock::new(&packages));
+                packages.clear();
+                block_vsize = 0;
+            }
+            packages.push((fees as f32 / vsize as f32, package.len(), fees, vsize));
+            block_vsize += vsize;
+            included.extend(package);
+        }
+        if !packages.is_empty() {
+            blocks.push(ProjectedBlock::new(&packages));
+        }
+        blocks
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
         let _timer = self.latency.with_label_values(&["update"]).start_timer();
         let new_txids = daemon
@@ -258,6 +611,7 @@ impl Mempool {
         self.add(to_add);
         // Remove missing transactions
         self.remove(to_remove);
+        self.expire_evicted();
 
         self.count
             .with_label_values(&["txs"])
@@ -270,11 +624,95 @@ impl Mempool {
                 .with_label_values(&["update_backlog_stats"])
                 .start_timer();
             self.backlog_stats = (BacklogStats::new(&self.feeinfo), Instant::now());
+            self.projected_blocks = self.project_blocks();
         }
 
         Ok(())
     }
 
+    // Persist the mempool transactions, to be restored by load_snapshot() on the next startup
+    pub fn save_snapshot(&self, path: &Path) -> Result<()> {
+        let _timer = self
+            .latency
+            .with_label_values(&["save_snapshot"])
+            .start_timer();
+        let snapshot = MempoolSnapshot {
+            txs: self.txstore.values().map(serialize).collect(),
+        };
+        let data = bincode::serialize(&snapshot).chain_err(|| "failed to serialize mempool")?;
+        // write to a temporary file first, so that a crash can't leave a truncated snapshot behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .chain_err(|| format!("failed to write mempool snapshot to {:?}", path))?;
+        info!("saved {} mempool txs to {:?}", snapshot.txs.len(), path);
+        Ok(())
+    }
+
+    // Restore a snapshot saved by save_snapshot(). Should be called once the chain index is
+    // up to date, before the first update() re-syncs the mempool with the daemon.
+    pub fn load_snapshot(&mut self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let _timer = self
+            .latency
+            .with_label_values(&["load_snapshot"])
+            .start_timer();
+        let data = fs::read(path).chain_err(|| format!("failed to read {:?}", path))?;
+        // the snapshot is only valid until the next update(), don't load it again after a crash
+        fs::remove_file(path).chain_err(|| format!("failed to remove {:?}", path))?;
+
+        let snapshot: MempoolSnapshot =
+            bincode::deserialize(&data).chain_err(|| "failed to parse mempool snapshot")?;
+        let mut txs: HashMap<Sha256dHash, Transaction> = snapshot
+            .txs
+            .iter()
+            .map(|rawtx| {
+                let tx: Transaction =
+                    deserialize(rawtx).chain_err(|| "failed to parse mempool tx")?;
+                Ok((tx.txid(), tx))
+            })
+            .collect::<Result<_>>()?;
+        let total = txs.len();
+
+        // Blocks may have been mined while we were down. Drop transactions that got confirmed,
+        // and (repeatedly) those spending outputs that are no longer available to them.
+        txs.retain(|txid, _| self.chain.tx_confirming_block(txid).is_none());
+        loop {
+            let invalid: Vec<Sha256dHash> = txs
+                .iter()
+                .filter(|(_, tx)| {
+                    tx.input
+                        .iter()
+                        .filter(|txin| has_prevout(txin))
+                        .map(|txin| txin.previous_output)
+                        .any(|prevout| {
+                            !txs.contains_key(&prevout.txid)
+                                && (self.chain.lookup_txo(&prevout).is_none()
+                                    || self.chain.lookup_spend(&prevout).is_some())
+                        })
+                })
+                .map(|(txid, _)| *txid)
+                .collect();
+            if invalid.is_empty() {
+                break;
+            }
+            for txid in invalid {
+                txs.remove(&txid);
+            }
+        }
+
+        info!(
+            "restored {} of {} mempool txs from {:?}",
+            txs.len(),
+            total,
+            path
+        );
+        self.add(txs.into_iter().map(|(_, tx)| tx).collect());
+        Ok(())
+    }
+
     pub fn add_by_txid(&mut self, daemon: &Daemon, txid: &Sha256dHash) {
         if let Ok(tx) = daemon.getmempooltx(&txid) {
             self.add(vec![tx])
@@ -307,6 +745,13 @@ impl Mempool {
             let tx = self.txstore.get(&txid).expect("missing mempool tx");
             let txid_bytes = full_hash(&txid[..]);
 
+            #[cfg(not(feature = "liquid"))]
+            {
+                if let Some(ref watchlist) = self.watchlist {
+                    watchlist.check_tx(tx, &txos, None);
+                }
+            }
+
             let prevouts: HashMap<u32, &TxOut> = tx
                 .input
                 .iter()
@@ -434,6 +879,12 @@ impl Mempool {
             .observe(to_remove.len() as f64);
         let _timer = self.latency.with_label_values(&["remove"]).start_timer();
 
+        // the transactions that didn't confirm, before they're gone
+        let evicted: HashMap<Sha256dHash, EvictedTx> = to_remove
+            .iter()
+            .filter_map(|txid| Some((**txid, self.eviction(txid)?)))
+            .collect();
+
         for txid in &to_remove {
             self.txstore
                 .remove(*txid)
@@ -446,10 +897,24 @@ impl Mempool {
         }
 
         // TODO: make it more efficient (currently it takes O(|mempool|) time)
-        self.history.retain(|_scripthash, entries| {
+        let evicted_history = &mut self.evicted_history;
+        self.history.retain(|scripthash, entries| {
+            for txid in entries.iter().map(|entry| entry.get_txid()) {
+                if evicted.contains_key(&txid) {
+                    let txids = evicted_history.entry(*scripthash).or_insert_with(Vec::new);
+                    if !txids.contains(&txid) {
+                        txids.push(txid);
+                    }
+                }
+            }
             entries.retain(|entry| !to_remove.contains(&entry.get_txid()));
             !entries.is_empty()
         });
+        for (txid, evicted_tx) in evicted {
+            if self.evicted.insert(txid, evicted_tx).is_none() {
+                self.evicted_order.push_back(txid);
+            }
+        }
 
         #[cfg(feature = "liquid")]
         asset::remove_mempool_tx_assets(
@@ -462,6 +927,65 @@ impl Mempool {
             .retain(|_outpoint, (txid, _vin)| !to_remove.contains(txid));
     }
 
+    // Why the transaction is leaving the mempool, or None if it's because it confirmed
+    fn eviction(&self, txid: &Sha256dHash) -> Option<EvictedTx> {
+        if self.chain.tx_confirming_block(txid).is_some() {
+            return None;
+        }
+        let tx = self.txstore.get(txid)?;
+        // the replacements' spending edges took the place of the transaction's
+        let conflict = tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .find_map(|txin| match self.edges.get(&txin.previous_output) {
+                Some((spending_txid, _)) if spending_txid != txid => {
+                    Some((EvictionReason::Replaced, *spending_txid))
+                }
+                _ => self
+                    .chain
+                    .lookup_spend(&txin.previous_output)
+                    .map(|spend| (EvictionReason::Conflicted, spend.txid)),
+            });
+        Some(EvictedTx {
+            tx: tx.clone(),
+            fee: self.feeinfo.get(txid).map_or(0, |feeinfo| feeinfo.fee),
+            reason: conflict.map_or(EvictionReason::Dropped, |(reason, _)| reason),
+            conflicting_txid: conflict.map(|(_, txid)| txid),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        })
+    }
+
+    fn expire_evicted(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut expired = false;
+        while let Some(txid) = self.evicted_order.front().cloned() {
+            let evicted_tx = &self.evicted[&txid];
+            if evicted_tx.time + EVICTED_TXS_TTL > now && self.evicted.len() <= EVICTED_TXS_MAX {
+                break;
+            }
+            self.evicted.remove(&txid);
+            self.evicted_order.pop_front();
+            expired = true;
+        }
+        if expired {
+            let evicted = &self.evicted;
+            self.evicted_history.retain(|_scripthash, txids| {
+                txids.retain(|txid| evicted.contains_key(txid));
+                !txids.is_empty()
+            });
+        }
+        self.count
+            .with_label_values(&["evicted"])
+            .set(self.evicted.len() as f64);
+    }
+
     #[cfg(feature = "liquid")]
     pub fn asset_history(&self, asset_id: &Sha256dHash, limit: usize) -> Vec<Transaction> {
         self.asset_history
@@ -478,6 +1002,35 @@ pub struct BacklogStats {
     pub fee_histogram: Vec<(f32, u32)>,
 }
 
+impl ProjectedBlock {
+    fn new(packages: &[(f32, usize, u64, u64)]) -> Self {
+        let vsize: u64 = packages.iter().map(|(_, _, _, vsize)| vsize).sum();
+        let mut feerates: Vec<(f32, u64)> = packages
+            .iter()
+            .map(|(feerate, _, _, vsize)| (*feerate, *vsize))
+            .collect();
+        feerates.sort_unstable_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+        // the fee rate paid by the vbyte in the middle of the block
+        let mut median_feerate = 0.0;
+        let mut below = 0;
+        for (feerate, package_vsize) in &feerates {
+            median_feerate = *feerate;
+            below += package_vsize;
+            if below * 2 >= vsize {
+                break;
+            }
+        }
+        ProjectedBlock {
+            tx_count: packages.iter().map(|(_, count, _, _)| count).sum(),
+            vsize,
+            total_fees: packages.iter().map(|(_, _, fees, _)| fees).sum(),
+            min_feerate: feerates.first().map_or(0.0, |(feerate, _)| *feerate),
+            median_feerate,
+            max_feerate: feerates.last().map_or(0.0, |(feerate, _)| *feerate),
+        }
+    }
+}
+
 impl BacklogStats {
     fn default() -> Self {
         BacklogStats {
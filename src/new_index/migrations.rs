@@ -0,0 +1,199 @@
+// In-place upgrades of the databases created with an older DB_VERSION, run on startup instead of
+// requiring a reindex. Each migration rewrites the rows under its prefix in batches, recording the
+// last migrated key under "m" (atomically with each batch) so that it resumes from there if
+// interrupted. The version is bumped once a migration completes, and they're chained until the
+// current version is reached. A version bump comes with the migrations of the databases whose rows
+// changed, the other databases are bumped as they are.
+
+use hex;
+
+use crate::errors::*;
+use crate::new_index::db::{DBFlush, DBRow, DB, DB_VERSION};
+use crate::new_index::progress::{IndexPhase, IndexProgress};
+use crate::util::Bytes;
+
+const CURSOR_KEY: &[u8] = b"m";
+const BATCH_SIZE: usize = 10_000;
+
+pub struct Migration {
+    pub db: &'static str, // "txstore", "history" or "cache"
+    pub from_version: u32,
+    pub description: &'static str,
+    pub prefix: &'static [u8],
+    // The rows replacing each migrated row, which is deleted. These must either keep the same key
+    // or be outside of `prefix`, so that they don't get migrated again.
+    pub migrate: fn(DBRow) -> Vec<DBRow>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    db: "cache",
+    from_version: 1,
+    description: "drop the cached script stats without the first and last seen blocks",
+    prefix: b"A",
+    migrate: drop_row,
+}];
+
+// For the cached rows, that are recomputed on demand
+fn drop_row(_row: DBRow) -> Vec<DBRow> {
+    vec![]
+}
+
+// Upgrade the `name` database to DB_VERSION
+pub fn run(db: &DB, name: &str, migrations: &[Migration], progress: &IndexProgress) -> Result<()> {
+    loop {
+        let version = db.version();
+        if version >= DB_VERSION {
+            return Ok(());
+        }
+        if !migrations.iter().any(|m| m.from_version == version) {
+            bail!(
+                "no migration for the {} database from version {}, please reindex",
+                name,
+                version
+            );
+        }
+        if let Some(migration) = migrations
+            .iter()
+            .find(|m| m.db == name && m.from_version == version)
+        {
+            info!(
+                "migrating the {} database from version {}: {}",
+                name, version, migration.description
+            );
+            let estimated_rows = db.stats().estimated_keys.unwrap_or(0) as usize;
+            progress.start_phase(IndexPhase::Migration, estimated_rows);
+            migrate(db, migration, progress);
+        }
+
+        db.write_with_deletes(
+            vec![DB::version_row(version + 1)],
+            vec![CURSOR_KEY.to_vec()],
+            DBFlush::Enable,
+        );
+        info!("migrated the {} database to version {}", name, version + 1);
+    }
+}
+
+fn migrate(db: &DB, migration: &Migration, progress: &IndexProgress) {
+    let mut cursor: Option<Bytes> = db.get(CURSOR_KEY);
+    if let Some(ref key) = cursor {
+        info!("resuming migration after {}", hex::encode(key));
+    }
+    loop {
+        let batch: Vec<DBRow> = {
+            let start = cursor.as_ref().map_or(migration.prefix, |key| &key[..]);
+            db.iter_scan_from(migration.prefix, start)
+                .filter(|row| Some(&row.key) != cursor.as_ref())
+                .take(BATCH_SIZE)
+                .collect()
+        };
+        let last_key = match batch.last() {
+            Some(row) => row.key.clone(),
+            None => break,
+        };
+        let migrated_count = batch.len();
+        let deletes: Vec<Bytes> = batch.iter().map(|row| row.key.clone()).collect();
+        let mut rows: Vec<DBRow> = batch.into_iter().flat_map(migration.migrate).collect();
+        let rows_count = rows.len();
+        rows.push(DBRow {
+            key: CURSOR_KEY.to_vec(),
+            value: last_key.clone(),
+        });
+        db.write_with_deletes(rows, deletes, DBFlush::Enable);
+        progress.advance(migrated_count, rows_count);
+        cursor = Some(last_key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Migration, MIGRATIONS};
+    use crate::new_index::db::{DBFlush, DBRow, DB, DB_VERSION};
+    use crate::new_index::progress::IndexProgress;
+
+    fn upper_case(row: DBRow) -> Vec<DBRow> {
+        vec![DBRow {
+            key: row.key,
+            value: row.value.to_ascii_uppercase(),
+        }]
+    }
+
+    #[test]
+    fn test_migration() {
+        let db = DB::in_memory();
+        let rows = (0..25_000u32)
+            .map(|i| DBRow {
+                key: [&b"X"[..], &i.to_be_bytes()[..]].concat(),
+                value: b"abc".to_vec(),
+            })
+            .collect();
+        db.write(rows, DBFlush::Disable);
+        // pretend the database was created by the previous version, with an interrupted migration
+        db.write(
+            vec![
+                DB::version_row(DB_VERSION - 1),
+                DBRow {
+                    key: b"m".to_vec(),
+                    value: [&b"X"[..], &9_999u32.to_be_bytes()[..]].concat(),
+                },
+            ],
+            DBFlush::Disable,
+        );
+
+        let migrations = [Migration {
+            db: "txstore",
+            from_version: DB_VERSION - 1,
+            description: "upper case",
+            prefix: b"X",
+            migrate: upper_case,
+        }];
+        let progress = IndexProgress::new();
+        run(&db, "txstore", &migrations, &progress).unwrap();
+
+        assert_eq!(db.version(), DB_VERSION);
+        assert_eq!(db.get(b"m"), None);
+        let values: Vec<Vec<u8>> = db.iter_scan(b"X").map(|row| row.value).collect();
+        assert_eq!(values.len(), 25_000);
+        // the rows before the cursor were already migrated
+        assert!(values[..10_000].iter().all(|v| v == b"abc"));
+        assert!(values[10_000..].iter().all(|v| v == b"ABC"));
+
+        // the other databases are bumped without changes
+        let other = DB::in_memory();
+        other.write(
+            vec![
+                DB::version_row(DB_VERSION - 1),
+                DBRow {
+                    key: b"Xa".to_vec(),
+                    value: b"abc".to_vec(),
+                },
+            ],
+            DBFlush::Disable,
+        );
+        run(&other, "history", &migrations, &progress).unwrap();
+        assert_eq!(other.version(), DB_VERSION);
+        assert_eq!(other.get(b"Xa"), Some(b"abc".to_vec()));
+
+        // unless there's no migration from their version at all
+        other.write(vec![DB::version_row(DB_VERSION - 1)], DBFlush::Disable);
+        run(&other, "history", &[], &progress).unwrap_err();
+    }
+
+    #[test]
+    fn test_drop_cached_stats() {
+        let db = DB::in_memory();
+        let row = |key: &[u8]| DBRow {
+            key: key.to_vec(),
+            value: b"abc".to_vec(),
+        };
+        db.write(
+            vec![DB::version_row(1), row(b"A1"), row(b"A2"), row(b"U1")],
+            DBFlush::Disable,
+        );
+        run(&db, "cache", MIGRATIONS, &IndexProgress::new()).unwrap();
+
+        assert_eq!(db.version(), DB_VERSION);
+        assert_eq!(db.iter_scan(b"A").count(), 0);
+        assert_eq!(db.get(b"U1"), Some(b"abc".to_vec()));
+    }
+}
@@ -0,0 +1,307 @@
+// Tags the blocks with their miner as they're indexed (with --index-miners), by matching their
+// coinbase transaction against a ruleset of mining pools: the addresses the coinbase pays, then the
+// tags found in its scriptSig. The ruleset is read from --miner-tags, a JSON file of the form:
+//
+//   [
+//     { "name": "Some Pool", "tags": ["/SomePool/"], "addresses": ["bc1q..."] }
+//   ]
+//
+// and otherwise defaults to the tags of a few large pools. The matches are written to the history
+// db:
+//
+//   G{blockhash} → {miner name}
+//
+// Untagged blocks have no row, and changes to the ruleset only apply to the blocks indexed since.
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::{Address, Script, Transaction};
+use serde_json;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::ChainQuery;
+use crate::util::Bytes;
+
+const DEFAULT_RULES: &str = r#"[
+    { "name": "Foundry USA", "tags": ["Foundry USA Pool"] },
+    { "name": "AntPool", "tags": ["Mined by AntPool", "/AntPool/"] },
+    { "name": "F2Pool", "tags": ["F2Pool", "七彩神仙鱼"] },
+    { "name": "ViaBTC", "tags": ["/ViaBTC/", "viabtc.com"] },
+    { "name": "Binance Pool", "tags": ["binance"] },
+    { "name": "Braiins Pool", "tags": ["/slush/"] },
+    { "name": "MARA Pool", "tags": ["MARA Pool"] },
+    { "name": "Luxor", "tags": ["Luxor"] },
+    { "name": "SpiderPool", "tags": ["SpiderPool"] },
+    { "name": "Poolin", "tags": ["poolin.com"] },
+    { "name": "OCEAN", "tags": ["OCEAN.XYZ"] }
+]"#;
+
+const SECONDS_PER_DAY: u32 = 86_400;
+const MAX_WINDOW_DAYS: u32 = 366;
+
+#[derive(Deserialize)]
+struct PoolRule {
+    name: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    addresses: Vec<String>,
+}
+
+struct Pool {
+    name: String,
+    tags: Vec<Vec<u8>>,
+    scripts: HashSet<Script>,
+}
+
+pub struct MinerTags {
+    pools: Vec<Pool>, // the first match wins
+}
+
+impl MinerTags {
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let tags = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .chain_err(|| format!("failed to read miner tags from {:?}", path))?;
+                MinerTags::parse(&contents)
+                    .chain_err(|| format!("invalid miner tags file at {:?}", path))?
+            }
+            None => MinerTags::parse(DEFAULT_RULES)?,
+        };
+        info!("loaded the tags of {} mining pools", tags.pools.len());
+        Ok(tags)
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let rules: Vec<PoolRule> =
+            serde_json::from_str(contents).chain_err(|| "invalid miner tags JSON")?;
+        let mut pools = vec![];
+        for rule in rules {
+            let mut scripts = HashSet::new();
+            for address in &rule.addresses {
+                let address = Address::from_str(address)
+                    .chain_err(|| format!("invalid address {} of {}", address, rule.name))?;
+                scripts.insert(address.script_pubkey());
+            }
+            pools.push(Pool {
+                name: rule.name,
+                tags: rule
+                    .tags
+                    .into_iter()
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::into_bytes)
+                    .collect(),
+                scripts,
+            });
+        }
+        Ok(MinerTags { pools })
+    }
+
+    // The payout addresses are more reliable than the tags, which anyone can put in a coinbase
+    pub fn identify(&self, coinbase: &Transaction) -> Option<&str> {
+        let script_sig = coinbase
+            .input
+            .first()
+            .map_or(&[][..], |txin| &txin.script_sig[..]);
+        self.pools
+            .iter()
+            .find(|pool| {
+                coinbase
+                    .output
+                    .iter()
+                    .any(|txo| pool.scripts.contains(&txo.script_pubkey))
+            })
+            .or_else(|| {
+                self.pools.iter().find(|pool| {
+                    pool.tags.iter().any(|tag| {
+                        script_sig
+                            .windows(tag.len())
+                            .any(|window| window == tag.as_slice())
+                    })
+                })
+            })
+            .map(|pool| pool.name.as_str())
+    }
+}
+
+fn miner_key(blockhash: &Sha256dHash) -> Bytes {
+    [&b"G"[..], &blockhash[..]].concat()
+}
+
+pub fn index_miners(block_entries: &[BlockEntry], tags: &MinerTags) -> Vec<DBRow> {
+    block_entries
+        .iter()
+        .filter_map(|b| {
+            let miner = tags.identify(b.block.txdata.first()?)?;
+            Some(DBRow {
+                key: miner_key(b.entry.hash()),
+                value: miner.as_bytes().to_vec(),
+            })
+        })
+        .collect()
+}
+
+pub fn block_miner(chain: &ChainQuery, blockhash: &Sha256dHash) -> Option<String> {
+    chain
+        .store()
+        .history_db()
+        .get(&miner_key(blockhash))
+        .map(|value| String::from_utf8(value).expect("invalid miner name"))
+}
+
+#[derive(Serialize)]
+pub struct PoolStats {
+    pub name: Option<String>, // null for the untagged blocks
+    pub block_count: u32,
+    pub share: f64, // of the window's blocks, in percent
+    pub total_fees: u64,
+}
+
+#[derive(Serialize)]
+pub struct MiningPools {
+    pub from_height: usize,
+    pub to_height: usize,
+    pub block_count: u32,
+    pub total_fees: u64,
+    pub pools: Vec<PoolStats>, // by block count, descending
+}
+
+// Parses a window of `<n>d`, `<n>w`, `<n>m` (30 days) or `<n>y` (365 days) into seconds, of up to
+// MAX_WINDOW_DAYS
+pub fn parse_window(window: &str) -> Option<u32> {
+    if window.len() < 2 || !window.is_char_boundary(window.len() - 1) {
+        return None;
+    }
+    let (count, unit) = window.split_at(window.len() - 1);
+    let days = match unit {
+        "d" => 1,
+        "w" => 7,
+        "m" => 30,
+        "y" => 365,
+        _ => return None,
+    };
+    let days = count.parse::<u32>().ok()?.checked_mul(days)?;
+    if days == 0 || days > MAX_WINDOW_DAYS {
+        return None;
+    }
+    Some(days * SECONDS_PER_DAY)
+}
+
+// The blocks mined by each pool, over the `window` seconds before the tip's time
+pub fn mining_pools(chain: &ChainQuery, window: u32) -> MiningPools {
+    let tip = chain.best_header();
+    let since = tip.header().time.saturating_sub(window);
+    let mut pools: HashMap<Option<String>, (u32, u64)> = HashMap::new();
+    let mut from_height = tip.height();
+    let mut height = tip.height();
+    while let Some(entry) = chain.header_by_height(height) {
+        if entry.header().time < since {
+            break;
+        }
+        let total_fees = chain
+            .get_block_stats(entry.hash())
+            .ok()
+            .flatten()
+            .map_or(0, |stats| stats.total_fees);
+        let pool = pools.entry(block_miner(chain, entry.hash())).or_default();
+        pool.0 += 1;
+        pool.1 += total_fees;
+        from_height = height;
+        if height == 0 {
+            break;
+        }
+        height -= 1;
+    }
+    let block_count: u32 = pools.values().map(|(count, _)| count).sum();
+    let mut pools: Vec<PoolStats> = pools
+        .into_iter()
+        .map(|(name, (count, total_fees))| PoolStats {
+            name,
+            block_count: count,
+            share: 100.0 * f64::from(count) / f64::from(block_count),
+            total_fees,
+        })
+        .collect();
+    pools.sort_by(|a, b| {
+        b.block_count
+            .cmp(&a.block_count)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    MiningPools {
+        from_height,
+        to_height: tip.height(),
+        block_count,
+        total_fees: pools.iter().map(|pool| pool.total_fees).sum(),
+        pools,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_window, MinerTags, DEFAULT_RULES};
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{Address, OutPoint, Transaction, TxIn, TxOut};
+    use std::str::FromStr;
+
+    fn coinbase(script_sig: &[u8], address: &str) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_slice(script_sig).into_script(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 625_000_000,
+                script_pubkey: Address::from_str(address).unwrap().script_pubkey(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_identify() {
+        let tags = MinerTags::parse(
+            r#"[
+                { "name": "Tagged", "tags": ["/Tagged/"] },
+                { "name": "Paid", "addresses": ["bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"] }
+            ]"#,
+        )
+        .unwrap();
+        let other = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+        assert_eq!(
+            tags.identify(&coinbase(b"\x03abc/Tagged/xyz", other)),
+            Some("Tagged")
+        );
+        // the payout address wins
+        assert_eq!(
+            tags.identify(&coinbase(
+                b"/Tagged/",
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+            )),
+            Some("Paid")
+        );
+        assert_eq!(tags.identify(&coinbase(b"/Other/", other)), None);
+        assert!(MinerTags::parse(r#"[{ "name": "Bad", "addresses": ["xyz"] }]"#).is_err());
+        assert!(MinerTags::parse(DEFAULT_RULES).is_ok());
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("1w"), Some(7 * 86_400));
+        assert_eq!(parse_window("3m"), Some(90 * 86_400));
+        assert_eq!(parse_window("1y"), Some(365 * 86_400));
+        assert_eq!(parse_window("2y"), None);
+        assert_eq!(parse_window("0d"), None);
+        assert_eq!(parse_window("w"), None);
+        assert_eq!(parse_window("1h"), None);
+    }
+}
@@ -1,13 +1,40 @@
+pub mod addresses;
+pub mod audit;
+mod budget;
+pub mod cluster;
+#[cfg(not(feature = "liquid"))]
+pub mod dailystats;
 pub mod db;
+#[cfg(not(feature = "liquid"))]
+pub mod export;
+#[cfg(not(feature = "liquid"))]
+pub mod feehistory;
 mod fetch;
+mod lookupcache;
+mod memdb;
 mod mempool;
+pub mod migrations;
+#[cfg(not(feature = "liquid"))]
+pub mod miners;
+pub mod opreturn;
 pub mod precache;
+pub mod prevouts;
+mod progress;
 mod query;
+#[cfg(not(feature = "liquid"))]
+pub mod richlist;
 pub mod schema;
+#[cfg(feature = "sled")]
+mod sleddb;
+pub mod snapshot;
+#[cfg(not(feature = "liquid"))]
+pub mod utxoset;
 
-pub use self::db::{DBRow, DB};
+pub use self::budget::QueryBudget;
+pub use self::db::{DBBackend, DBRow, KVStore, DB};
 pub use self::fetch::{BlockEntry, FetchFrom};
-pub use self::mempool::Mempool;
+pub use self::mempool::{EvictedTx, Mempool, PackageInfo};
+pub use self::progress::{IndexPhase, IndexProgress, ProgressSnapshot};
 pub use self::query::Query;
 pub use self::schema::{
     compute_script_hash, parse_hash, ChainQuery, FundingInfo, Indexer, ScriptStats, SpendingInfo,
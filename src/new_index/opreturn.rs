@@ -0,0 +1,99 @@
+// An index of OP_RETURN outputs by their data prefix, written to the history db as blocks are
+// indexed:
+//
+//   O{prefix}{height (big-endian u32)}{txid}{vout (big-endian u32)} → {data}
+//
+// where `data` is the raw script following the OP_RETURN opcode (including the push opcodes) and
+// `prefix` is its first PREFIX_LEN bytes, zero-padded. Blocks that are not part of the best chain
+// are filtered out at query time.
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::Hash;
+use hex;
+
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::ChainQuery;
+use crate::util::Bytes;
+
+const PREFIX_LEN: usize = 16;
+
+#[derive(Serialize)]
+pub struct OpReturnEntry {
+    pub txid: Sha256dHash,
+    pub vout: u32,
+    pub block_height: u32,
+    pub data: String, // hex
+}
+
+fn prefix_key(data: &[u8]) -> Bytes {
+    let mut key = vec![b'O'];
+    key.extend(data.iter().take(PREFIX_LEN));
+    key.resize(1 + PREFIX_LEN, 0);
+    key
+}
+
+pub fn index_opreturns(block_entries: &[BlockEntry]) -> Vec<DBRow> {
+    let mut rows = vec![];
+    for b in block_entries {
+        let height = b.entry.height() as u32;
+        for tx in &b.block.txdata {
+            let txid = tx.txid();
+            for (vout, txo) in tx.output.iter().enumerate() {
+                let script = &txo.script_pubkey;
+                if !script.is_op_return() {
+                    continue;
+                }
+                let data = &script[1..];
+                rows.push(DBRow {
+                    key: [
+                        &prefix_key(data)[..],
+                        &height.to_be_bytes()[..],
+                        &txid[..],
+                        &(vout as u32).to_be_bytes()[..],
+                    ]
+                    .concat(),
+                    value: data.to_vec(),
+                });
+            }
+        }
+    }
+    rows
+}
+
+// Find the OP_RETURN outputs whose data starts with `prefix`, confirmed at `from_height` or later.
+// Results are ordered by height for prefixes of PREFIX_LEN bytes or more, by data otherwise.
+pub fn search_opreturns(
+    chain: &ChainQuery,
+    prefix: &[u8],
+    from_height: u32,
+    limit: usize,
+) -> Vec<OpReturnEntry> {
+    let mut scan_prefix = vec![b'O'];
+    scan_prefix.extend(prefix.iter().take(PREFIX_LEN));
+    chain
+        .store()
+        .history_db()
+        .iter_scan(&scan_prefix)
+        .filter(|row| row.value.starts_with(prefix))
+        .map(|row| {
+            let key = &row.key[1 + PREFIX_LEN..];
+            OpReturnEntry {
+                block_height: u32::from_be_bytes(*array_ref![key, 0, 4]),
+                txid: Sha256dHash::from_slice(&key[4..36]).unwrap(),
+                vout: u32::from_be_bytes(*array_ref![key, 36, 4]),
+                data: hex::encode(&row.value),
+            }
+        })
+        .filter(|entry| entry.block_height >= from_height)
+        // skip transactions from orphaned blocks
+        .filter(|entry| {
+            chain
+                .tx_confirming_block(&entry.txid)
+                .map_or(false, |blockid| {
+                    blockid.height == entry.block_height as usize
+                })
+        })
+        .take(limit)
+        .collect()
+}
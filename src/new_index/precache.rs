@@ -1,6 +1,6 @@
 use crate::chain::address::Address;
 use crate::errors::*;
-use crate::new_index::ChainQuery;
+use crate::new_index::{ChainQuery, QueryBudget};
 use crate::util::{full_hash, FullHash};
 
 use crypto::digest::Digest;
@@ -30,7 +30,9 @@ pub fn precache(chain: &ChainQuery, scripthashes: Vec<FullHash>) {
                 if i % 5 == 0 {
                     debug!("Starting pre-cache for scripthash {}/{}", i + 1, total);
                 }
-                chain.stats(&scripthash[..]);
+                chain
+                    .stats(&scripthash[..], &QueryBudget::unlimited())
+                    .expect("unlimited budget");
                 //chain.utxo(&scripthash[..]);
             })
     });
@@ -0,0 +1,77 @@
+// An index of the outputs spent by each transaction, written to the history db as blocks are
+// indexed so that the inputs of confirmed transactions can be enriched without looking up their
+// previous outputs one by one:
+//
+//   p{txid} → {serialized prevouts}
+//
+// where the prevouts are listed in input order, skipping the inputs without one (coinbase and
+// peg-ins). Transactions indexed before this was enabled have no row, and fall back to the lookups.
+
+use bincode;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::consensus::encode::{deserialize, serialize};
+#[cfg(feature = "liquid")]
+use elements::encode::{deserialize, serialize};
+
+use std::collections::HashMap;
+
+use crate::chain::{OutPoint, Transaction, TxOut};
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::ChainQuery;
+use crate::util::{has_prevout, Bytes};
+
+fn prevouts_key(txid: &Sha256dHash) -> Bytes {
+    [&b"p"[..], &txid[..]].concat()
+}
+
+pub fn index_prevouts(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    prune_below: u32,
+) -> Vec<DBRow> {
+    block_entries
+        .iter()
+        .filter(|b| b.entry.height() as u32 >= prune_below)
+        .flat_map(|b| b.block.txdata.iter())
+        .filter(|tx| tx.input.iter().any(has_prevout))
+        .map(|tx| {
+            let prevouts: Vec<Bytes> = tx
+                .input
+                .iter()
+                .filter(|txin| has_prevout(txin))
+                .map(|txin| {
+                    let prevout = previous_txos_map
+                        .get(&txin.previous_output)
+                        .expect(&format!("missing previous txo {}", txin.previous_output));
+                    serialize(prevout)
+                })
+                .collect();
+            DBRow {
+                key: prevouts_key(&tx.txid()),
+                value: bincode::serialize(&prevouts).unwrap(),
+            }
+        })
+        .collect()
+}
+
+// The previous outputs spent by a confirmed transaction, keyed by outpoint (if indexed)
+pub fn lookup_prevouts(chain: &ChainQuery, tx: &Transaction) -> Option<HashMap<OutPoint, TxOut>> {
+    let row = chain.store().history_db().get(&prevouts_key(&tx.txid()))?;
+    let prevouts: Vec<Bytes> = bincode::deserialize(&row).expect("failed to parse prevouts");
+    Some(
+        tx.input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .zip(prevouts)
+            .map(|(txin, prevout)| {
+                (
+                    txin.previous_output,
+                    deserialize(&prevout).expect("failed to parse TxOut"),
+                )
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,102 @@
+use std::sync::RwLock;
+use std::time::Instant;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexPhase {
+    Starting,
+    Migration,
+    Headers,
+    Blocks,
+    History,
+    Compaction,
+    Synced,
+}
+
+struct ProgressState {
+    phase: IndexPhase,
+    phase_started: Instant,
+    daemon_height: Option<usize>,
+    steps_total: usize, // blocks to process (or ranges to compact) in the current phase
+    steps_done: usize,
+    rows_written: u64,
+}
+
+// Indexing progress, updated by the Indexer and reported via the REST API
+pub struct IndexProgress {
+    state: RwLock<ProgressState>,
+}
+
+#[derive(Serialize)]
+pub struct ProgressSnapshot {
+    pub phase: IndexPhase,
+    pub daemon_height: Option<usize>,
+    pub phase_total: usize,
+    pub phase_done: usize,
+    pub rows_written: u64,
+    pub phase_elapsed_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+}
+
+impl IndexProgress {
+    pub fn new() -> Self {
+        IndexProgress {
+            state: RwLock::new(ProgressState {
+                phase: IndexPhase::Starting,
+                phase_started: Instant::now(),
+                daemon_height: None,
+                steps_total: 0,
+                steps_done: 0,
+                rows_written: 0,
+            }),
+        }
+    }
+
+    pub fn start_phase(&self, phase: IndexPhase, steps_total: usize) {
+        let mut state = self.state.write().unwrap();
+        if state.phase != phase {
+            debug!("indexing phase {:?} ({} steps)", phase, steps_total);
+        }
+        state.phase = phase;
+        state.phase_started = Instant::now();
+        state.steps_total = steps_total;
+        state.steps_done = 0;
+    }
+
+    pub fn set_daemon_height(&self, height: usize) {
+        self.state.write().unwrap().daemon_height = Some(height);
+    }
+
+    pub fn advance(&self, steps: usize, rows: usize) {
+        let mut state = self.state.write().unwrap();
+        state.steps_done += steps;
+        state.rows_written += rows as u64;
+    }
+
+    pub fn phase(&self) -> IndexPhase {
+        self.state.read().unwrap().phase
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let state = self.state.read().unwrap();
+        let elapsed = state.phase_started.elapsed();
+        // extrapolate from the average rate of the current phase
+        let eta_secs = if state.steps_done > 0 && state.steps_done <= state.steps_total {
+            let remaining = (state.steps_total - state.steps_done) as f64;
+            let per_step = elapsed.as_millis() as f64 / 1000f64 / state.steps_done as f64;
+            Some((remaining * per_step) as u64)
+        } else {
+            None
+        };
+        ProgressSnapshot {
+            phase: state.phase,
+            daemon_height: state.daemon_height,
+            phase_total: state.steps_total,
+            phase_done: state.steps_done,
+            rows_written: state.rows_written,
+            phase_elapsed_secs: elapsed.as_secs(),
+            eta_secs,
+        }
+    }
+}
@@ -1,20 +1,31 @@
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use lru::LruCache;
 use rayon::prelude::*;
 
 use std::collections::{BTreeSet, HashMap};
-use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use std::time::{Duration, Instant};
 
+use crate::accounts::Accounts;
 use crate::chain::{OutPoint, Transaction, TxOut};
+use crate::consensus::ConsensusMonitor;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::new_index::{ChainQuery, Mempool, ScriptStats, SpendingInput, Utxo};
-use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
+use crate::new_index::audit::{self, AuditReport};
+use crate::new_index::{ChainQuery, Mempool, QueryBudget, ScriptStats, SpendingInput, Utxo};
+use crate::util::fees::TxFeeInfo;
+use crate::util::{has_prevout, is_spendable, BlockId, Bytes, FullHash, TransactionStatus};
+
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::export::HistoryExports;
+#[cfg(not(feature = "liquid"))]
+use crate::{prices::PriceFeed, watch::WatchList};
 
 #[cfg(feature = "liquid")]
 use crate::elements::{lookup_asset, AssetRegistry, LiquidAsset};
 
 const FEE_ESTIMATES_TTL: u64 = 60; // seconds
+const BLOCK_FEERATES_CACHE_SIZE: usize = 100; // blocks
 
 const CONF_TARGETS: [u16; 9] = [
     2u16, 3u16, 4u16, 6u16, 10u16, 20u16, 144u16, 504u16, 1008u16,
@@ -25,22 +36,68 @@ pub struct Query {
     mempool: Arc<RwLock<Mempool>>,
     daemon: Arc<Daemon>,
     cached_estimates: RwLock<Option<(HashMap<u16, f32>, Instant)>>,
+    cached_block_feerates: Mutex<LruCache<Sha256dHash, Arc<Vec<f32>>>>,
+    accounts: Arc<Accounts>,
+    consensus: Option<Arc<ConsensusMonitor>>,
 
+    #[cfg(not(feature = "liquid"))]
+    watchlist: Option<Arc<WatchList>>,
+    #[cfg(not(feature = "liquid"))]
+    prices: Option<Arc<PriceFeed>>,
+    #[cfg(not(feature = "liquid"))]
+    exports: HistoryExports,
     #[cfg(feature = "liquid")]
     asset_db: Option<AssetRegistry>,
 }
 
 impl Query {
     #[cfg(not(feature = "liquid"))]
-    pub fn new(chain: Arc<ChainQuery>, mempool: Arc<RwLock<Mempool>>, daemon: Arc<Daemon>) -> Self {
+    pub fn new(
+        chain: Arc<ChainQuery>,
+        mempool: Arc<RwLock<Mempool>>,
+        daemon: Arc<Daemon>,
+        accounts: Arc<Accounts>,
+        consensus: Option<Arc<ConsensusMonitor>>,
+        watchlist: Option<Arc<WatchList>>,
+        prices: Option<Arc<PriceFeed>>,
+    ) -> Self {
         Query {
             chain,
             mempool,
             daemon,
             cached_estimates: RwLock::new(None),
+            cached_block_feerates: Mutex::new(LruCache::new(BLOCK_FEERATES_CACHE_SIZE)),
+            accounts,
+            consensus,
+            watchlist,
+            prices,
+            exports: HistoryExports::default(),
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn watchlist(&self) -> Option<&WatchList> {
+        self.watchlist.as_ref().map(|watchlist| &**watchlist)
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn prices(&self) -> Option<&Arc<PriceFeed>> {
+        self.prices.as_ref()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn exports(&self) -> &HistoryExports {
+        &self.exports
+    }
+
+    pub fn consensus(&self) -> Option<&ConsensusMonitor> {
+        self.consensus.as_ref().map(|consensus| &**consensus)
+    }
+
+    pub fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
     pub fn chain(&self) -> &ChainQuery {
         &self.chain
     }
@@ -49,6 +106,10 @@ impl Query {
         self.mempool.read().unwrap()
     }
 
+    pub fn audit(&self, blocks: usize, outpoints: usize) -> Result<AuditReport> {
+        audit::audit(&self.chain, &self.daemon, blocks, outpoints)
+    }
+
     pub fn broadcast_raw(&self, txhex: &String) -> Result<Sha256dHash> {
         let txid = self.daemon.broadcast_raw(&txhex)?;
         self.mempool
@@ -58,18 +119,24 @@ impl Query {
         Ok(txid)
     }
 
-    pub fn utxo(&self, scripthash: &[u8]) -> Vec<Utxo> {
-        let mut utxos = self.chain.utxo(scripthash);
+    pub fn utxo(&self, scripthash: &[u8], budget: &QueryBudget) -> Result<Vec<Utxo>> {
+        let _span = tracing::info_span!("utxo").entered();
+        let mut utxos = self.chain.utxo(scripthash, budget)?;
         let mempool = self.mempool();
         utxos.retain(|utxo| !mempool.has_spend(&OutPoint::from(utxo)));
         utxos.extend(mempool.utxo(scripthash));
-        utxos
+        Ok(utxos)
     }
 
-    pub fn history_txids(&self, scripthash: &[u8]) -> Vec<(Sha256dHash, Option<BlockId>)> {
+    pub fn history_txids(
+        &self,
+        scripthash: &[u8],
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Sha256dHash, Option<BlockId>)>> {
+        let _span = tracing::info_span!("history_txids").entered();
         let confirmed_txids = self
             .chain
-            .history_txids(scripthash)
+            .history_txids(scripthash, budget)?
             .into_iter()
             .map(|(tx, b)| (tx, Some(b)));
 
@@ -79,14 +146,30 @@ impl Query {
             .into_iter()
             .map(|tx| (tx, None));
 
-        confirmed_txids.chain(mempool_txids).collect()
+        Ok(confirmed_txids.chain(mempool_txids).collect())
+    }
+
+    // Whether each of the scripthashes was ever used, in the chain or the mempool
+    pub fn scripthashes_used(&self, scripthashes: &[FullHash]) -> Vec<bool> {
+        let _span = tracing::info_span!("scripthashes_used", count = scripthashes.len()).entered();
+        let mut used = self.chain.scripthashes_used(scripthashes);
+        let mempool = self.mempool();
+        for (used, scripthash) in used.iter_mut().zip(scripthashes) {
+            *used = *used || mempool.has_history(&scripthash[..]);
+        }
+        used
     }
 
-    pub fn stats(&self, scripthash: &[u8]) -> (ScriptStats, ScriptStats) {
-        (
-            self.chain.stats(scripthash),
+    pub fn stats(
+        &self,
+        scripthash: &[u8],
+        budget: &QueryBudget,
+    ) -> Result<(ScriptStats, ScriptStats)> {
+        let _span = tracing::info_span!("stats").entered();
+        Ok((
+            self.chain.stats(scripthash, budget)?,
             self.mempool().stats(scripthash),
-        )
+        ))
     }
 
     pub fn lookup_txn(&self, txid: &Sha256dHash) -> Option<Transaction> {
@@ -162,11 +245,64 @@ impl Query {
         fresh
     }
 
+    // Get the sorted fee rates (in sat/vbyte) of the non-coinbase transactions in a block
+    pub fn block_feerates(&self, blockhash: &Sha256dHash) -> Result<Option<Arc<Vec<f32>>>> {
+        if let Some(feerates) = self.cached_block_feerates.lock().unwrap().get(blockhash) {
+            return Ok(Some(Arc::clone(feerates)));
+        }
+
+        let txids = match self.chain.get_block_txids(blockhash) {
+            Some(txids) => txids,
+            None => return Ok(None),
+        };
+        let txs = self.chain.lookup_txns(&txids)?;
+        let outpoints = txs
+            .iter()
+            .flat_map(|tx| {
+                tx.input
+                    .iter()
+                    .filter(|txin| has_prevout(txin))
+                    .map(|txin| txin.previous_output)
+            })
+            .collect();
+        let prevouts = self.chain.lookup_avail_txos(&outpoints);
+
+        let mut feerates = txs
+            .iter()
+            .skip(1) // coinbase
+            .map(|tx| {
+                let tx_prevouts = tx
+                    .input
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, txin)| has_prevout(txin))
+                    .map(|(index, txin)| {
+                        let prevout = prevouts.get(&txin.previous_output).chain_err(|| {
+                            format!("missing previous output {}", txin.previous_output)
+                        })?;
+                        Ok((index as u32, prevout))
+                    })
+                    .collect::<Result<HashMap<u32, &TxOut>>>()?;
+                Ok(TxFeeInfo::new(tx, &tx_prevouts).fee_per_vbyte)
+            })
+            .collect::<Result<Vec<f32>>>()?;
+        feerates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let feerates = Arc::new(feerates);
+        self.cached_block_feerates
+            .lock()
+            .unwrap()
+            .put(*blockhash, Arc::clone(&feerates));
+        Ok(Some(feerates))
+    }
+
     #[cfg(feature = "liquid")]
     pub fn new(
         chain: Arc<ChainQuery>,
         mempool: Arc<RwLock<Mempool>>,
         daemon: Arc<Daemon>,
+        accounts: Arc<Accounts>,
+        consensus: Option<Arc<ConsensusMonitor>>,
         asset_db: Option<AssetRegistry>,
     ) -> Self {
         Query {
@@ -175,6 +311,9 @@ impl Query {
             daemon,
             asset_db,
             cached_estimates: RwLock::new(None),
+            cached_block_feerates: Mutex::new(LruCache::new(BLOCK_FEERATES_CACHE_SIZE)),
+            accounts,
+            consensus,
         }
     }
 
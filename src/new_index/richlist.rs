@@ -0,0 +1,204 @@
+// An index of script balances ordered by value, maintained incrementally as blocks are indexed:
+//
+//   Y{scripthash} → {balance (i64)}                        (absent for zero balances)
+//   R{!balance (big-endian u64)}{scripthash} → {script}   (positive balances only)
+//
+// Balances are updated by summing deltas, so blocks can be processed in any order (balances may
+// be transiently negative during the initial sync, when blocks are read from blk*.dat files).
+// The deltas of the blocks orphaned by a reorg are subtracted back (see Indexer::update).
+
+use bincode;
+use bitcoin::Script;
+use hex;
+
+use std::collections::HashMap;
+
+use crate::chain::{Network, OutPoint, Transaction, TxOut};
+use crate::new_index::db::{DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::{compute_script_hash, ChainQuery};
+use crate::util::{full_hash, has_prevout, is_spendable, script_to_address, Bytes, FullHash};
+
+#[derive(Serialize)]
+pub struct RichListEntry {
+    pub scripthash: String,
+    pub address: Option<String>,
+    pub balance: u64,
+}
+
+fn balance_key(scripthash: &FullHash) -> Bytes {
+    [&b"Y"[..], &scripthash[..]].concat()
+}
+
+// inverted, so that the largest balances are iterated over first
+fn rank_key(balance: u64, scripthash: &FullHash) -> Bytes {
+    [&b"R"[..], &(!balance).to_be_bytes()[..], &scripthash[..]].concat()
+}
+
+fn get_balance(db: &DB, scripthash: &FullHash) -> i64 {
+    db.get(&balance_key(scripthash)).map_or(0, |val| {
+        bincode::deserialize(&val).expect("failed to parse balance")
+    })
+}
+
+// Returns the rows to write and the keys to delete to apply the balance changes of the given
+// blocks, alongside their history rows. Must not run concurrently with itself.
+pub fn index_balances(
+    db: &DB,
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> (Vec<DBRow>, Vec<Bytes>) {
+    let txs = block_entries.iter().flat_map(|b| b.block.txdata.iter());
+    balance_changes(db, txs, previous_txos_map, false)
+}
+
+// Like index_balances, to revert the balance changes of the transactions of orphaned blocks
+pub fn unindex_balances(
+    db: &DB,
+    txs: &[Transaction],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> (Vec<DBRow>, Vec<Bytes>) {
+    balance_changes(db, txs.iter(), previous_txos_map, true)
+}
+
+fn balance_changes<'a, I>(
+    db: &DB,
+    txs: I,
+    previous_txos_map: &'a HashMap<OutPoint, TxOut>,
+    revert: bool,
+) -> (Vec<DBRow>, Vec<Bytes>)
+where
+    I: Iterator<Item = &'a Transaction>,
+{
+    let mut deltas: HashMap<FullHash, (i64, &Script)> = HashMap::new();
+    for tx in txs {
+        for txo in tx.output.iter().filter(|txo| is_spendable(txo)) {
+            let scripthash = compute_script_hash(&txo.script_pubkey);
+            deltas
+                .entry(scripthash)
+                .or_insert((0, &txo.script_pubkey))
+                .0 += txo.value as i64;
+        }
+        for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+            let prevout = previous_txos_map
+                .get(&txin.previous_output)
+                .expect(&format!("missing outpoint {:?}", txin.previous_output));
+            let scripthash = compute_script_hash(&prevout.script_pubkey);
+            deltas
+                .entry(scripthash)
+                .or_insert((0, &prevout.script_pubkey))
+                .0 -= prevout.value as i64;
+        }
+    }
+
+    let mut rows = vec![];
+    let mut deletes = vec![];
+    for (scripthash, (delta, script)) in deltas {
+        if delta == 0 {
+            continue;
+        }
+        let delta = if revert { -delta } else { delta };
+        let old_balance = get_balance(db, &scripthash);
+        let new_balance = old_balance + delta;
+        if old_balance > 0 {
+            deletes.push(rank_key(old_balance as u64, &scripthash));
+        }
+        if new_balance > 0 {
+            rows.push(DBRow {
+                key: rank_key(new_balance as u64, &scripthash),
+                value: script.to_bytes(),
+            });
+        }
+        if new_balance == 0 {
+            deletes.push(balance_key(&scripthash));
+        } else {
+            rows.push(DBRow {
+                key: balance_key(&scripthash),
+                value: bincode::serialize(&new_balance).unwrap(),
+            });
+        }
+    }
+    (rows, deletes)
+}
+
+pub fn richlist(chain: &ChainQuery, network: &Network, limit: usize) -> Vec<RichListEntry> {
+    chain
+        .store()
+        .history_db()
+        .iter_scan(b"R")
+        .take(limit)
+        .map(|row| {
+            let balance = !u64::from_be_bytes(array_ref![row.key, 1, 8].clone());
+            let script = Script::from(row.value);
+            RichListEntry {
+                scripthash: hex::encode(full_hash(&row.key[9..])),
+                address: script_to_address(&script, network),
+                balance,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{balance_changes, balance_key, get_balance, unindex_balances};
+    use crate::chain::{OutPoint, Transaction, TxIn, TxOut};
+    use crate::new_index::compute_script_hash;
+    use crate::new_index::db::{DBFlush, DB};
+    use bitcoin::Script;
+    use std::collections::HashMap;
+
+    fn tx(input: Vec<OutPoint>, output: Vec<(&Script, u64)>) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: input
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: output
+                .into_iter()
+                .map(|(script, value)| TxOut {
+                    value,
+                    script_pubkey: script.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_revert_block() {
+        let db = DB::in_memory();
+        let (a, b) = (Script::from(vec![0x51]), Script::from(vec![0x52]));
+        let funding = tx(vec![OutPoint::null()], vec![(&a, 5000)]);
+        let (rows, deletes) =
+            balance_changes(&db, [funding.clone()].iter(), &HashMap::new(), false);
+        db.write_with_deletes(rows, deletes, DBFlush::Enable);
+
+        // a block spending it to b, later orphaned
+        let prevout = OutPoint::new(funding.txid(), 0);
+        let mut previous_txos_map = HashMap::new();
+        previous_txos_map.insert(prevout, funding.output[0].clone());
+        let spending = [tx(vec![prevout], vec![(&b, 3000), (&a, 1900)])];
+        let (rows, deletes) = balance_changes(&db, spending.iter(), &previous_txos_map, false);
+        db.write_with_deletes(rows, deletes, DBFlush::Enable);
+        assert_eq!(get_balance(&db, &compute_script_hash(&a)), 1900);
+        assert_eq!(get_balance(&db, &compute_script_hash(&b)), 3000);
+
+        let (rows, deletes) = unindex_balances(&db, &spending, &previous_txos_map);
+        db.write_with_deletes(rows, deletes, DBFlush::Enable);
+        assert_eq!(get_balance(&db, &compute_script_hash(&a)), 5000);
+        assert_eq!(get_balance(&db, &compute_script_hash(&b)), 0);
+        // the only ranked balance is a's
+        let ranked: Vec<Vec<u8>> = db.iter_scan(b"R").map(|row| row.value).collect();
+        assert_eq!(ranked, vec![a.to_bytes()]);
+        assert!(db
+            .get(&super::balance_key(&compute_script_hash(&b)))
+            .is_none());
+    }
+}
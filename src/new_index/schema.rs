@@ -1,21 +1,25 @@
 use bincode;
 use bitcoin::blockdata::script::Script;
+use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use itertools::Itertools;
 use rayon::prelude::*;
 
+use bitcoin::consensus::encode::VarInt;
 #[cfg(not(feature = "liquid"))]
 use bitcoin::consensus::encode::{deserialize, serialize};
+#[cfg(not(feature = "liquid"))]
+use bitcoin::util::uint::Uint256;
 #[cfg(feature = "liquid")]
 use elements::encode::{deserialize, serialize};
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::chain::{BlockHeader, OutPoint, Transaction, TxOut, Value};
+use crate::chain::{BlockHeader, Network, OutPoint, Transaction, TxOut, Value};
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{HistogramOpts, HistogramTimer, HistogramVec, Metrics};
@@ -24,13 +28,39 @@ use crate::util::{
     HeaderEntry, HeaderList,
 };
 
-use crate::new_index::db::{DBFlush, DBRow, ReverseScanIterator, ScanIterator, DB};
-use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchFrom};
+#[cfg(not(feature = "liquid"))]
+use crate::util::BlockStats;
+
+use crate::config::Config;
+use crate::new_index::addresses::index_addresses;
+use crate::new_index::cluster::index_clusters;
+use crate::new_index::db::{
+    DBFlush, DBOptions, DBRow, DBStats, ReverseScanIterator, ScanIterator, COMPACTION_STEPS, DB,
+    DB_VERSION,
+};
+use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchFrom, FetchOptions};
+use crate::new_index::lookupcache::LookupCache;
+use crate::new_index::migrations;
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::miners::{index_miners, MinerTags};
+use crate::new_index::opreturn::index_opreturns;
+use crate::new_index::prevouts::index_prevouts;
+use crate::new_index::progress::{IndexPhase, IndexProgress};
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::richlist::{index_balances, unindex_balances};
+use crate::new_index::QueryBudget;
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::{dailystats, feehistory};
+#[cfg(not(feature = "liquid"))]
+use crate::watch::WatchList;
 
 #[cfg(feature = "liquid")]
 use crate::elements::asset::{index_confirmed_tx_assets, IssuingInfo};
 
 const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
+const UNINDEX_BATCH_SIZE: usize = 100_000; // deleted rows
+#[cfg(not(feature = "liquid"))]
+const RESCAN_BATCH_BLOCKS: usize = 100;
 
 pub struct Store {
     // TODO: should be column families
@@ -40,17 +70,20 @@ pub struct Store {
     added_blockhashes: RwLock<HashSet<Sha256dHash>>,
     indexed_blockhashes: RwLock<HashSet<Sha256dHash>>,
     indexed_headers: RwLock<HeaderList>,
+    progress: IndexProgress,
+    lookup_cache: LookupCache,
+    reindex_from: Mutex<Option<usize>>, // requested by an admin, done by the next index update
 }
 
 impl Store {
-    pub fn open(path: &Path) -> Self {
-        let txstore_db = DB::open(&path.join("txstore"));
+    pub fn open(path: &Path, config: &Config) -> Self {
+        let txstore_db = DB::open_with(&path.join("txstore"), &DBOptions::new(config, "txstore"));
         let added_blockhashes = load_blockhashes(&txstore_db, &BlockRow::done_filter());
         debug!("{} blocks were added", added_blockhashes.len());
-        let history_db = DB::open(&path.join("history"));
+        let history_db = DB::open_with(&path.join("history"), &DBOptions::new(config, "history"));
         let indexed_blockhashes = load_blockhashes(&history_db, &BlockRow::done_filter());
         debug!("{} blocks were indexed", indexed_blockhashes.len());
-        let cache_db = DB::open(&path.join("cache"));
+        let cache_db = DB::open_with(&path.join("cache"), &DBOptions::new(config, "cache"));
 
         let headers = HeaderList::empty();
 
@@ -61,7 +94,218 @@ impl Store {
             added_blockhashes: RwLock::new(added_blockhashes),
             indexed_blockhashes: RwLock::new(indexed_blockhashes),
             indexed_headers: RwLock::new(headers),
+            progress: IndexProgress::new(),
+            lookup_cache: LookupCache::new(config.lookup_cache_mb * 1024 * 1024),
+            reindex_from: Mutex::new(None),
+        }
+    }
+
+    // Upgrade the databases created with an older version in-place (see migrations.rs)
+    pub fn migrate(&self) -> Result<()> {
+        let dbs = [
+            ("txstore", &self.txstore_db),
+            ("history", &self.history_db),
+            ("cache", &self.cache_db),
+        ];
+        if dbs.iter().all(|(_, db)| db.version() == DB_VERSION) {
+            return Ok(());
+        }
+        for (name, db) in &dbs {
+            migrations::run(db, name, migrations::MIGRATIONS, &self.progress)?;
+        }
+        // the migrations may have changed the rows loaded when the store was opened
+        *self.added_blockhashes.write().unwrap() =
+            load_blockhashes(&self.txstore_db, &BlockRow::done_filter());
+        *self.indexed_blockhashes.write().unwrap() =
+            load_blockhashes(&self.history_db, &BlockRow::done_filter());
+        Ok(())
+    }
+
+    // The rows of each block are written along with its done marker in a single batch, but the
+    // batches written without the WAL (until the initial sync is done) are lost on an unclean
+    // shutdown, and the txstore and history dbs lose them independently. Roll the indexed tip back
+    // to the last block that is fully added and indexed, along with all its ancestors, so that
+    // the missing blocks are indexed again.
+    pub fn repair(&self) {
+        let tip: Sha256dHash = match self.txstore_db.get(b"t") {
+            Some(tip) => deserialize(&tip).expect("failed to parse indexed tip"),
+            None => return,
+        };
+        let added_blockhashes = self.added_blockhashes.read().unwrap();
+        let indexed_blockhashes = self.indexed_blockhashes.read().unwrap();
+        let ahead = indexed_blockhashes.difference(&added_blockhashes).count();
+        if ahead > 0 {
+            warn!(
+                "{} indexed blocks are missing from the txstore, adding them again",
+                ahead
+            );
+        }
+
+        // from the tip down to the genesis block, as long as the headers are found
+        let headers = load_blockheaders(&self.txstore_db);
+        let mut blockhashes = vec![];
+        let mut blockhash = tip;
+        while let Some(header) = headers.get(&blockhash) {
+            blockhashes.push(blockhash);
+            blockhash = header.prev_blockhash;
+        }
+        let complete = blockhash == Sha256dHash::default();
+        let done = if complete {
+            blockhashes
+                .iter()
+                .rev()
+                .take_while(|blockhash| {
+                    added_blockhashes.contains(blockhash) && indexed_blockhashes.contains(blockhash)
+                })
+                .count()
+        } else {
+            0
+        };
+        if complete && done == blockhashes.len() {
+            return;
+        }
+        match done.checked_sub(1) {
+            Some(height) => {
+                warn!(
+                    "the indexed tip {} was not fully persisted, rolling back to height {}",
+                    tip, height
+                );
+                let new_tip = blockhashes[blockhashes.len() - done];
+                self.txstore_db.put(b"t", &serialize(&new_tip));
+            }
+            None => {
+                warn!("the indexed tip {} was not fully persisted, resyncing", tip);
+                self.txstore_db
+                    .write_with_deletes(vec![], vec![b"t".to_vec()], DBFlush::Enable);
+            }
+        }
+    }
+
+    // Index the blocks from `height` on again, on the next index update
+    pub fn request_reindex(&self, height: usize) {
+        *self.reindex_from.lock().unwrap() = Some(height);
+    }
+
+    // Deletes the history rows and the done markers of the indexed chain's blocks from `height` on,
+    // so that they're indexed again, along with the stats and utxo caches that may cover them.
+    // The history rows aren't keyed by height, so the whole index is scanned. The other optional
+    // indexes are overwritten in place when the blocks are indexed again (the rich list, which
+    // isn't, can't be reindexed).
+    // Returns the number of blocks to index again.
+    pub fn unindex_from(&self, height: usize) -> usize {
+        let tip: Sha256dHash = match self.txstore_db.get(b"t") {
+            Some(tip) => deserialize(&tip).expect("failed to parse indexed tip"),
+            None => return 0,
+        };
+        let headers = HeaderList::new(load_blockheaders(&self.txstore_db), tip);
+        let blockhashes: Vec<Sha256dHash> = headers
+            .iter()
+            .skip(height)
+            .map(|header| *header.hash())
+            .collect();
+        self.unindex_blocks(height, &blockhashes);
+        blockhashes.len()
+    }
+
+    // Like unindex_from, while the servers are running: the indexed chain is rolled back below
+    // `height` first, so that the queries made until the blocks are indexed again (and the stats
+    // and utxos they cache) don't cover them. Returns the rolled back blocks below `fork_height`
+    // (the first one replaced by the new headers), to be indexed again along with the new ones.
+    pub fn rollback_for_reindex(&self, height: usize, fork_height: usize) -> Vec<HeaderEntry> {
+        let (reindexed, blockhashes) = {
+            let mut headers = self.indexed_headers.write().unwrap();
+            let reindexed: Vec<HeaderEntry> = headers
+                .iter()
+                .skip(height)
+                .take(fork_height.saturating_sub(height))
+                .cloned()
+                .collect();
+            let blockhashes: Vec<Sha256dHash> = headers
+                .iter()
+                .skip(height)
+                .map(|header| *header.hash())
+                .collect();
+            if !blockhashes.is_empty() {
+                headers.truncate(height);
+                match headers.len() {
+                    0 => self.txstore_db.write_with_deletes(
+                        vec![],
+                        vec![b"t".to_vec()],
+                        DBFlush::Enable,
+                    ),
+                    _ => self.txstore_db.put(b"t", &serialize(headers.tip())),
+                }
+            }
+            (reindexed, blockhashes)
+        };
+        self.unindex_blocks(height, &blockhashes);
+        reindexed
+    }
+
+    fn unindex_blocks(&self, height: usize, blockhashes: &[Sha256dHash]) {
+        if blockhashes.is_empty() {
+            return;
+        }
+        info!(
+            "unindexing {} blocks from height {}",
+            blockhashes.len(),
+            height
+        );
+
+        let mut deletes: Vec<Bytes> = blockhashes
+            .iter()
+            .map(|hash| BlockRow::done_key(full_hash(&hash[..])))
+            .collect();
+        {
+            let mut indexed_blockhashes = self.indexed_blockhashes.write().unwrap();
+            for hash in blockhashes {
+                indexed_blockhashes.remove(hash);
+            }
         }
+        let mut unindexed_rows = 0;
+        for code in &[b'H', b'I'] {
+            for row in self.history_db.iter_scan(&[*code]) {
+                let key = row.key.clone();
+                if TxHistoryRow::from_row(row).key.confirmed_height as usize >= height {
+                    deletes.push(key);
+                    unindexed_rows += 1;
+                }
+                if deletes.len() >= UNINDEX_BATCH_SIZE {
+                    self.history_db.write_with_deletes(
+                        vec![],
+                        deletes.split_off(0),
+                        DBFlush::Enable,
+                    );
+                }
+            }
+        }
+        self.history_db
+            .write_with_deletes(vec![], deletes, DBFlush::Enable);
+
+        let cache_keys: Vec<Bytes> = self
+            .cache_db
+            .iter_scan(b"A")
+            .chain(self.cache_db.iter_scan(b"U"))
+            .map(|row| row.key)
+            .collect();
+        self.cache_db
+            .write_with_deletes(vec![], cache_keys, DBFlush::Enable);
+        info!(
+            "unindexed {} history rows, from height {}",
+            unindexed_rows, height
+        );
+    }
+
+    pub fn dbs(&self) -> [&DB; 3] {
+        [&self.txstore_db, &self.history_db, &self.cache_db]
+    }
+
+    // The tip as of the last completed index update, read from the database
+    pub fn indexed_tip(&self) -> Option<HeaderEntry> {
+        let tip: Sha256dHash =
+            deserialize(&self.txstore_db.get(b"t")?).expect("failed to parse indexed tip");
+        let headers = HeaderList::new(load_blockheaders(&self.txstore_db), tip);
+        headers.header_by_blockhash(&tip).cloned()
     }
 
     pub fn txstore_db(&self) -> &DB {
@@ -79,6 +323,34 @@ impl Store {
     pub fn done_initial_sync(&self) -> bool {
         self.txstore_db.get(b"t").is_some()
     }
+
+    pub fn flush(&self) {
+        debug!("flushing databases to disk");
+        self.txstore_db.flush();
+        self.history_db.flush();
+        self.cache_db.flush();
+    }
+
+    pub fn progress(&self) -> &IndexProgress {
+        &self.progress
+    }
+
+    // Estimated on-disk size of each database (in bytes)
+    pub fn db_sizes(&self) -> Vec<(&'static str, Option<u64>)> {
+        vec![
+            ("txstore", self.txstore_db.estimate_size()),
+            ("history", self.history_db.estimate_size()),
+            ("cache", self.cache_db.estimate_size()),
+        ]
+    }
+
+    pub fn db_stats(&self) -> Vec<(&'static str, DBStats)> {
+        vec![
+            ("txstore", self.txstore_db.stats()),
+            ("history", self.history_db.stats()),
+            ("cache", self.cache_db.stats()),
+        ]
+    }
 }
 
 type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
@@ -119,6 +391,11 @@ pub struct ScriptStats {
     pub funded_txo_sum: u64,
     #[cfg(not(feature = "liquid"))]
     pub spent_txo_sum: u64,
+    // the blocks of the first and most recent transactions, not set for the mempool
+    pub first_seen_height: Option<usize>,
+    pub first_seen_time: Option<u32>,
+    pub last_seen_height: Option<usize>,
+    pub last_seen_time: Option<u32>,
 }
 
 impl ScriptStats {
@@ -131,6 +408,32 @@ impl ScriptStats {
             funded_txo_sum: 0,
             #[cfg(not(feature = "liquid"))]
             spent_txo_sum: 0,
+            first_seen_height: None,
+            first_seen_time: None,
+            last_seen_height: None,
+            last_seen_time: None,
+        }
+    }
+
+    // Add up the stats of another script, except for the tx_count (as they may share transactions)
+    pub fn merge(&mut self, other: &ScriptStats) {
+        self.funded_txo_count += other.funded_txo_count;
+        self.spent_txo_count += other.spent_txo_count;
+        #[cfg(not(feature = "liquid"))]
+        {
+            self.funded_txo_sum += other.funded_txo_sum;
+            self.spent_txo_sum += other.spent_txo_sum;
+        }
+        if other.first_seen_height.is_some()
+            && (self.first_seen_height.is_none()
+                || other.first_seen_height < self.first_seen_height)
+        {
+            self.first_seen_height = other.first_seen_height;
+            self.first_seen_time = other.first_seen_time;
+        }
+        if other.last_seen_height > self.last_seen_height {
+            self.last_seen_height = other.last_seen_height;
+            self.last_seen_time = other.last_seen_time;
         }
     }
 }
@@ -139,6 +442,22 @@ pub struct Indexer {
     store: Arc<Store>,
     flush: DBFlush,
     from: FetchFrom,
+    fetch_options: FetchOptions,
+    pool: Arc<rayon::ThreadPool>, // for generating the rows (CPU-bound)
+    index_clusters: bool,
+    index_opreturn: bool,
+    index_addresses: bool,
+    network: Network,
+    index_prevouts: bool,
+    prune_below: u32,
+    #[cfg(not(feature = "liquid"))]
+    index_richlist: bool,
+    #[cfg(not(feature = "liquid"))]
+    index_watched_only: bool,
+    #[cfg(not(feature = "liquid"))]
+    watchlist: Option<Arc<WatchList>>,
+    #[cfg(not(feature = "liquid"))]
+    miner_tags: Option<MinerTags>,
     duration: HistogramVec,
 }
 
@@ -149,11 +468,33 @@ pub struct ChainQuery {
 
 // TODO: &[Block] should be an iterator / a queue.
 impl Indexer {
-    pub fn open(store: Arc<Store>, from: FetchFrom, metrics: &Metrics) -> Self {
+    pub fn open(store: Arc<Store>, from: FetchFrom, config: &Config, metrics: &Metrics) -> Self {
         Indexer {
             store,
             flush: DBFlush::Disable,
             from,
+            fetch_options: FetchOptions::new(config),
+            pool: Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(config.bulk_index_threads)
+                    .thread_name(|i| format!("index-{}", i))
+                    .build()
+                    .unwrap(),
+            ),
+            index_clusters: config.index_clusters,
+            index_opreturn: config.index_opreturn,
+            index_addresses: config.index_addresses,
+            network: config.network_type,
+            index_prevouts: config.index_prevouts,
+            prune_below: config.prune_below as u32,
+            #[cfg(not(feature = "liquid"))]
+            index_richlist: config.index_richlist,
+            #[cfg(not(feature = "liquid"))]
+            index_watched_only: config.index_watched_only,
+            #[cfg(not(feature = "liquid"))]
+            watchlist: None,
+            #[cfg(not(feature = "liquid"))]
+            miner_tags: None,
             duration: metrics.histogram_vec(
                 HistogramOpts::new("index_duration", "Index update duration (in seconds)"),
                 &["step"],
@@ -161,6 +502,16 @@ impl Indexer {
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn set_watchlist(&mut self, watchlist: Arc<WatchList>) {
+        self.watchlist = Some(watchlist);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn set_miner_tags(&mut self, miner_tags: MinerTags) {
+        self.miner_tags = Some(miner_tags);
+    }
+
     fn start_timer(&self, name: &str) -> HistogramTimer {
         self.duration.with_label_values(&[name]).start_timer()
     }
@@ -186,7 +537,9 @@ impl Indexer {
     fn start_auto_compactions(&self, db: &DB) {
         let key = b"F".to_vec();
         if db.get(&key).is_none() {
-            db.full_compaction();
+            let progress = &self.store.progress;
+            progress.start_phase(IndexPhase::Compaction, COMPACTION_STEPS);
+            db.full_compaction(|steps| progress.advance(steps, 0));
             db.put(&key, b"");
             assert!(db.get(&key).is_some());
         }
@@ -199,6 +552,7 @@ impl Indexer {
         let result = headers.order(new_headers);
         result.last().map(|tip| {
             info!("{:?} ({} left to process)", tip, result.len());
+            self.store.progress.set_daemon_height(tip.height());
         });
         Ok(result)
     }
@@ -206,30 +560,103 @@ impl Indexer {
     pub fn update(&mut self, daemon: &Daemon) -> Result<Sha256dHash> {
         let daemon = daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers = self.get_new_headers(&daemon, &tip)?;
+        let progress = &self.store.progress;
+        progress.start_phase(IndexPhase::Headers, 0);
+        let mut new_headers = self.get_new_headers(&daemon, &tip)?;
+        // the indexed blocks replaced by the new headers
+        let orphaned: Vec<HeaderEntry> = match new_headers.first() {
+            Some(first) => {
+                let headers = self.store.indexed_headers.read().unwrap();
+                headers.iter().skip(first.height()).cloned().collect()
+            }
+            None => vec![],
+        };
+        let reindex_from = self.store.reindex_from.lock().unwrap().take();
+        if let Some(height) = reindex_from {
+            let fork_height = new_headers
+                .first()
+                .map_or(usize::max_value(), HeaderEntry::height);
+            let reindexed = self.store.rollback_for_reindex(height, fork_height);
+            new_headers = [reindexed, new_headers].concat();
+        }
 
         let to_add = self.headers_to_add(&new_headers);
+        progress.start_phase(IndexPhase::Blocks, to_add.len());
         debug!(
             "adding transactions from {} blocks using {:?}",
             to_add.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_add)?.map(|blocks| self.add(&blocks));
+        // fetch -> generate the rows -> write, with each stage running in its own thread(s)
+        let pool = Arc::clone(&self.pool);
+        let duration = self.duration.clone();
+        start_fetcher(self.from, &daemon, to_add, &self.fetch_options)?
+            .then(
+                "add_process",
+                self.fetch_options.queue_size,
+                move |blocks| {
+                    let _timer = duration.with_label_values(&["add_process"]).start_timer();
+                    let rows = pool.install(|| add_blocks(&blocks));
+                    (blocks, rows)
+                },
+            )
+            .map(|(blocks, rows)| self.add(&blocks, rows));
         self.start_auto_compactions(&self.store.txstore_db);
 
         let to_index = self.headers_to_index(&new_headers);
+        self.store
+            .progress
+            .start_phase(IndexPhase::History, to_index.len());
         debug!(
             "indexing history from {} blocks using {:?}",
             to_index.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_index)?.map(|blocks| self.index(&blocks));
+        // fetch -> lookup the spent txos -> generate the rows and write
+        let store = Arc::clone(&self.store);
+        let duration = self.duration.clone();
+        let prune_below = self.prune_below;
+        let pruned_blockhashes: HashSet<Sha256dHash> = new_headers
+            .iter()
+            .filter(|entry| (entry.height() as u32) < prune_below)
+            .map(|entry| *entry.hash())
+            .collect();
+        start_fetcher(self.from, &daemon, to_index, &self.fetch_options)?
+            .then(
+                "index_lookup",
+                self.fetch_options.queue_size,
+                move |blocks| {
+                    let _timer = duration.with_label_values(&["index_lookup"]).start_timer();
+                    let previous_txos_map =
+                        lookup_txos(&store.txstore_db, &get_previous_txos(&blocks), false);
+                    let pruned_txos =
+                        get_pruned_txos(&store, &blocks, prune_below, &pruned_blockhashes);
+                    (blocks, previous_txos_map, pruned_txos)
+                },
+            )
+            .map(|(blocks, previous_txos_map, pruned_txos)| {
+                self.index(&blocks, &previous_txos_map, &pruned_txos)
+            });
         self.start_auto_compactions(&self.store.history_db);
 
         let mut headers = self.store.indexed_headers.write().unwrap();
+        #[cfg(not(feature = "liquid"))]
+        {
+            if self.index_richlist && !orphaned.is_empty() {
+                self.unindex_balances(&orphaned);
+            }
+        }
+        for orphaned in &orphaned {
+            self.store.lookup_cache.evict_block(orphaned.hash());
+        }
         headers.apply(new_headers);
         assert_eq!(tip, *headers.tip());
 
+        // the rows written without the WAL have to be persisted before the tip covering them
+        if let DBFlush::Disable = self.flush {
+            self.store.txstore_db.flush();
+            self.store.history_db.flush();
+        }
         // update the most recently indexed block
         self.store.txstore_db.put(b"t", &serialize(&tip));
 
@@ -241,19 +668,19 @@ impl Indexer {
         self.store.txstore_db.write(vec![], self.flush);
         self.store.history_db.write(vec![], self.flush);
 
+        self.store.progress.start_phase(IndexPhase::Synced, 0);
+
         Ok(tip)
     }
 
-    fn add(&self, blocks: &[BlockEntry]) {
+    fn add(&self, blocks: &[BlockEntry], rows: Vec<DBRow>) {
         // TODO: skip orphaned blocks?
-        let rows = {
-            let _timer = self.start_timer("add_process");
-            add_blocks(blocks)
-        };
+        let rows_count = rows.len();
         {
             let _timer = self.start_timer("add_write");
             self.store.txstore_db.write(rows, self.flush);
         }
+        self.store.progress.advance(blocks.len(), rows_count);
 
         self.store
             .added_blockhashes
@@ -262,12 +689,21 @@ impl Indexer {
             .extend(blocks.into_iter().map(|b| b.entry.hash()));
     }
 
-    fn index(&self, blocks: &[BlockEntry]) {
-        let previous_txos_map = {
-            let _timer = self.start_timer("index_lookup");
-            lookup_txos(&self.store.txstore_db, &get_previous_txos(blocks), false)
+    fn index(
+        &self,
+        blocks: &[BlockEntry],
+        previous_txos_map: &HashMap<OutPoint, TxOut>,
+        pruned_txos: &HashSet<OutPoint>,
+    ) {
+        // only index the history of the watched scripts in watch-only mode
+        #[cfg(not(feature = "liquid"))]
+        let filter = match self.watchlist {
+            Some(ref watchlist) if self.index_watched_only => Some(watchlist.scripthashes()),
+            _ => None,
         };
-        let rows = {
+        #[cfg(feature = "liquid")]
+        let filter: Option<HashSet<FullHash>> = None;
+        let mut rows = {
             let _timer = self.start_timer("index_process");
             let added_blockhashes = self.store.added_blockhashes.read().unwrap();
             for b in blocks {
@@ -277,9 +713,181 @@ impl Indexer {
                     panic!("cannot index block {} (missing from store)", blockhash);
                 }
             }
-            index_blocks(blocks, &previous_txos_map)
+            let prune_below = self.prune_below;
+            let filter = filter.as_ref();
+            self.pool.install(|| {
+                index_blocks(blocks, previous_txos_map, prune_below, pruned_txos, filter)
+            })
+        };
+        if self.index_clusters {
+            let _timer = self.start_timer("index_clusters");
+            rows.extend(index_clusters(
+                &self.store.history_db,
+                blocks,
+                previous_txos_map,
+            ));
+        }
+        if self.index_opreturn {
+            let _timer = self.start_timer("index_opreturn");
+            rows.extend(index_opreturns(blocks));
+        }
+        if self.index_addresses {
+            let _timer = self.start_timer("index_addresses");
+            rows.extend(index_addresses(blocks, &self.network));
+        }
+        if self.index_prevouts {
+            let _timer = self.start_timer("index_prevouts");
+            rows.extend(index_prevouts(blocks, previous_txos_map, self.prune_below));
+        }
+        #[cfg(not(feature = "liquid"))]
+        {
+            if let Some(ref miner_tags) = self.miner_tags {
+                let _timer = self.start_timer("index_miners");
+                rows.extend(index_miners(blocks, miner_tags));
+            }
+        }
+        #[cfg(not(feature = "liquid"))]
+        let deletes = if self.index_richlist {
+            let _timer = self.start_timer("index_richlist");
+            let (balance_rows, balance_deletes) =
+                index_balances(&self.store.history_db, blocks, previous_txos_map);
+            rows.extend(balance_rows);
+            balance_deletes
+        } else {
+            vec![]
+        };
+        #[cfg(feature = "liquid")]
+        let deletes = vec![];
+        let rows_count = rows.len();
+        self.store
+            .history_db
+            .write_with_deletes(rows, deletes, self.flush);
+        self.store.progress.advance(blocks.len(), rows_count);
+
+        // don't notify about the (possibly many) historical transactions of the initial sync
+        #[cfg(not(feature = "liquid"))]
+        match self.watchlist {
+            Some(ref watchlist) if self.store.done_initial_sync() => {
+                let _timer = self.start_timer("index_watchlist");
+                for b in blocks {
+                    let blockid = BlockId::from(&b.entry);
+                    for tx in &b.block.txdata {
+                        watchlist.check_tx(tx, previous_txos_map, Some(&blockid));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Reverts the rich list balance changes of the indexed blocks orphaned by a reorg, and deletes
+    // their done markers so that they're indexed (and their changes applied) again if they're
+    // part of the best chain again.
+    #[cfg(not(feature = "liquid"))]
+    fn unindex_balances(&self, orphaned: &[HeaderEntry]) {
+        let _timer = self.start_timer("unindex_richlist");
+        let mut indexed_blockhashes = self.store.indexed_blockhashes.write().unwrap();
+        for entry in orphaned {
+            if !indexed_blockhashes.remove(entry.hash()) {
+                continue;
+            }
+            info!(
+                "reverting the rich list balances of orphaned block {:?}",
+                entry
+            );
+            let txs = load_block_txs(&self.store.txstore_db, entry.hash());
+            let previous_txos_map =
+                lookup_txos(&self.store.txstore_db, &get_previous_txos_of(&txs), false);
+            let (rows, mut deletes) =
+                unindex_balances(&self.store.history_db, &txs, &previous_txos_map);
+            deletes.push(BlockRow::done_key(full_hash(&entry.hash()[..])));
+            self.store
+                .history_db
+                .write_with_deletes(rows, deletes, DBFlush::Enable);
+        }
+    }
+
+    // Index the history of the watched scripts that have a pending rescan (see PUT /watch),
+    // from the requested height up to the current tip, using the transactions in the txstore.
+    // Each call rescans at most RESCAN_BATCH_BLOCKS blocks and the rescans continue from there on
+    // the next one, so that new blocks keep being indexed in between. Returns whether rescans are
+    // still pending.
+    #[cfg(not(feature = "liquid"))]
+    pub fn rescan_watched(&self) -> bool {
+        let watchlist = match self.watchlist {
+            Some(ref watchlist) => watchlist,
+            None => return false,
+        };
+        let rescans = watchlist.pending_rescans();
+        // the history below prune_below isn't indexed
+        let from_height = match rescans.values().min() {
+            Some(height) => (*height).max(self.prune_below) as usize,
+            None => return false,
+        };
+        let (blockhashes, best_len) = {
+            let headers = self.store.indexed_headers.read().unwrap();
+            let blockhashes: Vec<(u32, Sha256dHash)> = headers
+                .iter()
+                .skip(from_height)
+                .take(RESCAN_BATCH_BLOCKS)
+                .map(|entry| (entry.height() as u32, *entry.hash()))
+                .collect();
+            (blockhashes, headers.len())
         };
-        self.store.history_db.write(rows, self.flush);
+        let next_height = from_height + blockhashes.len();
+        debug!(
+            "rescanning blocks {} to {} for {} watched scripts",
+            from_height,
+            next_height,
+            rescans.len()
+        );
+        let _timer = self.start_timer("rescan_watched");
+        for (height, blockhash) in blockhashes {
+            let scripthashes: HashSet<FullHash> = rescans
+                .iter()
+                .filter(|(_, from_height)| **from_height <= height)
+                .map(|(scripthash, _)| *scripthash)
+                .collect();
+            let txs = load_block_txs(&self.store.txstore_db, &blockhash);
+            let previous_txos_map =
+                lookup_txos(&self.store.txstore_db, &get_previous_txos_of(&txs), false);
+            let pruned_txos =
+                get_pruned_txos_of(&self.store, &txs, self.prune_below, &HashSet::new());
+            let mut rows = vec![];
+            for tx in &txs {
+                index_transaction(
+                    tx,
+                    height,
+                    &previous_txos_map,
+                    &pruned_txos,
+                    &mut rows,
+                    Some(&scripthashes),
+                );
+            }
+            self.store.history_db.write(rows, self.flush);
+        }
+        // the cached stats and utxos don't include the rescanned history
+        let deletes = rescans
+            .keys()
+            .flat_map(|scripthash| {
+                vec![
+                    StatsCacheRow::key(&scripthash[..]),
+                    UtxoCacheRow::key(&scripthash[..]),
+                ]
+            })
+            .collect();
+        self.store
+            .cache_db
+            .write_with_deletes(vec![], deletes, DBFlush::Enable);
+        self.store.history_db.write(vec![], DBFlush::Enable);
+        for (scripthash, from_height) in rescans {
+            if next_height >= best_len {
+                watchlist.rescan_done(&scripthash, from_height);
+            } else if (from_height as usize) < next_height {
+                watchlist.rescan_continue(&scripthash, from_height, next_height as u32);
+            }
+        }
+        next_height < best_len
     }
 }
 
@@ -304,18 +912,97 @@ impl ChainQuery {
 
     pub fn get_block_txids(&self, hash: &Sha256dHash) -> Option<Vec<Sha256dHash>> {
         let _timer = self.start_timer("get_block_txids");
-        self.store
+        if let Some(txids) = self.store.lookup_cache.get_block_txids(hash) {
+            return Some(txids);
+        }
+        let txids: Vec<Sha256dHash> = self
+            .store
             .txstore_db
             .get(&BlockRow::txids_key(full_hash(&hash[..])))
-            .map(|val| bincode::deserialize(&val).expect("failed to parse block txids"))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse block txids"))?;
+        self.store
+            .lookup_cache
+            .put_block_txids(*hash, txids.clone());
+        Some(txids)
+    }
+
+    // Lookup a slice of the block transactions, without loading the rest of the block.
+    // Returns None for unknown blocks, and fails if any of the transactions is missing.
+    pub fn get_block_txs(
+        &self,
+        hash: &Sha256dHash,
+        start_index: usize,
+        limit: usize,
+    ) -> Result<Option<Vec<Transaction>>> {
+        let _timer = self.start_timer("get_block_txs");
+        let txids: Vec<Sha256dHash> = match self.get_block_txids(hash) {
+            Some(txids) => txids.into_iter().skip(start_index).take(limit).collect(),
+            None => return Ok(None),
+        };
+        self.lookup_txns(&txids).map(Some)
     }
 
     pub fn get_block_meta(&self, hash: &Sha256dHash) -> Option<BlockMeta> {
         let _timer = self.start_timer("get_block_meta");
-        self.store
+        if let Some(meta) = self.store.lookup_cache.get_block_meta(hash) {
+            return Some(meta);
+        }
+        let meta: BlockMeta = self
+            .store
             .txstore_db
             .get(&BlockRow::meta_key(full_hash(&hash[..])))
-            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockMeta"))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockMeta"))?;
+        self.store.lookup_cache.put_block_meta(*hash, meta.clone());
+        Some(meta)
+    }
+
+    // Blocks indexed by older versions have no stored stats, compute them on the fly
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_stats(&self, hash: &Sha256dHash) -> Result<Option<BlockStats>> {
+        let _timer = self.start_timer("get_block_stats");
+        if let Some(val) = self
+            .store
+            .history_db
+            .get(&BlockRow::stats_key(full_hash(&hash[..])))
+        {
+            return Ok(Some(
+                bincode::deserialize(&val).expect("failed to parse BlockStats"),
+            ));
+        }
+        let txids = match self.get_block_txids(hash) {
+            Some(txids) => txids,
+            None => return Ok(None),
+        };
+        let txs = self.lookup_txns(&txids)?;
+        let outpoints = txs
+            .iter()
+            .flat_map(|tx| {
+                tx.input
+                    .iter()
+                    .filter(|txin| has_prevout(txin))
+                    .map(|txin| txin.previous_output)
+            })
+            .collect();
+        let prevouts = self.lookup_avail_txos(&outpoints);
+        Ok(Some(BlockStats::new(&txs, &prevouts)?))
+    }
+
+    // The consensus-encoded block, rebuilt from its header and transactions
+    pub fn get_block_raw(&self, hash: &Sha256dHash) -> Option<Bytes> {
+        let _timer = self.start_timer("get_block_raw");
+        let header = self.header_by_hash(hash)?;
+        let txids = self.get_block_txids(hash)?;
+        let mut raw = serialize(header.header());
+        raw.extend(bitcoin::consensus::encode::serialize(&VarInt(
+            txids.len() as u64
+        )));
+        for txid in &txids {
+            raw.extend(
+                self.lookup_raw_txn(txid)
+                    .expect("missing block transaction"),
+            );
+        }
+        Some(raw)
     }
 
     pub fn get_block_with_meta(&self, hash: &Sha256dHash) -> Option<BlockHeaderMeta> {
@@ -344,9 +1031,11 @@ impl ChainQuery {
         scripthash: &[u8],
         last_seen_txid: Option<&Sha256dHash>,
         limit: usize,
-    ) -> Vec<(Transaction, BlockId)> {
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
+        let _span = tracing::info_span!("history", limit).entered();
         // scripthash lookup
-        self._history(b'H', scripthash, last_seen_txid, limit)
+        self._history(b'H', scripthash, last_seen_txid, limit, budget)
     }
 
     fn _history(
@@ -355,10 +1044,14 @@ impl ChainQuery {
         hash: &[u8],
         last_seen_txid: Option<&Sha256dHash>,
         limit: usize,
-    ) -> Vec<(Transaction, BlockId)> {
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
         let _timer_scan = self.start_timer("history");
-        let txs_conf = self
-            .history_iter_scan_reverse(code, hash)
+        if let Some(last_seen_txid) = last_seen_txid {
+            self.check_not_reorged(last_seen_txid)?;
+        }
+        let txs_conf = budget
+            .limit_history(self.history_iter_scan_reverse(code, hash))
             .map(|row| TxHistoryRow::from_row(row).get_txid())
             // XXX: unique() requires keeping an in-memory list of all txids, can we avoid that?
             .unique()
@@ -374,32 +1067,56 @@ impl ChainQuery {
             .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
             .take(limit)
             .collect::<Vec<(Sha256dHash, BlockId)>>();
+        budget.check()?;
 
         let txids = txs_conf.iter().map(|t| t.0.clone()).collect();
-        self.lookup_txns(&txids)
+        Ok(self
+            .lookup_txns(&txids)
             .expect("failed looking up txs in history index")
             .into_iter()
             .zip(txs_conf)
             .map(|(tx, (_, blockid))| (tx, blockid))
-            .collect()
+            .collect())
+    }
+
+    // Whether each of the scripthashes has any confirmed history
+    pub fn scripthashes_used(&self, scripthashes: &[FullHash]) -> Vec<bool> {
+        let _timer = self.start_timer("scripthashes_used");
+        let prefixes: Vec<Bytes> = scripthashes
+            .iter()
+            .map(|scripthash| TxHistoryRow::filter(b'H', &scripthash[..]))
+            .collect();
+        self.store.history_db.has_prefixes(&prefixes)
     }
 
-    pub fn history_txids(&self, scripthash: &[u8]) -> Vec<(Sha256dHash, BlockId)> {
+    pub fn history_txids(
+        &self,
+        scripthash: &[u8],
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Sha256dHash, BlockId)>> {
         // scripthash lookup
-        self._history_txids(b'H', scripthash)
+        self._history_txids(b'H', scripthash, budget)
     }
 
-    fn _history_txids(&self, code: u8, hash: &[u8]) -> Vec<(Sha256dHash, BlockId)> {
+    fn _history_txids(
+        &self,
+        code: u8,
+        hash: &[u8],
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Sha256dHash, BlockId)>> {
         let _timer = self.start_timer("history_txids");
-        self.history_iter_scan(code, hash, 0)
+        let txids = budget
+            .limit_history(self.history_iter_scan(code, hash, 0))
             .map(|row| TxHistoryRow::from_row(row).get_txid())
             .unique()
             .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
-            .collect()
+            .collect();
+        budget.check()?;
+        Ok(txids)
     }
 
     // TODO: avoid duplication with stats/stats_delta?
-    pub fn utxo(&self, scripthash: &[u8]) -> Vec<Utxo> {
+    pub fn utxo(&self, scripthash: &[u8], budget: &QueryBudget) -> Result<Vec<Utxo>> {
         let _timer = self.start_timer("utxo");
 
         // get the last known utxo set and the blockhash it was updated for.
@@ -418,9 +1135,13 @@ impl ChainQuery {
 
         // update utxo set with new transactions since
         let (newutxos, lastblock, processed_items) = cache.map_or_else(
-            || self.utxo_delta(scripthash, HashMap::new(), 0),
-            |(oldutxos, blockheight)| self.utxo_delta(scripthash, oldutxos, blockheight + 1),
+            || self.utxo_delta(scripthash, HashMap::new(), 0, budget),
+            |(oldutxos, blockheight)| {
+                self.utxo_delta(scripthash, oldutxos, blockheight + 1, budget)
+            },
         );
+        // don't cache (or return) an incomplete set
+        budget.check()?;
 
         // save updated utxo set to cache
         if let Some(lastblock) = lastblock {
@@ -433,7 +1154,7 @@ impl ChainQuery {
         }
 
         // format as Utxo objects
-        newutxos
+        Ok(newutxos
             .into_iter()
             .map(|(outpoint, (blockid, value))| {
                 // in elements/liquid chains, we have to lookup the txo in order to get its
@@ -452,7 +1173,7 @@ impl ChainQuery {
                     asset: txo.asset,
                 }
             })
-            .collect()
+            .collect())
     }
 
     pub fn utxo_delta(
@@ -460,10 +1181,11 @@ impl ChainQuery {
         scripthash: &[u8],
         init_utxos: UtxoMap,
         start_height: usize,
+        budget: &QueryBudget,
     ) -> (UtxoMap, Option<Sha256dHash>, usize) {
         let _timer = self.start_timer("utxo_delta");
-        let history_iter = self
-            .history_iter_scan(b'H', scripthash, start_height)
+        let history_iter = budget
+            .limit(self.history_iter_scan(b'H', scripthash, start_height))
             .map(TxHistoryRow::from_row)
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
@@ -492,7 +1214,69 @@ impl ChainQuery {
         (utxos, lastblock, processed_items)
     }
 
-    pub fn stats(&self, scripthash: &[u8]) -> ScriptStats {
+    // Visit the confirmed utxos as of `height` for every indexed script (or only for the given
+    // scripthashes), one script at a time in scripthash order, with utxos sorted by outpoint.
+    pub fn for_each_utxo<F>(
+        &self,
+        scripthashes: Option<&[FullHash]>,
+        height: usize,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&FullHash, &BTreeMap<OutPoint, (BlockId, Value)>) -> Result<()>,
+    {
+        let _timer = self.start_timer("for_each_utxo");
+        let rows: Box<dyn Iterator<Item = DBRow>> = match scripthashes {
+            Some(scripthashes) => {
+                let mut scripthashes = scripthashes.to_vec();
+                scripthashes.sort();
+                scripthashes.dedup();
+                Box::new(
+                    scripthashes
+                        .into_iter()
+                        .flat_map(move |scripthash| self.history_iter_scan(b'H', &scripthash, 0)),
+                )
+            }
+            None => Box::new(self.store.history_db.iter_scan(&[b'H'])),
+        };
+
+        let groups = rows
+            .map(TxHistoryRow::from_row)
+            .filter(|history| history.key.confirmed_height as usize <= height)
+            .group_by(|history| history.key.hash);
+
+        for (scripthash, history) in &groups {
+            let mut funded = BTreeMap::new();
+            let mut spent = HashSet::new();
+            for history in history {
+                // skip rows of orphaned blocks and of blocks above the requested height
+                let blockid = match self.tx_confirming_block(&history.get_txid()) {
+                    Some(blockid) if blockid.height <= height => blockid,
+                    _ => continue,
+                };
+                match history.key.txinfo {
+                    TxHistoryInfo::Funding(ref info) => {
+                        funded.insert(history.get_outpoint(), (blockid, info.value));
+                    }
+                    TxHistoryInfo::Spending(_) => {
+                        spent.insert(history.get_outpoint());
+                    }
+                    #[cfg(feature = "liquid")]
+                    TxHistoryInfo::Issuing(_) | TxHistoryInfo::Burning(_) => unreachable!(),
+                };
+            }
+            let utxos: BTreeMap<OutPoint, (BlockId, Value)> = funded
+                .into_iter()
+                .filter(|(outpoint, _)| !spent.contains(outpoint))
+                .collect();
+            if !utxos.is_empty() {
+                f(&scripthash, &utxos)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self, scripthash: &[u8], budget: &QueryBudget) -> Result<ScriptStats> {
         let _timer = self.start_timer("stats");
 
         // get the last known stats and the blockhash they are updated for.
@@ -509,12 +1293,16 @@ impl ChainQuery {
 
         // update stats with new transactions since
         let (newstats, lastblock) = cache.map_or_else(
-            || self.stats_delta(scripthash, ScriptStats::default(), 0),
-            |(oldstats, blockheight)| self.stats_delta(scripthash, oldstats, blockheight + 1),
+            || self.stats_delta(scripthash, ScriptStats::default(), 0, budget),
+            |(oldstats, blockheight)| {
+                self.stats_delta(scripthash, oldstats, blockheight + 1, budget)
+            },
         );
+        // don't cache (or return) incomplete stats
+        budget.check()?;
 
-        // save updated stats to cache
-        if let Some(lastblock) = lastblock {
+        // save updated stats to cache, unless truncated (and returned as such)
+        if let (Some(lastblock), false) = (lastblock, budget.truncated()) {
             if newstats.funded_txo_count + newstats.spent_txo_count > MIN_HISTORY_ITEMS_TO_CACHE {
                 self.store.cache_db.write(
                     vec![StatsCacheRow::new(scripthash, &newstats, &lastblock).to_row()],
@@ -523,7 +1311,7 @@ impl ChainQuery {
             }
         }
 
-        newstats
+        Ok(newstats)
     }
 
     fn stats_delta(
@@ -531,10 +1319,11 @@ impl ChainQuery {
         scripthash: &[u8],
         init_stats: ScriptStats,
         start_height: usize,
+        budget: &QueryBudget,
     ) -> (ScriptStats, Option<Sha256dHash>) {
         let _timer = self.start_timer("stats_delta"); // TODO: measure also the number of txns processed.
-        let history_iter = self
-            .history_iter_scan(b'H', scripthash, start_height)
+        let history_iter = budget
+            .limit_history(self.history_iter_scan(b'H', scripthash, start_height))
             .map(TxHistoryRow::from_row)
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
@@ -554,6 +1343,14 @@ impl ChainQuery {
                 stats.tx_count += 1;
             }
 
+            // the rows are ordered by height
+            if stats.first_seen_height.is_none() {
+                stats.first_seen_height = Some(blockid.height);
+                stats.first_seen_time = Some(blockid.time);
+            }
+            stats.last_seen_height = Some(blockid.height);
+            stats.last_seen_time = Some(blockid.time);
+
             match history.key.txinfo {
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Funding(ref info) => {
@@ -606,6 +1403,19 @@ impl ChainQuery {
             .map(|header| header.height())
     }
 
+    // Get the best chain headers whose (hex) hash starts with `prefix`, by descending height
+    pub fn headers_by_hash_prefix(&self, prefix: &str, limit: usize) -> Vec<HeaderEntry> {
+        let _timer = self.start_timer("headers_by_hash_prefix");
+        self.store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .headers_by_hash_prefix(prefix, limit)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
     pub fn header_by_height(&self, height: usize) -> Option<HeaderEntry> {
         self.store
             .indexed_headers
@@ -643,10 +1453,39 @@ impl ChainQuery {
             .map(BlockId::from)
     }
 
+    // The headers of up to `count` blocks, from `start_height`
+    pub fn headers_from(&self, start_height: usize, count: usize) -> Vec<BlockHeader> {
+        self.store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .iter()
+            .skip(start_height)
+            .take(count)
+            .map(|entry| entry.header().clone())
+            .collect()
+    }
+
+    // The cumulative work of the chain up to the block at `height`
+    #[cfg(not(feature = "liquid"))]
+    pub fn chainwork_by_height(&self, height: usize) -> Option<Uint256> {
+        self.store.indexed_headers.read().unwrap().chainwork(height)
+    }
+
     pub fn best_height(&self) -> usize {
         self.store.indexed_headers.read().unwrap().len() - 1
     }
 
+    // None until the initial sync is completed
+    pub fn indexed_height(&self) -> Option<usize> {
+        self.store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .len()
+            .checked_sub(1)
+    }
+
     pub fn best_hash(&self) -> Sha256dHash {
         self.store.indexed_headers.read().unwrap().tip().clone()
     }
@@ -665,21 +1504,32 @@ impl ChainQuery {
         let _timer = self.start_timer("lookup_txns");
         txids
             .par_iter()
-            .map(|txid| self.lookup_txn(txid).chain_err(|| "missing tx"))
+            .map(|txid| {
+                self.lookup_txn(txid)
+                    .chain_err(|| ErrorKind::NotFound(format!("Transaction {} not found", txid)))
+            })
             .collect::<Result<Vec<Transaction>>>()
     }
 
     pub fn lookup_txn(&self, txid: &Sha256dHash) -> Option<Transaction> {
         let _timer = self.start_timer("lookup_txn");
-        self.lookup_raw_txn(txid).map(|rawtx| {
-            let txn: Transaction = deserialize(&rawtx).expect("failed to parse Transaction");
-            assert_eq!(*txid, txn.txid());
-            txn
-        })
+        if let Some(txn) = self.store.lookup_cache.get_tx(txid) {
+            return Some(txn);
+        }
+        let rawtx = self.store.txstore_db.get(&TxRow::key(&txid[..]))?;
+        let txn: Transaction = deserialize(&rawtx).expect("failed to parse Transaction");
+        assert_eq!(*txid, txn.txid());
+        self.store
+            .lookup_cache
+            .put_tx(*txid, txn.clone(), rawtx.len());
+        Some(txn)
     }
 
     pub fn lookup_raw_txn(&self, txid: &Sha256dHash) -> Option<Bytes> {
         let _timer = self.start_timer("lookup_raw_txn");
+        if let Some(txn) = self.store.lookup_cache.get_tx(txid) {
+            return Some(serialize(&txn));
+        }
         self.store.txstore_db.get(&TxRow::key(&txid[..]))
     }
 
@@ -713,6 +1563,46 @@ impl ChainQuery {
                 })
             })
     }
+    // Paginating from a transaction that was reorged out would otherwise skip the whole history
+    fn check_not_reorged(&self, txid: &Sha256dHash) -> Result<()> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        let blockhashes: Vec<Sha256dHash> = self
+            .store
+            .txstore_db
+            .iter_scan(&TxConfRow::filter(&txid[..]))
+            .map(|row| parse_hash(&TxConfRow::from_row(row).key.blockhash))
+            .collect();
+        match blockhashes.first() {
+            Some(blockhash)
+                if blockhashes
+                    .iter()
+                    .all(|hash| headers.header_by_blockhash(hash).is_none()) =>
+            {
+                bail!(ErrorKind::ReorgDetected(blockhash.to_hex()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The hashes of the best chain blocks, indexed by height
+    pub fn best_chain_hashes(&self) -> Vec<Sha256dHash> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        headers.iter().map(|entry| *entry.hash()).collect()
+    }
+
+    // Whether the transaction was confirmed by the block, even if it's no longer in the best chain
+    pub fn tx_confirmed_in(&self, txid: &Sha256dHash, blockhash: &Sha256dHash) -> bool {
+        let key = TxConfKey {
+            code: b'C',
+            txid: full_hash(&txid[..]),
+            blockhash: full_hash(&blockhash[..]),
+        };
+        self.store
+            .txstore_db
+            .get(&bincode::serialize(&key).unwrap())
+            .is_some()
+    }
+
     pub fn tx_confirming_block(&self, txid: &Sha256dHash) -> Option<BlockId> {
         let _timer = self.start_timer("tx_confirming_block");
         let headers = self.store.indexed_headers.read().unwrap();
@@ -754,13 +1644,18 @@ impl ChainQuery {
         asset_id: &Sha256dHash,
         last_seen_txid: Option<&Sha256dHash>,
         limit: usize,
-    ) -> Vec<(Transaction, BlockId)> {
-        self._history(b'I', &asset_id[..], last_seen_txid, limit)
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
+        self._history(b'I', &asset_id[..], last_seen_txid, limit, budget)
     }
 
     #[cfg(feature = "liquid")]
-    pub fn asset_history_txids(&self, asset_id: &Sha256dHash) -> Vec<(Sha256dHash, BlockId)> {
-        self._history_txids(b'I', &asset_id[..])
+    pub fn asset_history_txids(
+        &self,
+        asset_id: &Sha256dHash,
+        budget: &QueryBudget,
+    ) -> Result<Vec<(Sha256dHash, BlockId)>> {
+        self._history_txids(b'I', &asset_id[..], budget)
     }
 }
 
@@ -835,6 +1730,89 @@ fn get_previous_txos(block_entries: &[BlockEntry]) -> BTreeSet<OutPoint> {
         .collect()
 }
 
+#[cfg(not(feature = "liquid"))]
+fn get_previous_txos_of(txs: &[Transaction]) -> BTreeSet<OutPoint> {
+    txs.iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|txin| has_prevout(txin))
+        .map(|txin| txin.previous_output)
+        .collect()
+}
+
+// The transactions of a block added to the txstore, in order
+#[cfg(not(feature = "liquid"))]
+fn load_block_txs(txstore_db: &DB, blockhash: &Sha256dHash) -> Vec<Transaction> {
+    let txids: Vec<Sha256dHash> = txstore_db
+        .get(&BlockRow::txids_key(full_hash(&blockhash[..])))
+        .map(|val| bincode::deserialize(&val).expect("failed to parse block txids"))
+        .expect("missing block txids");
+    txids
+        .iter()
+        .map(|txid| {
+            let rawtx = txstore_db
+                .get(&TxRow::key(&txid[..]))
+                .expect("missing block transaction");
+            deserialize(&rawtx).expect("failed to parse Transaction")
+        })
+        .collect()
+}
+
+// The outputs spent by the blocks indexed with `prune_below` that were funded below it, by the
+// blocks in `pruned_blockhashes` (the ones being indexed) or by already indexed blocks
+fn get_pruned_txos(
+    store: &Store,
+    block_entries: &[BlockEntry],
+    prune_below: u32,
+    pruned_blockhashes: &HashSet<Sha256dHash>,
+) -> HashSet<OutPoint> {
+    let txs = block_entries
+        .iter()
+        .filter(|b| b.entry.height() as u32 >= prune_below)
+        .flat_map(|b| b.block.txdata.iter());
+    get_pruned_txos_of(store, txs, prune_below, pruned_blockhashes)
+}
+
+// Same as get_pruned_txos, for the transactions of blocks at or above `prune_below`
+fn get_pruned_txos_of<'a, I>(
+    store: &Store,
+    txs: I,
+    prune_below: u32,
+    pruned_blockhashes: &HashSet<Sha256dHash>,
+) -> HashSet<OutPoint>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+{
+    if prune_below == 0 {
+        return HashSet::new();
+    }
+    let headers = store.indexed_headers.read().unwrap();
+    let mut funded_below: HashMap<Sha256dHash, bool> = HashMap::new();
+    let mut pruned_txos = HashSet::new();
+    let outpoints = txs
+        .into_iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|txin| has_prevout(txin))
+        .map(|txin| txin.previous_output);
+    for outpoint in outpoints {
+        let pruned = *funded_below.entry(outpoint.txid).or_insert_with(|| {
+            store
+                .txstore_db
+                .iter_scan(&TxConfRow::filter(&outpoint.txid[..]))
+                .map(|row| parse_hash(&TxConfRow::from_row(row).key.blockhash))
+                .any(|blockhash| {
+                    pruned_blockhashes.contains(&blockhash)
+                        || headers
+                            .header_by_blockhash(&blockhash)
+                            .map_or(false, |entry| (entry.height() as u32) < prune_below)
+                })
+        });
+        if pruned {
+            pruned_txos.insert(outpoint);
+        }
+    }
+    pruned_txos
+}
+
 fn lookup_txos(
     txstore_db: &DB,
     outpoints: &BTreeSet<OutPoint>,
@@ -868,17 +1846,45 @@ fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
         .map(|val| deserialize(&val).expect("failed to parse TxOut"))
 }
 
+// The history of blocks below `prune_below` is not indexed (these blocks are still marked as done),
+// nor the spends of the outputs they fund (`pruned_txos`), and only the history of the scripts in
+// `filter` is when set
 fn index_blocks(
     block_entries: &[BlockEntry],
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    prune_below: u32,
+    pruned_txos: &HashSet<OutPoint>,
+    filter: Option<&HashSet<FullHash>>,
 ) -> Vec<DBRow> {
     block_entries
         .par_iter() // serialization is CPU-intensive
         .map(|b| {
             let mut rows = vec![];
-            for tx in &b.block.txdata {
-                let height = b.entry.height() as u32;
-                index_transaction(tx, height, previous_txos_map, &mut rows);
+            let height = b.entry.height() as u32;
+            if height >= prune_below {
+                for tx in &b.block.txdata {
+                    index_transaction(
+                        tx,
+                        height,
+                        previous_txos_map,
+                        pruned_txos,
+                        &mut rows,
+                        filter,
+                    );
+                }
+            }
+            #[cfg(not(feature = "liquid"))]
+            {
+                match BlockStats::with_feerates(&b.block.txdata, previous_txos_map) {
+                    Ok((stats, feerates)) => {
+                        rows.push(dailystats::block_row(b, &stats));
+                        rows.extend(feehistory::block_row(b, &feerates));
+                        rows.push(
+                            BlockRow::new_stats(full_hash(&b.entry.hash()[..]), &stats).to_row(),
+                        );
+                    }
+                    Err(e) => warn!("no stats for block {}: {}", b.entry.hash(), e),
+                }
             }
             rows.push(BlockRow::new_done(full_hash(&b.entry.hash()[..])).to_row()); // mark block as "indexed"
             rows
@@ -892,8 +1898,12 @@ fn index_transaction(
     tx: &Transaction,
     confirmed_height: u32,
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    pruned_txos: &HashSet<OutPoint>,
     rows: &mut Vec<DBRow>,
+    filter: Option<&HashSet<FullHash>>,
 ) {
+    let is_indexed =
+        |script: &Script| filter.map_or(true, |f| f.contains(&compute_script_hash(script)));
     // persist history index:
     //      H{funding-scripthash}{funding-height}F{funding-txid:vout} → ""
     //      H{funding-scripthash}{spending-height}S{spending-txid:vin}{funding-txid:vout} → ""
@@ -901,7 +1911,7 @@ fn index_transaction(
     //      S{funding-txid:vout}{spending-txid:vin} → ""
     let txid = full_hash(&tx.txid()[..]);
     for (txo_index, txo) in tx.output.iter().enumerate() {
-        if is_spendable(txo) {
+        if is_spendable(txo) && is_indexed(&txo.script_pubkey) {
             let history = TxHistoryRow::new(
                 &txo.script_pubkey,
                 confirmed_height,
@@ -921,19 +1931,25 @@ fn index_transaction(
         let prev_txo = previous_txos_map
             .get(&txi.previous_output)
             .expect(&format!("missing previous txo {}", txi.previous_output));
+        if !is_indexed(&prev_txo.script_pubkey) {
+            continue;
+        }
 
-        let history = TxHistoryRow::new(
-            &prev_txo.script_pubkey,
-            confirmed_height,
-            TxHistoryInfo::Spending(SpendingInfo {
-                txid,
-                vin: txi_index as u16,
-                prev_txid: full_hash(&txi.previous_output.txid[..]),
-                prev_vout: txi.previous_output.vout as u16,
-                value: prev_txo.value,
-            }),
-        );
-        rows.push(history.to_row());
+        // the funding row of pruned outputs is missing, so their spending row would be unmatched
+        if !pruned_txos.contains(&txi.previous_output) {
+            let history = TxHistoryRow::new(
+                &prev_txo.script_pubkey,
+                confirmed_height,
+                TxHistoryInfo::Spending(SpendingInfo {
+                    txid,
+                    vin: txi_index as u16,
+                    prev_txid: full_hash(&txi.previous_output.txid[..]),
+                    prev_vout: txi.previous_output.vout as u16,
+                    value: prev_txo.value,
+                }),
+            );
+            rows.push(history.to_row());
+        }
 
         let edge = TxEdgeRow::new(
             full_hash(&txi.previous_output.txid[..]),
@@ -1113,6 +2129,15 @@ impl BlockRow {
         }
     }
 
+    // stored in the history db, as computing it requires the previous txos
+    #[cfg(not(feature = "liquid"))]
+    fn new_stats(hash: FullHash, stats: &BlockStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Z', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
+    }
+
     fn new_done(hash: FullHash) -> BlockRow {
         BlockRow {
             key: BlockKey { code: b'D', hash },
@@ -1132,10 +2157,19 @@ impl BlockRow {
         [b"M", &hash[..]].concat()
     }
 
+    #[cfg(not(feature = "liquid"))]
+    fn stats_key(hash: FullHash) -> Bytes {
+        [b"Z", &hash[..]].concat()
+    }
+
     fn done_filter() -> Bytes {
         b"D".to_vec()
     }
 
+    fn done_key(hash: FullHash) -> Bytes {
+        [b"D", &hash[..]].concat()
+    }
+
     fn to_row(self) -> DBRow {
         DBRow {
             key: bincode::serialize(&self.key).unwrap(),
@@ -1412,3 +2446,223 @@ impl UtxoCacheRow {
         }
     }
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+    use bitcoin::hashes::Hash;
+    use bitcoin::util::hash::BitcoinHash;
+    use bitcoin::BlockHeader;
+
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    use super::{
+        compute_script_hash, get_pruned_txos, BlockRow, ChainQuery, FundingInfo, Store, TxConfKey,
+        TxConfRow, TxHistoryInfo, TxHistoryRow,
+    };
+    use crate::chain::{Block, OutPoint, Transaction, TxIn};
+    use crate::metrics::Metrics;
+    use crate::new_index::db::{DBFlush, DB};
+    use crate::new_index::fetch::BlockEntry;
+    use crate::new_index::lookupcache::LookupCache;
+    use crate::new_index::progress::IndexProgress;
+    use crate::new_index::QueryBudget;
+    use crate::util::{full_hash, HeaderEntry, HeaderList};
+
+    fn memory_store() -> Store {
+        Store {
+            txstore_db: DB::in_memory(),
+            history_db: DB::in_memory(),
+            cache_db: DB::in_memory(),
+            added_blockhashes: RwLock::new(HashSet::new()),
+            indexed_blockhashes: RwLock::new(HashSet::new()),
+            indexed_headers: RwLock::new(HeaderList::empty()),
+            progress: IndexProgress::new(),
+            lookup_cache: LookupCache::new(1 << 20),
+            reindex_from: Mutex::new(None),
+        }
+    }
+
+    fn funding_txid(entry: &HeaderEntry, i: u32) -> Sha256dHash {
+        Sha256dHash::hash(&[&entry.hash()[..], &i.to_be_bytes()].concat())
+    }
+
+    fn chain(count: u32) -> Vec<BlockHeader> {
+        let mut headers = vec![];
+        let mut prev_blockhash = Sha256dHash::default();
+        for time in 0..count {
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Sha256dHash::default(),
+                time,
+                bits: 0x207f_ffff,
+                nonce: 0,
+            };
+            prev_blockhash = header.bitcoin_hash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    // Index a block funding `count` outputs of `script` (by the txids from funding_txid)
+    fn index_block(store: &Store, entry: &HeaderEntry, script: &Script, count: u32) {
+        let blockhash = full_hash(&entry.hash()[..]);
+        let mut conf_rows = vec![];
+        let mut history_rows = vec![BlockRow::new_done(blockhash).to_row()];
+        for i in 0..count {
+            let txid = full_hash(&funding_txid(entry, i)[..]);
+            let conf_key = TxConfKey {
+                code: b'C',
+                txid,
+                blockhash,
+            };
+            conf_rows.push(TxConfRow { key: conf_key }.to_row());
+            let info = TxHistoryInfo::Funding(FundingInfo {
+                txid,
+                vout: 0,
+                value: 1000,
+            });
+            history_rows.push(TxHistoryRow::new(script, entry.height() as u32, info).to_row());
+        }
+        store.txstore_db.write(conf_rows, DBFlush::Enable);
+        store.history_db.write(history_rows, DBFlush::Enable);
+    }
+
+    #[test]
+    fn test_stats_during_reindex() {
+        let store = Arc::new(memory_store());
+        let query = ChainQuery::new(
+            Arc::clone(&store),
+            &Metrics::new("127.0.0.1:0".parse().unwrap()),
+        );
+        let script = Script::from(vec![0x51]);
+        let scripthash = compute_script_hash(&script);
+        let funded = || {
+            query
+                .stats(&scripthash, &QueryBudget::unlimited())
+                .unwrap()
+                .funded_txo_count
+        };
+
+        let entries = store.indexed_headers.read().unwrap().order(chain(3));
+        for entry in &entries {
+            index_block(&store, entry, &script, 120);
+        }
+        store
+            .indexed_headers
+            .write()
+            .unwrap()
+            .apply(entries.clone());
+        store.txstore_db.put(b"t", &serialize(entries[2].hash()));
+        assert_eq!(funded(), 360); // cached as of the tip
+
+        let reindexed = store.rollback_for_reindex(1, usize::max_value());
+        assert_eq!(reindexed, entries[1..].to_vec());
+        assert_eq!(store.indexed_tip().map(|tip| tip.height()), Some(0));
+        assert_eq!(funded(), 120);
+
+        // the rows of the reindexed blocks are only partially there meanwhile, but only the blocks
+        // below them are covered (and cached) until they're all indexed again
+        index_block(&store, &reindexed[1], &script, 120);
+        assert_eq!(funded(), 120);
+        index_block(&store, &reindexed[0], &script, 120);
+        store.indexed_headers.write().unwrap().apply(reindexed);
+        assert_eq!(funded(), 360);
+    }
+
+    #[test]
+    fn test_pruned_txos() {
+        let store = memory_store();
+        let script = Script::from(vec![0x51]);
+        let entries = store.indexed_headers.read().unwrap().order(chain(3));
+        for entry in &entries {
+            index_block(&store, entry, &script, 1);
+        }
+        store
+            .indexed_headers
+            .write()
+            .unwrap()
+            .apply(entries[..1].to_vec());
+
+        // block 2 spends the outputs of the indexed block 0, of block 1 (indexed along with it)
+        // and of its own, with the history pruned below block 2
+        let outpoints: Vec<OutPoint> = entries
+            .iter()
+            .map(|entry| OutPoint::new(funding_txid(entry, 0), 0))
+            .collect();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![],
+        };
+        let block = BlockEntry {
+            block: Block {
+                header: *entries[2].header(),
+                txdata: vec![tx],
+            },
+            entry: entries[2].clone(),
+            size: 0,
+        };
+        let pruned_blockhashes: HashSet<Sha256dHash> =
+            entries[..2].iter().map(|entry| *entry.hash()).collect();
+        let pruned_txos = get_pruned_txos(&store, &[block], 2, &pruned_blockhashes);
+        assert_eq!(
+            pruned_txos,
+            outpoints[..2]
+                .iter()
+                .cloned()
+                .collect::<HashSet<OutPoint>>()
+        );
+    }
+
+    #[test]
+    fn test_headers_by_hash_prefix() {
+        let headers = chain(300);
+        let mut list = HeaderList::empty();
+        // sorted into the index at once, then inserted into it
+        let entries = list.order(headers[..250].to_vec());
+        list.apply(entries);
+        let entries = list.order(headers[250..].to_vec());
+        list.apply(entries);
+        list.truncate(280);
+
+        let heights = |prefix: &str| -> Vec<usize> {
+            list.headers_by_hash_prefix(prefix, 5)
+                .into_iter()
+                .map(HeaderEntry::height)
+                .collect()
+        };
+        for height in &[0, 123, 279] {
+            let hash = list.header_by_height(*height).unwrap().hash().to_hex();
+            for len in &[1, 2, 64] {
+                let prefix = &hash[..*len];
+                let expected: Vec<usize> = list
+                    .iter()
+                    .rev()
+                    .filter(|entry| entry.hash().to_hex().starts_with(prefix))
+                    .take(5)
+                    .map(HeaderEntry::height)
+                    .collect();
+                assert!(expected.contains(height));
+                assert_eq!(heights(prefix), expected);
+            }
+        }
+        let hash = headers[290].bitcoin_hash().to_hex();
+        assert_eq!(heights(&hash), Vec::<usize>::new());
+        assert_eq!(heights("xyz"), Vec::<usize>::new());
+    }
+}
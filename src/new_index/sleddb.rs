@@ -0,0 +1,82 @@
+use sled;
+
+use std::path::{Path, PathBuf};
+
+use crate::new_index::db::{DBFlush, DBRow, DBStats, KVStore};
+use crate::util::Bytes;
+
+// A pure-Rust backend, for platforms where building RocksDB is impractical
+#[derive(Debug)]
+pub struct SledStore {
+    path: PathBuf,
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> Self {
+        SledStore {
+            path: path.to_path_buf(),
+            db: sled::open(path).expect("failed to open sled DB"),
+        }
+    }
+}
+
+fn to_row(item: sled::Result<(sled::IVec, sled::IVec)>) -> DBRow {
+    let (key, value) = item.expect("failed to iterate over sled DB");
+    DBRow {
+        key: key.to_vec(),
+        value: value.to_vec(),
+    }
+}
+
+impl KVStore for SledStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.db
+            .get(key)
+            .expect("failed to read from sled DB")
+            .map(|value| value.to_vec())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.db
+            .insert(key, value)
+            .expect("failed to write to sled DB");
+    }
+
+    fn write(&self, rows: Vec<DBRow>, deletes: Vec<Bytes>, flush: DBFlush) {
+        let mut batch = sled::Batch::default();
+        for key in deletes {
+            batch.remove(key);
+        }
+        for row in rows {
+            batch.insert(row.key, row.value);
+        }
+        self.db
+            .apply_batch(batch)
+            .expect("failed to write to sled DB");
+        if let DBFlush::Enable = flush {
+            self.flush();
+        }
+    }
+
+    fn iter_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a> {
+        Box::new(self.db.range(start_at.to_vec()..).map(to_row))
+    }
+
+    fn iter_reverse_from<'a>(&'a self, start_at: &[u8]) -> Box<dyn Iterator<Item = DBRow> + 'a> {
+        Box::new(self.db.range(..=start_at.to_vec()).rev().map(to_row))
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.db.flush() {
+            warn!("failed to flush {:?}: {}", self.path, e);
+        }
+    }
+
+    fn stats(&self) -> DBStats {
+        DBStats {
+            size: self.db.size_on_disk().ok(),
+            ..DBStats::default()
+        }
+    }
+}
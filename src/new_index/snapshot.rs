@@ -0,0 +1,118 @@
+// Point-in-time copies of the index, for seeding new instances without reindexing. A snapshot
+// directory holds a checkpoint of each database and a manifest.json describing it.
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use serde_json;
+use time;
+
+use std::fs;
+use std::path::Path;
+
+use crate::chain::Network;
+use crate::errors::*;
+use crate::new_index::db::DB_VERSION;
+use crate::new_index::Store;
+
+const DB_NAMES: &[&str] = &["txstore", "history", "cache"];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotManifest {
+    pub network: String,
+    pub tip_hash: Sha256dHash,
+    pub tip_height: usize,
+    pub db_version: u32,
+    pub created_at: u64, // unix timestamp
+}
+
+fn network_name(network: Network) -> String {
+    format!("{:?}", network).to_lowercase()
+}
+
+// Checkpoint the databases into `out` (which must not exist). This is consistent at the indexed
+// tip as long as the server isn't running, which is ensured by the databases' lock.
+pub fn create_snapshot(store: &Store, network: Network, out: &Path) -> Result<SnapshotManifest> {
+    if out.exists() {
+        bail!("snapshot directory {:?} already exists", out);
+    }
+    let tip = store
+        .indexed_tip()
+        .chain_err(|| "the index isn't synced yet, nothing to snapshot")?;
+    fs::create_dir_all(out).chain_err(|| format!("failed to create {:?}", out))?;
+
+    store.flush();
+    for (name, db) in DB_NAMES.iter().zip(store.dbs().iter()) {
+        info!("creating a checkpoint of the {} database", name);
+        db.checkpoint(&out.join(name))?;
+    }
+
+    let manifest = SnapshotManifest {
+        network: network_name(network),
+        tip_hash: *tip.hash(),
+        tip_height: tip.height(),
+        db_version: DB_VERSION,
+        created_at: time::get_time().sec as u64,
+    };
+    let manifest_json = serde_json::to_string_pretty(&manifest).unwrap();
+    fs::write(out.join("manifest.json"), manifest_json)
+        .chain_err(|| "failed to write the snapshot manifest")?;
+    Ok(manifest)
+}
+
+// Populate the (missing) index directory `db_path` from a snapshot. The indexer then catches up
+// from the snapshot's tip.
+pub fn restore_snapshot(from: &Path, db_path: &Path, network: Network) -> Result<()> {
+    if db_path.exists() {
+        warn!(
+            "not restoring the snapshot at {:?}, the index already exists at {:?}",
+            from, db_path
+        );
+        return Ok(());
+    }
+    let manifest: SnapshotManifest = serde_json::from_slice(
+        &fs::read(from.join("manifest.json")).chain_err(|| "failed to read snapshot manifest")?,
+    )
+    .chain_err(|| "invalid snapshot manifest")?;
+    if manifest.network != network_name(network) {
+        bail!(
+            "the snapshot is for {}, not {}",
+            manifest.network,
+            network_name(network)
+        );
+    }
+    // older versions get upgraded by the migrations
+    if manifest.db_version > DB_VERSION {
+        bail!(
+            "the snapshot was created by a newer version (db version {})",
+            manifest.db_version
+        );
+    }
+    info!(
+        "restoring the snapshot of block {} at height {} from {:?}",
+        manifest.tip_hash, manifest.tip_height, from
+    );
+    // copy to a temporary directory first, so that an interrupted restore isn't mistaken for an index
+    let tmp_path = db_path.with_extension("restoring");
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path).chain_err(|| format!("failed to remove {:?}", tmp_path))?;
+    }
+    for name in DB_NAMES {
+        copy_dir(&from.join(name), &tmp_path.join(name))?;
+    }
+    fs::rename(&tmp_path, db_path).chain_err(|| format!("failed to move to {:?}", db_path))?;
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).chain_err(|| format!("failed to create {:?}", to))?;
+    for entry in fs::read_dir(from).chain_err(|| format!("failed to list {:?}", from))? {
+        let entry = entry.chain_err(|| format!("failed to list {:?}", from))?;
+        let path = entry.path();
+        if path.is_dir() {
+            copy_dir(&path, &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(&path, to.join(entry.file_name()))
+                .chain_err(|| format!("failed to copy {:?}", path))?;
+        }
+    }
+    Ok(())
+}
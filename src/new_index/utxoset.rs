@@ -0,0 +1,187 @@
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use hex;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::chain::OutPoint;
+use crate::errors::*;
+use crate::new_index::ChainQuery;
+use crate::util::FullHash;
+
+const RECORD_LEN: usize = 80;
+// each export scans the whole history index
+const MAX_RUNNING_EXPORTS: usize = 2;
+
+static RUNNING_EXPORTS: AtomicUsize = AtomicUsize::new(0);
+
+// Held for the duration of an export, to bound the number of concurrent ones
+pub struct ExportPermit(());
+
+impl ExportPermit {
+    pub fn acquire() -> Option<Self> {
+        let mut running = RUNNING_EXPORTS.load(Ordering::SeqCst);
+        loop {
+            if running >= MAX_RUNNING_EXPORTS {
+                return None;
+            }
+            match RUNNING_EXPORTS.compare_exchange(
+                running,
+                running + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(ExportPermit(())),
+                Err(current) => running = current,
+            }
+        }
+    }
+}
+
+impl Drop for ExportPermit {
+    fn drop(&mut self) {
+        RUNNING_EXPORTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Binary,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(ExportFormat::Csv),
+            "bin" => Some(ExportFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Binary => "application/octet-stream",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UtxoSetSummary {
+    pub height: usize,
+    pub blockhash: Sha256dHash,
+    pub utxo_count: u64,
+    pub total_amount: u64,
+    // sha256 over the binary records, in export order
+    pub commitment: String,
+}
+
+// A fixed-size binary record (integers are little-endian):
+// txid (32, internal byte order) | vout (4) | scripthash (32) | height (4) | value (8)
+fn encode_record(
+    scripthash: &FullHash,
+    outpoint: &OutPoint,
+    height: u32,
+    value: u64,
+) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0..32].copy_from_slice(&outpoint.txid[..]);
+    record[32..36].copy_from_slice(&outpoint.vout.to_le_bytes());
+    record[36..68].copy_from_slice(&scripthash[..]);
+    record[68..72].copy_from_slice(&height.to_le_bytes());
+    record[72..80].copy_from_slice(&value.to_le_bytes());
+    record
+}
+
+// Write the confirmed utxo set as of `height` (restricted to `scripthashes`, if given).
+//
+// The binary format is a header (blockhash in internal byte order, height as u32), followed by
+// the records and a footer (utxo count and total amount as u64, then the 32 bytes commitment).
+// The CSV format has a row per utxo, with the header and footer as `#` comment lines.
+pub fn export_utxo_set<W: Write>(
+    chain: &ChainQuery,
+    height: usize,
+    scripthashes: Option<&[FullHash]>,
+    format: ExportFormat,
+    out: &mut W,
+) -> Result<UtxoSetSummary> {
+    let blockhash = *chain
+        .header_by_height(height)
+        .chain_err(|| format!("height {} is not indexed", height))?
+        .hash();
+    info!("exporting utxo set at height {} ({})", height, blockhash);
+
+    match format {
+        ExportFormat::Csv => write!(
+            out,
+            "# height={} blockhash={}\ntxid,vout,scripthash,height,value\n",
+            height, blockhash
+        ),
+        ExportFormat::Binary => out
+            .write_all(&blockhash[..])
+            .and_then(|_| out.write_all(&(height as u32).to_le_bytes())),
+    }
+    .chain_err(|| "failed to write utxo set header")?;
+
+    let mut sha2 = Sha256::new();
+    let mut utxo_count = 0u64;
+    let mut total_amount = 0u64;
+    chain.for_each_utxo(scripthashes, height, |scripthash, utxos| {
+        for (outpoint, (blockid, value)) in utxos {
+            let record = encode_record(scripthash, outpoint, blockid.height as u32, *value);
+            sha2.input(&record);
+            utxo_count += 1;
+            total_amount += value;
+            match format {
+                ExportFormat::Csv => writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    outpoint.txid,
+                    outpoint.vout,
+                    hex::encode(scripthash),
+                    blockid.height,
+                    value
+                ),
+                ExportFormat::Binary => out.write_all(&record),
+            }
+            .chain_err(|| "failed to write utxo set")?;
+        }
+        Ok(())
+    })?;
+
+    let mut commitment = [0u8; 32];
+    sha2.result(&mut commitment);
+
+    match format {
+        ExportFormat::Csv => writeln!(
+            out,
+            "# utxos={} total_amount={} commitment={}",
+            utxo_count,
+            total_amount,
+            hex::encode(&commitment)
+        ),
+        ExportFormat::Binary => out
+            .write_all(&utxo_count.to_le_bytes())
+            .and_then(|_| out.write_all(&total_amount.to_le_bytes()))
+            .and_then(|_| out.write_all(&commitment)),
+    }
+    .and_then(|_| out.flush())
+    .chain_err(|| "failed to write utxo set footer")?;
+
+    info!(
+        "exported {} utxos at height {} (commitment {})",
+        utxo_count,
+        height,
+        hex::encode(&commitment)
+    );
+    Ok(UtxoSetSummary {
+        height,
+        blockhash,
+        utxo_count,
+        total_amount,
+        commitment: hex::encode(&commitment),
+    })
+}
@@ -0,0 +1,193 @@
+// An optional price feed, for the REST API to annotate the values it returns with their fiat
+// amounts (with `?fiat=<currency>`). The feed is polled from --price-feed-url, which should return
+// a JSON object of the BTC price in each currency, e.g. `{"USD": 65000.5, "EUR": 60000}` (the
+// fields that aren't 3-letter currency codes, such as a timestamp, are ignored). The last price of
+// each day is persisted in its own db, to value the transactions at the price of the day they
+// confirmed in:
+//      P{currency}{day} → {price}
+// where the day counts from the unix epoch. The prices of the days before the feed was set up
+// can be imported with POST /internal/prices.
+
+use bincode;
+use hyper::Uri;
+use serde_json::{self, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::util::http::http_get;
+use crate::util::{spawn_thread, Bytes};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const SECONDS_PER_DAY: u32 = 86_400;
+
+#[derive(Deserialize)]
+pub struct DailyPrice {
+    pub currency: String,
+    pub time: u32, // any time within the day
+    pub price: f64,
+}
+
+pub struct PriceFeed {
+    db: DB,
+    current: RwLock<HashMap<String, f64>>,
+    daily: RwLock<HashMap<String, BTreeMap<u32, f64>>>,
+}
+
+fn price_key(currency: &str, day: u32) -> Bytes {
+    [&b"P"[..], currency.as_bytes(), &day.to_be_bytes()[..]].concat()
+}
+
+fn today() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now as u32 / SECONDS_PER_DAY
+}
+
+impl PriceFeed {
+    pub fn open(path: &Path) -> Self {
+        let db = DB::open(path);
+        db.enable_auto_compaction();
+        let mut daily: HashMap<String, BTreeMap<u32, f64>> = HashMap::new();
+        for row in db.iter_scan(b"P") {
+            let (currency, day) = row.key[1..].split_at(row.key.len() - 5);
+            let currency = String::from_utf8(currency.to_vec()).expect("invalid price currency");
+            let day = u32::from_be_bytes(*array_ref![day, 0, 4]);
+            let price = bincode::deserialize(&row.value).expect("failed to parse price");
+            daily.entry(currency).or_default().insert(day, price);
+        }
+        info!("loaded the daily prices of {} currencies", daily.len());
+        PriceFeed {
+            db,
+            current: RwLock::new(HashMap::new()),
+            daily: RwLock::new(daily),
+        }
+    }
+
+    // Polls the feed every `interval` from a background thread
+    pub fn start(feed: &Arc<PriceFeed>, url: &str, interval: Duration) -> Result<()> {
+        let url: Uri = url.parse().chain_err(|| "invalid price feed url")?;
+        if url.scheme_part().map(|s| s.as_str()) != Some("http") {
+            bail!("invalid price feed url (only http is supported)");
+        }
+        let feed = Arc::clone(feed);
+        spawn_thread("prices", move || loop {
+            match fetch_prices(&url) {
+                Ok(prices) => {
+                    debug!("fetched the prices of {} currencies", prices.len());
+                    let day = today();
+                    feed.put_daily(
+                        prices
+                            .iter()
+                            .map(|(currency, price)| (currency.clone(), day, *price))
+                            .collect(),
+                    );
+                    *feed.current.write().unwrap() = prices;
+                }
+                Err(e) => warn!("failed to fetch prices: {}", e.display_chain()),
+            }
+            thread::sleep(interval);
+        });
+        Ok(())
+    }
+
+    fn put_daily(&self, prices: Vec<(String, u32, f64)>) {
+        let rows = prices
+            .iter()
+            .map(|(currency, day, price)| DBRow {
+                key: price_key(currency, *day),
+                value: bincode::serialize(price).unwrap(),
+            })
+            .collect();
+        self.db.write(rows, DBFlush::Enable);
+        let mut daily = self.daily.write().unwrap();
+        for (currency, day, price) in prices {
+            daily.entry(currency).or_default().insert(day, price);
+        }
+    }
+
+    pub fn import(&self, prices: Vec<DailyPrice>) {
+        self.put_daily(
+            prices
+                .into_iter()
+                .map(|p| (p.currency.to_uppercase(), p.time / SECONDS_PER_DAY, p.price))
+                .collect(),
+        );
+    }
+
+    pub fn current(&self, currency: &str) -> Option<f64> {
+        self.current.read().unwrap().get(currency).cloned()
+    }
+
+    // The price of the day of `time`, if known
+    pub fn at(&self, currency: &str, time: u32) -> Option<f64> {
+        self.daily
+            .read()
+            .unwrap()
+            .get(currency)?
+            .get(&(time / SECONDS_PER_DAY))
+            .cloned()
+    }
+
+    pub fn current_prices(&self) -> HashMap<String, f64> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn currencies(&self) -> Vec<String> {
+        let mut currencies: Vec<String> = self.current.read().unwrap().keys().cloned().collect();
+        currencies.sort();
+        currencies
+    }
+}
+
+// The fiat amount of `sats` at `price` per BTC, rounded to the cent
+pub fn fiat_amount(sats: u64, price: f64) -> f64 {
+    (sats as f64 * price / 1e6).round() / 100.0
+}
+
+fn fetch_prices(url: &Uri) -> Result<HashMap<String, f64>> {
+    parse_prices(&http_get(url, FETCH_TIMEOUT)?)
+}
+
+fn parse_prices(body: &str) -> Result<HashMap<String, f64>> {
+    let prices: HashMap<String, Value> =
+        serde_json::from_str(body).chain_err(|| "invalid price feed JSON")?;
+    Ok(prices
+        .into_iter()
+        .filter(|(currency, _)| {
+            currency.len() == 3 && currency.chars().all(|c| c.is_ascii_alphabetic())
+        })
+        .filter_map(|(currency, price)| Some((currency.to_uppercase(), price.as_f64()?)))
+        .filter(|(_, price)| *price > 0.0)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fiat_amount, parse_prices};
+
+    #[test]
+    fn test_parse_prices() {
+        let prices =
+            parse_prices(r#"{"time": 1700000000, "USD": 37000.5, "eur": 34000, "XYZ": "?"}"#)
+                .unwrap();
+        assert_eq!(prices.len(), 2);
+        assert_eq!(prices["USD"], 37000.5);
+        assert_eq!(prices["EUR"], 34000.0);
+        assert!(parse_prices("[]").is_err());
+    }
+
+    #[test]
+    fn test_fiat_amount() {
+        assert_eq!(fiat_amount(100_000_000, 37000.5), 37000.5);
+        assert_eq!(fiat_amount(12_345, 37000.0), 4.57);
+        assert_eq!(fiat_amount(1, 37000.0), 0.0);
+    }
+}
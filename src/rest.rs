@@ -1,7 +1,21 @@
+use crate::accounts::{Account, AccountMember};
+use crate::apikeys::{Access, Permission, ANONYMOUS_PERMISSIONS};
 use crate::chain::{address, Network, OutPoint, Transaction, TxIn, TxOut};
 use crate::config::Config;
+use crate::electrum::get_status_hash;
 use crate::errors;
-use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo};
+use crate::new_index::prevouts::lookup_prevouts;
+use crate::new_index::{
+    addresses, cluster, compute_script_hash, opreturn, EvictedTx, PackageInfo, Query, QueryBudget,
+    ScriptStats, SpendingInput, Utxo,
+};
+use crate::traces;
+#[cfg(feature = "ui")]
+use crate::ui;
+#[cfg(not(feature = "liquid"))]
+use crate::util::fees::fee_for;
+use crate::util::fees::TxFeeInfo;
+use crate::util::keyscripts::KeyScripts;
 use crate::util::{
     full_hash, get_innerscripts, get_script_asm, get_tx_merkle_proof, has_prevout, is_coinbase,
     script_to_address, BlockHeaderMeta, BlockId, FullHash, TransactionStatus,
@@ -12,11 +26,50 @@ use bitcoin::consensus::encode;
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::{sha256d::Hash as Sha256dHash, Error as HashError};
 use bitcoin::{BitcoinHash, Script};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use flate2::{write::GzEncoder, Compression};
+use futures::future::{self, Either, Loop};
 use futures::sync::oneshot;
+use futures::Poll;
+use futures_cpupool::CpuPool;
 use hex::{self, FromHexError};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, ETAG, IF_NONE_MATCH, ORIGIN,
+    RANGE,
+};
 use hyper::rt::{self, Future, Stream};
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::service_fn;
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use itertools::Itertools;
+
+#[cfg(not(feature = "liquid"))]
+use {
+    crate::accounts::AccountXpub,
+    crate::new_index::feehistory::{fee_history, parse_interval},
+    crate::new_index::miners::{block_miner, mining_pools, parse_window},
+    crate::new_index::utxoset::{self, ExportFormat},
+    crate::new_index::{dailystats, db::DBStats, richlist},
+    crate::prices::{fiat_amount, DailyPrice, PriceFeed},
+    crate::util::bip21::{parse_bip21, PaymentRequest},
+    crate::util::coinselect::select_coins,
+    crate::util::xpub::{
+        check_network, derive_script, parse_xpub, XpubScriptType, XPUB_SCRIPT_TYPES,
+    },
+    crate::watch::{ConfirmationTarget, OutpointWatch, WatchEntry, WatchList},
+    base64,
+    bitcoin::secp256k1::Secp256k1,
+    bitcoin::util::bip32::ExtendedPubKey,
+    bitcoin::util::merkleblock::{MerkleBlock, PartialMerkleTree},
+    bitcoin::util::psbt::{self, PartiallySignedTransaction},
+    bitcoin::util::uint::Uint256,
+    futures::sync::mpsc,
+    futures::Sink,
+    std::mem,
+    url::percent_encoding::percent_decode,
+};
 
 #[cfg(feature = "liquid")]
 use {
@@ -27,11 +80,20 @@ use {
 
 use serde::Serialize;
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::num::ParseIntError;
+use std::os::unix::fs::PermissionsExt;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::thread;
+use std::time::{Duration, Instant};
+use time;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_uds::{UnixListener, UnixStream};
 use url::form_urlencoded;
 
 const CHAIN_TXS_PER_PAGE: usize = 25;
@@ -40,7 +102,84 @@ const BLOCK_LIMIT: usize = 10;
 
 const TTL_LONG: u32 = 157784630; // ttl for static resources (5 years)
 const TTL_SHORT: u32 = 10; // ttl for volatie resources
+const TTL_TIP_DUE: u32 = 2; // ttl for tip-dependent resources once a new block is due
+#[cfg(feature = "ui")]
+const UI_TTL: u32 = 3600; // the explorer's assets only change on upgrades
 const CONF_FINAL: usize = 10; // reorgs deeper than this are considered unlikely
+const COINBASE_MATURITY: u32 = 100; // confirmations before the coinbase outputs can be spent
+#[cfg(not(feature = "liquid"))]
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+#[cfg(not(feature = "liquid"))]
+const DAILY_STATS_DEFAULT_DAYS: u32 = 30;
+#[cfg(not(feature = "liquid"))]
+const DAILY_STATS_MAX_DAYS: u32 = 366;
+#[cfg(not(feature = "liquid"))]
+const FEE_HISTORY_MAX_POINTS: usize = 2016;
+#[cfg(not(feature = "liquid"))]
+const RICHLIST_DEFAULT_LIMIT: usize = 100;
+#[cfg(not(feature = "liquid"))]
+const RICHLIST_MAX_LIMIT: usize = 1000;
+#[cfg(not(feature = "liquid"))]
+const HISTORY_EXPORT_DEFAULT_LIMIT: usize = 1000;
+#[cfg(not(feature = "liquid"))]
+const HISTORY_EXPORT_MAX_LIMIT: usize = 10000;
+
+const SEARCH_MAX_RESULTS: usize = 10;
+const SEARCH_MIN_PREFIX_LEN: usize = 8;
+
+const ADDRESSES_MIN_PREFIX_LEN: usize = 4;
+const ADDRESSES_DEFAULT_LIMIT: usize = 10;
+const ADDRESSES_MAX_LIMIT: usize = 50;
+
+const OPRETURNS_DEFAULT_LIMIT: usize = 50;
+const OPRETURNS_MAX_LIMIT: usize = 500;
+
+const AUDIT_DEFAULT_BLOCKS: usize = 10;
+const AUDIT_MAX_BLOCKS: usize = 100;
+const AUDIT_DEFAULT_OUTPOINTS: usize = 100;
+const AUDIT_MAX_OUTPOINTS: usize = 1000;
+
+const HEADERS_MAX_COUNT: usize = 2016;
+
+#[cfg(not(feature = "liquid"))]
+const OUTPOINTS_WATCH_MAX: usize = 1000; // per request
+
+#[cfg(not(feature = "liquid"))]
+const XPUB_PROBE_INDEXES: u32 = 5; // of the receive and change chains
+#[cfg(not(feature = "liquid"))]
+const XPUB_GAP_LIMIT: u32 = 20;
+#[cfg(not(feature = "liquid"))]
+const XPUB_MAX_GAP_LIMIT: u32 = 100;
+#[cfg(not(feature = "liquid"))]
+const XPUB_MAX_ADDRESSES: u32 = 10_000; // per chain
+
+const FEEBUMP_MAX_TARGET: u16 = 1008; // blocks
+#[cfg(not(feature = "liquid"))]
+const DEFAULT_INPUT_VSIZE: u64 = 68; // a P2WPKH spend
+const FEEBUMP_DEFAULT_CHILD_VSIZE: u32 = 110; // a one-input one-output P2WPKH spend
+
+const LONG_POLL_DEFAULT_TIMEOUT: u64 = 30; // seconds
+const LONG_POLL_MAX_TIMEOUT: u64 = 60;
+
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+// JSON-RPC 2.0 error codes
+const JSONRPC_PARSE_ERROR: i32 = -32700;
+const JSONRPC_INVALID_REQUEST: i32 = -32600;
+const JSONRPC_METHOD_NOT_FOUND: i32 = -32601;
+const JSONRPC_INVALID_PARAMS: i32 = -32602;
+const JSONRPC_NOT_FOUND: i32 = -32001;
+const JSONRPC_SERVER_ERROR: i32 = -32000;
+
+#[cfg(not(feature = "liquid"))]
+const BLOCK_INTERVAL: i64 = 600; // expected time between blocks (in seconds)
+#[cfg(feature = "liquid")]
+const BLOCK_INTERVAL: i64 = 60;
+
+#[cfg(not(feature = "liquid"))]
+const RETARGET_INTERVAL: usize = 2016; // blocks
+#[cfg(not(feature = "liquid"))]
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
 
 #[derive(Serialize, Deserialize)]
 struct BlockValue {
@@ -57,6 +196,12 @@ struct BlockValue {
     nonce: u32,
     #[cfg(not(feature = "liquid"))]
     bits: u32,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_value: Option<u64>, // only set for the fiat amounts
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    miner: Option<String>, // with --index-miners
     #[cfg(feature = "liquid")]
     #[serde(skip_serializing_if = "Option::is_none")]
     proof: Option<BlockProofValue>,
@@ -84,6 +229,10 @@ impl From<BlockHeaderMeta> for BlockValue {
             bits: header.bits,
             #[cfg(not(feature = "liquid"))]
             nonce: header.nonce,
+            #[cfg(not(feature = "liquid"))]
+            total_value: None,
+            #[cfg(not(feature = "liquid"))]
+            miner: None,
 
             #[cfg(feature = "liquid")]
             proof: Some(BlockProofValue::from(&header.proof)),
@@ -104,6 +253,12 @@ struct TransactionValue {
     fee: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<TransactionStatus>,
+    confirmations: u32,
+    status_detail: StatusDetail,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    package: Option<PackageInfo>, // unconfirmed transactions only
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    analytics: Option<TxAnalytics>,
 }
 
 impl TransactionValue {
@@ -111,8 +266,11 @@ impl TransactionValue {
         tx: Transaction,
         blockid: Option<BlockId>,
         prevouts: &HashMap<OutPoint, TxOut>,
+        tip_height: usize,
         config: &Config,
     ) -> Self {
+        let (confirmations, status_detail) =
+            depth(blockid.as_ref().map(|b| b.height), tip_height, config);
         let vins: Vec<TxInValue> = tx
             .input
             .iter()
@@ -157,7 +315,92 @@ impl TransactionValue {
             weight: tx.get_weight() as u32,
             fee,
             status: Some(TransactionStatus::from(blockid)),
+            confirmations,
+            status_detail,
+            package: None,
+            analytics: None,
+        }
+    }
+}
+
+// Optional per-transaction analytics, included with ?analytics=1
+#[derive(Serialize, Deserialize)]
+struct TxAnalytics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coin_days_destroyed: Option<f64>,
+    avg_input_age: f64, // in blocks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feerate_percentile: Option<f32>, // relative to the other transactions in its block
+}
+
+impl TxAnalytics {
+    // Returns None for coinbase transactions. Unconfirmed transactions are evaluated as if they
+    // were included in the next block, and inputs spending unconfirmed outputs have an age of 0.
+    fn new(
+        tx: &Transaction,
+        blockid: Option<&BlockId>,
+        prevouts: &HashMap<OutPoint, TxOut>,
+        prevout_blocks: &HashMap<Sha256dHash, BlockId>,
+        query: &Query,
+    ) -> Option<Self> {
+        let inputs: Vec<&TxIn> = tx.input.iter().filter(|txin| has_prevout(txin)).collect();
+        if inputs.is_empty() {
+            return None;
         }
+        let height = blockid.map_or_else(|| query.chain().best_height() + 1, |b| b.height);
+
+        let total_age: usize = inputs
+            .iter()
+            .filter_map(|txin| prevout_blocks.get(&txin.previous_output.txid))
+            .map(|prev_block| height.saturating_sub(prev_block.height))
+            .sum();
+
+        #[cfg(not(feature = "liquid"))]
+        let coin_days_destroyed = if prevouts.is_empty() {
+            None // prevouts are disabled
+        } else {
+            let time = blockid.map_or_else(|| time::get_time().sec as u32, |b| b.time);
+            Some(
+                inputs
+                    .iter()
+                    .filter_map(|txin| {
+                        let prevout = prevouts.get(&txin.previous_output)?;
+                        let prev_block = prevout_blocks.get(&txin.previous_output.txid)?;
+                        let days = time.saturating_sub(prev_block.time) as f64 / 86400f64;
+                        Some(prevout.value as f64 / 100_000_000f64 * days)
+                    })
+                    .sum(),
+            )
+        };
+        #[cfg(feature = "liquid")]
+        let coin_days_destroyed = None; // amounts are confidential
+
+        let feerate_percentile = blockid.and_then(|blockid| {
+            let tx_prevouts = inputs
+                .iter()
+                .enumerate()
+                .map(|(index, txin)| Some((index as u32, prevouts.get(&txin.previous_output)?)))
+                .collect::<Option<HashMap<u32, &TxOut>>>()?;
+            let feerate = TxFeeInfo::new(tx, &tx_prevouts).fee_per_vbyte;
+            let block_feerates = query.block_feerates(&blockid.hash).unwrap_or_else(|err| {
+                warn!(
+                    "failed to get the feerates of block {}: {}",
+                    blockid.hash, err
+                );
+                None
+            })?;
+            let lower = block_feerates
+                .iter()
+                .filter(|rate| **rate < feerate)
+                .count();
+            Some(100f32 * lower as f32 / block_feerates.len() as f32)
+        });
+
+        Some(TxAnalytics {
+            coin_days_destroyed,
+            avg_input_age: total_age as f64 / inputs.len() as f64,
+            feerate_percentile,
+        })
     }
 }
 
@@ -301,27 +544,10 @@ impl TxOutValue {
         let script_asm = get_script_asm(&script);
         let script_addr = script_to_address(&script, &config.network_type);
 
-        // TODO should the following something to put inside rust-elements lib?
         let script_type = if is_fee {
             "fee"
-        } else if script.is_empty() {
-            "empty"
-        } else if script.is_op_return() {
-            "op_return"
-        } else if script.is_p2pk() {
-            "p2pk"
-        } else if script.is_p2pkh() {
-            "p2pkh"
-        } else if script.is_p2sh() {
-            "p2sh"
-        } else if script.is_v0_p2wpkh() {
-            "v0_p2wpkh"
-        } else if script.is_v0_p2wsh() {
-            "v0_p2wsh"
-        } else if script.is_provably_unspendable() {
-            "provably_unspendable"
         } else {
-            "unknown"
+            get_script_type(&script)
         };
 
         #[cfg(feature = "liquid")]
@@ -346,11 +572,43 @@ impl TxOutValue {
     }
 }
 
+// Depth-based status of a transaction or UTXO, so that clients don't have to work it out from the
+// tip height themselves
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum StatusDetail {
+    Unconfirmed,
+    Confirming, // with less than --settled-confirmations
+    Settled,
+}
+
+fn depth(block_height: Option<usize>, tip_height: usize, config: &Config) -> (u32, StatusDetail) {
+    match block_height {
+        None => (0, StatusDetail::Unconfirmed),
+        Some(height) => {
+            let confirmations = (tip_height.saturating_sub(height) + 1) as u32;
+            if confirmations >= config.settled_confirmations {
+                (confirmations, StatusDetail::Settled)
+            } else {
+                (confirmations, StatusDetail::Confirming)
+            }
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct UtxoValue {
     txid: Sha256dHash,
     vout: u32,
     status: TransactionStatus,
+    confirmations: u32,
+    status_detail: StatusDetail,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scriptpubkey_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scriptpubkey_address: Option<String>,
+    coinbase: bool,
+    mature: bool, // false for the coinbase outputs that can't be spent yet
 
     #[cfg(not(feature = "liquid"))]
     value: u64,
@@ -371,12 +629,30 @@ struct UtxoValue {
     #[serde(skip_serializing_if = "Option::is_none")]
     assetcommitment: Option<String>,
 }
-impl From<Utxo> for UtxoValue {
-    fn from(utxo: Utxo) -> Self {
+impl UtxoValue {
+    fn new(
+        utxo: Utxo,
+        script: Option<&Script>,
+        coinbase: bool,
+        tip_height: usize,
+        config: &Config,
+    ) -> Self {
+        let (confirmations, status_detail) = depth(
+            utxo.confirmed.as_ref().map(|b| b.height),
+            tip_height,
+            config,
+        );
         UtxoValue {
             txid: utxo.txid,
             vout: utxo.vout,
             status: TransactionStatus::from(utxo.confirmed),
+            confirmations,
+            status_detail,
+            scriptpubkey_type: script.map(|script| get_script_type(script).to_string()),
+            scriptpubkey_address: script
+                .and_then(|script| script_to_address(script, &config.network_type)),
+            coinbase,
+            mature: !coinbase || confirmations >= COINBASE_MATURITY,
 
             #[cfg(not(feature = "liquid"))]
             value: utxo.value,
@@ -405,6 +681,35 @@ impl From<Utxo> for UtxoValue {
     }
 }
 
+// The UTXOs of a single script, along with its type and address (which are looked up once, from
+// the first UTXO's transaction) and whether they're coinbase outputs (the first transaction of
+// their block)
+fn utxo_values(utxos: Vec<Utxo>, query: &Query, config: &Config) -> Vec<UtxoValue> {
+    let tip_height = query.chain().best_height();
+    let script = utxos.first().and_then(|utxo| {
+        query
+            .lookup_txn(&utxo.txid)
+            .and_then(|tx| tx.output.get(utxo.vout as usize).cloned())
+            .map(|txout| txout.script_pubkey)
+    });
+    let mut coinbase_txids: HashMap<Sha256dHash, Option<Sha256dHash>> = HashMap::new();
+    utxos
+        .into_iter()
+        .map(|utxo| {
+            let coinbase = utxo.confirmed.as_ref().map_or(false, |blockid| {
+                let coinbase_txid = coinbase_txids.entry(blockid.hash).or_insert_with(|| {
+                    query
+                        .chain()
+                        .get_block_txids(&blockid.hash)
+                        .and_then(|txids| txids.first().cloned())
+                });
+                *coinbase_txid == Some(utxo.txid)
+            });
+            UtxoValue::new(utxo, script.as_ref(), coinbase, tip_height, config)
+        })
+        .collect()
+}
+
 #[derive(Serialize)]
 struct SpendingValue {
     spent: bool,
@@ -436,6 +741,427 @@ impl Default for SpendingValue {
     }
 }
 
+// The result of POST /psbt/decode, with the inputs resolved against the index rather than
+// trusting the UTXOs the PSBT claims to spend
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct PsbtValue {
+    txid: Sha256dHash,
+    version: u32,
+    locktime: u32,
+    inputs: Vec<PsbtInputValue>,
+    outputs: Vec<TxOutValue>,
+    fee: Option<u64>,             // if all the inputs were found
+    estimated_vsize: Option<u32>, // once signed, if the input types are known
+    feerate: Option<f32>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct PsbtInputValue {
+    txid: Sha256dHash,
+    vout: u32,
+    prevout: Option<TxOutValue>,       // None if not found
+    status: Option<TransactionStatus>, // of the funding transaction
+    spending: Option<SpendingValue>,   // if already spent by another transaction
+    utxo_mismatch: bool,               // if the PSBT's UTXO differs from the indexed one
+}
+
+// The result of GET /difficulty, for the current retarget epoch
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct DifficultyValue {
+    difficulty: f64,
+    bits: u32,
+    epoch_start_height: usize,
+    next_retarget_height: usize,
+    blocks_remaining: usize,
+    progress: f64, // the share of the epoch's blocks already mined, in percent
+    average_block_time: f64, // over the epoch so far, in seconds
+    estimated_change: f64, // of the difficulty at the next retarget, in percent
+    estimated_retarget_time: u64, // at the average block time
+    previous_change: Option<f64>, // at the last retarget, in percent
+}
+
+// Bitcoin Core's GetDifficulty: the ratio of the maximum target to the current one
+#[cfg(not(feature = "liquid"))]
+fn difficulty_from_bits(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = f64::from(0x0000_ffff) / f64::from(bits & 0x00ff_ffff);
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+    difficulty
+}
+
+#[cfg(not(feature = "liquid"))]
+impl DifficultyValue {
+    fn new(query: &Query) -> Self {
+        let chain = query.chain();
+        let tip = chain.best_header();
+        let height = tip.height();
+        let epoch_start_height = height - height % RETARGET_INTERVAL;
+        let epoch_start = chain
+            .header_by_height(epoch_start_height)
+            .expect("missing epoch start header");
+        let next_retarget_height = epoch_start_height + RETARGET_INTERVAL;
+        let blocks_remaining = next_retarget_height - height;
+        let mined = height - epoch_start_height;
+        let average_block_time = if mined > 0 {
+            f64::from(tip.header().time.saturating_sub(epoch_start.header().time)) / mined as f64
+        } else {
+            BLOCK_INTERVAL as f64
+        };
+        // the retarget is bounded to a factor of 4 either way
+        let estimated_change = ((BLOCK_INTERVAL as f64 / average_block_time.max(1.0))
+            .max(0.25)
+            .min(4.0)
+            - 1.0)
+            * 100.0;
+        let difficulty = difficulty_from_bits(tip.header().bits);
+        let previous_change = epoch_start_height
+            .checked_sub(1)
+            .and_then(|height| chain.header_by_height(height))
+            .map(|previous| {
+                (difficulty / difficulty_from_bits(previous.header().bits) - 1.0) * 100.0
+            });
+        DifficultyValue {
+            difficulty,
+            bits: tip.header().bits,
+            epoch_start_height,
+            next_retarget_height,
+            blocks_remaining,
+            progress: mined as f64 * 100.0 / RETARGET_INTERVAL as f64,
+            average_block_time,
+            estimated_change,
+            estimated_retarget_time: u64::from(tip.header().time)
+                + (blocks_remaining as f64 * average_block_time) as u64,
+            previous_change,
+        }
+    }
+}
+
+// The result of GET /halving
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct HalvingValue {
+    height: usize,
+    halvings: usize, // so far
+    subsidy: u64,
+    next_halving_height: usize,
+    blocks_remaining: usize,
+    next_subsidy: u64,
+    estimated_time: u64, // at the expected block interval
+}
+
+#[cfg(not(feature = "liquid"))]
+fn block_subsidy(halvings: usize) -> u64 {
+    if halvings >= 64 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+impl HalvingValue {
+    fn new(query: &Query, config: &Config) -> Self {
+        let halving_interval = match config.network_type {
+            Network::Regtest | Network::Custom => 150,
+            _ => 210_000,
+        };
+        let tip = query.chain().best_header();
+        let height = tip.height();
+        let halvings = height / halving_interval;
+        let next_halving_height = (halvings + 1) * halving_interval;
+        let blocks_remaining = next_halving_height - height;
+        HalvingValue {
+            height,
+            halvings,
+            subsidy: block_subsidy(halvings),
+            next_halving_height,
+            blocks_remaining,
+            next_subsidy: block_subsidy(halvings + 1),
+            estimated_time: u64::from(tip.header().time)
+                + blocks_remaining as u64 * BLOCK_INTERVAL as u64,
+        }
+    }
+}
+
+// The result of GET /block/:hash/proof, for SPV clients to check how much work buries the block.
+// The amounts of work are 256-bit big-endian hex numbers, like bitcoind's chainwork.
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct WorkProofValue {
+    height: usize,
+    header: String,
+    work: String,      // of the block alone
+    chainwork: String, // up to and including the block
+    tip_height: usize,
+    tip_hash: Sha256dHash,
+    tip_chainwork: String,
+    confirmations: usize,
+    work_since: String, // of the blocks on top of it
+}
+
+#[cfg(not(feature = "liquid"))]
+fn work_hex(work: Uint256) -> String {
+    work.0
+        .iter()
+        .rev()
+        .map(|word| format!("{:016x}", word))
+        .collect()
+}
+
+#[cfg(not(feature = "liquid"))]
+impl WorkProofValue {
+    fn new(query: &Query, hash: &Sha256dHash) -> Result<Self, HttpError> {
+        let chain = query.chain();
+        let tip = chain.best_header();
+        let height = chain
+            .height_by_hash(hash)
+            .filter(|height| *height <= tip.height())
+            .ok_or_else(HttpError::block_not_found)?;
+        let header = chain
+            .header_by_height(height)
+            .filter(|entry| entry.hash() == hash)
+            .ok_or_else(HttpError::block_not_found)?;
+        // both are missing if the chain was reorged since
+        let chainwork = chain
+            .chainwork_by_height(height)
+            .ok_or_else(HttpError::block_not_found)?;
+        let tip_chainwork = chain
+            .chainwork_by_height(tip.height())
+            .ok_or_else(HttpError::block_not_found)?;
+        Ok(WorkProofValue {
+            height,
+            header: hex::encode(encode::serialize(header.header())),
+            work: work_hex(header.header().work()),
+            chainwork: work_hex(chainwork),
+            tip_height: tip.height(),
+            tip_hash: *tip.hash(),
+            tip_chainwork: work_hex(tip_chainwork),
+            confirmations: tip.height() - height + 1,
+            work_since: work_hex(tip_chainwork - chainwork),
+        })
+    }
+}
+
+// The result of GET /payment/:bip21uri/status
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct PaymentStatusValue {
+    address: String,
+    amount: Option<u64>,
+    label: Option<String>,
+    message: Option<String>,
+    status: &'static str, // unpaid, underpaid, paid or overpaid
+    received: u64,        // including the mempool
+    confirmed_received: u64,
+    payments: Vec<PaymentValue>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct PaymentValue {
+    txid: Sha256dHash,
+    value: u64, // paid to the address by this transaction
+    status: TransactionStatus,
+}
+
+// Sums the outputs paying to the requested address. Without an amount, any payment counts.
+#[cfg(not(feature = "liquid"))]
+fn payment_status(
+    request: PaymentRequest,
+    query: &Query,
+    config: &Config,
+    budget: &QueryBudget,
+) -> Result<PaymentStatusValue, HttpError> {
+    let scripthash = address_to_scripthash(&request.address, &config.network_type)?;
+    let mut payments = vec![];
+    for (txid, blockid) in query
+        .history_txids(&scripthash, budget)?
+        .into_iter()
+        .unique_by(|(txid, _)| *txid)
+    {
+        let tx = match query.lookup_txn(&txid) {
+            Some(tx) => tx,
+            None => continue, // evicted from the mempool meanwhile
+        };
+        let value: u64 = tx
+            .output
+            .iter()
+            .filter(|txout| compute_script_hash(&txout.script_pubkey) == scripthash)
+            .map(|txout| txout.value)
+            .sum();
+        if value > 0 {
+            payments.push(PaymentValue {
+                txid,
+                value,
+                status: TransactionStatus::from(blockid),
+            });
+        }
+    }
+    let received: u64 = payments.iter().map(|p| p.value).sum();
+    let confirmed_received: u64 = payments
+        .iter()
+        .filter(|p| p.status.confirmed)
+        .map(|p| p.value)
+        .sum();
+    let status = match request.amount {
+        _ if received == 0 => "unpaid",
+        Some(amount) if received < amount => "underpaid",
+        Some(amount) if received > amount => "overpaid",
+        _ => "paid",
+    };
+    Ok(PaymentStatusValue {
+        address: request.address,
+        amount: request.amount,
+        label: request.label,
+        message: request.message,
+        status,
+        received,
+        confirmed_received,
+        payments,
+    })
+}
+
+#[cfg(not(feature = "liquid"))]
+impl PsbtValue {
+    fn new(psbt: &PartiallySignedTransaction, query: &Query, config: &Config) -> Self {
+        let tx = &psbt.global.unsigned_tx;
+        let mut total_in = Some(0u64);
+        let mut witness_weight = Some(0usize);
+        let inputs: Vec<PsbtInputValue> = tx
+            .input
+            .iter()
+            .zip(&psbt.inputs)
+            .map(|(txin, input)| {
+                let outpoint = txin.previous_output;
+                let prevout = query
+                    .lookup_txn(&outpoint.txid)
+                    .and_then(|prev_tx| prev_tx.output.into_iter().nth(outpoint.vout as usize));
+                let claimed = input.witness_utxo.clone().or_else(|| {
+                    input
+                        .non_witness_utxo
+                        .as_ref()
+                        .and_then(|prev_tx| prev_tx.output.get(outpoint.vout as usize).cloned())
+                });
+                total_in = match prevout {
+                    Some(ref prevout) => total_in.map(|total| total + prevout.value),
+                    None => None,
+                };
+                witness_weight = match (witness_weight, prevout.as_ref()) {
+                    (Some(weight), Some(prevout)) => {
+                        estimate_input_weight(input, &prevout.script_pubkey).map(|w| weight + w)
+                    }
+                    _ => None,
+                };
+                let spending = query
+                    .lookup_spend(&outpoint)
+                    .filter(|spend| spend.txid != tx.txid())
+                    .map(SpendingValue::from);
+                PsbtInputValue {
+                    txid: outpoint.txid,
+                    vout: outpoint.vout,
+                    utxo_mismatch: match (&claimed, &prevout) {
+                        (Some(claimed), Some(prevout)) => claimed != prevout,
+                        _ => false,
+                    },
+                    status: prevout
+                        .as_ref()
+                        .map(|_| query.get_tx_status(&outpoint.txid)),
+                    prevout: prevout
+                        .as_ref()
+                        .map(|prevout| TxOutValue::new(prevout, config)),
+                    spending,
+                }
+            })
+            .collect();
+
+        let total_out: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        let fee = total_in.and_then(|total_in| total_in.checked_sub(total_out));
+        // including the segwit marker and flag, which is off by half a vbyte for legacy ones
+        let estimated_vsize =
+            witness_weight.map(|weight| ((tx.get_weight() + weight + 2 + 3) / 4) as u32);
+        PsbtValue {
+            txid: tx.txid(),
+            version: tx.version,
+            locktime: tx.lock_time,
+            inputs,
+            outputs: tx
+                .output
+                .iter()
+                .map(|txout| TxOutValue::new(txout, config))
+                .collect(),
+            fee,
+            estimated_vsize,
+            feerate: match (fee, estimated_vsize) {
+                (Some(fee), Some(vsize)) => Some(fee as f32 / vsize as f32),
+                _ => None,
+            },
+        }
+    }
+}
+
+// The weight an input adds to the unsigned transaction once signed, using its final scripts if
+// it's finalized, or the typical signature sizes of single-key scripts otherwise
+#[cfg(not(feature = "liquid"))]
+fn estimate_input_weight(input: &psbt::Input, script_pubkey: &Script) -> Option<usize> {
+    if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+        let script_sig_len = input.final_script_sig.as_ref().map_or(0, |s| s.len());
+        let witness_len = input.final_script_witness.as_ref().map_or(0, |witness| {
+            1 + witness.iter().map(|item| 1 + item.len()).sum::<usize>()
+        });
+        return Some(4 * script_sig_len + witness_len);
+    }
+    // a 72-byte signature and a 33-byte compressed public key, with their length prefixes
+    const SIG_AND_KEY_LEN: usize = 1 + 72 + 1 + 33;
+    if script_pubkey.is_v0_p2wpkh() {
+        Some(1 + SIG_AND_KEY_LEN)
+    } else if script_pubkey.is_p2pkh() {
+        Some(4 * SIG_AND_KEY_LEN)
+    } else if script_pubkey.is_p2sh()
+        && input
+            .redeem_script
+            .as_ref()
+            .map_or(false, |script| script.is_v0_p2wpkh())
+    {
+        // the scriptSig pushes the 22-byte redeem script
+        Some(4 * 23 + 1 + SIG_AND_KEY_LEN)
+    } else {
+        None
+    }
+}
+
+// TODO should the following something to put inside rust-elements lib?
+fn get_script_type(script: &Script) -> &'static str {
+    if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_v0_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
+    } else {
+        "unknown"
+    }
+}
+
 fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     height.map_or(TTL_SHORT, |height| {
         if query.chain().best_height() - height >= CONF_FINAL {
@@ -446,71 +1172,259 @@ fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     })
 }
 
+// ttl for resources that change whenever a new block arrives, shortened once the
+// tip is older than the expected block interval
+fn ttl_by_tip(query: &Query) -> u32 {
+    let tip_time = query.chain().best_header().header().time as i64;
+    if time::get_time().sec - tip_time >= BLOCK_INTERVAL {
+        TTL_TIP_DUE
+    } else {
+        TTL_SHORT
+    }
+}
+
 fn prepare_txs(
     txs: Vec<(Transaction, Option<BlockId>)>,
     query: &Query,
     config: &Config,
+    analytics: bool,
 ) -> Vec<TransactionValue> {
     let prevouts = if config.prevout_enabled {
-        let outpoints = txs
-            .iter()
-            .flat_map(|(tx, _)| {
-                tx.input
-                    .iter()
-                    .filter(|txin| has_prevout(txin))
-                    .map(|txin| txin.previous_output)
-            })
-            .collect();
+        let mut prevouts = HashMap::new();
+        let mut outpoints = BTreeSet::new();
+        for (tx, blockid) in &txs {
+            let indexed = match blockid {
+                Some(_) if config.index_prevouts => lookup_prevouts(query.chain(), tx),
+                _ => None,
+            };
+            match indexed {
+                Some(indexed) => prevouts.extend(indexed),
+                None => outpoints.extend(
+                    tx.input
+                        .iter()
+                        .filter(|txin| has_prevout(txin))
+                        .map(|txin| txin.previous_output),
+                ),
+            }
+        }
+        if !outpoints.is_empty() {
+            prevouts.extend(query.lookup_txos(&outpoints));
+        }
+        prevouts
+    } else {
+        HashMap::new()
+    };
 
-        query.lookup_txos(&outpoints)
+    let prevout_blocks: HashMap<Sha256dHash, BlockId> = if analytics {
+        txs.iter()
+            .flat_map(|(tx, _)| tx.input.iter().filter(|txin| has_prevout(txin)))
+            .map(|txin| txin.previous_output.txid)
+            .unique()
+            .filter_map(|txid| Some((txid, query.chain().tx_confirming_block(&txid)?)))
+            .collect()
     } else {
         HashMap::new()
     };
 
+    let tip_height = query.chain().best_height();
     txs.into_iter()
-        .map(|(tx, blockid)| TransactionValue::new(tx, blockid, &prevouts, config))
+        .map(|(tx, blockid)| {
+            let tx_analytics = if analytics {
+                TxAnalytics::new(&tx, blockid.as_ref(), &prevouts, &prevout_blocks, query)
+            } else {
+                None
+            };
+            let package = match blockid {
+                None => query.mempool().package_info(&tx.txid()),
+                Some(_) => None,
+            };
+            let mut value = TransactionValue::new(tx, blockid, &prevouts, tip_height, config);
+            value.package = package;
+            value.analytics = tx_analytics;
+            value
+        })
         .collect()
 }
 
 type BoxFut = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
 
 pub fn run_server(config: Arc<Config>, query: Arc<Query>) -> Handle {
-    let addr = &config.http_addr;
-    info!("REST server running on {}", addr);
+    let listener = Listener::bind(&config);
 
     let config = Arc::new(config.clone());
+    // the handlers block on database reads, so they run on a dedicated pool instead of the event
+    // loop, which is left to deal with the connections
+    let workers = CpuPool::new(config.http_workers);
+    // the expensive queries get a few workers of their own, so that they queue behind each other
+    // rather than in front of the interactive ones
+    let heavy_workers = CpuPool::new(config.http_heavy_workers);
+    let pending = Arc::new(AtomicUsize::new(0));
+    let waiters = Waiters::start();
+    let service_waiters = Arc::clone(&waiters);
 
     let new_service = move || {
         let query = Arc::clone(&query);
         let config = Arc::clone(&config);
+        let workers = workers.clone();
+        let heavy_workers = heavy_workers.clone();
+        let pending = Arc::clone(&pending);
+        let waiters = Arc::clone(&service_waiters);
 
         service_fn(move |req: Request<Body>| -> BoxFut {
             let method = req.method().clone();
             let uri = req.uri().clone();
+            let (origin, vary_origin) = {
+                let cors = &config.runtime.read().unwrap().cors;
+                (allowed_origin(req.headers(), cors), varies_by_origin(cors))
+            };
+            let accepts_gzip = accepts_gzip(req.headers());
+            let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+            let headers = req.headers().clone();
             let query = Arc::clone(&query);
             let config = Arc::clone(&config);
-            let future = req.into_body().concat2().and_then(move |body| {
-                let mut resp =
-                    handle_request(method, uri, body, &query, &config).unwrap_or_else(|err| {
-                        warn!("{:?}", err);
-                        Response::builder()
-                            .status(err.0)
-                            .header("Content-Type", "text/plain")
-                            .body(Body::from(err.1))
-                            .unwrap()
+            let workers = match request_priority(&method, &uri) {
+                Priority::Interactive => workers.clone(),
+                Priority::Heavy => heavy_workers.clone(),
+            };
+            let pending = Arc::clone(&pending);
+            let waiters = Arc::clone(&waiters);
+            let future = req
+                .into_body()
+                .concat2()
+                .and_then(move |body| -> BoxFut {
+                    let access = match authenticate(&headers, &config) {
+                        Ok(access) => access,
+                        Err(err) => return Box::new(future::ok(err.to_response())),
+                    };
+                    // shed the load once the workers fall too far behind, rather than letting
+                    // the latency of every request grow with the queue
+                    let slot = match Slot::acquire(&pending, config.http_max_pending) {
+                        Some(slot) => slot,
+                        None => {
+                            return Box::new(future::ok(
+                                HttpError::new(
+                                    ErrorCode::ServerBusy,
+                                    "The server is busy. Please try again later.".to_string(),
+                                )
+                                .to_response(),
+                            ))
+                        }
+                    };
+                    let handler_config = Arc::clone(&config);
+                    let long_poll_deadline = long_poll_deadline(&uri);
+                    let run = Arc::new(move |body: hyper::Chunk| {
+                        let span = tracing::info_span!(
+                            "http_request",
+                            method = %method,
+                            path = uri.path(),
+                            status = tracing::field::Empty,
+                            rows = tracing::field::Empty
+                        );
+                        let _entered = span.enter();
+                        let mut resp = handle_request(
+                            method.clone(),
+                            uri.clone(),
+                            body,
+                            &headers,
+                            &access,
+                            &query,
+                            &handler_config,
+                        )
+                        .unwrap_or_else(|err| {
+                            warn!("{:?}", err);
+                            err.to_response()
+                        });
+                        span.record("status", &u64::from(resp.status().as_u16()));
+                        // the id of the request in the logs and the traces
+                        if let Some(request_id) = traces::current_trace_id() {
+                            resp.headers_mut().insert(
+                                "X-Request-Id",
+                                HeaderValue::from_str(&request_id).unwrap(),
+                            );
+                        }
+                        resp
                     });
-                if let Some(ref origins) = config.cors {
-                    resp.headers_mut()
-                        .insert("Access-Control-Allow-Origin", origins.parse().unwrap());
-                }
-                Ok(resp)
-            });
+                    let deadline = match long_poll_deadline {
+                        Some(deadline) => deadline,
+                        None => {
+                            return Box::new(workers.spawn_fn(
+                                move || -> Result<_, hyper::Error> {
+                                    let _slot = slot;
+                                    Ok(run(body))
+                                },
+                            ))
+                        }
+                    };
+                    // long-polls are handled again on every update, until their response changes
+                    // or they time out. Only the first run counts against the pending requests.
+                    let body = body.to_vec();
+                    Box::new(future::loop_fn(Some(slot), move |slot| {
+                        // subscribed before running, so that no update is missed in between
+                        let update = waiters.wait(deadline);
+                        let run = Arc::clone(&run);
+                        let body = hyper::Chunk::from(body.clone());
+                        workers
+                            .spawn_fn(move || -> Result<_, hyper::Error> {
+                                let _slot = slot;
+                                Ok(run(body))
+                            })
+                            .and_then(move |resp| {
+                                if resp.extensions().get::<Unchanged>().is_none()
+                                    || Instant::now() >= deadline
+                                {
+                                    Either::A(future::ok(Loop::Break(resp)))
+                                } else {
+                                    Either::B(update.then(|_| -> Result<_, hyper::Error> {
+                                        Ok(Loop::Continue(None))
+                                    }))
+                                }
+                            })
+                    }))
+                })
+                .and_then(move |mut resp| {
+                    if let Some(ref if_none_match) = if_none_match {
+                        if is_not_modified(&resp, if_none_match) {
+                            resp = not_modified(resp);
+                        }
+                    }
+                    if let Some(origin) = origin {
+                        resp.headers_mut()
+                            .insert("Access-Control-Allow-Origin", origin);
+                    }
+                    // including the responses to disallowed origins, which may be cached too
+                    if vary_origin {
+                        resp.headers_mut()
+                            .append("Vary", HeaderValue::from_static("Origin"));
+                    }
+                    if accepts_gzip && config.http_compression_threshold > 0 {
+                        compress_response(resp, config.http_compression_threshold)
+                    } else {
+                        Box::new(future::ok(resp))
+                    }
+                });
             Box::new(future)
         })
     };
 
+    // connections above the limit are closed right away, by dropping them
+    let connections = Arc::new(AtomicUsize::new(0));
+    let max_connections = config.http_max_connections;
+    let incoming = listener.incoming().filter_map(move |stream| {
+        match Slot::acquire(&connections, max_connections) {
+            Some(slot) => Some(LimitedStream {
+                stream,
+                _slot: slot,
+            }),
+            None => {
+                debug!("too many connections, rejecting {}", stream);
+                None
+            }
+        }
+    });
+
     let (tx, rx) = oneshot::channel::<()>();
-    let server = Server::bind(&addr)
+    let server = Server::builder(incoming)
+        .http1_keepalive(config.http_keepalive)
         .serve(new_service)
         .with_graceful_shutdown(rx)
         .map_err(|e| eprintln!("server error: {}", e));
@@ -520,30 +1434,397 @@ pub fn run_server(config: Arc<Config>, query: Arc<Query>) -> Handle {
         thread: thread::spawn(move || {
             rt::run(server);
         }),
+        waiters,
     }
 }
 
-pub struct Handle {
-    tx: oneshot::Sender<()>,
-    thread: thread::JoinHandle<()>,
+// Marks the response of a long-poll whose state didn't change yet, to hold it until it does
+struct Unchanged;
+
+fn mark_unchanged(resp: &mut Response<Body>) {
+    resp.extensions_mut().insert(Unchanged);
 }
 
-impl Handle {
-    pub fn stop(self) {
-        self.tx.send(()).expect("failed to send shutdown signal");
-        self.thread.join().expect("REST server failed");
+// The long-polls waiting for an update, each until its deadline
+struct Waiters(Mutex<Vec<(Instant, oneshot::Sender<()>)>>);
+
+impl Waiters {
+    // The expired waiters are dropped every second, which wakes them up one last time
+    fn start() -> Arc<Self> {
+        let waiters = Arc::new(Waiters(Mutex::new(vec![])));
+        let weak: Weak<Waiters> = Arc::downgrade(&waiters);
+        thread::spawn(move || {
+            while let Some(waiters) = weak.upgrade() {
+                waiters.expire();
+                drop(waiters);
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+        waiters
+    }
+
+    fn wait(&self, deadline: Instant) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.0.lock().unwrap().push((deadline, tx));
+        rx
+    }
+
+    fn notify(&self) {
+        for (_, tx) in self.0.lock().unwrap().drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    fn expire(&self) {
+        let now = Instant::now();
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(deadline, tx)| *deadline > now && !tx.is_canceled());
     }
 }
 
-fn handle_request(
-    method: Method,
-    uri: hyper::Uri,
-    body: hyper::Chunk,
-    query: &Query,
-    config: &Config,
-) -> Result<Response<Body>, HttpError> {
-    // TODO it looks hyper does not have routing and query parsing :(
-    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
+// Only the requests waiting for a change are long-polls, held for up to ?timeout seconds
+fn long_poll_deadline(uri: &hyper::Uri) -> Option<Instant> {
+    let params: HashMap<String, String> = form_urlencoded::parse(uri.query()?.as_bytes())
+        .into_owned()
+        .collect();
+    if !params.contains_key("wait_above") && !params.contains_key("wait_for_change") {
+        return None;
+    }
+    let timeout = params
+        .get("timeout")
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .unwrap_or(LONG_POLL_DEFAULT_TIMEOUT)
+        .min(LONG_POLL_MAX_TIMEOUT);
+    Some(Instant::now() + Duration::from_secs(timeout))
+}
+
+// Holds one of a limited number of slots (connections or pending requests), released on drop
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn acquire(counter: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        if counter.fetch_add(1, Ordering::SeqCst) >= max {
+            counter.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(Arc::clone(counter)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Where the REST server accepts connections from
+enum Listener {
+    Tcp(AddrIncoming),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bind(config: &Config) -> Self {
+        match config.http_socket_file {
+            Some(ref path) => {
+                // left behind by a previous run
+                if path.exists() {
+                    fs::remove_file(path).expect("failed to remove the old REST server socket");
+                }
+                let listener = UnixListener::bind(path).expect("failed to bind the REST server");
+                fs::set_permissions(path, fs::Permissions::from_mode(config.http_socket_mode))
+                    .expect("failed to set the REST server socket permissions");
+                info!("REST server running on {:?}", path);
+                Listener::Unix(listener)
+            }
+            None => {
+                let addr = &config.http_addr;
+                let mut incoming =
+                    AddrIncoming::bind(addr).expect("failed to bind the REST server");
+                incoming.set_nodelay(true);
+                info!("REST server running on {}", addr);
+                Listener::Tcp(incoming)
+            }
+        }
+    }
+
+    fn incoming(self) -> Box<dyn Stream<Item = Connection, Error = io::Error> + Send> {
+        match self {
+            Listener::Tcp(incoming) => Box::new(incoming.map(Connection::Tcp)),
+            Listener::Unix(listener) => Box::new(listener.incoming().map(Connection::Unix)),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(AddrStream),
+    Unix(UnixStream),
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connection::Tcp(stream) => write!(f, "{}", stream.remote_addr()),
+            Connection::Unix(_) => write!(f, "a socket connection"),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Connection::Tcp(stream) => AsyncWrite::shutdown(stream),
+            Connection::Unix(stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
+
+// A connection counted against --http-max-connections while open
+struct LimitedStream {
+    stream: Connection,
+    _slot: Slot,
+}
+
+impl Read for LimitedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for LimitedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for LimitedStream {}
+
+impl AsyncWrite for LimitedStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+// Returns the value for the Access-Control-Allow-Origin header, if the request origin is allowed
+fn allowed_origin(headers: &HeaderMap, cors: &[String]) -> Option<HeaderValue> {
+    if cors.iter().any(|allowed| allowed == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+    let origin = headers.get(ORIGIN)?;
+    if cors
+        .iter()
+        .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+    {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+// Whether the responses depend on the request origin, when only specific origins are allowed
+fn varies_by_origin(cors: &[String]) -> bool {
+    !cors.is_empty() && cors.iter().all(|allowed| allowed != "*")
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            parts.next() == Some("gzip") && parts.all(|param| param != "q=0" && param != "q=0.0")
+        })
+}
+
+// Weak validator, as the same resource may be served in different content encodings
+fn etag(body: &[u8]) -> String {
+    let mut hash = [0u8; 32];
+    let mut sha2 = Sha256::new();
+    sha2.input(body);
+    sha2.result(&mut hash);
+    format!("W/\"{}\"", hex::encode(&hash[..16]))
+}
+
+fn is_not_modified(resp: &Response<Body>, if_none_match: &HeaderValue) -> bool {
+    if resp.status() != StatusCode::OK {
+        return false;
+    }
+    let etag = match resp.headers().get(ETAG).and_then(|v| v.to_str().ok()) {
+        Some(etag) => etag.trim_start_matches("W/"),
+        None => return false,
+    };
+    if_none_match.to_str().map_or(false, |value| {
+        value
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    })
+}
+
+fn not_modified(resp: Response<Body>) -> Response<Body> {
+    let (mut parts, _) = resp.into_parts();
+    parts.status = StatusCode::NOT_MODIFIED;
+    parts.headers.remove("Content-Type");
+    Response::from_parts(parts, Body::empty())
+}
+
+fn cache_control(ttl: u32) -> String {
+    if ttl >= TTL_LONG {
+        format!("public, max-age={:}, immutable", ttl)
+    } else {
+        format!("public, max-age={:}", ttl)
+    }
+}
+
+// Compress JSON responses larger than `threshold` bytes using gzip. Brotli is deliberately not
+// supported (the clients that accept it all accept gzip too), so `br` is ignored in Accept-Encoding
+fn compress_response(resp: Response<Body>, threshold: usize) -> BoxFut {
+    let is_json = resp
+        .headers()
+        .get("Content-Type")
+        .map_or(false, |ct| *ct == "application/json");
+    if !is_json {
+        return Box::new(future::ok(resp));
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    Box::new(body.concat2().map(move |chunk| {
+        if chunk.len() < threshold {
+            return Response::from_parts(parts, Body::from(chunk));
+        }
+        let mut encoder = GzEncoder::new(Vec::with_capacity(chunk.len() / 4), Compression::fast());
+        match encoder.write_all(&chunk).and_then(|_| encoder.finish()) {
+            Ok(compressed) => {
+                parts
+                    .headers
+                    .insert("Content-Encoding", HeaderValue::from_static("gzip"));
+                parts
+                    .headers
+                    .append("Vary", HeaderValue::from_static("Accept-Encoding"));
+                Response::from_parts(parts, Body::from(compressed))
+            }
+            Err(e) => {
+                warn!("gzip compression failed: {}", e);
+                Response::from_parts(parts, Body::from(chunk))
+            }
+        }
+    }))
+}
+
+// Adapts a blocking writer (running on its own thread) to a streamed response body
+#[cfg(not(feature = "liquid"))]
+struct BodyWriter {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BodyWriter {
+    fn new(sender: mpsc::Sender<Vec<u8>>) -> Self {
+        BodyWriter {
+            sender: Some(sender),
+            buf: Vec::with_capacity(STREAM_CHUNK_SIZE),
+        }
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+impl io::Write for BodyWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= STREAM_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    // blocks until the chunk is accepted, fails once the client is gone
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(STREAM_CHUNK_SIZE));
+        let sender = self
+            .sender
+            .take()
+            .and_then(|sender| sender.send(chunk).wait().ok());
+        match sender {
+            Some(sender) => {
+                self.sender = Some(sender);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "response body dropped",
+            )),
+        }
+    }
+}
+
+pub struct Handle {
+    tx: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+    waiters: Arc<Waiters>,
+}
+
+impl Handle {
+    // Wakes up the long-polls, to be called after every index or mempool update
+    pub fn notify(&self) {
+        self.waiters.notify();
+    }
+
+    pub fn stop(self) {
+        self.tx.send(()).expect("failed to send shutdown signal");
+        self.thread.join().expect("REST server failed");
+    }
+}
+
+fn handle_request(
+    method: Method,
+    uri: hyper::Uri,
+    body: hyper::Chunk,
+    headers: &HeaderMap,
+    access: &Access,
+    query: &Arc<Query>,
+    config: &Config,
+) -> Result<Response<Body>, HttpError> {
+    // TODO it looks hyper does not have routing and query parsing :(
+    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
     let query_params = match uri.query() {
         Some(value) => form_urlencoded::parse(&value.as_bytes())
             .into_owned()
@@ -552,6 +1833,48 @@ fn handle_request(
     };
 
     info!("handle {:?} {:?}", method, uri);
+
+    // admins may ?force=1 the scan of the full history
+    let force = query_params
+        .get("force")
+        .map_or(false, |force| force == "1");
+    check_permission(access, required_permission(&method, &path, force))?;
+    let budget =
+        QueryBudget::new(config).truncate_history(if force { 0 } else { config.max_history_rows });
+
+    let analytics = query_params
+        .get("analytics")
+        .map_or(false, |value| value == "1" || value == "true");
+    let format = DataFormat {
+        fields: query_params
+            .get("fields")
+            .map(|fields| parse_fields(fields)),
+        cbor: query_params.get("format").map_or_else(
+            || {
+                headers
+                    .get(ACCEPT)
+                    .and_then(|accept| accept.to_str().ok())
+                    .map_or(false, accepts_cbor)
+            },
+            |format| format == "cbor",
+        ),
+        #[cfg(not(feature = "liquid"))]
+        fiat: match query_params.get("fiat") {
+            Some(currency) => Some(fiat_prices(currency, query)?),
+            None => None,
+        },
+    };
+
+    let is_status = path.get(0) == Some(&"internal") && path.get(1) == Some(&"status");
+    // the explorer shows the indexing error of the endpoints it queries
+    let is_ui = cfg!(feature = "ui") && path.get(0) == Some(&"ui");
+    if query.chain().indexed_height().is_none() && !is_status && !is_ui {
+        bail!(HttpError::new(
+            ErrorCode::IndexingInProgress,
+            "Initial indexing is in progress, see /internal/status".to_string()
+        ));
+    }
+
     match (
         &method,
         path.get(0),
@@ -560,28 +1883,53 @@ fn handle_request(
         path.get(3),
         path.get(4),
     ) {
+        // relative to /ui/, so that the assets resolve under any path prefix
+        #[cfg(feature = "ui")]
+        (&Method::GET, Some(&"ui"), None, None, None, None) => Ok(Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header("Location", "ui/")
+            .body(Body::empty())
+            .unwrap()),
+        #[cfg(feature = "ui")]
+        (&Method::GET, Some(&"ui"), Some(name), None, None, None) => {
+            let asset =
+                ui::asset(name).ok_or_else(|| HttpError::not_found("Not found".to_string()))?;
+            Ok(Response::builder()
+                .header("Content-Type", asset.content_type)
+                .header("Cache-Control", cache_control(UI_TTL))
+                .header(ETAG, etag(asset.content))
+                .body(Body::from(asset.content))
+                .unwrap())
+        }
+
         (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None, None) => http_message(
             StatusCode::OK,
             query.chain().best_hash().to_hex(),
-            TTL_SHORT,
+            ttl_by_tip(query),
         ),
 
-        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None, None) => http_message(
-            StatusCode::OK,
-            query.chain().best_height().to_string(),
-            TTL_SHORT,
-        ),
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None, None) => {
+            let best_height = query.chain().best_height();
+            let mut resp =
+                http_message(StatusCode::OK, best_height.to_string(), ttl_by_tip(query))?;
+            if let Some(wait_above) = query_params.get("wait_above") {
+                if best_height <= wait_above.parse::<usize>()? {
+                    mark_unchanged(&mut resp);
+                }
+            }
+            Ok(resp)
+        }
 
         (&Method::GET, Some(&"blocks"), start_height, None, None, None) => {
             let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
-            blocks(&query, start_height)
+            blocks(&query, start_height, &format)
         }
         (&Method::GET, Some(&"block-height"), Some(height), None, None, None) => {
             let height = height.parse::<usize>()?;
             let header = query
                 .chain()
                 .header_by_height(height)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| HttpError::block_not_found())?;
             let ttl = ttl_by_depth(Some(height), query);
             http_message(StatusCode::OK, header.hash().to_hex(), ttl)
         }
@@ -590,9 +1938,49 @@ fn handle_request(
             let blockhm = query
                 .chain()
                 .get_block_with_meta(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
-            let block_value = BlockValue::from(blockhm);
-            json_response(block_value, TTL_LONG)
+                .ok_or_else(|| HttpError::block_not_found())?;
+            #[allow(unused_mut)]
+            let mut block_value = BlockValue::from(blockhm);
+            #[cfg(not(feature = "liquid"))]
+            {
+                if format.fiat.is_some() {
+                    block_value.total_value = block_total_value(query, &hash);
+                }
+                block_value.miner = block_miner(query.chain(), &hash);
+            }
+            data_response(block_value, TTL_LONG, &format)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"raw"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let raw = query
+                .chain()
+                .get_block_raw(&hash)
+                .ok_or_else(|| HttpError::block_not_found())?;
+            let ttl = ttl_by_depth(query.chain().get_block_status(&hash).height, query);
+            raw_response(raw, ttl, &query_params, headers)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"proof"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            json_response(WorkProofValue::new(query, &hash)?, ttl_by_tip(query))
+        }
+        (&Method::GET, Some(&"headers"), None, None, None, None) => {
+            let start_height = query_params
+                .get("start_height")
+                .ok_or_else(|| HttpError::from("Missing start_height".to_string()))?
+                .parse::<usize>()?;
+            let count = match query_params.get("count") {
+                Some(count) => count.parse::<usize>()?.min(HEADERS_MAX_COUNT),
+                None => HEADERS_MAX_COUNT,
+            };
+            if start_height > query.chain().best_height() {
+                bail!(HttpError::block_not_found());
+            }
+            let block_headers = query.chain().headers_from(start_height, count);
+            let raw: Vec<u8> = block_headers.iter().flat_map(encode::serialize).collect();
+            let last_height = start_height + block_headers.len().saturating_sub(1);
+            let ttl = ttl_by_depth(Some(last_height), query);
+            raw_response(raw, ttl, &query_params, headers)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"status"), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
@@ -600,12 +1988,21 @@ fn handle_request(
             let ttl = ttl_by_depth(status.height, query);
             json_response(status, ttl)
         }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"stats"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let stats = query
+                .chain()
+                .get_block_stats(&hash)?
+                .ok_or_else(|| HttpError::block_not_found())?;
+            json_response(stats, TTL_LONG)
+        }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txids"), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let txids = query
                 .chain()
                 .get_block_txids(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| HttpError::block_not_found())?;
             json_response(txids, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txid"), Some(index), None) => {
@@ -614,7 +2011,7 @@ fn handle_request(
             let txids = query
                 .chain()
                 .get_block_txids(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| HttpError::block_not_found())?;
             if index >= txids.len() {
                 bail!(HttpError::not_found("tx index out of range".to_string()));
             }
@@ -622,56 +2019,88 @@ fn handle_request(
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txs"), start_index, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let txids = query
+            let meta = query
                 .chain()
-                .get_block_txids(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .get_block_meta(&hash)
+                .ok_or_else(|| HttpError::block_not_found())?;
 
             let start_index = start_index
                 .map_or(0u32, |el| el.parse().unwrap_or(0))
                 .max(0u32) as usize;
-            if start_index >= txids.len() {
+            if start_index >= meta.tx_count as usize {
                 bail!(HttpError::not_found("start index out of range".to_string()));
             } else if start_index % CHAIN_TXS_PER_PAGE != 0 {
                 bail!(HttpError::from(format!(
                     "start index must be a multipication of {}",
                     CHAIN_TXS_PER_PAGE
-                )));
+                ))
+                .with_details(json!({ "page_size": CHAIN_TXS_PER_PAGE })));
             }
 
             // header_by_hash() only returns the BlockId for non-orphaned blocks,
             // or None for orphaned
             let confirmed_blockid = query.chain().blockid_by_hash(&hash);
 
-            let txs = txids
-                .iter()
-                .skip(start_index)
-                .take(CHAIN_TXS_PER_PAGE)
-                .map(|txid| {
-                    query
-                        .lookup_txn(&txid)
-                        .map(|tx| (tx, confirmed_blockid.clone()))
-                        .ok_or_else(|| "missing tx".to_string())
-                })
-                .collect::<Result<Vec<(Transaction, Option<BlockId>)>, _>>()?;
+            // only the requested page of transactions is read from the store
+            let txs = query
+                .chain()
+                .get_block_txs(&hash, start_index, CHAIN_TXS_PER_PAGE)?
+                .ok_or_else(|| HttpError::block_not_found())?
+                .into_iter()
+                .map(|tx| (tx, confirmed_blockid.clone()))
+                .collect();
 
             // XXX orphraned blocks alway get TTL_SHORT
             let ttl = ttl_by_depth(confirmed_blockid.map(|b| b.height), query);
 
-            json_response(prepare_txs(txs, query, config), ttl)
+            data_response(prepare_txs(txs, query, config, analytics), ttl, &format)
         }
         (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None, None)
-        | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None, None) => {
+        | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None, None)
+        | (&Method::GET, Some(script_type @ &"script"), Some(script_str), None, None, None)
+        | (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"stats"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"stats"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"script"),
+            Some(script_str),
+            Some(&"stats"),
+            None,
+            None,
+        ) => {
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            let stats = query.stats(&script_hash[..]);
-            json_response(
-                json!({
-                    *script_type: script_str,
-                    "chain_stats": stats.0,
-                    "mempool_stats": stats.1,
-                }),
-                TTL_SHORT,
-            )
+            let stats = query.stats(&script_hash[..], &budget)?;
+            let mut value = json!({
+                *script_type: script_str,
+                "chain_stats": stats.0,
+                "mempool_stats": stats.1,
+            });
+            if *script_type == "script" {
+                // there's no address form for most of the scripts queried this way, describe them
+                let script = Script::from(hex::decode(script_str)?);
+                value["scriptpubkey_type"] = json!(get_script_type(&script));
+                value["scriptpubkey_asm"] = json!(get_script_asm(&script));
+                value["scriptpubkey_address"] =
+                    json!(script_to_address(&script, &config.network_type));
+            }
+            if budget.truncated() {
+                value["history_truncated"] = json!(true);
+            }
+            data_response(value, TTL_SHORT, &format)
         }
         (
             &Method::GET,
@@ -688,6 +2117,14 @@ fn handle_request(
             Some(&"txs"),
             None,
             None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"script"),
+            Some(script_str),
+            Some(&"txs"),
+            None,
+            None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
 
@@ -704,12 +2141,25 @@ fn handle_request(
             txs.extend(
                 query
                     .chain()
-                    .history(&script_hash[..], None, CHAIN_TXS_PER_PAGE)
+                    .history(&script_hash[..], None, CHAIN_TXS_PER_PAGE, &budget)?
                     .into_iter()
                     .map(|(tx, blockid)| (tx, Some(blockid))),
             );
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            let mut resp =
+                history_response(prepare_txs(txs, query, config, analytics), &budget, &format)?;
+            // the electrum status of the full history, for the clients to long-poll on
+            let wait_for_change = query_params.get("wait_for_change");
+            if wait_for_change.is_some() || query_params.get("status").map_or(false, |s| s == "1") {
+                let status = get_status_hash(query.history_txids(&script_hash[..], &budget)?)
+                    .map_or_else(String::new, hex::encode);
+                if wait_for_change == Some(&status) {
+                    mark_unchanged(&mut resp);
+                }
+                resp.headers_mut()
+                    .insert("X-Address-Status", HeaderValue::from_str(&status).unwrap());
+            }
+            Ok(resp)
         }
 
         (
@@ -727,6 +2177,14 @@ fn handle_request(
             Some(&"txs"),
             Some(&"chain"),
             last_seen_txid,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"script"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
             let last_seen_txid = last_seen_txid.and_then(|txid| Sha256dHash::from_hex(txid).ok());
@@ -737,12 +2195,13 @@ fn handle_request(
                     &script_hash[..],
                     last_seen_txid.as_ref(),
                     CHAIN_TXS_PER_PAGE,
-                )
+                    &budget,
+                )?
                 .into_iter()
                 .map(|(tx, blockid)| (tx, Some(blockid)))
                 .collect();
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            history_response(prepare_txs(txs, query, config, analytics), &budget, &format)
         }
         (
             &Method::GET,
@@ -759,6 +2218,14 @@ fn handle_request(
             Some(&"txs"),
             Some(&"mempool"),
             None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"script"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"mempool"),
+            None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
 
@@ -768,8 +2235,23 @@ fn handle_request(
                 .into_iter()
                 .map(|tx| (tx, None))
                 .collect();
+            let txs = prepare_txs(txs, query, config, analytics);
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            if !query_params
+                .get("include_evicted")
+                .map_or(false, |value| value == "1" || value == "true")
+            {
+                return data_response(txs, TTL_SHORT, &format);
+            }
+            let mut values: Vec<serde_json::Value> = txs.into_iter().map(|tx| json!(tx)).collect();
+            values.extend(
+                query
+                    .mempool()
+                    .evicted_history(&script_hash[..])
+                    .into_iter()
+                    .map(|evicted_tx| evicted_tx_json(evicted_tx, config)),
+            );
+            data_response(values, TTL_SHORT, &format)
         }
 
         (
@@ -787,37 +2269,135 @@ fn handle_request(
             Some(&"utxo"),
             None,
             None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"script"),
+            Some(script_str),
+            Some(&"utxo"),
+            None,
+            None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            let utxos: Vec<UtxoValue> = query
-                .utxo(&script_hash[..])
-                .into_iter()
-                .map(UtxoValue::from)
-                .collect();
-            // XXX paging?
-            json_response(utxos, TTL_SHORT)
-        }
-        (&Method::GET, Some(&"tx"), Some(hash), None, None, None) => {
-            let hash = Sha256dHash::from_hex(hash)?;
-            let tx = query
-                .lookup_txn(&hash)
-                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
-            let blockid = query.chain().tx_confirming_block(&hash);
-            let ttl = ttl_by_depth(blockid.as_ref().map(|b| b.height), query);
-
-            let tx = prepare_txs(vec![(tx, blockid)], query, config).remove(0);
-
-            json_response(tx, ttl)
-        }
-        (&Method::GET, Some(&"tx"), Some(hash), Some(&"hex"), None, None) => {
-            let hash = Sha256dHash::from_hex(hash)?;
-            let rawtx = query
-                .lookup_raw_txn(&hash)
-                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
-            let ttl = ttl_by_depth(query.get_tx_status(&hash).block_height, query);
-            http_message(StatusCode::OK, hex::encode(rawtx), ttl)
+            let utxos = utxo_values(query.utxo(&script_hash[..], &budget)?, query, config);
+            let (utxos, filtered_count, filtered_value) =
+                filter_utxos(utxos, script_type, script_str, &query_params)?;
+            let (utxos, next_after) = paginate_utxos(utxos, &query_params)?;
+            let mut resp = data_response(utxos, TTL_SHORT, &format)?;
+            let headers = resp.headers_mut();
+            headers.insert("X-Filtered-Count", HeaderValue::from(filtered_count));
+            headers.insert("X-Filtered-Value", HeaderValue::from(filtered_value));
+            if let Some(next_after) = next_after {
+                headers.insert("X-Next-After", HeaderValue::from_str(&next_after).unwrap());
+            }
+            Ok(resp)
         }
-        (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None, None) => {
+        #[cfg(not(feature = "liquid"))]
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"select"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"select"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"script"),
+            Some(script_str),
+            Some(&"select"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
+            let target = query_params
+                .get("target")
+                .ok_or_else(|| HttpError::from("Missing target".to_string()))?
+                .parse::<u64>()?;
+            let fee_rate = parse_feerate(
+                query_params
+                    .get("feerate")
+                    .ok_or_else(|| HttpError::from("Missing feerate".to_string()))?,
+            )?;
+            let input_vsize = utxo_input_vsize(script_type, script_str, &query_params)?;
+            // the immature coinbase outputs can't be spent yet
+            let utxos: Vec<UtxoValue> =
+                utxo_values(query.utxo(&script_hash[..], &budget)?, query, config)
+                    .into_iter()
+                    .filter(|utxo| utxo.mature)
+                    .collect();
+            let values: Vec<u64> = utxos.iter().map(|utxo| utxo.value).collect();
+            let selection = select_coins(&values, input_vsize, target, fee_rate)
+                .ok_or_else(|| HttpError::from("Insufficient funds".to_string()))?;
+            let inputs: Vec<&UtxoValue> = selection.inputs.iter().map(|i| &utxos[*i]).collect();
+            json_response(
+                json!({
+                    "inputs": inputs,
+                    "fee": selection.fee,
+                    "change": selection.change,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"cluster"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"cluster"),
+            None,
+            None,
+        ) if config.index_clusters => {
+            let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
+            json_response(
+                cluster::cluster_info(query.chain(), &script_hash),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), None, None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let tx = query
+                .lookup_txn(&hash)
+                .ok_or_else(|| HttpError::tx_not_found())?;
+            let blockid = query.chain().tx_confirming_block(&hash);
+            let ttl = ttl_by_depth(blockid.as_ref().map(|b| b.height), query);
+
+            let tx = prepare_txs(vec![(tx, blockid)], query, config, analytics).remove(0);
+
+            data_response(tx, ttl, &format)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"hex"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let rawtx = query
+                .lookup_raw_txn(&hash)
+                .ok_or_else(|| HttpError::tx_not_found())?;
+            let ttl = ttl_by_depth(query.get_tx_status(&hash).block_height, query);
+            http_message(StatusCode::OK, hex::encode(rawtx), ttl)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"raw"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let rawtx = query
+                .lookup_raw_txn(&hash)
+                .ok_or_else(|| HttpError::tx_not_found())?;
+            let ttl = ttl_by_depth(query.get_tx_status(&hash).block_height, query);
+            raw_response(rawtx, ttl, &query_params, headers)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let status = query.get_tx_status(&hash);
             let ttl = ttl_by_depth(status.block_height, query);
@@ -827,7 +2407,10 @@ fn handle_request(
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"merkle-proof"), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let blockid = query.chain().tx_confirming_block(&hash).ok_or_else(|| {
-                HttpError::not_found("Transaction not found or is unconfirmed".to_string())
+                HttpError::new(
+                    ErrorCode::TxNotFound,
+                    "Transaction not found or is unconfirmed".to_string(),
+                )
             })?;
             let (merkle, pos) = get_tx_merkle_proof(query.chain(), &hash, &blockid.hash)?;
             let merkle: Vec<String> = merkle.into_iter().map(|txid| txid.to_hex()).collect();
@@ -855,11 +2438,56 @@ fn handle_request(
             );
             json_response(spend, ttl)
         }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(relation @ &"ancestors"), None, None)
+        | (&Method::GET, Some(&"tx"), Some(hash), Some(relation @ &"descendants"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let tx = query
+                .lookup_txn(&hash)
+                .ok_or_else(|| HttpError::tx_not_found())?;
+            let txs = {
+                let mempool = query.mempool();
+                let txids = match *relation {
+                    "ancestors" => mempool.ancestors(&hash),
+                    _ => mempool.descendants(&tx),
+                };
+                txids
+                    .iter()
+                    .filter_map(|txid| mempool.lookup_txn(txid))
+                    .map(|tx| (tx, None))
+                    .collect()
+            };
+            json_response(prepare_txs(txs, query, config, false), TTL_SHORT)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"feebump"), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let target = match query_params.get("target") {
+                Some(target) => target.parse::<u16>()?,
+                None => 1,
+            };
+            if target == 0 || target > FEEBUMP_MAX_TARGET {
+                bail!(HttpError::from(format!(
+                    "The target must be between 1 and {} blocks",
+                    FEEBUMP_MAX_TARGET
+                )));
+            }
+            let child_vsize = match query_params.get("child_vsize") {
+                Some(vsize) => vsize.parse::<u32>()?,
+                None => FEEBUMP_DEFAULT_CHILD_VSIZE,
+            };
+            let bump = query.mempool().fee_bump(&hash, target, child_vsize);
+            match bump {
+                Some(bump) => json_response(bump, TTL_SHORT),
+                None if query.chain().tx_confirming_block(&hash).is_some() => Err(HttpError::from(
+                    "The transaction is already confirmed".to_string(),
+                )),
+                None => Err(HttpError::tx_not_found()),
+            }
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"outspends"), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let tx = query
                 .lookup_txn(&hash)
-                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+                .ok_or_else(|| HttpError::tx_not_found())?;
             let spends: Vec<SpendingValue> = query
                 .lookup_tx_spends(tx)
                 .into_iter()
@@ -873,6 +2501,71 @@ fn handle_request(
             // @TODO long ttl if all outputs are either spent long ago or unspendable
             json_response(spends, TTL_SHORT)
         }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"outpoint"), Some(outpoint_str), Some(&"spenders"), None, None) => {
+            let outpoint = parse_outpoint(outpoint_str)?;
+            let confirmed = query.chain().lookup_spend(&outpoint);
+            let mempool = query.mempool();
+            let unconfirmed = mempool.lookup_spend(&outpoint);
+            // the spenders that were replaced or conflicted out of the mempool recently
+            let evicted: Vec<serde_json::Value> = mempool
+                .evicted_spends(&outpoint)
+                .into_iter()
+                .map(|(vin, evicted_tx)| {
+                    json!({
+                        "txid": evicted_tx.tx.txid(),
+                        "vin": vin,
+                        "fee": evicted_tx.fee,
+                        "eviction_reason": evicted_tx.reason,
+                        "conflicting_txid": evicted_tx.conflicting_txid,
+                        "evicted_at": evicted_tx.time,
+                    })
+                })
+                .collect();
+            let spenders_count =
+                confirmed.iter().count() + unconfirmed.iter().count() + evicted.len();
+            json_response(
+                json!({
+                    "outpoint": outpoint_str,
+                    "spent": confirmed.is_some() || unconfirmed.is_some(),
+                    "double_spent": spenders_count > 1,
+                    "confirmed": confirmed.map(SpendingValue::from),
+                    "mempool": unconfirmed.map(SpendingValue::from),
+                    "evicted": evicted,
+                }),
+                0,
+            )
+        }
+        (&Method::POST, Some(&"psbt"), Some(&"decode"), None, None, None) => {
+            let psbt = base64::decode(String::from_utf8(body.to_vec())?.trim())
+                .map_err(|_| HttpError::from("Invalid base64".to_string()))?;
+            let psbt: PartiallySignedTransaction = encode::deserialize(&psbt)
+                .map_err(|e| HttpError::from(format!("Invalid PSBT: {}", e)))?;
+            json_response(PsbtValue::new(&psbt, query, config), 0)
+        }
+        (&Method::POST, Some(&"addresses"), Some(&"used"), None, None, None) => {
+            let items: Vec<String> = serde_json::from_slice(&body)?;
+            check_batch_size(items.len(), config)?;
+            let (scripthashes, errors) = batch_scripthashes(&items, &config.network_type);
+            if query_params
+                .get("partial")
+                .map_or(false, |p| p == "1" || p == "true")
+            {
+                // null in place of the invalid ones, which are listed with the reason
+                let valid: Vec<FullHash> = scripthashes.iter().filter_map(|s| *s).collect();
+                let mut used = query.scripthashes_used(&valid).into_iter();
+                let used: Vec<Option<bool>> = scripthashes
+                    .iter()
+                    .map(|scripthash| scripthash.and_then(|_| used.next()))
+                    .collect();
+                return json_response(json!({ "used": used, "errors": errors }), TTL_SHORT);
+            }
+            if !errors.is_empty() {
+                bail!(batch_error(errors));
+            }
+            let scripthashes: Vec<FullHash> = scripthashes.into_iter().flatten().collect();
+            json_response(query.scripthashes_used(&scripthashes), TTL_SHORT)
+        }
         (&Method::GET, Some(&"broadcast"), None, None, None, None)
         | (&Method::POST, Some(&"tx"), None, None, None, None) => {
             // accept both POST and GET for backward compatibility.
@@ -884,15 +2577,16 @@ fn handle_request(
                     .cloned()
                     .ok_or_else(|| HttpError::from("Missing tx".to_string()))?,
             };
-            let txid = query
-                .broadcast_raw(&txhex)
-                .map_err(|err| HttpError::from(err.description().to_string()))?;
+            let txid = query.broadcast_raw(&txhex).map_err(broadcast_error)?;
             http_message(StatusCode::OK, txid.to_hex(), 0)
         }
 
         (&Method::GET, Some(&"mempool"), None, None, None, None) => {
             json_response(query.mempool().backlog_stats(), TTL_SHORT)
         }
+        (&Method::GET, Some(&"mempool"), Some(&"projected-blocks"), None, None, None) => {
+            json_response(query.mempool().projected_blocks(), TTL_SHORT)
+        }
         (&Method::GET, Some(&"mempool"), Some(&"txids"), None, None, None) => {
             json_response(query.mempool().txids(), TTL_SHORT)
         }
@@ -902,178 +2596,2287 @@ fn handle_request(
             json_response(recent, TTL_SHORT /* TODO: TTL TBD */)
         }
 
-        (&Method::GET, Some(&"fee-estimates"), None, None, None, None) => {
-            json_response(query.estimate_fee_targets(), TTL_SHORT)
+        (&Method::GET, Some(&"internal"), Some(&"status"), None, None, None) => {
+            let store = query.chain().store();
+            let db_size: HashMap<&str, Option<u64>> = store.db_sizes().into_iter().collect();
+            json_response(
+                json!({
+                    "indexed_height": query.chain().indexed_height(),
+                    "progress": store.progress().snapshot(),
+                    "db_size": db_size,
+                }),
+                0,
+            )
         }
 
-        #[cfg(feature = "liquid")]
-        (&Method::GET, Some(&"asset"), Some(asset_str), None, None, None) => {
-            let asset_id = Sha256dHash::from_hex(asset_str)?;
-            let asset_entry = query
-                .lookup_asset(&asset_id)?
-                .ok_or_else(|| HttpError::not_found("Asset id not found".to_string()))?;
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"internal"), Some(&"audit"), None, None, None) => {
+            let blocks = match query_params.get("blocks") {
+                Some(blocks) => blocks.parse::<usize>()?.min(AUDIT_MAX_BLOCKS),
+                None => AUDIT_DEFAULT_BLOCKS,
+            };
+            let outpoints = match query_params.get("outpoints") {
+                Some(outpoints) => outpoints.parse::<usize>()?.min(AUDIT_MAX_OUTPOINTS),
+                None => AUDIT_DEFAULT_OUTPOINTS,
+            };
+            json_response(query.audit(blocks, outpoints)?, 0)
+        }
+        (&Method::POST, Some(&"internal"), Some(&"reindex"), None, None, None) => {
+            let from_height = query_params
+                .get("from_height")
+                .ok_or_else(|| HttpError::from("Missing from_height".to_string()))?
+                .parse::<usize>()?;
+            let best_height = query.chain().best_height();
+            if from_height > best_height {
+                bail!(HttpError::from(format!(
+                    "from_height is above the tip at {}",
+                    best_height
+                )));
+            }
+            if config.index_richlist {
+                bail!(HttpError::new(
+                    ErrorCode::NotImplemented,
+                    "The rich list index can't be reindexed from a height".to_string()
+                ));
+            }
+            query.chain().store().request_reindex(from_height);
+            info!("reindex from height {} requested", from_height);
+            json_response(
+                json!({ "from_height": from_height, "blocks": best_height - from_height + 1 }),
+                0,
+            )
+        }
+        (&Method::GET, Some(&"internal"), Some(&"consensus"), None, None, None) => {
+            let consensus = query.consensus().ok_or_else(|| {
+                HttpError::new(
+                    ErrorCode::NotImplemented,
+                    "No header sources are configured".to_string(),
+                )
+            })?;
+            let (height, sources) = consensus.statuses();
+            json_response(json!({ "height": height, "sources": sources }), 0)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"prices"), None, None, None, None) => {
+            let prices = price_feed(query)?;
+            json_response(prices.current_prices(), TTL_SHORT)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::POST, Some(&"internal"), Some(&"prices"), None, None, None) => {
+            let prices = price_feed(query)?;
+            let daily: Vec<DailyPrice> = serde_json::from_slice(&body)?;
+            if daily.iter().any(|p| !p.price.is_finite() || p.price <= 0.0) {
+                bail!(HttpError::from("Invalid price".to_string()));
+            }
+            let count = daily.len();
+            prices.import(daily);
+            json_response(json!({ "imported": count }), 0)
+        }
+        (&Method::GET, Some(&"internal"), Some(&"dbstats"), None, None, None)
+            if config.admin_token.is_some() =>
+        {
+            let stats: HashMap<&str, DBStats> =
+                query.chain().store().db_stats().into_iter().collect();
+            json_response(stats, 0)
+        }
 
-            json_response(asset_entry, TTL_SHORT)
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"stats"), Some(&"daily"), None, None, None) => {
+            // `from` and `to` are unix timestamps, defaulting to the last month
+            let to = match query_params.get("to") {
+                Some(to) => to.parse::<u32>()?,
+                None => time::get_time().sec as u32,
+            };
+            let from = match query_params.get("from") {
+                Some(from) => from.parse::<u32>()?,
+                None => to.saturating_sub(DAILY_STATS_DEFAULT_DAYS * 86400),
+            };
+            let (from_day, to_day) = (dailystats::day_of(from), dailystats::day_of(to));
+            if from_day > to_day || to_day - from_day >= DAILY_STATS_MAX_DAYS {
+                bail!(HttpError::from(format!(
+                    "Invalid range, up to {} days can be requested",
+                    DAILY_STATS_MAX_DAYS
+                )));
+            }
+            json_response(
+                dailystats::daily_stats(query.chain(), from_day, to_day),
+                TTL_SHORT,
+            )
         }
 
-        #[cfg(feature = "liquid")]
-        (&Method::GET, Some(&"asset"), Some(asset_str), Some(&"txs"), None, None) => {
-            let asset_id = Sha256dHash::from_hex(asset_str)?;
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"fees"), Some(&"history"), None, None, None) => {
+            // `from` and `to` are unix timestamps, defaulting to the last month
+            let to = match query_params.get("to") {
+                Some(to) => to.parse::<u32>()?,
+                None => time::get_time().sec as u32,
+            };
+            let from = match query_params.get("from") {
+                Some(from) => from.parse::<u32>()?,
+                None => to.saturating_sub(DAILY_STATS_DEFAULT_DAYS * 86400),
+            };
+            if from > to || (to - from) / 86400 >= DAILY_STATS_MAX_DAYS {
+                bail!(HttpError::from(format!(
+                    "Invalid range, up to {} days can be requested",
+                    DAILY_STATS_MAX_DAYS
+                )));
+            }
+            let interval = match query_params.get("interval") {
+                Some(interval) => Some(parse_interval(interval).ok_or_else(|| {
+                    HttpError::from(format!("Invalid interval {} (e.g. 1h, 1d or 1w)", interval))
+                })?),
+                None => None,
+            };
+            let points = fee_history(query.chain(), from, to, interval);
+            if points.len() > FEE_HISTORY_MAX_POINTS {
+                bail!(HttpError::from(format!(
+                    "Too many points, up to {} can be requested (try a larger interval)",
+                    FEE_HISTORY_MAX_POINTS
+                )));
+            }
+            json_response(points, TTL_SHORT)
+        }
 
-            let mut txs = vec![];
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"validate-address"), Some(addr), None, None, None) => {
+            json_response(validate_address(addr, &config.network_type), TTL_LONG)
+        }
 
-            txs.extend(
-                query
-                    .mempool()
-                    .asset_history(&asset_id, MAX_MEMPOOL_TXS)
-                    .into_iter()
-                    .map(|tx| (tx, None)),
-            );
+        (&Method::GET, Some(&"search"), None, None, None, None) => {
+            let q = query_params
+                .get("q")
+                .map(|q| q.trim())
+                .filter(|q| !q.is_empty())
+                .ok_or_else(|| HttpError::from("Missing query".to_string()))?;
+            let results = search(q, query, config);
+            if results.is_empty() {
+                bail!(HttpError::not_found("No matching results".to_string()));
+            }
+            json_response(results, TTL_SHORT)
+        }
 
-            txs.extend(
-                query
-                    .chain()
-                    .asset_history(&asset_id, None, CHAIN_TXS_PER_PAGE)
-                    .into_iter()
-                    .map(|(tx, blockid)| (tx, Some(blockid))),
-            );
+        (&Method::GET, Some(&"addresses"), None, None, None, None) if config.index_addresses => {
+            let prefix = query_params
+                .get("prefix")
+                .ok_or_else(|| HttpError::from("Missing prefix".to_string()))?;
+            if prefix.len() < ADDRESSES_MIN_PREFIX_LEN {
+                bail!(HttpError::from(format!(
+                    "The prefix must be at least {} characters long",
+                    ADDRESSES_MIN_PREFIX_LEN
+                )));
+            }
+            let limit = match query_params.get("limit") {
+                Some(limit) => limit.parse::<usize>()?.min(ADDRESSES_MAX_LIMIT),
+                None => ADDRESSES_DEFAULT_LIMIT,
+            };
+            let addresses = addresses::search_addresses(query.chain(), prefix, limit);
+            json_response(addresses, TTL_SHORT)
+        }
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+        // the URI's parameters may be percent-encoded within the path, or passed as the query
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"payment"), Some(bip21_uri), Some(&"status"), None, None) => {
+            let mut bip21_uri = String::from_utf8(percent_decode(bip21_uri.as_bytes()).collect())?;
+            if let (false, Some(params)) = (bip21_uri.contains('?'), uri.query()) {
+                bip21_uri = format!("{}?{}", bip21_uri, params);
+            }
+            let request = parse_bip21(&bip21_uri)?;
+            json_response(payment_status(request, query, config, &budget)?, 0)
         }
 
-        #[cfg(feature = "liquid")]
-        (
-            &Method::GET,
-            Some(&"asset"),
-            Some(asset_str),
-            Some(&"txs"),
-            Some(&"chain"),
-            last_seen_txid,
-        ) => {
-            let asset_id = Sha256dHash::from_hex(asset_str)?;
-            let last_seen_txid = last_seen_txid.and_then(|txid| Sha256dHash::from_hex(txid).ok());
+        (&Method::GET, Some(&"opreturns"), None, None, None, None) if config.index_opreturn => {
+            let prefix = hex::decode(
+                query_params
+                    .get("prefix")
+                    .ok_or_else(|| HttpError::from("Missing prefix".to_string()))?,
+            )?;
+            if prefix.is_empty() {
+                bail!(HttpError::from("Empty prefix".to_string()));
+            }
+            let from_height = match query_params.get("from_height") {
+                Some(height) => height.parse::<u32>()?,
+                None => 0,
+            };
+            let limit = match query_params.get("limit") {
+                Some(limit) => limit.parse::<usize>()?.min(OPRETURNS_MAX_LIMIT),
+                None => OPRETURNS_DEFAULT_LIMIT,
+            };
+            let entries = opreturn::search_opreturns(query.chain(), &prefix, from_height, limit);
+            data_response(entries, TTL_SHORT, &format)
+        }
 
-            let txs = query
-                .chain()
-                .asset_history(&asset_id, last_seen_txid.as_ref(), CHAIN_TXS_PER_PAGE)
-                .into_iter()
-                .map(|(tx, blockid)| (tx, Some(blockid)))
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"richlist"), None, None, None, None) if config.index_richlist => {
+            let limit = match query_params.get("limit") {
+                Some(limit) => limit.parse::<usize>()?.min(RICHLIST_MAX_LIMIT),
+                None => RICHLIST_DEFAULT_LIMIT,
+            };
+            let entries = richlist::richlist(query.chain(), &config.network_type, limit);
+            json_response(entries, TTL_SHORT)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"internal"), Some(&"utxoset"), None, None, None)
+            if config.utxo_export =>
+        {
+            let best_height = query.chain().best_height();
+            let height = match query_params.get("height") {
+                Some(height) => height.parse::<usize>()?,
+                None => best_height,
+            };
+            if height > best_height {
+                bail!(HttpError::block_not_found());
+            }
+            let format = match query_params.get("format") {
+                Some(format) => ExportFormat::from_name(format)
+                    .ok_or_else(|| HttpError::from("Invalid format".to_string()))?,
+                None => ExportFormat::Csv,
+            };
+            let scripthashes = match query_params.get("scripthashes") {
+                Some(list) => Some(
+                    list.split(',')
+                        .map(parse_scripthash)
+                        .collect::<Result<Vec<FullHash>, HttpError>>()?,
+                ),
+                None => None,
+            };
+
+            let permit = utxoset::ExportPermit::acquire().ok_or_else(|| {
+                HttpError::new(
+                    ErrorCode::ServerBusy,
+                    "Too many exports in progress. Please try again later.".to_string(),
+                )
+            })?;
+
+            // the export can take a while, stream it from a separate thread
+            let (sender, receiver) = mpsc::channel(1);
+            let query = Arc::clone(query);
+            thread::spawn(move || {
+                let _permit = permit;
+                let mut writer = BodyWriter::new(sender);
+                let scripthashes = scripthashes.as_ref().map(Vec::as_slice);
+                if let Err(e) = utxoset::export_utxo_set(
+                    query.chain(),
+                    height,
+                    scripthashes,
+                    format,
+                    &mut writer,
+                ) {
+                    warn!("utxo set export failed: {}", e);
+                }
+            });
+            let body = receiver.map_err(|_| io::Error::new(io::ErrorKind::Other, "export failed"));
+            Ok(Response::builder()
+                .header("Content-Type", format.content_type())
+                .header("Cache-Control", cache_control(0))
+                .body(Body::wrap_stream(body))
+                .unwrap())
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"export"), Some(&"history"), None, None, None)
+            if config.history_export =>
+        {
+            let limit = match query_params.get("limit") {
+                Some(limit) => limit.parse::<usize>()?.max(1).min(HISTORY_EXPORT_MAX_LIMIT),
+                None => HISTORY_EXPORT_DEFAULT_LIMIT,
+            };
+            let page = match query_params.get("cursor") {
+                Some(cursor) => query.exports().page(query.chain(), cursor, limit)?,
+                None => {
+                    let since_height = match query_params.get("since_height") {
+                        Some(height) => Some(height.parse::<u32>()?),
+                        None => None,
+                    };
+                    if !query.exports().available() {
+                        bail!(HttpError::new(
+                            ErrorCode::ServerBusy,
+                            "Too many exports in progress. Please try again later.".to_string()
+                        ));
+                    }
+                    query.exports().start(
+                        query.chain(),
+                        &config.db_path.join("exports"),
+                        since_height,
+                        limit,
+                    )?
+                }
+            };
+            json_response(page, 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"xpub"), Some(xpub), Some(&"scripttype"), None, None) => {
+            let (xpub, hinted) = parse_xpub(xpub, bitcoin::Network::from(&config.network_type))
+                .map_err(HttpError::from)?;
+            let (detected, used_counts) = detect_xpub_script_type(query, &xpub)?;
+            let used: HashMap<_, usize> = XPUB_SCRIPT_TYPES
+                .iter()
+                .map(|script_type| json!(script_type).as_str().unwrap().to_string())
+                .zip(used_counts)
+                .collect();
+            json_response(
+                json!({
+                    "detected": detected,
+                    "hinted": hinted,
+                    "probed": XPUB_PROBE_INDEXES * 2,
+                    "used": used,
+                }),
+                TTL_SHORT,
+            )
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"xpub"), Some(xpub_str), None, None, None) => {
+            let (xpub, script_type, gap_limit) =
+                xpub_query_params(query, xpub_str, &query_params, &config.network_type)?;
+            let used = scan_xpub(query, &xpub, script_type, gap_limit, &config.network_type)?;
+            let scripthashes: Vec<FullHash> = used
+                .iter()
+                .map(|(_, _, script)| compute_script_hash(script))
+                .collect();
+            let ((chain_stats, mempool_stats), stats) =
+                combined_stats(query, &scripthashes, &budget)?;
+            let addresses: Vec<serde_json::Value> = used
+                .iter()
+                .zip(stats)
+                .map(|((chain, index, script), (chain_stats, mempool_stats))| {
+                    json!({
+                        "address": script_to_address(script, &config.network_type),
+                        "chain": if *chain == 0 { "external" } else { "internal" },
+                        "index": index,
+                        "chain_stats": chain_stats,
+                        "mempool_stats": mempool_stats,
+                    })
+                })
+                .collect();
+            let mut value = json!({
+                "xpub": xpub_str,
+                "script_type": script_type,
+                "gap_limit": gap_limit,
+                "addresses": addresses,
+                "chain_stats": chain_stats,
+                "mempool_stats": mempool_stats,
+            });
+            if budget.truncated() {
+                value["history_truncated"] = json!(true);
+            }
+            data_response(value, TTL_SHORT, &format)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"xpub"), Some(xpub_str), Some(&"select"), None, None) => {
+            let (xpub, script_type, gap_limit) =
+                xpub_query_params(query, xpub_str, &query_params, &config.network_type)?;
+            let target = query_params
+                .get("target")
+                .ok_or_else(|| HttpError::from("Missing target".to_string()))?
+                .parse::<u64>()?;
+            let fee_rate = parse_feerate(
+                query_params
+                    .get("feerate")
+                    .ok_or_else(|| HttpError::from("Missing feerate".to_string()))?,
+            )?;
+            let used = scan_xpub(query, &xpub, script_type, gap_limit, &config.network_type)?;
+            // all the addresses have the same script type
+            let input_vsize = match query_params.get("input_vsize") {
+                Some(input_vsize) => input_vsize.parse::<u64>()?,
+                None => used
+                    .first()
+                    .and_then(|(_, _, script)| spend_vsize(script))
+                    .unwrap_or(DEFAULT_INPUT_VSIZE),
+            };
+            // the immature coinbase outputs can't be spent yet
+            let mut utxos: Vec<(u32, u32, UtxoValue)> = vec![];
+            for (chain, index, script) in &used {
+                let script_hash = compute_script_hash(script);
+                let values = utxo_values(query.utxo(&script_hash[..], &budget)?, query, config);
+                utxos.extend(
+                    values
+                        .into_iter()
+                        .filter(|utxo| utxo.mature)
+                        .map(|utxo| (*chain, *index, utxo)),
+                );
+            }
+            let values: Vec<u64> = utxos.iter().map(|(_, _, utxo)| utxo.value).collect();
+            let selection = select_coins(&values, input_vsize, target, fee_rate)
+                .ok_or_else(|| HttpError::from("Insufficient funds".to_string()))?;
+            // along with the derivation path of their address, for signing
+            let inputs: Vec<serde_json::Value> = selection
+                .inputs
+                .iter()
+                .map(|i| {
+                    let (chain, index, utxo) = &utxos[*i];
+                    let mut input = json!(utxo);
+                    input["chain"] = json!(if *chain == 0 { "external" } else { "internal" });
+                    input["index"] = json!(index);
+                    input
+                })
                 .collect();
+            json_response(
+                json!({
+                    "inputs": inputs,
+                    "fee": selection.fee,
+                    "change": selection.change,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(kind @ &"pubkey"), Some(hex_str), None, None, None)
+        | (&Method::GET, Some(kind @ &"redeemscript"), Some(hex_str), None, None, None) => {
+            let keyscripts = KeyScripts::parse(kind, hex_str).map_err(HttpError::from)?;
+            let scripts = keyscripts.scripts();
+            let scripthashes: Vec<FullHash> = scripts
+                .iter()
+                .map(|(_, script)| compute_script_hash(script))
+                .collect();
+            let ((chain_stats, mempool_stats), stats) =
+                combined_stats(query, &scripthashes, &budget)?;
+            let scripts: Vec<serde_json::Value> = scripts
+                .iter()
+                .zip(&scripthashes)
+                .zip(stats)
+                .map(
+                    |(((script_type, script), scripthash), (chain_stats, mempool_stats))| {
+                        json!({
+                            "type": script_type,
+                            "scriptpubkey": hex::encode(script.as_bytes()),
+                            "address": script_to_address(script, &config.network_type),
+                            "scripthash": hex::encode(scripthash),
+                            "chain_stats": chain_stats,
+                            "mempool_stats": mempool_stats,
+                        })
+                    },
+                )
+                .collect();
+            let mut value = json!({
+                *kind: keyscripts.id(),
+                "scripts": scripts,
+                "chain_stats": chain_stats,
+                "mempool_stats": mempool_stats,
+            });
+            if budget.truncated() {
+                value["history_truncated"] = json!(true);
+            }
+            data_response(value, TTL_SHORT, &format)
+        }
+        (&Method::POST, Some(&"account"), Some(name), None, None, None) => {
+            if !is_valid_account_name(name) {
+                bail!(HttpError::from("Invalid account name".to_string()));
+            }
+            let request: AccountRequest = serde_json::from_slice(&body)?;
+            #[cfg(not(feature = "liquid"))]
+            let empty = request.members.is_empty() && request.xpubs.is_empty();
+            #[cfg(feature = "liquid")]
+            let empty = request.members.is_empty();
+            if empty {
+                bail!(HttpError::from("Accounts must have members".to_string()));
+            }
+            check_batch_size(request.members.len(), config)?;
+            // the script type is detected once, when it's neither given nor implied by the version
+            #[cfg(not(feature = "liquid"))]
+            let xpubs = request
+                .xpubs
+                .iter()
+                .map(|xpub| {
+                    let (_, script_type, gap_limit) = xpub_scan_params(
+                        query,
+                        &xpub.xpub,
+                        xpub.script_type.as_ref().map(String::as_str),
+                        xpub.gap_limit,
+                        &config.network_type,
+                    )?;
+                    Ok(AccountXpub {
+                        xpub: xpub.xpub.clone(),
+                        script_type,
+                        gap_limit,
+                    })
+                })
+                .collect::<Result<Vec<AccountXpub>, HttpError>>()?;
+            let (scripthashes, errors) = batch_scripthashes(&request.members, &config.network_type);
+            if !errors.is_empty() {
+                bail!(batch_error(errors));
+            }
+            let mut seen = HashSet::new();
+            let mut members = vec![];
+            for (member, scripthash) in request
+                .members
+                .into_iter()
+                .zip(scripthashes.into_iter().flatten())
+            {
+                if seen.insert(scripthash) {
+                    members.push(AccountMember { member, scripthash });
+                }
+            }
+            let account = Account {
+                members,
+                #[cfg(not(feature = "liquid"))]
+                xpubs,
+            };
+            let value = account_json(name, &account);
+            query.accounts().put(name, account);
+            json_response(value, 0)
+        }
+        (&Method::GET, Some(&"account"), Some(name), None, None, None) => {
+            json_response(account_json(name, &get_account(query, name)?), 0)
+        }
+        (&Method::DELETE, Some(&"account"), Some(name), None, None, None) => {
+            if !query.accounts().remove(name) {
+                bail!(HttpError::not_found("Account not found".to_string()));
+            }
+            http_message(StatusCode::OK, "removed".to_string(), 0)
+        }
+        (&Method::GET, Some(&"account"), Some(name), Some(&"stats"), None, None) => {
+            let account = get_group(query, "account", name, &config.network_type)?;
+            let ((chain_stats, mempool_stats), _) =
+                combined_stats(query, &account.scripthashes(), &budget)?;
+            let mut value = json!({
+                "account": name,
+                "chain_stats": chain_stats,
+                "mempool_stats": mempool_stats,
+            });
+            if budget.truncated() {
+                value["history_truncated"] = json!(true);
+            }
+            data_response(value, TTL_SHORT, &format)
+        }
+        (&Method::GET, Some(group @ &"account"), Some(name), Some(&"txs"), None, None)
+        | (&Method::GET, Some(group @ &"pubkey"), Some(name), Some(&"txs"), None, None)
+        | (&Method::GET, Some(group @ &"redeemscript"), Some(name), Some(&"txs"), None, None) => {
+            let account = get_group(query, group, name, &config.network_type)?;
+            let txids = account_history_txids(query, &account, &budget)?;
+            // the unconfirmed transactions first, like the address history
+            let txids = txids
+                .iter()
+                .filter(|(_, blockid)| blockid.is_none())
+                .take(MAX_MEMPOOL_TXS)
+                .chain(
+                    txids
+                        .iter()
+                        .filter(|(_, blockid)| blockid.is_some())
+                        .take(CHAIN_TXS_PER_PAGE),
+                )
+                .cloned()
+                .collect();
+            history_response(
+                prepare_txs(lookup_history(query, txids), query, config, analytics),
+                &budget,
+                &format,
+            )
+        }
+        (
+            &Method::GET,
+            Some(group @ &"account"),
+            Some(name),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
+        )
+        | (
+            &Method::GET,
+            Some(group @ &"pubkey"),
+            Some(name),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
+        )
+        | (
+            &Method::GET,
+            Some(group @ &"redeemscript"),
+            Some(name),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
+        ) => {
+            let account = get_group(query, group, name, &config.network_type)?;
+            let last_seen_txid = last_seen_txid.and_then(|txid| Sha256dHash::from_hex(txid).ok());
+            let txids = account_history_txids(query, &account, &budget)?
+                .into_iter()
+                .filter(|(_, blockid)| blockid.is_some())
+                .skip_while(|(txid, _)| {
+                    last_seen_txid.map_or(false, |last_seen_txid| last_seen_txid != *txid)
+                })
+                .skip(match last_seen_txid {
+                    Some(_) => 1, // skip the last_seen_txid itself
+                    None => 0,
+                })
+                .take(CHAIN_TXS_PER_PAGE)
+                .collect();
+            history_response(
+                prepare_txs(lookup_history(query, txids), query, config, analytics),
+                &budget,
+                &format,
+            )
+        }
+        (&Method::GET, Some(group @ &"account"), Some(name), Some(&"utxo"), None, None)
+        | (&Method::GET, Some(group @ &"pubkey"), Some(name), Some(&"utxo"), None, None)
+        | (&Method::GET, Some(group @ &"redeemscript"), Some(name), Some(&"utxo"), None, None) => {
+            let account = get_group(query, group, name, &config.network_type)?;
+            let mut utxos = vec![];
+            let (mut filtered_count, mut filtered_value) = (0, 0);
+            for member in &account.members {
+                let member_utxos =
+                    utxo_values(query.utxo(&member.scripthash[..], &budget)?, query, config);
+                let script_type = match parse_scripthash(&member.member) {
+                    Ok(_) => "scripthash",
+                    Err(_) => "address",
+                };
+                let (member_utxos, count, value) =
+                    filter_utxos(member_utxos, script_type, &member.member, &query_params)?;
+                utxos.extend(member_utxos);
+                filtered_count += count;
+                filtered_value += value;
+            }
+            let (utxos, next_after) = paginate_utxos(utxos, &query_params)?;
+            let mut resp = data_response(utxos, TTL_SHORT, &format)?;
+            let headers = resp.headers_mut();
+            headers.insert("X-Filtered-Count", HeaderValue::from(filtered_count));
+            headers.insert("X-Filtered-Value", HeaderValue::from(filtered_value));
+            if let Some(next_after) = next_after {
+                headers.insert("X-Next-After", HeaderValue::from_str(&next_after).unwrap());
+            }
+            Ok(resp)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::PUT, Some(&"watch"), Some(addr), None, None, None)
+            if config.admin_token.is_some() =>
+        {
+            let scripthash = address_to_scripthash(addr, &config.network_type)?;
+            let request: WatchRequest = if body.is_empty() {
+                WatchRequest::default()
+            } else {
+                serde_json::from_slice(&body)?
+            };
+            let entry = WatchEntry {
+                address: addr.to_string(),
+                label: request.label,
+            };
+            let watchlist = watchlist(query)?;
+            watchlist.add(scripthash, entry);
+            if let Some(from_height) = request.rescan_from {
+                watchlist.add_rescan(scripthash, from_height);
+            }
+            http_message(StatusCode::OK, "watching".to_string(), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::DELETE, Some(&"watch"), Some(addr), None, None, None)
+            if config.admin_token.is_some() =>
+        {
+            let scripthash = address_to_scripthash(addr, &config.network_type)?;
+            if !watchlist(query)?.remove(&scripthash) {
+                bail!(HttpError::not_found("Address is not watched".to_string()));
+            }
+            http_message(StatusCode::OK, "removed".to_string(), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::PUT, Some(&"watch"), Some(kind @ &"pubkey"), Some(hex_str), None, None)
+        | (&Method::PUT, Some(&"watch"), Some(kind @ &"redeemscript"), Some(hex_str), None, None)
+            if config.admin_token.is_some() =>
+        {
+            let keyscripts = KeyScripts::parse(kind, hex_str).map_err(HttpError::from)?;
+            let request: WatchRequest = if body.is_empty() {
+                WatchRequest::default()
+            } else {
+                serde_json::from_slice(&body)?
+            };
+            let watchlist = watchlist(query)?;
+            for (script_type, script) in keyscripts.scripts() {
+                let scripthash = compute_script_hash(&script);
+                // the forms without an address are reported as <type>:<pubkey or script hex>
+                let address = script_to_address(&script, &config.network_type)
+                    .unwrap_or_else(|| format!("{}:{}", script_type, keyscripts.id()));
+                let entry = WatchEntry {
+                    address,
+                    label: request.label.clone(),
+                };
+                watchlist.add(scripthash, entry);
+                if let Some(from_height) = request.rescan_from {
+                    watchlist.add_rescan(scripthash, from_height);
+                }
+            }
+            http_message(StatusCode::OK, "watching".to_string(), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::DELETE, Some(&"watch"), Some(kind @ &"pubkey"), Some(hex_str), None, None)
+        | (
+            &Method::DELETE,
+            Some(&"watch"),
+            Some(kind @ &"redeemscript"),
+            Some(hex_str),
+            None,
+            None,
+        ) if config.admin_token.is_some() => {
+            let keyscripts = KeyScripts::parse(kind, hex_str).map_err(HttpError::from)?;
+            let watchlist = watchlist(query)?;
+            let removed = keyscripts
+                .scripts()
+                .iter()
+                .filter(|(_, script)| watchlist.remove(&compute_script_hash(script)))
+                .count();
+            if removed == 0 {
+                bail!(HttpError::not_found("Not watched".to_string()));
+            }
+            http_message(StatusCode::OK, "removed".to_string(), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::POST, Some(&"notify"), None, None, None, None)
+            if config.admin_token.is_some() =>
+        {
+            let request: NotifyRequest = serde_json::from_slice(&body)?;
+            let txid = Sha256dHash::from_hex(&request.txid)?;
+            let url = request
+                .url
+                .parse::<hyper::Uri>()
+                .ok()
+                .filter(|url| url.scheme_part().map(|s| s.as_str()) == Some("http"))
+                .ok_or_else(|| {
+                    HttpError::from("Invalid url (only http is supported)".to_string())
+                })?;
+            if request.confirmations == 0 {
+                bail!(HttpError::from("Invalid confirmations target".to_string()));
+            }
+            let watchlist = watchlist(query)?;
+            watchlist.add_target(
+                &txid,
+                ConfirmationTarget::new(url.to_string(), request.confirmations),
+            );
+            // the target may already be reached
+            watchlist.check_confirmations(query.chain());
+            http_message(StatusCode::OK, "registered".to_string(), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::POST, Some(&"outpoints"), Some(&"watch"), None, None, None)
+        | (&Method::DELETE, Some(&"outpoints"), Some(&"watch"), None, None, None)
+            if config.admin_token.is_some() =>
+        {
+            let request: OutpointsWatchRequest = serde_json::from_slice(&body)?;
+            if request.outpoints.is_empty() || request.outpoints.len() > OUTPOINTS_WATCH_MAX {
+                bail!(HttpError::from(format!(
+                    "Between 1 and {} outpoints are allowed",
+                    OUTPOINTS_WATCH_MAX
+                )));
+            }
+            let outpoints = request
+                .outpoints
+                .iter()
+                .map(|outpoint| parse_outpoint(outpoint))
+                .collect::<Result<Vec<OutPoint>, HttpError>>()?;
+            let watchlist = watchlist(query)?;
+            if method == Method::DELETE {
+                let removed = outpoints
+                    .iter()
+                    .filter(|outpoint| watchlist.remove_outpoint(outpoint))
+                    .count();
+                return json_response(json!({ "removed": removed }), 0);
+            }
+            let url = match request.url {
+                Some(ref url) => Some(
+                    url.parse::<hyper::Uri>()
+                        .ok()
+                        .filter(|url| url.scheme_part().map(|s| s.as_str()) == Some("http"))
+                        .ok_or_else(|| {
+                            HttpError::from("Invalid url (only http is supported)".to_string())
+                        })?
+                        .to_string(),
+                ),
+                None if config.watch_webhook_url.is_none() => bail!(HttpError::from(
+                    "A url is required without --watch-webhook-url".to_string()
+                )),
+                None => None,
+            };
+            for outpoint in &outpoints {
+                watchlist.add_outpoint(
+                    *outpoint,
+                    OutpointWatch {
+                        label: request.label.clone(),
+                        url: url.clone(),
+                    },
+                );
+            }
+            json_response(json!({ "watching": outpoints.len() }), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"outpoints"), Some(outpoint_str), Some(&"status"), None, None) => {
+            let outpoint = parse_outpoint(outpoint_str)?;
+            let tx = query
+                .lookup_txn(&outpoint.txid)
+                .ok_or_else(|| HttpError::tx_not_found())?;
+            if outpoint.vout as usize >= tx.output.len() {
+                bail!(HttpError::not_found("Output not found".to_string()));
+            }
+            let spend = query
+                .lookup_spend(&outpoint)
+                .map_or_else(SpendingValue::default, SpendingValue::from);
+            let ttl = ttl_by_depth(
+                spend.status.as_ref().and_then(|status| status.block_height),
+                query,
+            );
+            json_response(
+                json!({
+                    "outpoint": outpoint_str,
+                    "status": query.get_tx_status(&outpoint.txid),
+                    "spent": spend.spent,
+                    "spending": spend,
+                    "watched": query
+                        .watchlist()
+                        .map_or(false, |watchlist| watchlist.is_outpoint_watched(&outpoint)),
+                }),
+                ttl,
+            )
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"watchlist"), None, None, None, None)
+            if config.admin_token.is_some() =>
+        {
+            let entries: Vec<serde_json::Value> = watchlist(query)?
+                .list()
+                .into_iter()
+                .map(|(scripthash, entry)| {
+                    json!({ "scripthash": scripthash, "address": entry.address, "label": entry.label })
+                })
+                .collect();
+            json_response(entries, 0)
+        }
+
+        (&Method::POST, Some(&"rpc"), None, None, None, None) => {
+            match handle_jsonrpc(&body, access, query, config) {
+                Some(response) => json_response(response, 0),
+                None => Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()),
+            }
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"mining"), Some(&"pools"), None, None, None)
+            if config.index_miners =>
+        {
+            let window = query_params.get("window").map_or("1w", String::as_str);
+            let window = parse_window(window).ok_or_else(|| {
+                HttpError::from(format!("Invalid window {} (e.g. 1d, 1w, 1m or 1y)", window))
+            })?;
+            json_response(mining_pools(query.chain(), window), ttl_by_tip(query))
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"difficulty"), None, None, None, None) => {
+            json_response(DifficultyValue::new(query), ttl_by_tip(query))
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"halving"), None, None, None, None) => {
+            json_response(HalvingValue::new(query, config), ttl_by_tip(query))
+        }
+
+        (&Method::GET, Some(&"fee-estimates"), None, None, None, None) => {
+            json_response(query.estimate_fee_targets(), TTL_SHORT)
+        }
+
+        #[cfg(feature = "liquid")]
+        (&Method::GET, Some(&"asset"), Some(asset_str), None, None, None) => {
+            let asset_id = Sha256dHash::from_hex(asset_str)?;
+            let asset_entry = query
+                .lookup_asset(&asset_id)?
+                .ok_or_else(|| HttpError::not_found("Asset id not found".to_string()))?;
+
+            json_response(asset_entry, TTL_SHORT)
+        }
+
+        #[cfg(feature = "liquid")]
+        (&Method::GET, Some(&"asset"), Some(asset_str), Some(&"txs"), None, None) => {
+            let asset_id = Sha256dHash::from_hex(asset_str)?;
+
+            let mut txs = vec![];
+
+            txs.extend(
+                query
+                    .mempool()
+                    .asset_history(&asset_id, MAX_MEMPOOL_TXS)
+                    .into_iter()
+                    .map(|tx| (tx, None)),
+            );
+
+            txs.extend(
+                query
+                    .chain()
+                    .asset_history(&asset_id, None, CHAIN_TXS_PER_PAGE, &budget)?
+                    .into_iter()
+                    .map(|(tx, blockid)| (tx, Some(blockid))),
+            );
+
+            data_response(
+                prepare_txs(txs, query, config, analytics),
+                TTL_SHORT,
+                &format,
+            )
+        }
+
+        #[cfg(feature = "liquid")]
+        (
+            &Method::GET,
+            Some(&"asset"),
+            Some(asset_str),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
+        ) => {
+            let asset_id = Sha256dHash::from_hex(asset_str)?;
+            let last_seen_txid = last_seen_txid.and_then(|txid| Sha256dHash::from_hex(txid).ok());
+
+            let txs = query
+                .chain()
+                .asset_history(
+                    &asset_id,
+                    last_seen_txid.as_ref(),
+                    CHAIN_TXS_PER_PAGE,
+                    &budget,
+                )?
+                .into_iter()
+                .map(|(tx, blockid)| (tx, Some(blockid)))
+                .collect();
+
+            data_response(
+                prepare_txs(txs, query, config, analytics),
+                TTL_SHORT,
+                &format,
+            )
+        }
+
+        #[cfg(feature = "liquid")]
+        (&Method::GET, Some(&"asset"), Some(asset_str), Some(&"txs"), Some(&"mempool"), None) => {
+            let asset_id = Sha256dHash::from_hex(asset_str)?;
+
+            let txs = query
+                .mempool()
+                .asset_history(&asset_id, MAX_MEMPOOL_TXS)
+                .into_iter()
+                .map(|tx| (tx, None))
+                .collect();
+
+            data_response(
+                prepare_txs(txs, query, config, analytics),
+                TTL_SHORT,
+                &format,
+            )
+        }
+
+        _ => Err(HttpError::not_found(format!(
+            "endpoint does not exist {:?}",
+            uri.path()
+        ))),
+    }
+}
+
+fn http_message(
+    status: StatusCode,
+    message: String,
+    ttl: u32,
+) -> Result<Response<Body>, HttpError> {
+    let mut resp = Response::builder();
+    resp.status(status)
+        .header("Content-Type", "text/plain")
+        .header("Cache-Control", cache_control(ttl));
+    if ttl > 0 {
+        resp.header(ETAG, etag(message.as_bytes()));
+    }
+    Ok(resp.body(Body::from(message)).unwrap())
+}
+
+fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
+    let value = serde_json::to_string(&value)?;
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", cache_control(ttl))
+        .header(ETAG, etag(value.as_bytes()))
+        .body(Body::from(value))
+        .unwrap())
+}
+
+// Handles a single JSON-RPC 2.0 request or a batch of requests. Notifications (requests without
+// an id) are executed, but get no response.
+fn handle_jsonrpc(
+    body: &[u8],
+    access: &Access,
+    query: &Query,
+    config: &Config,
+) -> Option<serde_json::Value> {
+    let request: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(jsonrpc_error(
+                json!(null),
+                JSONRPC_PARSE_ERROR,
+                e.to_string(),
+            ))
+        }
+    };
+    match request {
+        serde_json::Value::Array(ref batch) if batch.is_empty() => Some(jsonrpc_error(
+            json!(null),
+            JSONRPC_INVALID_REQUEST,
+            "Empty batch".to_string(),
+        )),
+        serde_json::Value::Array(batch) => {
+            let responses: Vec<serde_json::Value> = batch
+                .into_iter()
+                .filter_map(|request| jsonrpc_call(request, access, query, config))
+                .collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(serde_json::Value::Array(responses))
+            }
+        }
+        request => jsonrpc_call(request, access, query, config),
+    }
+}
+
+fn jsonrpc_call(
+    request: serde_json::Value,
+    access: &Access,
+    query: &Query,
+    config: &Config,
+) -> Option<serde_json::Value> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(|method| method.as_str());
+    if request.get("jsonrpc") != Some(&json!("2.0")) || method.is_none() {
+        return Some(jsonrpc_error(
+            id.unwrap_or(json!(null)),
+            JSONRPC_INVALID_REQUEST,
+            "Invalid request".to_string(),
+        ));
+    }
+    let params = match request.get("params") {
+        Some(serde_json::Value::Array(params)) => params.as_slice(),
+        None => &[],
+        Some(_) => {
+            return Some(jsonrpc_error(
+                id.unwrap_or(json!(null)),
+                JSONRPC_INVALID_PARAMS,
+                "Only positional params are supported".to_string(),
+            ))
+        }
+    };
+
+    let method = method.unwrap();
+    let result = check_permission(access, jsonrpc_permission(method))
+        .and_then(|_| jsonrpc_method(method, params, query, config));
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(err) => {
+            let code = match err.code.status() {
+                StatusCode::NOT_IMPLEMENTED => JSONRPC_METHOD_NOT_FOUND,
+                StatusCode::BAD_REQUEST => JSONRPC_INVALID_PARAMS,
+                StatusCode::NOT_FOUND => JSONRPC_NOT_FOUND,
+                _ => JSONRPC_SERVER_ERROR,
+            };
+            jsonrpc_error(id, code, err.message)
+        }
+    })
+}
+
+fn jsonrpc_error(id: serde_json::Value, code: i32, message: String) -> serde_json::Value {
+    json!({ "jsonrpc": "2.0", "error": { "code": code, "message": message }, "id": id })
+}
+
+fn jsonrpc_param<'a>(params: &'a [serde_json::Value], index: usize) -> Result<&'a str, HttpError> {
+    params
+        .get(index)
+        .and_then(|param| param.as_str())
+        .ok_or_else(|| HttpError::from(format!("Missing or invalid param #{}", index)))
+}
+
+// The permission each JSON-RPC method requires, like the REST routes they mirror (POST /rpc
+// itself only requires Read)
+fn jsonrpc_permission(method: &str) -> Permission {
+    match method {
+        "sendrawtransaction" => Permission::Broadcast,
+        _ => Permission::Read,
+    }
+}
+
+// Maps the JSON-RPC methods (named after their bitcoind counterparts) onto the query layer
+fn jsonrpc_method(
+    method: &str,
+    params: &[serde_json::Value],
+    query: &Query,
+    config: &Config,
+) -> Result<serde_json::Value, HttpError> {
+    Ok(match method {
+        "getblockcount" => json!(query.chain().best_height()),
+        "getbestblockhash" => json!(query.chain().best_hash().to_hex()),
+        "getblockhash" => {
+            let height = params
+                .get(0)
+                .and_then(|param| param.as_u64())
+                .ok_or_else(|| HttpError::from("Missing or invalid param #0".to_string()))?;
+            let header = query
+                .chain()
+                .header_by_height(height as usize)
+                .ok_or_else(|| HttpError::block_not_found())?;
+            json!(header.hash().to_hex())
+        }
+        "getblock" => {
+            let hash = Sha256dHash::from_hex(jsonrpc_param(params, 0)?)?;
+            let blockhm = query
+                .chain()
+                .get_block_with_meta(&hash)
+                .ok_or_else(|| HttpError::block_not_found())?;
+            serde_json::to_value(BlockValue::from(blockhm))?
+        }
+        "getrawtransaction" => {
+            let txid = Sha256dHash::from_hex(jsonrpc_param(params, 0)?)?;
+            let verbose = params.get(1).map_or(false, |verbose| {
+                verbose.as_bool().unwrap_or(false) || verbose.as_u64().unwrap_or(0) > 0
+            });
+            if verbose {
+                let tx = query
+                    .lookup_txn(&txid)
+                    .ok_or_else(|| HttpError::tx_not_found())?;
+                let blockid = query.chain().tx_confirming_block(&txid);
+                serde_json::to_value(
+                    prepare_txs(vec![(tx, blockid)], query, config, false).remove(0),
+                )?
+            } else {
+                let rawtx = query
+                    .lookup_raw_txn(&txid)
+                    .ok_or_else(|| HttpError::tx_not_found())?;
+                json!(hex::encode(rawtx))
+            }
+        }
+        "sendrawtransaction" => {
+            let txid = query
+                .broadcast_raw(&jsonrpc_param(params, 0)?.to_string())
+                .map_err(broadcast_error)?;
+            json!(txid.to_hex())
+        }
+        "getaddressinfo" => {
+            let address = jsonrpc_param(params, 0)?;
+            let script_hash = address_to_scripthash(address, &config.network_type)?;
+            let stats = query.stats(&script_hash[..], &QueryBudget::new(config))?;
+            json!({
+                "address": address,
+                "chain_stats": stats.0,
+                "mempool_stats": stats.1,
+            })
+        }
+        #[cfg(not(feature = "liquid"))]
+        "gettxoutproof" => json!(txout_proof(params, query)?),
+        _ => {
+            return Err(HttpError::new(
+                ErrorCode::NotImplemented,
+                format!("Method not found: {}", method),
+            ))
+        }
+    })
+}
+
+// Returns a hex-encoded merkleblock proving the inclusion of the given txids, like bitcoind.
+// The params are the txids and, optionally, the hash of the block they're in.
+#[cfg(not(feature = "liquid"))]
+fn txout_proof(params: &[serde_json::Value], query: &Query) -> Result<String, HttpError> {
+    let txids = params
+        .get(0)
+        .and_then(|txids| txids.as_array())
+        .filter(|txids| !txids.is_empty())
+        .ok_or_else(|| HttpError::from("Missing or invalid param #0".to_string()))?
+        .iter()
+        .map(|txid| {
+            let txid = txid
+                .as_str()
+                .ok_or_else(|| HttpError::from("Invalid txid".to_string()))?;
+            Ok(Sha256dHash::from_hex(txid)?)
+        })
+        .collect::<Result<HashSet<Sha256dHash>, HttpError>>()?;
+
+    let blockid = match params.get(1) {
+        Some(_) => {
+            let blockhash = Sha256dHash::from_hex(jsonrpc_param(params, 1)?)?;
+            let blockid = query.chain().blockid_by_hash(&blockhash);
+            if blockid.is_none() && query.chain().get_block_meta(&blockhash).is_some() {
+                bail!(HttpError::new(
+                    ErrorCode::BlockReorged,
+                    "Block is no longer in the best chain".to_string()
+                )
+                .with_details(json!({ "block_hash": blockhash.to_hex() })));
+            }
+            blockid
+        }
+        None => query
+            .chain()
+            .tx_confirming_block(txids.iter().next().unwrap()),
+    }
+    .ok_or_else(|| HttpError::block_not_found())?;
+
+    let block_txids = query
+        .chain()
+        .get_block_txids(&blockid.hash)
+        .ok_or_else(|| HttpError::block_not_found())?;
+    let matches: Vec<bool> = block_txids
+        .iter()
+        .map(|txid| txids.contains(txid))
+        .collect();
+    if matches.iter().filter(|matched| **matched).count() != txids.len() {
+        bail!(HttpError::not_found(
+            "Not all transactions found in the block".to_string()
+        ));
+    }
+    let header = query
+        .chain()
+        .header_by_height(blockid.height)
+        .ok_or_else(|| HttpError::block_not_found())?;
+    let merkle_block = MerkleBlock {
+        header: *header.header(),
+        txn: PartialMerkleTree::from_txids(&block_txids, &matches),
+    };
+    Ok(hex::encode(encode::serialize(&merkle_block)))
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SearchResult {
+    Block { hash: Sha256dHash, height: usize },
+    Tx { txid: Sha256dHash },
+    Address { address: String },
+}
+
+// Resolve a search query to a block (by height, hash or hash prefix), a transaction (by txid, or
+// txid prefix for unconfirmed ones) or an address. Confirmed transactions can't be looked up by
+// prefix, as txids are indexed in their internal byte order (reversed from their hex form).
+fn search(q: &str, query: &Query, config: &Config) -> Vec<SearchResult> {
+    let chain = query.chain();
+    if let Ok(height) = q.parse::<usize>() {
+        return chain
+            .header_by_height(height)
+            .map(|header| SearchResult::Block {
+                hash: *header.hash(),
+                height,
+            })
+            .into_iter()
+            .collect();
+    }
+
+    if address_to_scripthash(q, &config.network_type).is_ok() {
+        return vec![SearchResult::Address {
+            address: q.to_string(),
+        }];
+    }
+
+    let q = q.to_lowercase();
+    if q.len() < SEARCH_MIN_PREFIX_LEN || !q.chars().all(|c| c.is_digit(16)) {
+        return vec![];
+    }
+    if let Ok(hash) = Sha256dHash::from_hex(&q) {
+        if let Some(height) = chain.height_by_hash(&hash) {
+            return vec![SearchResult::Block { hash, height }];
+        }
+        if query.lookup_raw_txn(&hash).is_some() {
+            return vec![SearchResult::Tx { txid: hash }];
+        }
+        return vec![];
+    }
+
+    // only the unconfirmed txids are matched by prefix, as the confirmed ones aren't indexed by it
+    let mut results: Vec<SearchResult> = chain
+        .headers_by_hash_prefix(&q, SEARCH_MAX_RESULTS)
+        .into_iter()
+        .map(|header| SearchResult::Block {
+            hash: *header.hash(),
+            height: header.height(),
+        })
+        .collect();
+    results.extend(
+        query
+            .mempool()
+            .txids()
+            .into_iter()
+            .filter(|txid| txid.to_hex().starts_with(&q))
+            .take(SEARCH_MAX_RESULTS - results.len())
+            .map(|txid| SearchResult::Tx { txid: *txid }),
+    );
+    results
+}
+
+// Only keep the requested top-level fields of the object(s) in `value`
+fn select_fields(value: serde_json::Value, fields: &HashSet<String>) -> serde_json::Value {
+    match value {
+        serde_json::Value::Array(values) => serde_json::Value::Array(
+            values
+                .into_iter()
+                .map(|value| select_fields(value, fields))
+                .collect(),
+        ),
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.into_iter()
+                .filter(|(key, _)| fields.contains(key))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn parse_fields(fields: &str) -> HashSet<String> {
+    fields
+        .split(',')
+        .map(|field| field.trim().to_string())
+        .filter(|field| !field.is_empty())
+        .collect()
+}
+
+// How to serialize the data returned by the tx, address and block endpoints
+struct DataFormat {
+    fields: Option<HashSet<String>>, // the sparse fieldset requested with `?fields=a,b,c`
+    cbor: bool, // requested with `Accept: application/cbor` or `?format=cbor`, JSON otherwise
+    #[cfg(not(feature = "liquid"))]
+    fiat: Option<FiatPrices>, // requested with `?fiat=<currency>`
+}
+
+#[cfg(not(feature = "liquid"))]
+struct FiatPrices {
+    currency: String,
+    current: f64,
+    feed: Arc<PriceFeed>,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl FiatPrices {
+    // At the current price, and at the price of the day of `time` (if confirmed and known)
+    fn amounts(&self, sats: u64, time: Option<u32>) -> serde_json::Value {
+        json!({
+            "current": fiat_amount(sats, self.current),
+            "at_confirmation": time
+                .and_then(|time| self.feed.at(&self.currency, time))
+                .map(|price| fiat_amount(sats, price)),
+        })
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+fn price_feed(query: &Query) -> Result<&Arc<PriceFeed>, HttpError> {
+    query.prices().ok_or_else(|| {
+        HttpError::new(
+            ErrorCode::NotImplemented,
+            "No price feed is configured".to_string(),
+        )
+    })
+}
+
+#[cfg(not(feature = "liquid"))]
+fn fiat_prices(currency: &str, query: &Query) -> Result<FiatPrices, HttpError> {
+    let feed = price_feed(query)?;
+    let currency = currency.to_uppercase();
+    let current = feed.current(&currency).ok_or_else(|| {
+        HttpError::from(format!(
+            "Unsupported currency {} (available: {})",
+            currency,
+            feed.currencies().join(", ")
+        ))
+    })?;
+    Ok(FiatPrices {
+        currency,
+        current,
+        feed: Arc::clone(feed),
+    })
+}
+
+#[cfg(not(feature = "liquid"))]
+fn get_u64(map: &serde_json::Map<String, serde_json::Value>, key: &str) -> Option<u64> {
+    map.get(key).and_then(serde_json::Value::as_u64)
+}
+
+// Adds the fiat amounts of the transaction outputs and UTXOs (`value_fiat`), the address balances
+// (`balance_fiat`, at the current price only) and the block totals (`total_value_fiat`)
+#[cfg(not(feature = "liquid"))]
+fn annotate_fiat(value: &mut serde_json::Value, fiat: &FiatPrices) {
+    let map = match value {
+        serde_json::Value::Array(values) => {
+            for value in values {
+                annotate_fiat(value, fiat);
+            }
+            return;
+        }
+        serde_json::Value::Object(map) => map,
+        _ => return,
+    };
+    let block_time = map
+        .get("status")
+        .and_then(|status| status.get("block_time"))
+        .and_then(serde_json::Value::as_u64)
+        .map(|time| time as u32);
+
+    if let Some(serde_json::Value::Array(vouts)) = map.get_mut("vout") {
+        for vout in vouts {
+            if let Some(vout) = vout.as_object_mut() {
+                if let Some(sats) = get_u64(vout, "value") {
+                    vout.insert("value_fiat".to_string(), fiat.amounts(sats, block_time));
+                }
+            }
+        }
+    }
+    if map.contains_key("status") {
+        if let Some(sats) = get_u64(map, "value") {
+            map.insert("value_fiat".to_string(), fiat.amounts(sats, block_time));
+        }
+    }
+    if let Some(total_value) = get_u64(map, "total_value") {
+        let timestamp = get_u64(map, "timestamp").map(|time| time as u32);
+        map.insert(
+            "total_value_fiat".to_string(),
+            fiat.amounts(total_value, timestamp),
+        );
+    }
+    let balance = ["chain_stats", "mempool_stats"]
+        .iter()
+        .map(|key| {
+            let stats = map.get(*key)?.as_object()?;
+            Some(get_u64(stats, "funded_txo_sum")? as i64 - get_u64(stats, "spent_txo_sum")? as i64)
+        })
+        .sum::<Option<i64>>();
+    if let Some(balance) = balance {
+        map.insert(
+            "balance_fiat".to_string(),
+            json!({ "current": fiat_amount(balance.max(0) as u64, fiat.current) }),
+        );
+    }
+}
+
+fn data_response<T: Serialize>(
+    value: T,
+    ttl: u32,
+    format: &DataFormat,
+) -> Result<Response<Body>, HttpError> {
+    let plain = format.fields.is_none();
+    #[cfg(not(feature = "liquid"))]
+    let plain = plain && format.fiat.is_none();
+    if plain {
+        return encoded_response(value, ttl, format.cbor);
+    }
+    let mut value = serde_json::to_value(value)?;
+    if let Some(ref fields) = format.fields {
+        value = select_fields(value, fields);
+    }
+    #[allow(unused_mut)]
+    let mut ttl = ttl;
+    #[cfg(not(feature = "liquid"))]
+    {
+        if let Some(ref fiat) = format.fiat {
+            annotate_fiat(&mut value, fiat);
+            // as the current prices change
+            ttl = ttl.min(TTL_SHORT);
+        }
+    }
+    encoded_response(value, ttl, format.cbor)
+}
+
+// Cached by the Accept header, which the encoding depends on (unless set with `?format=`)
+fn encoded_response<T: Serialize>(
+    value: T,
+    ttl: u32,
+    cbor: bool,
+) -> Result<Response<Body>, HttpError> {
+    let mut resp = if cbor {
+        cbor_response(value, ttl)?
+    } else {
+        json_response(value, ttl)?
+    };
+    resp.headers_mut()
+        .append("Vary", HeaderValue::from_static("Accept"));
+    Ok(resp)
+}
+
+// Whether an Accept header prefers CBOR over JSON, by the quality of the most specific media
+// range matching each of them (e.g. `application/cbor, */*;q=0.1`)
+fn accepts_cbor(accept: &str) -> bool {
+    accept_quality(accept, CBOR_CONTENT_TYPE) > accept_quality(accept, "application/json")
+}
+
+fn accept_quality(accept: &str, media_type: &str) -> f32 {
+    let subtypes = format!("{}/*", media_type.split('/').next().unwrap_or(""));
+    let mut best: Option<(u8, f32)> = None; // the specificity and quality of the matching range
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let range_type = params.next().unwrap_or("").to_lowercase();
+        let specificity = if range_type == media_type {
+            2
+        } else if range_type == subtypes {
+            1
+        } else if range_type == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = params
+            .filter_map(|param| {
+                let mut parts = param.splitn(2, '=').map(str::trim);
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name.eq_ignore_ascii_case("q") => {
+                        value.parse::<f32>().ok()
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        if best.map_or(true, |(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+// The consensus-encoded bytes, as hex with ?format=hex or else as binary, of which a single range
+// may be requested with a `Range: bytes=...` header
+fn raw_response(
+    raw: Vec<u8>,
+    ttl: u32,
+    query_params: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, HttpError> {
+    if query_params
+        .get("format")
+        .map_or(false, |format| format == "hex")
+    {
+        return http_message(StatusCode::OK, hex::encode(raw), ttl);
+    }
+    let mut resp = Response::builder();
+    resp.header("Content-Type", "application/octet-stream")
+        .header("Cache-Control", cache_control(ttl))
+        .header("Accept-Ranges", "bytes")
+        .header(ETAG, etag(&raw));
+    let range = headers
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map_or(ByteRange::Full, |range| byte_range(range, raw.len()));
+    Ok(match range {
+        ByteRange::Full => resp.body(Body::from(raw)).unwrap(),
+        ByteRange::Partial(first, last) => resp
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", first, last, raw.len()),
+            )
+            .body(Body::from(raw[first..=last].to_vec()))
+            .unwrap(),
+        ByteRange::Unsatisfiable => resp
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{}", raw.len()))
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(usize, usize), // the first and last bytes, inclusive
+    Unsatisfiable,
+}
+
+// Parses a `bytes=<first>-<last>`, `bytes=<first>-` or `bytes=-<suffix length>` range of a `len`
+// bytes body. Malformed and multiple ranges are ignored, to serve the full body instead.
+fn byte_range(range: &str, len: usize) -> ByteRange {
+    let range = range.trim();
+    if !range.starts_with("bytes=") || range.contains(',') {
+        return ByteRange::Full;
+    }
+    let mut bounds = range["bytes=".len()..].splitn(2, '-').map(str::trim);
+    let (first, last) = match (bounds.next(), bounds.next()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match (first.parse::<usize>(), last.parse::<usize>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return ByteRange::Full,
+    };
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last)
+}
+
+fn cbor_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
+    let value = serde_cbor::to_vec(&value)
+        .map_err(|e| HttpError::from(format!("failed to serialize to CBOR: {}", e)))?;
+    Ok(Response::builder()
+        .header("Content-Type", CBOR_CONTENT_TYPE)
+        .header("Cache-Control", cache_control(ttl))
+        .header(ETAG, etag(&value))
+        .body(Body::from(value))
+        .unwrap())
+}
+
+// The total value of the block's outputs, from its stats
+#[cfg(not(feature = "liquid"))]
+fn block_total_value(query: &Query, hash: &Sha256dHash) -> Option<u64> {
+    query
+        .chain()
+        .get_block_stats(hash)
+        .ok()?
+        .map(|stats| stats.total_out)
+}
+
+fn blocks(
+    query: &Query,
+    start_height: Option<usize>,
+    format: &DataFormat,
+) -> Result<Response<Body>, HttpError> {
+    let mut values = Vec::new();
+    let mut current_hash = match start_height {
+        Some(height) => query
+            .chain()
+            .header_by_height(height)
+            .ok_or_else(|| HttpError::block_not_found())?
+            .hash()
+            .clone(),
+        None => query.chain().best_hash(),
+    };
+
+    let zero = [0u8; 32];
+    for _ in 0..BLOCK_LIMIT {
+        let blockhm = query
+            .chain()
+            .get_block_with_meta(&current_hash)
+            .ok_or_else(|| HttpError::block_not_found())?;
+        current_hash = blockhm.header_entry.header().prev_blockhash.clone();
+        #[cfg(not(feature = "liquid"))]
+        let hash = *blockhm.header_entry.hash();
+
+        #[allow(unused_mut)]
+        let mut value = BlockValue::from(blockhm);
+
+        #[cfg(not(feature = "liquid"))]
+        {
+            if format.fiat.is_some() {
+                value.total_value = block_total_value(query, &hash);
+            }
+            value.miner = block_miner(query.chain(), &hash);
+        }
+
+        #[cfg(feature = "liquid")]
+        {
+            // exclude proof in block list view
+            value.proof = None;
+        }
+        values.push(value);
+
+        if &current_hash[..] == &zero[..] {
+            break;
+        }
+    }
+    let ttl = match start_height {
+        Some(height) => ttl_by_depth(Some(height), query),
+        None => ttl_by_tip(query),
+    };
+    data_response(values, ttl, format)
+}
+
+fn to_scripthash(
+    script_type: &str,
+    script_str: &str,
+    network: &Network,
+) -> Result<FullHash, HttpError> {
+    match script_type {
+        "address" => address_to_scripthash(script_str, network),
+        "scripthash" => parse_scripthash(script_str),
+        "script" => Ok(compute_script_hash(&Script::from(hex::decode(script_str)?))),
+        _ => bail!("Invalid script type".to_string()),
+    }
+}
+
+#[allow(unused_variables)] // `network` is unused in liquid mode
+fn address_to_scripthash(addr: &str, network: &Network) -> Result<FullHash, HttpError> {
+    let addr = address::Address::from_str(addr)?;
+
+    #[cfg(not(feature = "liquid"))]
+    let is_expected_net = network.is_address_network(addr.network);
+
+    #[cfg(feature = "liquid")]
+    let is_expected_net = addr.params == network.address_params();
+
+    if !is_expected_net {
+        bail!(HttpError::new(
+            ErrorCode::InvalidAddress,
+            "Address on invalid network".to_string()
+        ))
+    }
+
+    Ok(compute_script_hash(&addr.script_pubkey()))
+}
+
+// The version of a witness program (BIP141) script, if it is one
+#[cfg(not(feature = "liquid"))]
+fn witness_version(script: &Script) -> Option<u8> {
+    let bytes = script.as_bytes();
+    if bytes.len() < 4 || bytes.len() > 42 || bytes[1] as usize != bytes.len() - 2 {
+        return None;
+    }
+    match bytes[0] {
+        0x00 => Some(0),
+        op @ 0x51..=0x60 => Some(op - 0x50), // OP_1 to OP_16
+        _ => None,
+    }
+}
+
+// Invalid addresses are described rather than rejected, like bitcoind's validateaddress
+#[cfg(not(feature = "liquid"))]
+fn validate_address(addr: &str, network: &Network) -> serde_json::Value {
+    let parsed = match address::Address::from_str(addr) {
+        Ok(parsed) => parsed,
+        Err(e) => return json!({ "address": addr, "isvalid": false, "error": e.to_string() }),
+    };
+    // base58 addresses can't tell testnet, signet and regtest apart
+    let detected_network = match parsed.network {
+        bitcoin::Network::Bitcoin => "mainnet",
+        bitcoin::Network::Testnet => "testnet",
+        bitcoin::Network::Regtest => "regtest",
+    };
+    let script = parsed.script_pubkey();
+    let canonical = parsed.to_string();
+    json!({
+        "address": addr,
+        "isvalid": true,
+        "network": detected_network,
+        "network_match": network.is_address_network(parsed.network),
+        "canonical": canonical,
+        "is_canonical": canonical == addr,
+        "script_type": get_script_type(&script),
+        "witness_version": witness_version(&script),
+        "scriptpubkey": hex::encode(script.as_bytes()),
+        "scripthash": hex::encode(compute_script_hash(&script)),
+    })
+}
+
+// The vsize a spend of the (single-key) script adds to a transaction
+#[cfg(not(feature = "liquid"))]
+fn spend_vsize(script: &Script) -> Option<u64> {
+    if script.is_v0_p2wpkh() {
+        Some(68)
+    } else if script.is_p2pkh() {
+        Some(148)
+    } else if script.is_p2sh() {
+        Some(91) // assuming P2SH-wrapped P2WPKH
+    } else {
+        None
+    }
+}
+
+// The ?input_vsize= parameter, or the vsize of a spend of the script if it's known
+#[cfg(not(feature = "liquid"))]
+fn utxo_input_vsize(
+    script_type: &str,
+    script_str: &str,
+    query_params: &HashMap<String, String>,
+) -> Result<u64, HttpError> {
+    if let Some(input_vsize) = query_params.get("input_vsize") {
+        return Ok(input_vsize.parse::<u64>()?);
+    }
+    let script = match script_type {
+        "address" => Some(address::Address::from_str(script_str)?.script_pubkey()),
+        "script" => Some(Script::from(hex::decode(script_str)?)),
+        _ => None,
+    };
+    Ok(script
+        .and_then(|script| spend_vsize(&script))
+        .unwrap_or(DEFAULT_INPUT_VSIZE))
+}
+
+#[cfg(not(feature = "liquid"))]
+fn parse_feerate(fee_rate: &str) -> Result<f32, HttpError> {
+    fee_rate
+        .parse::<f32>()
+        .ok()
+        .filter(|fee_rate| fee_rate.is_finite() && *fee_rate >= 0.0)
+        .ok_or_else(|| HttpError::from("Invalid feerate".to_string()))
+}
+
+type UtxoSortKey = (u64, Sha256dHash, u32);
+
+fn utxo_sort_key(utxo: &UtxoValue, sort: &str) -> UtxoSortKey {
+    let key = match sort {
+        #[cfg(not(feature = "liquid"))]
+        "value" => utxo.value,
+        #[cfg(feature = "liquid")]
+        "value" => utxo.value.unwrap_or(0),
+        // unconfirmed ones last
+        "height" => utxo
+            .status
+            .block_height
+            .map_or(u64::max_value(), |h| h as u64),
+        _ => 0, // by outpoint
+    };
+    (key, utxo.txid, utxo.vout)
+}
+
+// Sort the UTXOs by ?sort=outpoint|value|height (and ?order=asc|desc), and return a page of
+// ?limit= of them starting ?after= the cursor returned with the previous page, which is then
+// returned for the next one (if any). The UTXOs are kept in index order without these.
+fn paginate_utxos(
+    mut utxos: Vec<UtxoValue>,
+    query_params: &HashMap<String, String>,
+) -> Result<(Vec<UtxoValue>, Option<String>), HttpError> {
+    let sort = query_params.get("sort").map(String::as_str);
+    let limit = match query_params.get("limit") {
+        Some(limit) => Some(limit.parse::<usize>()?),
+        None => None,
+    };
+    let after = query_params.get("after");
+    if sort.is_none() && limit.is_none() && after.is_none() {
+        return Ok((utxos, None));
+    }
+    let sort = match sort {
+        None => "outpoint",
+        Some(sort @ "outpoint") | Some(sort @ "value") | Some(sort @ "height") => sort,
+        Some(_) => bail!(HttpError::from("Invalid sort".to_string())),
+    };
+    let descending = match query_params.get("order").map(String::as_str) {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => bail!(HttpError::from("Invalid order".to_string())),
+    };
+
+    utxos.sort_by_key(|utxo| utxo_sort_key(utxo, sort));
+    if descending {
+        utxos.reverse();
+    }
+    if let Some(after) = after {
+        let after = parse_utxo_cursor(after)?;
+        utxos.retain(|utxo| {
+            let key = utxo_sort_key(utxo, sort);
+            if descending {
+                key < after
+            } else {
+                key > after
+            }
+        });
+    }
+    let next_after = match limit {
+        Some(limit) if utxos.len() > limit => {
+            utxos.truncate(limit);
+            utxos.last().map(|utxo| {
+                let (key, txid, vout) = utxo_sort_key(utxo, sort);
+                format!("{}:{}:{}", key, txid, vout)
+            })
+        }
+        _ => None,
+    };
+    Ok((utxos, next_after))
+}
+
+fn parse_utxo_cursor(cursor: &str) -> Result<UtxoSortKey, HttpError> {
+    let parts: Vec<&str> = cursor.split(':').collect();
+    if parts.len() != 3 {
+        bail!(HttpError::from("Invalid cursor".to_string()));
+    }
+    Ok((
+        parts[0].parse()?,
+        Sha256dHash::from_hex(parts[1])?,
+        parts[2].parse()?,
+    ))
+}
+
+// As `<txid>:<vout>`
+fn parse_outpoint(outpoint: &str) -> Result<OutPoint, HttpError> {
+    let parts: Vec<&str> = outpoint.split(':').collect();
+    if parts.len() != 2 {
+        bail!(HttpError::from("Invalid outpoint".to_string()));
+    }
+    Ok(OutPoint {
+        txid: Sha256dHash::from_hex(parts[0])?,
+        vout: parts[1].parse()?,
+    })
+}
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
-        }
+#[derive(Deserialize)]
+struct AccountRequest {
+    #[serde(default)]
+    members: Vec<String>, // addresses or scripthashes
+    #[cfg(not(feature = "liquid"))]
+    #[serde(default)]
+    xpubs: Vec<AccountXpubRequest>,
+}
 
-        #[cfg(feature = "liquid")]
-        (&Method::GET, Some(&"asset"), Some(asset_str), Some(&"txs"), Some(&"mempool"), None) => {
-            let asset_id = Sha256dHash::from_hex(asset_str)?;
+#[cfg(not(feature = "liquid"))]
+#[derive(Deserialize)]
+struct AccountXpubRequest {
+    xpub: String,
+    script_type: Option<String>,
+    gap_limit: Option<u32>,
+}
 
-            let txs = query
-                .mempool()
-                .asset_history(&asset_id, MAX_MEMPOOL_TXS)
-                .into_iter()
-                .map(|tx| (tx, None))
-                .collect();
+fn is_valid_account_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
-        }
+fn get_account(query: &Query, name: &str) -> Result<Account, HttpError> {
+    query
+        .accounts()
+        .get(name)
+        .ok_or_else(|| HttpError::not_found("Account not found".to_string()))
+}
 
-        _ => Err(HttpError::not_found(format!(
-            "endpoint does not exist {:?}",
-            uri.path()
-        ))),
+// The scripts of a public key or redeem script are queried like the members of an account
+fn get_group(
+    query: &Query,
+    group: &str,
+    id: &str,
+    network: &Network,
+) -> Result<Account, HttpError> {
+    if group == "account" {
+        return with_xpub_members(query, get_account(query, id)?, network);
     }
+    let members = KeyScripts::parse(group, id)
+        .map_err(HttpError::from)?
+        .scripts()
+        .iter()
+        .map(|(_, script)| {
+            let scripthash = compute_script_hash(script);
+            AccountMember {
+                member: hex::encode(scripthash),
+                scripthash,
+            }
+        })
+        .collect();
+    Ok(Account {
+        members,
+        #[cfg(not(feature = "liquid"))]
+        xpubs: vec![],
+    })
 }
 
-fn http_message(
-    status: StatusCode,
-    message: String,
-    ttl: u32,
-) -> Result<Response<Body>, HttpError> {
-    Ok(Response::builder()
-        .status(status)
-        .header("Content-Type", "text/plain")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
-        .body(Body::from(message))
-        .unwrap())
+// Adds the used addresses of the account's xpubs to its members, scanning them anew
+#[cfg(not(feature = "liquid"))]
+fn with_xpub_members(
+    query: &Query,
+    mut account: Account,
+    network: &Network,
+) -> Result<Account, HttpError> {
+    let mut scripthashes: HashSet<FullHash> = account.scripthashes().into_iter().collect();
+    for account_xpub in &account.xpubs {
+        let (xpub, _) = parse_xpub(&account_xpub.xpub, bitcoin::Network::from(network))
+            .map_err(HttpError::from)?;
+        let used = scan_xpub(
+            query,
+            &xpub,
+            account_xpub.script_type,
+            account_xpub.gap_limit,
+            network,
+        )?;
+        for (_, _, script) in used {
+            let scripthash = compute_script_hash(&script);
+            if scripthashes.insert(scripthash) {
+                account.members.push(AccountMember {
+                    member: script_to_address(&script, network)
+                        .unwrap_or_else(|| hex::encode(scripthash)),
+                    scripthash,
+                });
+            }
+        }
+    }
+    Ok(account)
 }
 
-fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
-    let value = serde_json::to_string(&value)?;
-    Ok(Response::builder()
-        .header("Content-Type", "application/json")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
-        .body(Body::from(value))
-        .unwrap())
+#[cfg(feature = "liquid")]
+fn with_xpub_members(
+    _query: &Query,
+    account: Account,
+    _network: &Network,
+) -> Result<Account, HttpError> {
+    Ok(account)
 }
 
-fn blocks(query: &Query, start_height: Option<usize>) -> Result<Response<Body>, HttpError> {
-    let mut values = Vec::new();
-    let mut current_hash = match start_height {
-        Some(height) => query
-            .chain()
-            .header_by_height(height)
-            .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?
-            .hash()
-            .clone(),
-        None => query.chain().best_hash(),
-    };
+#[cfg(not(feature = "liquid"))]
+fn account_json(name: &str, account: &Account) -> serde_json::Value {
+    let members: Vec<&str> = account.members.iter().map(|m| m.member.as_str()).collect();
+    json!({ "account": name, "members": members, "xpubs": account.xpubs })
+}
 
-    let zero = [0u8; 32];
-    for _ in 0..BLOCK_LIMIT {
-        let blockhm = query
-            .chain()
-            .get_block_with_meta(&current_hash)
-            .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
-        current_hash = blockhm.header_entry.header().prev_blockhash.clone();
+#[cfg(feature = "liquid")]
+fn account_json(name: &str, account: &Account) -> serde_json::Value {
+    let members: Vec<&str> = account.members.iter().map(|m| m.member.as_str()).collect();
+    json!({ "account": name, "members": members })
+}
 
-        #[allow(unused_mut)]
-        let mut value = BlockValue::from(blockhm);
+// The transactions of all the members, de-duplicated, with the unconfirmed ones first and then
+// the most recent ones first
+fn account_history_txids(
+    query: &Query,
+    account: &Account,
+    budget: &QueryBudget,
+) -> Result<Vec<(Sha256dHash, Option<BlockId>)>, HttpError> {
+    let mut txids: HashMap<Sha256dHash, Option<BlockId>> = HashMap::new();
+    for scripthash in account.scripthashes() {
+        txids.extend(query.history_txids(&scripthash[..], budget)?);
+    }
+    let mut txids: Vec<(Sha256dHash, Option<BlockId>)> = txids.into_iter().collect();
+    txids.sort_by_key(|(txid, blockid)| {
+        (
+            std::cmp::Reverse(blockid.as_ref().map_or(usize::max_value(), |b| b.height)),
+            *txid,
+        )
+    });
+    Ok(txids)
+}
 
-        #[cfg(feature = "liquid")]
-        {
-            // exclude proof in block list view
-            value.proof = None;
+// The stats of several scripts taken together, along with each one's
+fn combined_stats(
+    query: &Query,
+    scripthashes: &[FullHash],
+    budget: &QueryBudget,
+) -> Result<((ScriptStats, ScriptStats), Vec<(ScriptStats, ScriptStats)>), HttpError> {
+    let mut chain_stats = ScriptStats::default();
+    let mut mempool_stats = ScriptStats::default();
+    let mut chain_txids = HashSet::new();
+    let mut mempool_txids = HashSet::new();
+    let mut stats = vec![];
+    for scripthash in scripthashes {
+        let (chain, mempool) = query.stats(&scripthash[..], budget)?;
+        chain_stats.merge(&chain);
+        mempool_stats.merge(&mempool);
+        stats.push((chain, mempool));
+        // the transactions shared by several scripts count once
+        for (txid, blockid) in query.history_txids(&scripthash[..], budget)? {
+            match blockid {
+                Some(_) => chain_txids.insert(txid),
+                None => mempool_txids.insert(txid),
+            };
         }
-        values.push(value);
+    }
+    chain_stats.tx_count = chain_txids.len();
+    mempool_stats.tx_count = mempool_txids.len();
+    Ok(((chain_stats, mempool_stats), stats))
+}
 
-        if &current_hash[..] == &zero[..] {
-            break;
+// Probes the first addresses of both chains under each script type, returning the most used type
+// (preferring the earlier XPUB_SCRIPT_TYPES on ties) and the count of used addresses per type
+#[cfg(not(feature = "liquid"))]
+fn detect_xpub_script_type(
+    query: &Query,
+    xpub: &ExtendedPubKey,
+) -> Result<(Option<XpubScriptType>, Vec<usize>), HttpError> {
+    let secp = Secp256k1::verification_only();
+    let mut scripthashes = vec![];
+    for script_type in &XPUB_SCRIPT_TYPES {
+        for chain in 0..2 {
+            for index in 0..XPUB_PROBE_INDEXES {
+                let script = derive_script(&secp, xpub, *script_type, chain, index)
+                    .map_err(HttpError::from)?;
+                scripthashes.push(compute_script_hash(&script));
+            }
+        }
+    }
+    let used_counts: Vec<usize> = query
+        .scripthashes_used(&scripthashes)
+        .chunks((XPUB_PROBE_INDEXES * 2) as usize)
+        .map(|used| used.iter().filter(|used| **used).count())
+        .collect();
+    let mut detected = None;
+    let mut best_count = 0;
+    for (script_type, count) in XPUB_SCRIPT_TYPES.iter().zip(&used_counts) {
+        if *count > best_count {
+            detected = Some(*script_type);
+            best_count = *count;
         }
     }
-    json_response(values, TTL_SHORT)
+    Ok((detected, used_counts))
 }
 
-fn to_scripthash(
-    script_type: &str,
-    script_str: &str,
+// The xpub of a request, along with the script type to derive its addresses with (`?script_type=`,
+// the one implied by the key's version or else the detected one) and the `?gap_limit=` to scan with
+#[cfg(not(feature = "liquid"))]
+fn xpub_query_params(
+    query: &Query,
+    xpub_str: &str,
+    query_params: &HashMap<String, String>,
     network: &Network,
-) -> Result<FullHash, HttpError> {
-    match script_type {
-        "address" => address_to_scripthash(script_str, network),
-        "scripthash" => parse_scripthash(script_str),
-        _ => bail!("Invalid script type".to_string()),
+) -> Result<(ExtendedPubKey, XpubScriptType, u32), HttpError> {
+    let gap_limit = match query_params.get("gap_limit") {
+        Some(gap_limit) => Some(gap_limit.parse::<u32>()?),
+        None => None,
+    };
+    xpub_scan_params(
+        query,
+        xpub_str,
+        query_params.get("script_type").map(String::as_str),
+        gap_limit,
+        network,
+    )
+}
+
+#[cfg(not(feature = "liquid"))]
+fn xpub_scan_params(
+    query: &Query,
+    xpub_str: &str,
+    script_type: Option<&str>,
+    gap_limit: Option<u32>,
+    network: &Network,
+) -> Result<(ExtendedPubKey, XpubScriptType, u32), HttpError> {
+    let (xpub, hinted) =
+        parse_xpub(xpub_str, bitcoin::Network::from(network)).map_err(HttpError::from)?;
+    let script_type = match (script_type, hinted) {
+        (Some(script_type), _) => script_type.parse().map_err(HttpError::from)?,
+        (None, Some(hinted)) => hinted,
+        // plain xpubs are mostly BIP44 ones
+        (None, None) => detect_xpub_script_type(query, &xpub)?
+            .0
+            .unwrap_or(XpubScriptType::P2pkh),
+    };
+    let gap_limit = gap_limit.unwrap_or(XPUB_GAP_LIMIT);
+    if gap_limit == 0 || gap_limit > XPUB_MAX_GAP_LIMIT {
+        bail!(HttpError::from(format!(
+            "gap_limit must be between 1 and {}",
+            XPUB_MAX_GAP_LIMIT
+        )));
     }
+    Ok((xpub, script_type, gap_limit))
 }
 
-#[allow(unused_variables)] // `network` is unused in liquid mode
-fn address_to_scripthash(addr: &str, network: &Network) -> Result<FullHash, HttpError> {
-    let addr = address::Address::from_str(addr)?;
+// Derives the addresses of the external and internal (change) chains until each has `gap_limit`
+// unused ones in a row, returning the used ones as (chain, index, script). Both chains are checked
+// together, a batch at a time. Keys of another network are rejected before deriving anything.
+#[cfg(not(feature = "liquid"))]
+fn scan_xpub(
+    query: &Query,
+    xpub: &ExtendedPubKey,
+    script_type: XpubScriptType,
+    gap_limit: u32,
+    network: &Network,
+) -> Result<Vec<(u32, u32, Script)>, HttpError> {
+    check_network(xpub, bitcoin::Network::from(network)).map_err(HttpError::from)?;
+    let secp = Secp256k1::verification_only();
+    // the next index to derive and the count of unused addresses in a row, per chain
+    let mut chains = [(0u32, 0u32); 2];
+    let mut used = vec![];
+    loop {
+        let mut batch = vec![];
+        for (chain, (next, unused)) in chains.iter_mut().enumerate() {
+            let end = (*next + gap_limit - *unused).min(XPUB_MAX_ADDRESSES);
+            for index in *next..end {
+                let script = derive_script(&secp, xpub, script_type, chain as u32, index)
+                    .map_err(HttpError::from)?;
+                batch.push((chain, index, script));
+            }
+            *next = end;
+        }
+        if batch.is_empty() {
+            return Ok(used);
+        }
+        let scripthashes: Vec<FullHash> = batch
+            .iter()
+            .map(|(_, _, script)| compute_script_hash(script))
+            .collect();
+        let batch_used = query.scripthashes_used(&scripthashes);
+        for ((chain, index, script), is_used) in batch.into_iter().zip(batch_used) {
+            if is_used {
+                chains[chain].1 = 0;
+                used.push((chain as u32, index, script));
+            } else {
+                chains[chain].1 += 1;
+            }
+        }
+    }
+}
+
+// Without the prevouts, which may have been evicted along with it
+fn evicted_tx_json(evicted_tx: &EvictedTx, config: &Config) -> serde_json::Value {
+    let mut value = json!(TransactionValue::new(
+        evicted_tx.tx.clone(),
+        None,
+        &HashMap::new(),
+        0, // unconfirmed anyway
+        config
+    ));
+    value["fee"] = json!(evicted_tx.fee);
+    value["status"] = json!({
+        "confirmed": false,
+        "evicted": true,
+        "eviction_reason": evicted_tx.reason,
+        "conflicting_txid": evicted_tx.conflicting_txid,
+        "evicted_at": evicted_tx.time,
+    });
+    value
+}
+
+fn lookup_history(
+    query: &Query,
+    txids: Vec<(Sha256dHash, Option<BlockId>)>,
+) -> Vec<(Transaction, Option<BlockId>)> {
+    txids
+        .into_iter()
+        .filter_map(|(txid, blockid)| query.lookup_txn(&txid).map(|tx| (tx, blockid)))
+        .collect()
+}
+
+// The address history, flagged with an X-History-Truncated header if it only covers part of it
+fn history_response(
+    txs: Vec<TransactionValue>,
+    budget: &QueryBudget,
+    format: &DataFormat,
+) -> Result<Response<Body>, HttpError> {
+    let mut resp = data_response(txs, TTL_SHORT, format)?;
+    if budget.truncated() {
+        resp.headers_mut()
+            .insert("X-History-Truncated", HeaderValue::from_static("1"));
+    }
+    Ok(resp)
+}
+
+// Apply the ?confirmed_only=1, ?min_value= and ?exclude_dust_at_feerate= filters (the latter
+// excluding the UTXOs worth less than the fee to spend them), returning the kept UTXOs along with
+// the count and total value of the filtered out ones
+fn filter_utxos(
+    utxos: Vec<UtxoValue>,
+    script_type: &str,
+    script_str: &str,
+    query_params: &HashMap<String, String>,
+) -> Result<(Vec<UtxoValue>, usize, u64), HttpError> {
+    let confirmed_only = query_params
+        .get("confirmed_only")
+        .map_or(false, |value| value == "1" || value == "true");
 
     #[cfg(not(feature = "liquid"))]
-    let is_expected_net = {
-        let addr_network = Network::from(&addr.network);
-        (addr_network == *network
-            || (addr_network == Network::Testnet && *network == Network::Regtest))
+    let min_value = {
+        let mut min_value = match query_params.get("min_value") {
+            Some(min_value) => min_value.parse::<u64>()?,
+            None => 0,
+        };
+        if let Some(fee_rate) = query_params.get("exclude_dust_at_feerate") {
+            let input_vsize = utxo_input_vsize(script_type, script_str, query_params)?;
+            min_value = min_value.max(fee_for(parse_feerate(fee_rate)?, input_vsize) + 1);
+        }
+        min_value
     };
-
     #[cfg(feature = "liquid")]
-    let is_expected_net = addr.params == network.address_params();
+    let _ = (script_type, script_str);
 
-    if !is_expected_net {
-        bail!(HttpError::from("Address on invalid network".to_string()))
-    }
+    let (kept, filtered): (Vec<UtxoValue>, Vec<UtxoValue>) = utxos.into_iter().partition(|utxo| {
+        #[cfg(not(feature = "liquid"))]
+        {
+            if utxo.value < min_value {
+                return false;
+            }
+        }
+        !confirmed_only || utxo.status.confirmed
+    });
 
-    Ok(compute_script_hash(&addr.script_pubkey()))
+    #[cfg(not(feature = "liquid"))]
+    let filtered_value = filtered.iter().map(|utxo| utxo.value).sum();
+    #[cfg(feature = "liquid")]
+    let filtered_value = filtered.iter().filter_map(|utxo| utxo.value).sum();
+    Ok((kept, filtered.len(), filtered_value))
 }
 
 fn parse_scripthash(scripthash: &str) -> Result<FullHash, HttpError> {
@@ -1085,21 +4888,285 @@ fn parse_scripthash(scripthash: &str) -> Result<FullHash, HttpError> {
     }
 }
 
+// The items of the batch endpoints are addresses or (Electrum-style) scripthashes, either prefixed
+// with `scripthash:` or as bare hex, which can't be mistaken for an address
+fn item_scripthash(item: &str, network: &Network) -> Result<FullHash, HttpError> {
+    if item.starts_with("scripthash:") {
+        return parse_scripthash(&item["scripthash:".len()..]);
+    }
+    parse_scripthash(item).or_else(|_| address_to_scripthash(item, network))
+}
+
+// Resolves all the items of a batch, collecting the invalid ones as errors that say which failed
+// and why, instead of stopping at the first
+fn batch_scripthashes(
+    items: &[String],
+    network: &Network,
+) -> (Vec<Option<FullHash>>, Vec<serde_json::Value>) {
+    let mut errors = vec![];
+    let scripthashes = items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| match item_scripthash(item, network) {
+            Ok(scripthash) => Some(scripthash),
+            Err(e) => {
+                errors.push(json!({ "index": index, "address": item, "reason": e.message }));
+                None
+            }
+        })
+        .collect();
+    (scripthashes, errors)
+}
+
+fn check_batch_size(size: usize, config: &Config) -> Result<(), HttpError> {
+    if size > config.http_max_batch_size {
+        bail!(HttpError::new(
+            ErrorCode::BatchTooLarge,
+            format!("Too many addresses (max {})", config.http_max_batch_size),
+        )
+        .with_details(json!({ "max_batch_size": config.http_max_batch_size })));
+    }
+    Ok(())
+}
+
+fn batch_error(errors: Vec<serde_json::Value>) -> HttpError {
+    HttpError::new(
+        ErrorCode::InvalidAddress,
+        format!("{} invalid addresses", errors.len()),
+    )
+    .with_details(json!({ "errors": errors }))
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Deserialize, Default)]
+struct WatchRequest {
+    #[serde(default)]
+    label: String,
+    // index the history of the script again from this height (for --index-watched-only)
+    rescan_from: Option<u32>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Deserialize)]
+struct NotifyRequest {
+    txid: String,
+    confirmations: u32,
+    url: String,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Deserialize)]
+struct OutpointsWatchRequest {
+    outpoints: Vec<String>, // txid:vout
+    #[serde(default)]
+    label: String,
+    // the webhook to notify when they get spent, instead of --watch-webhook-url
+    url: Option<String>,
+}
+
+#[cfg(not(feature = "liquid"))]
+fn watchlist(query: &Query) -> Result<&WatchList, HttpError> {
+    query.watchlist().ok_or_else(HttpError::generic)
+}
+
+// Identifies the client by its `Authorization: Bearer <token>` header, which is either the admin
+// token or an API key, and counts the request against its rate limit
+fn authenticate(headers: &HeaderMap, config: &Config) -> Result<Access, HttpError> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .map(|value| value.as_bytes())
+        .filter(|value| value.starts_with(b"Bearer "))
+        .map(|value| &value[7..]);
+    if let (Some(token), Some(admin_token)) = (token, config.admin_token.as_ref()) {
+        if fixed_time_eq(token, admin_token.as_bytes()) {
+            return Ok(Access::admin());
+        }
+    }
+    let access = match (token, config.api_keys.as_ref()) {
+        (Some(token), Some(api_keys)) => api_keys.authenticate(token).ok_or_else(|| {
+            HttpError::new(ErrorCode::Unauthorized, "Invalid API key".to_string())
+        })?,
+        (None, Some(api_keys)) => api_keys.anonymous(),
+        (_, None) => Access::anonymous(ANONYMOUS_PERMISSIONS),
+    };
+    if !access.acquire() {
+        bail!(HttpError::new(
+            ErrorCode::RateLimited,
+            "Rate limit exceeded. Please try again later.".to_string()
+        ));
+    }
+    Ok(access)
+}
+
+#[derive(Debug, PartialEq)]
+enum Priority {
+    Interactive,
+    Heavy, // scans of many scripts or of the whole index
+}
+
+fn request_priority(method: &Method, uri: &hyper::Uri) -> Priority {
+    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
+    let forced = uri.query().map_or(false, |query| {
+        form_urlencoded::parse(query.as_bytes()).any(|(key, value)| key == "force" && value == "1")
+    });
+    match (method, path.get(0).cloned(), path.get(1).cloned()) {
+        _ if forced => Priority::Heavy,
+        (_, Some("xpub"), _)
+        | (_, Some("account"), _)
+        | (_, Some("pubkey"), _)
+        | (_, Some("redeemscript"), _)
+        | (_, Some("richlist"), _)
+        | (_, Some("search"), _)
+        | (_, Some("stats"), _)
+        | (_, Some("opreturns"), _)
+        | (_, Some("fees"), Some("history"))
+        | (_, Some("mining"), Some("pools"))
+        | (_, Some("export"), Some("history"))
+        | (&Method::POST, Some("addresses"), Some("used"))
+        | (_, Some("internal"), Some("audit"))
+        | (_, Some("internal"), Some("dbstats"))
+        | (_, Some("internal"), Some("utxoset")) => Priority::Heavy,
+        _ => Priority::Interactive,
+    }
+}
+
+fn required_permission(method: &Method, path: &[&str], force: bool) -> Permission {
+    match (method, path.get(0).cloned(), path.get(1).cloned()) {
+        (&Method::POST, Some("tx"), None) | (&Method::GET, Some("broadcast"), None) => {
+            Permission::Broadcast
+        }
+        (_, Some("watch"), _) | (_, Some("watchlist"), _) | (&Method::POST, Some("notify"), _) => {
+            Permission::Watch
+        }
+        (_, Some("outpoints"), Some("watch")) => Permission::Watch,
+        (&Method::POST, Some("account"), _) | (&Method::DELETE, Some("account"), _) => {
+            Permission::Watch
+        }
+        (_, Some("internal"), Some("dbstats"))
+        | (_, Some("internal"), Some("audit"))
+        | (_, Some("internal"), Some("reindex"))
+        | (_, Some("internal"), Some("utxoset"))
+        | (_, Some("internal"), Some("prices"))
+        | (_, Some("internal"), Some("consensus"))
+        | (_, Some("export"), Some("history")) => Permission::Admin,
+        _ if force => Permission::Admin,
+        _ => Permission::Read,
+    }
+}
+
+fn check_permission(access: &Access, permission: Permission) -> Result<(), HttpError> {
+    if !access.allows(permission) {
+        bail!(HttpError::new(
+            ErrorCode::Unauthorized,
+            format!("Missing the {:?} permission", permission).to_lowercase()
+        ));
+    }
+    Ok(())
+}
+
+// Returned as the "code" of the JSON error responses, for clients to tell the errors apart
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    InvalidRequest,
+    InvalidAddress,
+    NotFound,
+    TxNotFound,
+    BlockNotFound,
+    BlockReorged,
+    TxRejected,
+    Unauthorized,
+    NotImplemented,
+    RateLimited,
+    QueryTimeout,
+    QueryTooLarge,
+    BatchTooLarge,
+    ServerBusy,
+    IndexingInProgress,
+    DaemonUnavailable,
+    StorageError,
+    InternalError,
+}
+
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidAddress | ErrorCode::TxRejected => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::NotFound
+            | ErrorCode::TxNotFound
+            | ErrorCode::BlockNotFound
+            | ErrorCode::BlockReorged => StatusCode::NOT_FOUND,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::QueryTooLarge | ErrorCode::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::IndexingInProgress
+            | ErrorCode::DaemonUnavailable
+            | ErrorCode::QueryTimeout
+            | ErrorCode::ServerBusy => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::StorageError | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 #[derive(Debug)]
-struct HttpError(StatusCode, String);
+struct HttpError {
+    code: ErrorCode,
+    message: String,
+    details: Option<serde_json::Value>,
+}
 
 impl HttpError {
+    fn new(code: ErrorCode, message: String) -> Self {
+        HttpError {
+            code,
+            message,
+            details: None,
+        }
+    }
+    fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
     fn not_found(msg: String) -> Self {
-        HttpError(StatusCode::NOT_FOUND, msg)
+        HttpError::new(ErrorCode::NotFound, msg)
+    }
+    fn tx_not_found() -> Self {
+        HttpError::new(ErrorCode::TxNotFound, "Transaction not found".to_string())
+    }
+    fn block_not_found() -> Self {
+        HttpError::new(ErrorCode::BlockNotFound, "Block not found".to_string())
     }
     fn generic() -> Self {
-        HttpError::from("We encountered an error. Please try again later.".to_string())
+        HttpError::new(
+            ErrorCode::InternalError,
+            "We encountered an error. Please try again later.".to_string(),
+        )
+    }
+
+    fn to_response(&self) -> Response<Body> {
+        let body = json!({ "code": self.code, "message": self.message, "details": self.details });
+        Response::builder()
+            .status(self.code.status())
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+// Rejections by bitcoind are the client's fault, unlike failing to reach it
+fn broadcast_error(err: errors::Error) -> HttpError {
+    match err.kind() {
+        errors::ErrorKind::DaemonUnreachable(_) => HttpError::from(err),
+        _ => HttpError::new(ErrorCode::TxRejected, err.to_string()),
     }
 }
 
 impl From<String> for HttpError {
     fn from(msg: String) -> Self {
-        HttpError(StatusCode::BAD_REQUEST, msg)
+        HttpError::new(ErrorCode::InvalidRequest, msg)
     }
 }
 impl From<ParseIntError> for HttpError {
@@ -1129,16 +5196,51 @@ impl From<bitcoin::hashes::hex::Error> for HttpError {
 impl From<bitcoin::util::address::Error> for HttpError {
     fn from(_e: bitcoin::util::address::Error) -> Self {
         //HttpError::from(e.description().to_string())
-        HttpError::from("Invalid Bitcoin address".to_string())
+        HttpError::new(
+            ErrorCode::InvalidAddress,
+            "Invalid Bitcoin address".to_string(),
+        )
     }
 }
 impl From<errors::Error> for HttpError {
     fn from(e: errors::Error) -> Self {
-        warn!("errors::Error: {:?}", e);
-        match e.description().to_string().as_ref() {
-            "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}" => {
-                HttpError::not_found("Block not found".to_string())
+        match e.kind() {
+            // the client's own errors aren't worth a warning
+            errors::ErrorKind::NotFound(msg) => {
+                debug!("errors::Error: {:?}", e);
+                return HttpError::not_found(msg.clone());
+            }
+            errors::ErrorKind::InvalidInput(msg) => {
+                debug!("errors::Error: {:?}", e);
+                return HttpError::from(msg.clone());
             }
+            _ => warn!("errors::Error: {:?}", e),
+        }
+        match e.kind() {
+            errors::ErrorKind::DaemonUnreachable(_) => HttpError::new(
+                ErrorCode::DaemonUnavailable,
+                "The daemon is unavailable. Please try again later.".to_string(),
+            ),
+            // the scanned rows hint at how far a paginated request would get
+            errors::ErrorKind::QueryTimeout(scanned_rows) => HttpError::new(
+                ErrorCode::QueryTimeout,
+                "The query timed out. Try paginating with a smaller range.".to_string(),
+            )
+            .with_details(json!({ "scanned_rows": scanned_rows })),
+            errors::ErrorKind::QueryTooLarge(scanned_rows) => HttpError::new(
+                ErrorCode::QueryTooLarge,
+                "The query scans too many rows. Try paginating with a smaller range.".to_string(),
+            )
+            .with_details(json!({ "scanned_rows": scanned_rows })),
+            errors::ErrorKind::ReorgDetected(blockhash) => HttpError::new(
+                ErrorCode::BlockReorged,
+                "Block is no longer in the best chain".to_string(),
+            )
+            .with_details(json!({ "block_hash": blockhash })),
+            errors::ErrorKind::StorageError(_) => HttpError::new(
+                ErrorCode::StorageError,
+                "The storage failed. Please try again later.".to_string(),
+            ),
             _ => HttpError::generic(),
         }
     }
@@ -1163,16 +5265,49 @@ impl From<std::string::FromUtf8Error> for HttpError {
 #[cfg(feature = "liquid")]
 impl From<address::AddressError> for HttpError {
     fn from(e: address::AddressError) -> Self {
-        HttpError::from(e.to_string())
+        HttpError::new(ErrorCode::InvalidAddress, e.to_string())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::rest::HttpError;
+    use crate::apikeys::Permission;
+    use crate::rest::{
+        accepts_cbor, accepts_gzip, allowed_origin, byte_range, http_message, is_not_modified,
+        jsonrpc_permission, parse_fields, required_permission, select_fields, varies_by_origin,
+        ByteRange, HttpError, TTL_LONG,
+    };
+    use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, ORIGIN};
+    use hyper::{Method, StatusCode};
     use serde_json::Value;
     use std::collections::HashMap;
 
+    #[test]
+    fn test_required_permission() {
+        let permission = |method: &Method, path: &str| {
+            let path: Vec<&str> = path.split('/').collect();
+            required_permission(method, &path, false)
+        };
+        assert_eq!(permission(&Method::GET, "tx"), Permission::Read);
+        assert_eq!(permission(&Method::POST, "tx"), Permission::Broadcast);
+        assert_eq!(permission(&Method::POST, "watch"), Permission::Watch);
+        assert_eq!(
+            permission(&Method::GET, "internal/utxoset"),
+            Permission::Admin
+        );
+        assert_eq!(
+            permission(&Method::GET, "export/history"),
+            Permission::Admin
+        );
+        // the broadcasts through the JSON-RPC bridge are checked per method
+        assert_eq!(permission(&Method::POST, "rpc"), Permission::Read);
+        assert_eq!(
+            jsonrpc_permission("sendrawtransaction"),
+            Permission::Broadcast
+        );
+        assert_eq!(jsonrpc_permission("getblockcount"), Permission::Read);
+    }
+
     #[test]
     fn test_parse_query_param() {
         let mut query_params = HashMap::new();
@@ -1213,6 +5348,25 @@ mod tests {
         assert_eq!(10, limit);
     }
 
+    #[test]
+    fn test_select_fields() {
+        let fields = parse_fields("txid, fee,,status");
+        assert_eq!(fields.len(), 3);
+
+        let txs = json!([
+            { "txid": "aa", "fee": 1, "vin": [], "status": { "confirmed": true } },
+            { "txid": "bb", "vout": [] },
+        ]);
+        assert_eq!(
+            select_fields(txs, &fields),
+            json!([
+                { "txid": "aa", "fee": 1, "status": { "confirmed": true } },
+                { "txid": "bb" },
+            ])
+        );
+        assert_eq!(select_fields(json!("aa"), &fields), json!("aa"));
+    }
+
     #[test]
     fn test_parse_value_param() {
         let v: Value = json!({ "confirmations": 10 });
@@ -1234,4 +5388,163 @@ mod tests {
 
         assert!(err.is_err());
     }
+
+    #[test]
+    fn test_cors_and_encoding_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_static("https://example.com"));
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("br, gzip;q=0.8"));
+
+        let cors = vec!["https://example.com".to_string()];
+        assert_eq!(
+            allowed_origin(&headers, &cors),
+            Some(HeaderValue::from_static("https://example.com"))
+        );
+        assert_eq!(
+            allowed_origin(&headers, &["https://other.com".to_string()]),
+            None
+        );
+        assert_eq!(
+            allowed_origin(&HeaderMap::new(), &["*".to_string()]),
+            Some(HeaderValue::from_static("*"))
+        );
+        assert_eq!(allowed_origin(&headers, &[]), None);
+        assert!(varies_by_origin(&cors));
+        assert!(!varies_by_origin(&["*".to_string()]));
+        assert!(!varies_by_origin(&[]));
+        assert!(accepts_gzip(&headers));
+
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip;q=0, deflate"),
+        );
+        assert!(!accepts_gzip(&headers));
+    }
+
+    #[test]
+    fn test_conditional_request() {
+        let resp = http_message(StatusCode::OK, "hello".to_string(), TTL_LONG).unwrap();
+        assert!(resp.headers()[CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .ends_with("immutable"));
+
+        let etag = resp.headers()["ETag"].clone();
+        assert!(is_not_modified(&resp, &etag));
+        assert!(is_not_modified(
+            &resp,
+            &HeaderValue::from_static("\"abc\", *")
+        ));
+        assert!(!is_not_modified(
+            &resp,
+            &HeaderValue::from_static("W/\"abc\"")
+        ));
+
+        let other = http_message(StatusCode::OK, "world".to_string(), TTL_LONG).unwrap();
+        assert!(!is_not_modified(&other, &etag));
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(byte_range("bytes=900-", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(byte_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(
+            byte_range("bytes=500-5000", 1000),
+            ByteRange::Partial(500, 999)
+        );
+        assert_eq!(byte_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=0-1,5-6", 1000), ByteRange::Full);
+        assert_eq!(byte_range("bytes=10-5", 1000), ByteRange::Full);
+        assert_eq!(byte_range("items=0-1", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_accepts_cbor() {
+        assert!(accepts_cbor("application/cbor"));
+        assert!(accepts_cbor("application/cbor, */*;q=0.1"));
+        assert!(accepts_cbor("application/json;q=0.5, application/cbor"));
+        assert!(!accepts_cbor("application/json, application/cbor;q=0.9"));
+        assert!(!accepts_cbor("application/cbor;q=0, */*"));
+        assert!(!accepts_cbor("application/*"));
+        assert!(!accepts_cbor("text/html"));
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_difficulty_and_subsidy() {
+        use crate::rest::{block_subsidy, difficulty_from_bits};
+
+        assert_eq!(difficulty_from_bits(0x1d00ffff), 1.0);
+        assert!((difficulty_from_bits(0x1b0404cb) - 16307.420938523983).abs() < 1e-6);
+        assert_eq!(block_subsidy(0), 5_000_000_000);
+        assert_eq!(block_subsidy(4), 312_500_000);
+        assert_eq!(block_subsidy(64), 0);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_validate_address() {
+        use crate::chain::Network;
+        use crate::rest::validate_address;
+
+        let value = validate_address(
+            "BC1QW508D6QEJXTDG4C5R3ZARVARY0C5XW7KV8F3T4",
+            &Network::Bitcoin,
+        );
+        assert_eq!(value["isvalid"], true);
+        assert_eq!(value["network"], "mainnet");
+        assert_eq!(value["network_match"], true);
+        assert_eq!(
+            value["canonical"],
+            "bc1qw508d6qejxtdg4c5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert_eq!(value["is_canonical"], false);
+        assert_eq!(value["script_type"], "v0_p2wpkh");
+        assert_eq!(value["witness_version"], 0);
+        assert_eq!(
+            value["scriptpubkey"],
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+
+        let value = validate_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", &Network::Testnet);
+        assert_eq!(value["script_type"], "p2pkh");
+        assert_eq!(value["network_match"], false);
+        assert!(value["witness_version"].is_null());
+
+        let value = validate_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN3", &Network::Bitcoin);
+        assert_eq!(value["isvalid"], false);
+        assert!(value["error"].is_string());
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        use crate::errors::{Error, ErrorKind};
+
+        let status = |kind: ErrorKind| HttpError::from(Error::from(kind)).code.status();
+        assert_eq!(
+            status(ErrorKind::NotFound("Block not found".to_string())),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(ErrorKind::InvalidInput("Invalid height".to_string())),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(ErrorKind::DaemonUnreachable("down".to_string())),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(ErrorKind::ReorgDetected("00".to_string())),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(ErrorKind::StorageError("full".to_string())),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            status(ErrorKind::Msg("other".to_string())),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
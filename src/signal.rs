@@ -1,5 +1,7 @@
 use chan;
 use chan_signal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::errors::*;
@@ -7,12 +9,18 @@ use crate::errors::*;
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     signal: chan::Receiver<chan_signal::Signal>,
+    reload: Arc<AtomicBool>, // set on SIGHUP, shared by all clones
 }
 
 impl Waiter {
     pub fn new() -> Waiter {
         Waiter {
-            signal: chan_signal::notify(&[chan_signal::Signal::INT, chan_signal::Signal::TERM]),
+            signal: chan_signal::notify(&[
+                chan_signal::Signal::INT,
+                chan_signal::Signal::TERM,
+                chan_signal::Signal::HUP,
+            ]),
+            reload: Arc::new(AtomicBool::new(false)),
         }
     }
     pub fn wait(&self, duration: Duration) -> Result<()> {
@@ -20,8 +28,11 @@ impl Waiter {
         let timeout = chan::after(duration);
         chan_select! {
             signal.recv() -> s => {
-                if let Some(sig) = s {
-                    bail!(ErrorKind::Interrupt(sig));
+                match s {
+                    // SIGHUP only wakes up the waiter, the reload is handled by take_reload()
+                    Some(chan_signal::Signal::HUP) => self.reload.store(true, Ordering::SeqCst),
+                    Some(sig) => bail!(ErrorKind::Interrupt(sig)),
+                    None => (),
                 }
             },
             timeout.recv() => {},
@@ -31,4 +42,8 @@ impl Waiter {
     pub fn poll(&self) -> Result<()> {
         self.wait(Duration::from_secs(0))
     }
+    // Returns true if a SIGHUP was received since the last call
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
 }
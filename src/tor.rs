@@ -0,0 +1,157 @@
+// Publishes the REST API as a Tor onion service through the control port of a running Tor, so
+// that it can be reached without opening clearnet ports. The service's key is kept in the db
+// directory so that the onion address stays the same across restarts, and the service is
+// removed by Tor when the control connection is closed (on shutdown).
+
+use hex;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, Secret};
+use crate::errors::*;
+
+pub struct OnionService {
+    pub hostname: String,
+    _control: TcpStream, // the service lives as long as the connection
+}
+
+struct TorControl {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl TorControl {
+    // Sends the command and returns the (unprefixed) lines of its successful reply
+    fn command(&mut self, command: &str) -> Result<Vec<String>> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .chain_err(|| "failed to write to the Tor control port")?;
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            self.reader
+                .read_line(&mut line)
+                .chain_err(|| "failed to read from the Tor control port")?;
+            let line = line.trim_end();
+            if line.len() < 4 {
+                bail!("unexpected Tor control reply: {:?}", line);
+            }
+            let (status, separator, text) = (&line[..3], &line[3..4], &line[4..]);
+            if status != "250" {
+                bail!("Tor control error: {}", line);
+            }
+            lines.push(text.to_string());
+            if separator == " " {
+                return Ok(lines);
+            }
+        }
+    }
+
+    fn authenticate(&mut self, password: Option<&str>) -> Result<()> {
+        let info = self.command("PROTOCOLINFO 1")?;
+        let auth = info
+            .iter()
+            .find(|line| line.starts_with("AUTH "))
+            .chain_err(|| "missing Tor auth methods")?;
+        let command = match password {
+            Some(password) => format!(
+                "AUTHENTICATE \"{}\"",
+                password.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            None if auth_methods(auth).contains(&"NULL") => "AUTHENTICATE".to_string(),
+            None if auth_methods(auth).contains(&"COOKIE") => {
+                let cookie_file = auth
+                    .split("COOKIEFILE=")
+                    .nth(1)
+                    .map(|quoted| quoted.trim_matches('"'))
+                    .chain_err(|| "missing Tor cookie file")?;
+                let cookie = fs::read(cookie_file)
+                    .chain_err(|| format!("failed to read the Tor cookie {}", cookie_file))?;
+                format!("AUTHENTICATE {}", hex::encode(cookie))
+            }
+            None => bail!("unsupported Tor auth methods ({}), set a password", auth),
+        };
+        self.command(&command)?;
+        Ok(())
+    }
+}
+
+// "AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=..." => ["COOKIE", "SAFECOOKIE"]
+fn auth_methods(auth: &str) -> Vec<&str> {
+    auth.split(' ')
+        .find(|part| part.starts_with("METHODS="))
+        .map_or(vec![], |methods| methods[8..].split(',').collect())
+}
+
+// Where Tor forwards the connections to: the REST server's socket file or address
+fn onion_target(config: &Config) -> String {
+    match config.http_socket_file {
+        Some(ref path) => format!("unix:{}", path.display()),
+        None if config.http_addr.ip().is_unspecified() => {
+            format!("127.0.0.1:{}", config.http_addr.port())
+        }
+        None => config.http_addr.to_string(),
+    }
+}
+
+fn key_path(config: &Config) -> PathBuf {
+    config.db_path.join("onion_key")
+}
+
+pub fn publish_onion_service(config: &Config) -> Result<OnionService> {
+    let addr = config
+        .tor_control_addr
+        .expect("missing Tor control address");
+    let stream = TcpStream::connect(addr)
+        .chain_err(|| format!("failed to connect to the Tor control port at {}", addr))?;
+    let mut control = TorControl {
+        reader: BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?),
+        writer: stream,
+    };
+    control.authenticate(config.tor_control_password.as_ref().map(Secret::as_str))?;
+
+    let key_path = key_path(config);
+    let key = match fs::read_to_string(&key_path) {
+        Ok(key) => key.trim().to_string(),
+        Err(_) => "NEW:ED25519-V3".to_string(),
+    };
+    let reply = control.command(&format!(
+        "ADD_ONION {} Port={},{}",
+        key,
+        config.tor_onion_port,
+        onion_target(config)
+    ))?;
+    for line in &reply {
+        if line.starts_with("PrivateKey=") {
+            save_key(&key_path, &line["PrivateKey=".len()..])?;
+        }
+    }
+    let service_id = reply
+        .iter()
+        .find(|line| line.starts_with("ServiceID="))
+        .map(|line| &line["ServiceID=".len()..])
+        .chain_err(|| "missing onion service id")?;
+    Ok(OnionService {
+        hostname: format!("{}.onion", service_id),
+        _control: control.writer,
+    })
+}
+
+// Readable by the owner only, as it's the service's identity
+fn save_key(path: &Path, key: &str) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .chain_err(|| format!("failed to create {:?}", path))?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+        .chain_err(|| format!("failed to set the permissions of {:?}", path))?;
+    file.write_all(key.as_bytes())
+        .chain_err(|| format!("failed to write {:?}", path))
+}
@@ -0,0 +1,322 @@
+// Collects the `tracing` spans of the REST requests, the history scans and the bitcoind calls,
+// with their timings and fields (such as the scanned rows). The finished spans are logged at the
+// trace level, and exported to an OpenTelemetry collector if --otlp-addr is set: they're batched
+// from a background thread and posted as OTLP/HTTP JSON to http://<otlp-addr>/v1/traces, and
+// dropped rather than queued without bounds if the collector can't keep up. The trace id of the
+// innermost span entered by a thread is also logged along with its log lines, as a request id.
+
+use serde_json::Value;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry, SpanRef};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+const EXPORT_QUEUE_SIZE: usize = 8192; // spans
+const EXPORT_BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    // the trace ids of the spans entered by this thread, innermost last
+    static ENTERED_TRACES: RefCell<Vec<[u8; 16]>> = RefCell::new(vec![]);
+}
+
+pub fn current_trace_id() -> Option<String> {
+    ENTERED_TRACES.with(|traces| traces.borrow().last().map(hex::encode))
+}
+
+enum AttributeValue {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttributeValue::Int(value) => write!(f, "{}", value),
+            AttributeValue::Bool(value) => write!(f, "{}", value),
+            AttributeValue::Str(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+struct SpanData {
+    name: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    started: Instant,
+    attributes: Vec<(&'static str, AttributeValue)>,
+}
+
+impl SpanData {
+    fn set(&mut self, key: &'static str, value: AttributeValue) {
+        match self.attributes.iter_mut().find(|(k, _)| *k == key) {
+            Some(attribute) => attribute.1 = value,
+            None => self.attributes.push((key, value)),
+        }
+    }
+}
+
+impl Visit for SpanData {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field.name(), AttributeValue::Int(value as i64));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), AttributeValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), AttributeValue::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), AttributeValue::Str(format!("{:?}", value)));
+    }
+}
+
+struct TraceLayer {
+    ids: Mutex<u64>, // xorshift state
+    exporter: Option<Mutex<SyncSender<(SpanData, Duration)>>>,
+}
+
+impl TraceLayer {
+    fn next_id(&self) -> u64 {
+        let mut state = self.ids.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn new_trace_id(&self) -> [u8; 16] {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&self.next_id().to_be_bytes());
+        trace_id[8..].copy_from_slice(&self.next_id().to_be_bytes());
+        trace_id
+    }
+}
+
+// The trace and span ids of the span's parent, if any
+fn parent_ids<S>(span: &SpanRef<S>) -> Option<([u8; 16], [u8; 8])>
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    let parent = span.parent()?;
+    let extensions = parent.extensions();
+    let data = extensions.get::<SpanData>()?;
+    Some((data.trace_id, data.span_id))
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("unknown span");
+        let (trace_id, parent_span_id) = match parent_ids(&span) {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (self.new_trace_id(), None),
+        };
+        let mut data = SpanData {
+            name: attrs.metadata().name(),
+            trace_id,
+            span_id: self.next_id().to_be_bytes(),
+            parent_span_id,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            attributes: vec![],
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions().get::<SpanData>() {
+                ENTERED_TRACES.with(|traces| traces.borrow_mut().push(data.trace_id));
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if span.extensions().get::<SpanData>().is_some() {
+                ENTERED_TRACES.with(|traces| traces.borrow_mut().pop());
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let data = match span.extensions_mut().remove::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+        let duration = data.started.elapsed();
+        trace!(
+            "{} took {:?} [{}]",
+            data.name,
+            duration,
+            data.attributes
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        if let Some(ref exporter) = self.exporter {
+            // dropped if the queue is full
+            let _ = exporter.lock().unwrap().try_send((data, duration));
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs() as u128 * 1_000_000_000 + since_epoch.subsec_nanos() as u128).to_string()
+}
+
+fn otlp_json(spans: &[(SpanData, Duration)]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|(data, duration)| {
+            let attributes: Vec<Value> = data
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+                        AttributeValue::Bool(value) => json!({ "boolValue": value }),
+                        AttributeValue::Str(value) => json!({ "stringValue": value }),
+                    };
+                    json!({ "key": key, "value": value })
+                })
+                .collect();
+            json!({
+                "traceId": hex::encode(data.trace_id),
+                "spanId": hex::encode(data.span_id),
+                "parentSpanId": data.parent_span_id.map_or_else(String::new, hex::encode),
+                "name": data.name,
+                "kind": 1, // internal
+                "startTimeUnixNano": unix_nanos(data.start),
+                "endTimeUnixNano": unix_nanos(data.start + *duration),
+                "attributes": attributes,
+            })
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "electrs" } }],
+            },
+            "scopeSpans": [{ "scope": { "name": "electrs" }, "spans": spans }],
+        }]
+    })
+}
+
+fn post_spans(addr: SocketAddr, spans: &[(SpanData, Duration)]) -> Result<()> {
+    let body = otlp_json(spans).to_string();
+    let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream
+        .set_read_timeout(Some(EXPORT_TIMEOUT))
+        .chain_err(|| "failed to set the read timeout")?;
+    stream
+        .set_write_timeout(Some(EXPORT_TIMEOUT))
+        .chain_err(|| "failed to set the write timeout")?;
+    write!(
+        stream,
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        body.len(),
+        body
+    )
+    .chain_err(|| "failed to send the spans")?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .chain_err(|| "failed to read the response")?;
+    let status = response.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.1 2") && !status.starts_with("HTTP/1.0 2") {
+        bail!("unexpected response: {:?}", status);
+    }
+    Ok(())
+}
+
+fn export_spans(addr: SocketAddr, receiver: Receiver<(SpanData, Duration)>) {
+    let mut batch = vec![];
+    let mut last_export = Instant::now();
+    loop {
+        match receiver.recv_timeout(EXPORT_INTERVAL) {
+            Ok(span) => batch.push(span),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if batch.len() >= EXPORT_BATCH_SIZE
+            || (!batch.is_empty() && last_export.elapsed() >= EXPORT_INTERVAL)
+        {
+            if let Err(e) = post_spans(addr, &batch) {
+                warn!(
+                    "failed to export {} spans to {}: {}",
+                    batch.len(),
+                    addr,
+                    e.display_chain()
+                );
+            }
+            batch.clear();
+            last_export = Instant::now();
+        }
+    }
+}
+
+pub fn init(config: &Config) -> Result<()> {
+    let exporter = config.otlp_addr.map(|addr| {
+        let (sender, receiver) = sync_channel(EXPORT_QUEUE_SIZE);
+        spawn_thread("otlp", move || export_spans(addr, receiver));
+        info!("exporting traces to {}", addr);
+        Mutex::new(sender)
+    });
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64
+        ^ (u64::from(process::id()) << 32);
+    let layer = TraceLayer {
+        ids: Mutex::new(seed | 1), // xorshift never leaves 0
+        exporter,
+    };
+    tracing::subscriber::set_global_default(Registry::default().with(layer))
+        .chain_err(|| "failed to set the tracing subscriber")
+}
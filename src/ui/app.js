@@ -0,0 +1,283 @@
+// A minimal explorer over the REST API, routed by the URL hash: #/, #/block/<hash>, #/tx/<txid>
+// and #/address/<address>. The API is served from the parent path of /ui.
+(function () {
+  'use strict';
+
+  var API = location.pathname.replace(/\/ui\/?[^/]*$/, '');
+  var TXS_PER_PAGE = 25;
+  var main = document.getElementById('main');
+
+  function esc(value) {
+    return String(value).replace(/[&<>"']/g, function (c) {
+      return { '&': '&amp;', '<': '&lt;', '>': '&gt;', '"': '&quot;', "'": '&#39;' }[c];
+    });
+  }
+
+  function api(path) {
+    return fetch(API + path).then(function (resp) {
+      if (!resp.ok) {
+        return resp.text().then(function (text) {
+          var message = text;
+          try {
+            message = JSON.parse(text).message;
+          } catch (e) {}
+          throw new Error(message || resp.statusText);
+        });
+      }
+      var type = resp.headers.get('Content-Type') || '';
+      return type.indexOf('application/json') === 0 ? resp.json() : resp.text();
+    });
+  }
+
+  function btc(sats) {
+    // the values are blinded with Liquid
+    if (typeof sats !== 'number' || isNaN(sats)) {
+      return '<span class="muted">confidential</span>';
+    }
+    return (sats / 1e8).toFixed(8) + ' BTC';
+  }
+
+  function time(timestamp) {
+    return new Date(timestamp * 1000).toISOString().replace('T', ' ').replace(/\.\d+Z$/, ' UTC');
+  }
+
+  function link(kind, id, text) {
+    return '<a href="#/' + kind + '/' + encodeURIComponent(id) + '">' + esc(text || id) + '</a>';
+  }
+
+  function details(rows) {
+    return '<table class="details">' + rows.map(function (row) {
+      return '<tr><th>' + esc(row[0]) + '</th><td>' + row[1] + '</td></tr>';
+    }).join('') + '</table>';
+  }
+
+  function status(status) {
+    if (!status.confirmed) {
+      return 'Unconfirmed';
+    }
+    return 'Confirmed in block ' + link('block', status.block_hash, status.block_height) +
+      ' (' + esc(time(status.block_time)) + ')';
+  }
+
+  function show(html) {
+    main.innerHTML = html;
+  }
+
+  function showError(err) {
+    show('<div class="error">' + esc(err.message) + '</div>');
+  }
+
+  function blocksTable(blocks) {
+    return '<table><tr><th>Height</th><th>Hash</th><th>Time</th><th class="num">Transactions</th>' +
+      '<th class="num">Size (kB)</th></tr>' + blocks.map(function (block) {
+        return '<tr><td>' + link('block', block.id, block.height) + '</td>' +
+          '<td class="hash">' + link('block', block.id) + '</td>' +
+          '<td>' + esc(time(block.timestamp)) + '</td>' +
+          '<td class="num">' + block.tx_count + '</td>' +
+          '<td class="num">' + (block.size / 1000).toFixed(1) + '</td></tr>';
+      }).join('') + '</table>';
+  }
+
+  function homePage(startHeight) {
+    var path = startHeight === undefined ? '/blocks' : '/blocks/' + startHeight;
+    return api(path).then(function (blocks) {
+      var html = '<h1>Latest blocks</h1>' + blocksTable(blocks);
+      var last = blocks[blocks.length - 1];
+      if (last && last.height > 0) {
+        html += '<button id="older" data-height="' + (last.height - 1) + '">Older blocks</button>';
+      }
+      show(html);
+      var older = document.getElementById('older');
+      if (older) {
+        older.onclick = function () {
+          homePage(Number(older.getAttribute('data-height'))).catch(showError);
+        };
+      }
+    });
+  }
+
+  function txSummary(tx) {
+    var inputs = tx.vin.map(function (vin) {
+      if (vin.is_coinbase) {
+        return '<div class="muted">Coinbase</div>';
+      }
+      var prevout = vin.prevout || {};
+      return '<div>' + (prevout.scriptpubkey_address
+        ? link('address', prevout.scriptpubkey_address)
+        : link('tx', vin.txid, vin.txid + ':' + vin.vout)) +
+        ' <span class="muted">' + btc(prevout.value) + '</span></div>';
+    }).join('');
+    var outputs = tx.vout.map(function (vout) {
+      var target = vout.scriptpubkey_address
+        ? link('address', vout.scriptpubkey_address)
+        : '<span class="muted">' + esc(vout.scriptpubkey_type) + '</span>';
+      return '<div>' + target + ' <span class="muted">' + btc(vout.value) + '</span></div>';
+    }).join('');
+    return '<div class="tx"><div class="mono">' + link('tx', tx.txid) + '</div>' +
+      '<div class="io"><div>' + inputs + '</div><div>' + outputs + '</div></div></div>';
+  }
+
+  function blockPage(hash) {
+    return Promise.all([api('/block/' + hash), api('/block/' + hash + '/status')]).then(function (res) {
+      var block = res[0];
+      var blockStatus = res[1];
+      var rows = [
+        ['Hash', '<span class="mono">' + esc(block.id) + '</span>'],
+        ['Height', esc(block.height)],
+        ['Status', blockStatus.in_best_chain ? 'In the best chain' : 'Orphaned'],
+        ['Time', esc(time(block.timestamp))],
+        ['Transactions', esc(block.tx_count)],
+        ['Size', esc(block.size) + ' bytes'],
+        ['Weight', esc(block.weight) + ' WU'],
+        ['Merkle root', '<span class="mono">' + esc(block.merkle_root) + '</span>'],
+      ];
+      if (block.previousblockhash) {
+        rows.push(['Previous block', '<span class="mono">' + link('block', block.previousblockhash) + '</span>']);
+      }
+      if (blockStatus.next_best) {
+        rows.push(['Next block', '<span class="mono">' + link('block', blockStatus.next_best) + '</span>']);
+      }
+      if (block.miner) {
+        rows.push(['Miner', esc(block.miner)]);
+      }
+      show('<h1>Block ' + esc(block.height) + '</h1>' + details(rows) +
+        '<h2>Transactions</h2><div id="txs"></div>');
+      return blockTxs(hash, 0, block.tx_count);
+    });
+  }
+
+  function blockTxs(hash, start, count) {
+    return api('/block/' + hash + '/txs/' + start).then(function (txs) {
+      var container = document.getElementById('txs');
+      container.insertAdjacentHTML('beforeend', txs.map(txSummary).join(''));
+      var next = start + TXS_PER_PAGE;
+      if (next < count) {
+        container.insertAdjacentHTML('beforeend', '<button id="more">More transactions</button>');
+        document.getElementById('more').onclick = function () {
+          this.parentNode.removeChild(this);
+          blockTxs(hash, next, count).catch(showError);
+        };
+      }
+    });
+  }
+
+  function txPage(txid) {
+    return Promise.all([api('/tx/' + txid), api('/tx/' + txid + '/outspends')]).then(function (res) {
+      var tx = res[0];
+      var outspends = res[1];
+      var rows = [
+        ['Txid', '<span class="mono">' + esc(tx.txid) + '</span>'],
+        ['Status', status(tx.status)],
+        ['Size', esc(tx.size) + ' bytes'],
+        ['Weight', esc(tx.weight) + ' WU'],
+        ['Version', esc(tx.version)],
+        ['Locktime', esc(tx.locktime)],
+      ];
+      if (typeof tx.fee === 'number') {
+        rows.push(['Fee', btc(tx.fee) + ' (' + (tx.fee / (tx.weight / 4)).toFixed(1) + ' sat/vB)']);
+      }
+      var inputs = tx.vin.map(function (vin, index) {
+        var prevout = vin.prevout || {};
+        var source = vin.is_coinbase
+          ? 'Coinbase'
+          : link('tx', vin.txid, vin.txid + ':' + vin.vout);
+        return '<tr><td>' + index + '</td><td class="hash">' + source + '</td><td class="hash">' +
+          (prevout.scriptpubkey_address ? link('address', prevout.scriptpubkey_address) : '') +
+          '</td><td class="num">' + (vin.is_coinbase ? '' : btc(prevout.value)) + '</td></tr>';
+      }).join('');
+      var outputs = tx.vout.map(function (vout, index) {
+        var spend = outspends[index] || {};
+        var spent = spend.spent
+          ? 'Spent by ' + link('tx', spend.txid, spend.txid.substr(0, 16) + '…')
+          : '<span class="muted">Unspent</span>';
+        return '<tr><td>' + index + '</td><td class="hash">' +
+          (vout.scriptpubkey_address
+            ? link('address', vout.scriptpubkey_address)
+            : '<span class="muted">' + esc(vout.scriptpubkey_type) + '</span>') +
+          '</td><td>' + spent + '</td><td class="num">' + btc(vout.value) + '</td></tr>';
+      }).join('');
+      show('<h1>Transaction</h1>' + details(rows) +
+        '<h2>Inputs</h2><table><tr><th>#</th><th>Previous output</th><th>Address</th>' +
+        '<th class="num">Value</th></tr>' + inputs + '</table>' +
+        '<h2>Outputs</h2><table><tr><th>#</th><th>Address</th><th>Spending</th>' +
+        '<th class="num">Value</th></tr>' + outputs + '</table>');
+    });
+  }
+
+  function addressPage(address) {
+    return api('/address/' + address).then(function (info) {
+      var chain = info.chain_stats;
+      var mempool = info.mempool_stats;
+      var rows = [
+        ['Address', '<span class="mono">' + esc(info.address) + '</span>'],
+        ['Confirmed balance', btc(chain.funded_txo_sum - chain.spent_txo_sum)],
+        ['Unconfirmed balance', btc(mempool.funded_txo_sum - mempool.spent_txo_sum)],
+        ['Transactions', esc(chain.tx_count + mempool.tx_count)],
+        ['Total received', btc(chain.funded_txo_sum + mempool.funded_txo_sum)],
+      ];
+      show('<h1>Address</h1>' + details(rows) + '<h2>Transactions</h2><div id="txs"></div>');
+      return addressTxs(address, '/address/' + address + '/txs');
+    });
+  }
+
+  function addressTxs(address, path) {
+    return api(path).then(function (txs) {
+      var container = document.getElementById('txs');
+      container.insertAdjacentHTML('beforeend', txs.map(txSummary).join(''));
+      var confirmed = txs.filter(function (tx) {
+        return tx.status.confirmed;
+      });
+      if (confirmed.length >= TXS_PER_PAGE) {
+        var last = confirmed[confirmed.length - 1].txid;
+        container.insertAdjacentHTML('beforeend', '<button id="more">More transactions</button>');
+        document.getElementById('more').onclick = function () {
+          this.parentNode.removeChild(this);
+          addressTxs(address, '/address/' + address + '/txs/chain/' + last).catch(showError);
+        };
+      }
+    });
+  }
+
+  function route() {
+    var parts = location.hash.replace(/^#\/?/, '').split('/').map(decodeURIComponent);
+    var page;
+    switch (parts[0]) {
+      case 'block':
+        page = blockPage(parts[1]);
+        break;
+      case 'tx':
+        page = txPage(parts[1]);
+        break;
+      case 'address':
+        page = addressPage(parts[1]);
+        break;
+      default:
+        page = homePage();
+    }
+    show('<div class="muted">Loading…</div>');
+    page.catch(showError);
+  }
+
+  document.getElementById('search').onsubmit = function (event) {
+    event.preventDefault();
+    var input = document.getElementById('search-input');
+    var q = input.value.trim();
+    if (!q) {
+      return;
+    }
+    api('/search?q=' + encodeURIComponent(q)).then(function (results) {
+      var result = results[0];
+      input.value = '';
+      if (result.type === 'block') {
+        location.hash = '#/block/' + result.hash;
+      } else if (result.type === 'tx') {
+        location.hash = '#/tx/' + result.txid;
+      } else {
+        location.hash = '#/address/' + encodeURIComponent(result.address);
+      }
+    }).catch(showError);
+  };
+
+  window.addEventListener('hashchange', route);
+  route();
+})();
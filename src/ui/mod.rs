@@ -0,0 +1,22 @@
+// A minimal block explorer (blocks, transactions and addresses), served at /ui by the REST server
+// when built with the `ui` feature. Its assets are embedded in the binary, and it only uses the
+// JSON endpoints, relative to where /ui is served so that it keeps working behind a reverse proxy
+// under a path prefix.
+
+pub struct Asset {
+    pub content: &'static [u8],
+    pub content_type: &'static str,
+}
+
+pub fn asset(name: &str) -> Option<Asset> {
+    let (content, content_type): (&'static [u8], _) = match name {
+        "" | "index.html" => (include_bytes!("index.html"), "text/html; charset=utf-8"),
+        "app.js" => (include_bytes!("app.js"), "application/javascript"),
+        "style.css" => (include_bytes!("style.css"), "text/css"),
+        _ => return None,
+    };
+    Some(Asset {
+        content,
+        content_type,
+    })
+}
@@ -0,0 +1,114 @@
+// Parses the BIP21 payment URIs, of the form:
+//
+//   bitcoin:<address>[?amount=<BTC>][&label=<label>][&message=<message>]
+//
+// where the parameters are percent-encoded. The URIs with a `req-` parameter that isn't
+// understood are invalid, as the BIP requires.
+
+use url::form_urlencoded;
+
+#[derive(Debug, PartialEq)]
+pub struct PaymentRequest {
+    pub address: String,
+    pub amount: Option<u64>, // in satoshis
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+pub fn parse_bip21(uri: &str) -> Result<PaymentRequest, String> {
+    let colon = uri
+        .find(':')
+        .ok_or_else(|| "Missing URI scheme".to_string())?;
+    if !uri[..colon].eq_ignore_ascii_case("bitcoin") {
+        return Err(format!("Invalid URI scheme: {}", &uri[..colon]));
+    }
+    let rest = &uri[colon + 1..];
+    let (address, params) = match rest.find('?') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => (rest, ""),
+    };
+    if address.is_empty() {
+        return Err("Missing address".to_string());
+    }
+    let mut request = PaymentRequest {
+        address: address.to_string(),
+        amount: None,
+        label: None,
+        message: None,
+    };
+    for (key, value) in form_urlencoded::parse(params.as_bytes()) {
+        match key.as_ref() {
+            "amount" => request.amount = Some(parse_btc_amount(&value)?),
+            "label" => request.label = Some(value.into_owned()),
+            "message" => request.message = Some(value.into_owned()),
+            key if key.starts_with("req-") => {
+                return Err(format!("Unsupported required parameter: {}", key))
+            }
+            _ => (),
+        }
+    }
+    Ok(request)
+}
+
+// "0.001" => 100000, with up to 8 decimals
+pub fn parse_btc_amount(amount: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid amount: {}", amount);
+    let (whole, fraction) = match amount.find('.') {
+        Some(pos) => (&amount[..pos], &amount[pos + 1..]),
+        None => (amount, ""),
+    };
+    if (whole.is_empty() && fraction.is_empty())
+        || fraction.len() > 8
+        || !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+    let whole: u64 = match whole {
+        "" => 0,
+        whole => whole.parse().map_err(|_| invalid())?,
+    };
+    let fraction: u64 = format!("{:0<8}", fraction).parse().map_err(|_| invalid())?;
+    whole
+        .checked_mul(100_000_000)
+        .and_then(|sats| sats.checked_add(fraction))
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bip21, parse_btc_amount};
+
+    #[test]
+    fn test_parse_bip21() {
+        let request = parse_bip21(
+            "bitcoin:175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W?amount=20.3&label=Luke%20Jr&foo=bar",
+        )
+        .unwrap();
+        assert_eq!(request.address, "175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W");
+        assert_eq!(request.amount, Some(2_030_000_000));
+        assert_eq!(request.label.as_ref().map(String::as_str), Some("Luke Jr"));
+        assert_eq!(request.message, None);
+
+        let request = parse_bip21("BITCOIN:175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W").unwrap();
+        assert_eq!(request.amount, None);
+
+        assert!(parse_bip21("175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W").is_err());
+        assert!(parse_bip21("litecoin:175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W").is_err());
+        assert!(parse_bip21("bitcoin:175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W?req-x=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_btc_amount() {
+        assert_eq!(parse_btc_amount("1"), Ok(100_000_000));
+        assert_eq!(parse_btc_amount("0.001"), Ok(100_000));
+        assert_eq!(parse_btc_amount(".5"), Ok(50_000_000));
+        assert_eq!(parse_btc_amount("0.00000001"), Ok(1));
+        assert!(parse_btc_amount("0.000000001").is_err());
+        assert!(parse_btc_amount("1e3").is_err());
+        assert!(parse_btc_amount("-1").is_err());
+        assert!(parse_btc_amount(".").is_err());
+    }
+}
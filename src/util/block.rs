@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
 use std::fmt;
-use std::iter::FromIterator;
+use std::iter::{once, FromIterator};
 use std::slice;
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
@@ -9,6 +10,8 @@ use time;
 
 #[cfg(not(feature = "liquid"))]
 use bitcoin::consensus::encode::serialize;
+#[cfg(not(feature = "liquid"))]
+use bitcoin::util::uint::Uint256;
 #[cfg(feature = "liquid")]
 use elements::encode::serialize;
 
@@ -16,6 +19,11 @@ use crate::chain::{Block, BlockHeader};
 use crate::errors::*;
 use crate::new_index::BlockEntry;
 
+#[cfg(not(feature = "liquid"))]
+use crate::chain::{OutPoint, Transaction, TxOut};
+#[cfg(not(feature = "liquid"))]
+use crate::util::has_prevout;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BlockId {
     pub height: usize,
@@ -69,10 +77,16 @@ impl fmt::Debug for HeaderEntry {
     }
 }
 
+// Beyond this many new headers, the hash index is sorted again rather than inserted into
+const HASH_INDEX_MAX_INSERTS: usize = 100;
+
 pub struct HeaderList {
     headers: Vec<HeaderEntry>,
     heights: HashMap<Sha256dHash, usize>,
+    by_hash: Vec<usize>, // the heights of the headers, sorted by their (displayed) hash
     tip: Sha256dHash,
+    #[cfg(not(feature = "liquid"))]
+    chainwork: Vec<Uint256>, // the cumulative work of the chain up to each header
 }
 
 impl HeaderList {
@@ -80,7 +94,10 @@ impl HeaderList {
         HeaderList {
             headers: vec![],
             heights: HashMap::new(),
+            by_hash: vec![],
             tip: Sha256dHash::default(),
+            #[cfg(not(feature = "liquid"))]
+            chainwork: vec![],
         }
     }
 
@@ -187,13 +204,64 @@ impl HeaderList {
             new_height
         );
         self.headers.split_off(new_height); // keep [0..new_height) entries
+        self.by_hash.retain(|height| *height < new_height);
+        #[cfg(not(feature = "liquid"))]
+        let mut last_work: Option<(u32, Uint256)> = None;
+        #[cfg(not(feature = "liquid"))]
+        self.chainwork.truncate(new_height);
         for new_header in new_headers {
             let height = new_header.height();
             assert_eq!(height, self.headers.len());
+            #[cfg(not(feature = "liquid"))]
+            {
+                // the work only changes along with the difficulty, so it's computed once per retarget
+                let bits = new_header.header().bits;
+                let work = match last_work {
+                    Some((last_bits, work)) if last_bits == bits => work,
+                    _ => new_header.header().work(),
+                };
+                last_work = Some((bits, work));
+                let chainwork = match self.chainwork.last() {
+                    Some(prev_chainwork) => *prev_chainwork + work,
+                    None => work,
+                };
+                self.chainwork.push(chainwork);
+            }
             self.tip = *new_header.hash();
             self.headers.push(new_header);
             self.heights.insert(self.tip, height);
         }
+        self.index_hashes(new_height);
+    }
+
+    // Adds the headers from `from_height` on to the hash index
+    fn index_hashes(&mut self, from_height: usize) {
+        let headers = &self.headers;
+        let by_hash = &mut self.by_hash;
+        let cmp = |a: &usize, b: &usize| cmp_hashes(headers[*a].hash(), headers[*b].hash());
+        if headers.len() - from_height <= HASH_INDEX_MAX_INSERTS {
+            for height in from_height..headers.len() {
+                let pos = by_hash
+                    .binary_search_by(|other| cmp(other, &height))
+                    .unwrap_or_else(|pos| pos);
+                by_hash.insert(pos, height);
+            }
+        } else {
+            by_hash.extend(from_height..headers.len());
+            by_hash.sort_unstable_by(cmp);
+        }
+    }
+
+    // Drops the headers from `height` on
+    pub fn truncate(&mut self, height: usize) {
+        self.headers.truncate(height);
+        self.by_hash.retain(|h| *h < height);
+        #[cfg(not(feature = "liquid"))]
+        self.chainwork.truncate(height);
+        self.tip = self
+            .headers
+            .last()
+            .map_or(Sha256dHash::default(), |h| *h.hash());
     }
 
     pub fn header_by_blockhash(&self, blockhash: &Sha256dHash) -> Option<&HeaderEntry> {
@@ -206,6 +274,39 @@ impl HeaderList {
         }
     }
 
+    // The headers whose (hex) hash starts with `prefix`, by descending height
+    pub fn headers_by_hash_prefix(&self, prefix: &str, limit: usize) -> Vec<&HeaderEntry> {
+        let nibbles: Option<Vec<u8>> = prefix
+            .chars()
+            .map(|c| c.to_digit(16).map(|digit| digit as u8))
+            .collect();
+        let nibbles = match nibbles {
+            Some(nibbles) => nibbles,
+            None => return vec![],
+        };
+        let cmp = |height: &usize| cmp_hash_prefix(self.headers[*height].hash(), &nibbles);
+        // the matching hashes are next to each other in the index, from the first one not below it
+        let start = self
+            .by_hash
+            .binary_search_by(|height| cmp(height).then(Ordering::Greater))
+            .unwrap_or_else(|pos| pos);
+        let mut highest = BinaryHeap::new(); // of the matching heights, lowest first
+        for height in self.by_hash[start..]
+            .iter()
+            .take_while(|height| cmp(height) == Ordering::Equal)
+        {
+            highest.push(Reverse(*height));
+            if highest.len() > limit {
+                highest.pop();
+            }
+        }
+        highest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(height)| &self.headers[height])
+            .collect()
+    }
+
     pub fn header_by_height(&self, height: usize) -> Option<&HeaderEntry> {
         self.headers.get(height).map(|entry| {
             assert_eq!(entry.height(), height);
@@ -232,11 +333,31 @@ impl HeaderList {
         self.headers.len()
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn chainwork(&self, height: usize) -> Option<Uint256> {
+        self.chainwork.get(height).cloned()
+    }
+
     pub fn iter(&self) -> slice::Iter<HeaderEntry> {
         self.headers.iter()
     }
 }
 
+// Orders the hashes as they're displayed (in reverse byte order)
+fn cmp_hashes(a: &Sha256dHash, b: &Sha256dHash) -> Ordering {
+    a[..].iter().rev().cmp(b[..].iter().rev())
+}
+
+// Compares the first hex digits of the displayed hash to `nibbles`
+fn cmp_hash_prefix(hash: &Sha256dHash, nibbles: &[u8]) -> Ordering {
+    hash[..]
+        .iter()
+        .rev()
+        .flat_map(|byte| once(byte >> 4).chain(once(byte & 0xf)))
+        .take(nibbles.len())
+        .cmp(nibbles.iter().cloned())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BlockStatus {
     pub in_best_chain: bool,
@@ -262,13 +383,107 @@ impl BlockStatus {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockMeta {
     pub tx_count: u32,
     pub size: u32,
     pub weight: u32,
 }
 
+// Aggregated block statistics, computed when the block history is indexed
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockStats {
+    pub total_fees: u64,
+    pub subsidy: u64,     // the coinbase outputs value minus the fees
+    pub avg_feerate: f32, // in sat/vbyte, total fees over the total vsize
+    pub median_feerate: f32,
+    pub input_count: u32,
+    pub output_count: u32,
+    pub segwit_tx_count: u32,
+    pub segwit_share: f32, // of the non-coinbase transactions
+    pub total_out: u64,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BlockStats {
+    pub fn new(txdata: &[Transaction], prevouts: &HashMap<OutPoint, TxOut>) -> Result<Self> {
+        Ok(BlockStats::with_feerates(txdata, prevouts)?.0)
+    }
+
+    // Along with the sorted fee rates of the non-coinbase transactions
+    pub fn with_feerates(
+        txdata: &[Transaction],
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Result<(Self, Vec<f32>)> {
+        let mut total_fees = 0u64;
+        let mut total_vsize = 0u64;
+        let mut feerates = vec![];
+        let mut segwit_tx_count = 0u32;
+        // the first transaction is the coinbase
+        for tx in txdata.iter().skip(1) {
+            let mut total_in = 0u64;
+            for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+                let prevout = prevouts
+                    .get(&txin.previous_output)
+                    .chain_err(|| format!("missing previous output {}", txin.previous_output))?;
+                total_in += prevout.value;
+            }
+            let total_out: u64 = tx.output.iter().map(|txout| txout.value).sum();
+            let fee = total_in
+                .checked_sub(total_out)
+                .chain_err(|| format!("transaction {} spends more than its inputs", tx.txid()))?;
+            let vsize = (tx.get_weight() as u64 + 3) / 4;
+            total_fees += fee;
+            total_vsize += vsize;
+            feerates.push(fee as f32 / vsize as f32);
+            if tx.input.iter().any(|txin| !txin.witness.is_empty()) {
+                segwit_tx_count += 1;
+            }
+        }
+        feerates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let coinbase_out: u64 = txdata
+            .first()
+            .map_or(0, |tx| tx.output.iter().map(|txout| txout.value).sum());
+        let non_coinbase_count = txdata.len().saturating_sub(1);
+
+        let stats = BlockStats {
+            total_fees,
+            subsidy: coinbase_out.saturating_sub(total_fees),
+            avg_feerate: if total_vsize > 0 {
+                total_fees as f32 / total_vsize as f32
+            } else {
+                0f32
+            },
+            median_feerate: median(&feerates),
+            input_count: txdata.iter().map(|tx| tx.input.len() as u32).sum(),
+            output_count: txdata.iter().map(|tx| tx.output.len() as u32).sum(),
+            segwit_tx_count,
+            segwit_share: if non_coinbase_count > 0 {
+                segwit_tx_count as f32 / non_coinbase_count as f32
+            } else {
+                0f32
+            },
+            total_out: txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .map(|txout| txout.value)
+                .sum(),
+        };
+        Ok((stats, feerates))
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+pub fn median(sorted: &[f32]) -> f32 {
+    match sorted.len() {
+        0 => 0f32,
+        len if len % 2 == 0 => (sorted[len / 2 - 1] + sorted[len / 2]) / 2f32,
+        len => sorted[len / 2],
+    }
+}
+
 pub struct BlockHeaderMeta {
     pub header_entry: HeaderEntry,
     pub meta: BlockMeta,
@@ -0,0 +1,177 @@
+// Coin selection over the UTXOs of a single script, so that light wallets don't have to download
+// all of them to build a transaction. Branch-and-bound looks for a changeless selection first,
+// falling back to spending the largest UTXOs first with a change output.
+
+use crate::util::fees::fee_for;
+
+const BNB_MAX_TRIES: usize = 100_000;
+const DUST_LIMIT: u64 = 546; // in satoshis
+
+// The vsize of a transaction with no inputs and a single (P2WPKH) output, without its change
+const BASE_VSIZE: u64 = 11 + 31;
+const CHANGE_OUTPUT_VSIZE: u64 = 31; // a P2WPKH output
+
+#[derive(Debug, PartialEq)]
+pub struct Selection {
+    pub inputs: Vec<usize>, // indexes into the candidate values
+    pub fee: u64,
+    pub change: u64, // 0 for changeless selections
+}
+
+// Select among the `values` of the UTXOs (each adding `input_vsize` when spent) to pay `target`
+// at `fee_rate` (in sat/vbyte). Returns None if they don't add up to enough.
+pub fn select_coins(
+    values: &[u64],
+    input_vsize: u64,
+    target: u64,
+    fee_rate: f32,
+) -> Option<Selection> {
+    let input_fee = fee_for(fee_rate, input_vsize);
+    // the UTXOs worth spending, by decreasing effective value
+    let mut candidates: Vec<(usize, u64)> = values
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| **value > input_fee)
+        .map(|(index, value)| (index, value - input_fee))
+        .collect();
+    candidates.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let needed = target + fee_for(fee_rate, BASE_VSIZE);
+    // creating a change output, and spending it later
+    let cost_of_change = fee_for(fee_rate, CHANGE_OUTPUT_VSIZE + input_vsize);
+
+    if let Some(selected) = branch_and_bound(&candidates, needed, cost_of_change) {
+        let effective: u64 = selected.iter().map(|i| candidates[*i].1).sum();
+        // the excess (less than the cost of change) goes to the fees
+        let fee = effective - target + input_fee * selected.len() as u64;
+        return Some(Selection {
+            inputs: selected.iter().map(|i| candidates[*i].0).collect(),
+            fee,
+            change: 0,
+        });
+    }
+    largest_first(&candidates, target, needed, input_fee, fee_rate)
+}
+
+// Depth-first search for a subset whose effective value is within [needed, needed+cost_of_change]
+fn branch_and_bound(
+    candidates: &[(usize, u64)],
+    needed: u64,
+    cost_of_change: u64,
+) -> Option<Vec<usize>> {
+    let mut remaining: u64 = candidates.iter().map(|(_, value)| value).sum();
+    if remaining < needed {
+        return None;
+    }
+    let mut selected: Vec<usize> = vec![];
+    let mut total = 0;
+    let mut best: Option<(Vec<usize>, u64)> = None;
+    let mut index = 0;
+
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if total + remaining < needed || total > needed + cost_of_change {
+            true
+        } else if total >= needed {
+            if best
+                .as_ref()
+                .map_or(true, |(_, waste)| total - needed < *waste)
+            {
+                best = Some((selected.clone(), total - needed));
+            }
+            true
+        } else {
+            index >= candidates.len()
+        };
+
+        if backtrack {
+            // undo the inclusions up to the last one, and try omitting it instead
+            while index > 0 && selected.last() != Some(&(index - 1)) {
+                index -= 1;
+                remaining += candidates[index].1;
+            }
+            if index == 0 {
+                break;
+            }
+            let last = selected.pop().unwrap();
+            total -= candidates[last].1;
+        } else {
+            // include the next candidate
+            remaining -= candidates[index].1;
+            total += candidates[index].1;
+            selected.push(index);
+            index += 1;
+        }
+    }
+    best.map(|(selected, _)| selected)
+}
+
+fn largest_first(
+    candidates: &[(usize, u64)],
+    target: u64,
+    needed: u64,
+    input_fee: u64,
+    fee_rate: f32,
+) -> Option<Selection> {
+    let change_fee = fee_for(fee_rate, CHANGE_OUTPUT_VSIZE);
+    let mut inputs = vec![];
+    let mut total = 0;
+    for (index, value) in candidates {
+        inputs.push(*index);
+        total += value;
+        if total >= needed + change_fee + DUST_LIMIT {
+            let change = total - needed - change_fee;
+            return Some(Selection {
+                fee: total - target - change + input_fee * inputs.len() as u64,
+                inputs,
+                change,
+            });
+        }
+    }
+    // not enough for a change output, the excess goes to the fees
+    if total >= needed {
+        return Some(Selection {
+            fee: total - target + input_fee * inputs.len() as u64,
+            inputs,
+            change: 0,
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select_coins, Selection};
+
+    #[test]
+    fn test_changeless_selection() {
+        // at 1 sat/vbyte, 68 per input and 42 for the rest: only the 2nd and 3rd UTXOs add up to
+        // 80_000 with less excess than the cost of a change output
+        let values = [100_000, 50_068, 30_150, 10_000];
+        let selection = select_coins(&values, 68, 80_000, 1.0).unwrap();
+        assert_eq!(selection.change, 0);
+        let mut inputs = selection.inputs.clone();
+        inputs.sort();
+        assert_eq!(inputs, vec![1, 2]);
+        assert_eq!(selection.fee, 50_068 + 30_150 - 80_000);
+    }
+
+    #[test]
+    fn test_fallback_with_change() {
+        let values = [100_000, 50_000];
+        let selection = select_coins(&values, 68, 120_000, 1.0).unwrap();
+        assert_eq!(selection.inputs, vec![0, 1]);
+        // 2 inputs, the base and the change output
+        assert_eq!(selection.fee, 2 * 68 + 42 + 31);
+        assert_eq!(selection.change, 150_000 - 120_000 - selection.fee);
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        assert_eq!(
+            select_coins(&[1_000, 2_000], 68, 5_000, 1.0),
+            None::<Selection>
+        );
+        // worth less than spending them
+        assert_eq!(select_coins(&[50, 60], 68, 10, 1.0), None);
+    }
+}
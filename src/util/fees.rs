@@ -4,6 +4,11 @@ use crate::chain::{Transaction, TxOut};
 use std::collections::HashMap;
 
 const VSIZE_BIN_WIDTH: u32 = 50_000; // in vbytes
+pub const BLOCK_VSIZE: u64 = 1_000_000; // in vbytes
+
+// bitcoind's defaults, in sat/vbyte
+pub const MIN_RELAY_FEERATE: f32 = 1.0;
+pub const INCREMENTAL_RELAY_FEERATE: f32 = 1.0;
 
 pub struct TxFeeInfo {
     pub fee: u64,   // in satoshis
@@ -67,3 +72,21 @@ pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+// The fee rate needed to get into the next `blocks` blocks, if they were filled with the highest
+// paying transactions of the histogram (accurate to the histogram's bin width)
+pub fn feerate_for_blocks(histogram: &[(f32, u32)], blocks: u16) -> f32 {
+    let mut vsize = 0;
+    for (fee_rate, bin_size) in histogram {
+        vsize += *bin_size as u64;
+        if vsize > blocks as u64 * BLOCK_VSIZE {
+            return fee_rate.max(MIN_RELAY_FEERATE);
+        }
+    }
+    MIN_RELAY_FEERATE
+}
+
+// The fee (in satoshis) paying `fee_rate` for `vsize`
+pub fn fee_for(fee_rate: f32, vsize: u64) -> u64 {
+    (fee_rate * vsize as f32).ceil() as u64
+}
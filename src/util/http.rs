@@ -0,0 +1,50 @@
+use hyper::Uri;
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::errors::*;
+
+// A plain HTTP/1.0 GET of an http:// URL, which saves us from chunked responses. Fails unless the
+// response status is 2xx.
+pub fn http_get(url: &Uri, timeout: Duration) -> Result<String> {
+    let host = url
+        .host()
+        .chain_err(|| format!("missing host in {}", url))?;
+    let port = url.port_u16().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| format!("failed to resolve {}", host))?
+        .next()
+        .chain_err(|| format!("no address for {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .chain_err(|| "failed to set the read timeout")?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n",
+        url.path_and_query().map_or("/", |path| path.as_str()),
+        host
+    )
+    .chain_err(|| format!("failed to send the request to {}", addr))?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .chain_err(|| format!("failed to read the response of {}", addr))?;
+    let (head, body) = response
+        .find("\r\n\r\n")
+        .map(|pos| (&response[..pos], &response[pos + 4..]))
+        .chain_err(|| format!("invalid response from {}", addr))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status
+        .split(' ')
+        .nth(1)
+        .map_or(true, |code| !code.starts_with('2'))
+    {
+        bail!("unexpected response: {:?}", status);
+    }
+    Ok(body.to_string())
+}
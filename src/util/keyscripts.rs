@@ -0,0 +1,117 @@
+// The output scripts a raw public key or a redeem script can be paid to, to query and watch them as
+// a whole. This covers the P2PK outputs of the early coins and the bare multisig outputs, which have
+// no address.
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::{Address, Network as BNetwork, PublicKey, Script};
+
+const MAX_REDEEM_SCRIPT_SIZE: usize = 10_000; // as for witness scripts
+
+pub enum KeyScripts {
+    Pubkey(PublicKey),
+    RedeemScript(Script),
+}
+
+impl KeyScripts {
+    // `kind` is either "pubkey" or "redeemscript"
+    pub fn parse(kind: &str, hex_str: &str) -> Result<Self, String> {
+        let bytes = hex::decode(hex_str).map_err(|_| format!("Invalid {} hex", kind))?;
+        match kind {
+            "pubkey" => PublicKey::from_slice(&bytes)
+                .map(KeyScripts::Pubkey)
+                .map_err(|_| "Invalid public key".to_string()),
+            "redeemscript" if bytes.is_empty() || bytes.len() > MAX_REDEEM_SCRIPT_SIZE => {
+                Err("Invalid redeem script size".to_string())
+            }
+            "redeemscript" => Ok(KeyScripts::RedeemScript(Script::from(bytes))),
+            _ => Err(format!("Invalid kind {}", kind)),
+        }
+    }
+
+    // The lowercase hex of the public key (as given, compressed or not) or redeem script, that the
+    // scripts are reported under
+    pub fn id(&self) -> String {
+        match self {
+            KeyScripts::Pubkey(key) => hex::encode(key.to_bytes()),
+            KeyScripts::RedeemScript(script) => hex::encode(script.as_bytes()),
+        }
+    }
+
+    // The network doesn't matter for the scripts. Uncompressed keys only get the legacy forms, as
+    // the segwit ones can't be spent with them.
+    pub fn scripts(&self) -> Vec<(&'static str, Script)> {
+        let network = BNetwork::Bitcoin;
+        match self {
+            KeyScripts::Pubkey(key) => {
+                let mut scripts = vec![
+                    (
+                        "p2pk",
+                        Builder::new()
+                            .push_slice(&key.to_bytes())
+                            .push_opcode(opcodes::all::OP_CHECKSIG)
+                            .into_script(),
+                    ),
+                    ("p2pkh", Address::p2pkh(key, network).script_pubkey()),
+                ];
+                if key.compressed {
+                    scripts.push(("p2wpkh", Address::p2wpkh(key, network).script_pubkey()));
+                    scripts.push((
+                        "p2sh-p2wpkh",
+                        Address::p2shwpkh(key, network).script_pubkey(),
+                    ));
+                }
+                scripts
+            }
+            KeyScripts::RedeemScript(script) => vec![
+                ("bare", script.clone()),
+                ("p2sh", Address::p2sh(script, network).script_pubkey()),
+                ("p2wsh", Address::p2wsh(script, network).script_pubkey()),
+                (
+                    "p2sh-p2wsh",
+                    Address::p2shwsh(script, network).script_pubkey(),
+                ),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyScripts;
+
+    #[test]
+    fn test_pubkey_scripts() {
+        // the key of the first P2PK output paid to Hal Finney, in block 170
+        let uncompressed = "0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3";
+        let keyscripts = KeyScripts::parse("pubkey", uncompressed).unwrap();
+        let scripts = keyscripts.scripts();
+        assert_eq!(
+            scripts.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(),
+            vec!["p2pk", "p2pkh"]
+        );
+        assert_eq!(
+            hex::encode(scripts[0].1.as_bytes()),
+            format!("41{}ac", uncompressed)
+        );
+        assert_eq!(keyscripts.id(), uncompressed);
+
+        let compressed = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let scripts = KeyScripts::parse("pubkey", compressed).unwrap().scripts();
+        assert_eq!(scripts.len(), 4);
+        assert_eq!(
+            hex::encode(scripts[2].1.as_bytes()),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+
+        assert!(KeyScripts::parse("pubkey", "02abcd").is_err());
+        assert!(KeyScripts::parse("redeemscript", "").is_err());
+        assert_eq!(
+            KeyScripts::parse("redeemscript", "51ae")
+                .unwrap()
+                .scripts()
+                .len(),
+            4
+        );
+    }
+}
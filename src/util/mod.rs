@@ -1,10 +1,20 @@
+#[cfg(not(feature = "liquid"))]
+pub mod bip21;
 mod block;
 mod merkle;
 mod script;
 mod transaction;
 
+#[cfg(not(feature = "liquid"))]
+pub mod coinselect;
 pub mod fees;
+pub mod http;
+pub mod keyscripts;
+#[cfg(not(feature = "liquid"))]
+pub mod xpub;
 
+#[cfg(not(feature = "liquid"))]
+pub use self::block::{median, BlockStats};
 pub use self::block::{BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, HeaderEntry, HeaderList};
 pub use self::merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
 pub use self::script::{get_innerscripts, get_script_asm, script_to_address};
@@ -0,0 +1,156 @@
+// Derives the scripts of the receive and change addresses of an extended public key, under each of
+// the single-key script types that BIP44/49/84 wallets use.
+
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::{Address, Network as BNetwork, Script};
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum XpubScriptType {
+    #[serde(rename = "p2wpkh")]
+    P2wpkh,
+    #[serde(rename = "p2sh-p2wpkh")]
+    P2shP2wpkh,
+    #[serde(rename = "p2pkh")]
+    P2pkh,
+}
+
+// By order of preference, when several show usage
+pub const XPUB_SCRIPT_TYPES: [XpubScriptType; 3] = [
+    XpubScriptType::P2wpkh,
+    XpubScriptType::P2shP2wpkh,
+    XpubScriptType::P2pkh,
+];
+
+impl FromStr for XpubScriptType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "p2wpkh" => Ok(XpubScriptType::P2wpkh),
+            "p2sh-p2wpkh" => Ok(XpubScriptType::P2shP2wpkh),
+            "p2pkh" => Ok(XpubScriptType::P2pkh),
+            _ => Err(format!("Invalid script type: {}", s)),
+        }
+    }
+}
+
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+// The SLIP-132 versions (ypub, zpub and their testnet upub, vpub) imply a script type, but aren't
+// understood by the bip32 parser
+fn slip132_version(version: &[u8]) -> Option<([u8; 4], XpubScriptType)> {
+    match version {
+        [0x04, 0x9d, 0x7c, 0xb2] => Some((XPUB_VERSION, XpubScriptType::P2shP2wpkh)),
+        [0x04, 0xb2, 0x47, 0x46] => Some((XPUB_VERSION, XpubScriptType::P2wpkh)),
+        [0x04, 0x4a, 0x52, 0x62] => Some((TPUB_VERSION, XpubScriptType::P2shP2wpkh)),
+        [0x04, 0x5f, 0x1c, 0xf6] => Some((TPUB_VERSION, XpubScriptType::P2wpkh)),
+        _ => None,
+    }
+}
+
+// Mainnet keys (xpub, ypub, zpub) are only valid on mainnet, and testnet keys (tpub, upub, vpub) on
+// the other networks
+pub fn check_network(xpub: &ExtendedPubKey, network: BNetwork) -> Result<(), String> {
+    if (xpub.network == BNetwork::Bitcoin) != (network == BNetwork::Bitcoin) {
+        return Err(format!("Invalid xpub: not a {} key", network));
+    }
+    Ok(())
+}
+
+// Parses an xpub (or ypub/zpub) of `network`, along with the script type its version implies (if any)
+pub fn parse_xpub(
+    xpub: &str,
+    network: BNetwork,
+) -> Result<(ExtendedPubKey, Option<XpubScriptType>), String> {
+    let mut data = base58::from_check(xpub).map_err(|_| "Invalid xpub".to_string())?;
+    if data.len() != 78 {
+        return Err("Invalid xpub".to_string());
+    }
+    let script_type = match slip132_version(&data[..4]) {
+        Some((version, script_type)) => {
+            data[..4].copy_from_slice(&version);
+            Some(script_type)
+        }
+        None => None,
+    };
+    let xpub = ExtendedPubKey::from_str(&base58::check_encode_slice(&data))
+        .map_err(|e| format!("Invalid xpub: {}", e))?;
+    check_network(&xpub, network)?;
+    Ok((xpub, script_type))
+}
+
+// The script of the address at `chain`/`index` (the external chain 0 for receiving, the internal
+// chain 1 for change)
+pub fn derive_script<C: Verification>(
+    secp: &Secp256k1<C>,
+    xpub: &ExtendedPubKey,
+    script_type: XpubScriptType,
+    chain: u32,
+    index: u32,
+) -> Result<Script, String> {
+    let path = [
+        ChildNumber::from_normal_idx(chain).map_err(|e| e.to_string())?,
+        ChildNumber::from_normal_idx(index).map_err(|e| e.to_string())?,
+    ];
+    let key = xpub
+        .derive_pub(secp, &path)
+        .map_err(|e| e.to_string())?
+        .public_key;
+    let address = match script_type {
+        XpubScriptType::P2wpkh => Address::p2wpkh(&key, xpub.network),
+        XpubScriptType::P2shP2wpkh => Address::p2shwpkh(&key, xpub.network),
+        XpubScriptType::P2pkh => Address::p2pkh(&key, xpub.network),
+    };
+    Ok(address.script_pubkey())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_script, parse_xpub, XpubScriptType};
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{Address, Network};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_zpub_derivation() {
+        // the BIP84 test vector
+        let (xpub, script_type) = parse_xpub("zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs", Network::Bitcoin).unwrap();
+        assert_eq!(script_type, Some(XpubScriptType::P2wpkh));
+        assert_eq!(xpub.network, Network::Bitcoin);
+        let script = derive_script(
+            &Secp256k1::verification_only(),
+            &xpub,
+            XpubScriptType::P2wpkh,
+            0,
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            script,
+            Address::from_str("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu")
+                .unwrap()
+                .script_pubkey()
+        );
+    }
+
+    #[test]
+    fn test_invalid_xpub() {
+        assert!(parse_xpub("xpub123", Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_xpub_network() {
+        let zpub = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+        assert!(parse_xpub(zpub, Network::Testnet).is_err());
+        assert!(parse_xpub(zpub, Network::Regtest).is_err());
+        let tpub = "tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp";
+        assert!(parse_xpub(tpub, Network::Testnet).is_ok());
+        assert!(parse_xpub(tpub, Network::Regtest).is_ok());
+        assert!(parse_xpub(tpub, Network::Bitcoin).is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use bincode;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use futures::sync::mpsc;
+use hex;
+use hyper::rt::{self, Future, Stream};
+use hyper::{Body, Client, Request, Uri};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+
+use crate::chain::{OutPoint, Transaction, TxOut};
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::new_index::{compute_script_hash, parse_hash, ChainQuery};
+use crate::util::{full_hash, has_prevout, BlockId, Bytes, FullHash};
+
+// Watched scripts and confirmation targets are persisted in their own db:
+//      W{scripthash} → {entry}
+//      C{txid} → {target}
+//      R{scripthash} → {from-height} (pending rescans, see Indexer::rescan_watched)
+//      O{txid}{vout} → {outpoint watch}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WatchEntry {
+    pub address: String,
+    pub label: String,
+}
+
+#[derive(Serialize)]
+pub struct WatchEvent {
+    address: String,
+    label: String,
+    txid: String,
+    received: u64, // in satoshis
+    spent: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_height: Option<usize>, // None for mempool transactions
+}
+
+// A funding output (such as a lightning channel's) to notify about when it gets spent, until it's
+// removed from the watch list
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutpointWatch {
+    pub label: String,
+    pub url: Option<String>, // the webhook to notify, instead of --watch-webhook-url
+}
+
+#[derive(Serialize)]
+pub struct OutpointSpentEvent {
+    outpoint: String,
+    label: String,
+    status: &'static str, // "spent"
+    spending_txid: String,
+    spending_vin: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_height: Option<usize>, // None for mempool transactions
+}
+
+// A one-off notification for when a transaction reaches the given number of confirmations
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfirmationTarget {
+    pub url: String,
+    pub confirmations: u32,
+    // the block the transaction was last seen confirmed in, to detect reorgs
+    confirmed_in: Option<FullHash>,
+}
+
+impl ConfirmationTarget {
+    pub fn new(url: String, confirmations: u32) -> Self {
+        ConfirmationTarget {
+            url,
+            confirmations,
+            confirmed_in: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConfirmationEvent {
+    txid: String,
+    status: &'static str, // "confirmed" or "reorged"
+    confirmations: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_height: Option<usize>,
+}
+
+pub struct WatchList {
+    db: DB,
+    scripts: RwLock<HashMap<FullHash, WatchEntry>>,
+    targets: Mutex<HashMap<FullHash, ConfirmationTarget>>,
+    rescans: Mutex<HashMap<FullHash, u32>>,
+    outpoints: RwLock<HashMap<OutPoint, OutpointWatch>>,
+    webhook_url: Option<Uri>,
+    notifier: mpsc::UnboundedSender<(Uri, String)>,
+}
+
+fn entry_key(scripthash: &FullHash) -> Bytes {
+    [&b"W"[..], &scripthash[..]].concat()
+}
+
+fn target_key(txid: &FullHash) -> Bytes {
+    [&b"C"[..], &txid[..]].concat()
+}
+
+fn rescan_key(scripthash: &FullHash) -> Bytes {
+    [&b"R"[..], &scripthash[..]].concat()
+}
+
+fn outpoint_key(outpoint: &OutPoint) -> Bytes {
+    [
+        &b"O"[..],
+        &outpoint.txid[..],
+        &outpoint.vout.to_be_bytes()[..],
+    ]
+    .concat()
+}
+
+fn load_rows<T: DeserializeOwned>(db: &DB, prefix: &[u8]) -> HashMap<FullHash, T> {
+    db.iter_scan(prefix)
+        .map(|row| {
+            let value = bincode::deserialize(&row.value).expect("failed to parse watch row");
+            (full_hash(&row.key[1..]), value)
+        })
+        .collect()
+}
+
+impl WatchList {
+    pub fn open(path: &Path, webhook_url: Option<Uri>) -> Self {
+        let db = DB::open(path);
+        db.enable_auto_compaction();
+        let scripts = load_rows(&db, b"W");
+        let targets = load_rows(&db, b"C");
+        let rescans = load_rows(&db, b"R");
+        let outpoints: HashMap<OutPoint, OutpointWatch> = db
+            .iter_scan(b"O")
+            .map(|row| {
+                let outpoint = OutPoint {
+                    txid: parse_hash(&full_hash(&row.key[1..33])),
+                    vout: u32::from_be_bytes(*array_ref![row.key, 33, 4]),
+                };
+                let watch = bincode::deserialize(&row.value).expect("failed to parse watch row");
+                (outpoint, watch)
+            })
+            .collect();
+        info!(
+            "loaded {} watched scripts, {} watched outpoints and {} confirmation targets",
+            scripts.len(),
+            outpoints.len(),
+            targets.len()
+        );
+        WatchList {
+            db,
+            scripts: RwLock::new(scripts),
+            targets: Mutex::new(targets),
+            rescans: Mutex::new(rescans),
+            outpoints: RwLock::new(outpoints),
+            webhook_url,
+            notifier: start_notifier(),
+        }
+    }
+
+    fn put<T: Serialize>(&self, key: Bytes, value: &T) {
+        self.db.write(
+            vec![DBRow {
+                key,
+                value: bincode::serialize(value).unwrap(),
+            }],
+            DBFlush::Enable,
+        );
+    }
+
+    fn delete(&self, key: Bytes) {
+        self.db
+            .write_with_deletes(vec![], vec![key], DBFlush::Enable);
+    }
+
+    fn notify<T: Serialize>(&self, url: Uri, event: &T) {
+        let body = serde_json::to_string(event).unwrap();
+        if self.notifier.unbounded_send((url, body)).is_err() {
+            warn!("webhook notifier is gone");
+        }
+    }
+
+    pub fn add(&self, scripthash: FullHash, entry: WatchEntry) {
+        self.put(entry_key(&scripthash), &entry);
+        self.scripts.write().unwrap().insert(scripthash, entry);
+    }
+
+    pub fn remove(&self, scripthash: &FullHash) -> bool {
+        self.delete(entry_key(scripthash));
+        self.delete(rescan_key(scripthash));
+        self.rescans.lock().unwrap().remove(scripthash);
+        self.scripts.write().unwrap().remove(scripthash).is_some()
+    }
+
+    pub fn scripthashes(&self) -> HashSet<FullHash> {
+        self.scripts.read().unwrap().keys().cloned().collect()
+    }
+
+    // Request the history of a watched script to be indexed again starting at `from_height`
+    pub fn add_rescan(&self, scripthash: FullHash, from_height: u32) {
+        let mut rescans = self.rescans.lock().unwrap();
+        let height = rescans.entry(scripthash).or_insert(from_height);
+        *height = (*height).min(from_height);
+        self.put(rescan_key(&scripthash), &*height);
+    }
+
+    pub fn pending_rescans(&self) -> HashMap<FullHash, u32> {
+        self.rescans.lock().unwrap().clone()
+    }
+
+    // Called once the rescan requested from `from_height` is complete. Rescans requested from
+    // an earlier height in the meantime are kept.
+    pub fn rescan_done(&self, scripthash: &FullHash, from_height: u32) {
+        let mut rescans = self.rescans.lock().unwrap();
+        if rescans.get(scripthash).map_or(false, |h| *h >= from_height) {
+            self.delete(rescan_key(scripthash));
+            rescans.remove(scripthash);
+        }
+    }
+
+    // Called once the rescan requested from `from_height` reached `next_height`, to continue it
+    // from there. Rescans requested from an earlier height in the meantime are kept.
+    pub fn rescan_continue(&self, scripthash: &FullHash, from_height: u32, next_height: u32) {
+        let mut rescans = self.rescans.lock().unwrap();
+        if let Some(height) = rescans.get_mut(scripthash) {
+            if *height >= from_height {
+                *height = next_height;
+                self.put(rescan_key(scripthash), &*height);
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<(String, WatchEntry)> {
+        let mut entries: Vec<(String, WatchEntry)> = self
+            .scripts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(scripthash, entry)| (hex::encode(scripthash), entry.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.1.address.cmp(&b.1.address));
+        entries
+    }
+
+    // Replaces the existing watch of the same outpoint, if any
+    pub fn add_outpoint(&self, outpoint: OutPoint, watch: OutpointWatch) {
+        self.put(outpoint_key(&outpoint), &watch);
+        self.outpoints.write().unwrap().insert(outpoint, watch);
+    }
+
+    pub fn remove_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.delete(outpoint_key(outpoint));
+        self.outpoints.write().unwrap().remove(outpoint).is_some()
+    }
+
+    pub fn is_outpoint_watched(&self, outpoint: &OutPoint) -> bool {
+        self.outpoints.read().unwrap().contains_key(outpoint)
+    }
+
+    // Notify about the watched scripts funded or spent by `tx`, and the watched outpoints it spends.
+    // `prevouts` should contain the outputs spent by it.
+    pub fn check_tx(
+        &self,
+        tx: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+        blockid: Option<&BlockId>,
+    ) {
+        self.check_outpoints(tx, blockid);
+
+        let webhook_url = match self.webhook_url {
+            Some(ref url) => url,
+            None => return,
+        };
+        let scripts = self.scripts.read().unwrap();
+        if scripts.is_empty() {
+            return;
+        }
+
+        // scripthash -> (received, spent)
+        let mut matched: HashMap<FullHash, (u64, u64)> = HashMap::new();
+        for txo in &tx.output {
+            let scripthash = compute_script_hash(&txo.script_pubkey);
+            if scripts.contains_key(&scripthash) {
+                matched.entry(scripthash).or_insert((0, 0)).0 += txo.value;
+            }
+        }
+        for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+            if let Some(prevout) = prevouts.get(&txin.previous_output) {
+                let scripthash = compute_script_hash(&prevout.script_pubkey);
+                if scripts.contains_key(&scripthash) {
+                    matched.entry(scripthash).or_insert((0, 0)).1 += prevout.value;
+                }
+            }
+        }
+
+        for (scripthash, (received, spent)) in matched {
+            let entry = &scripts[&scripthash];
+            let event = WatchEvent {
+                address: entry.address.clone(),
+                label: entry.label.clone(),
+                txid: tx.txid().to_string(),
+                received,
+                spent,
+                block_height: blockid.map(|b| b.height),
+            };
+            self.notify(webhook_url.clone(), &event);
+        }
+    }
+
+    // The spends are notified once seen in the mempool, and again once confirmed
+    fn check_outpoints(&self, tx: &Transaction, blockid: Option<&BlockId>) {
+        let outpoints = self.outpoints.read().unwrap();
+        if outpoints.is_empty() {
+            return;
+        }
+        for (vin, txin) in tx.input.iter().enumerate() {
+            let watch = match outpoints.get(&txin.previous_output) {
+                Some(watch) => watch,
+                None => continue,
+            };
+            let url = match watch.url {
+                Some(ref url) => url.parse().ok(), // validated when registered
+                None => self.webhook_url.clone(),
+            };
+            let url = match url {
+                Some(url) => url,
+                None => continue,
+            };
+            let event = OutpointSpentEvent {
+                outpoint: format!(
+                    "{}:{}",
+                    txin.previous_output.txid, txin.previous_output.vout
+                ),
+                label: watch.label.clone(),
+                status: "spent",
+                spending_txid: tx.txid().to_string(),
+                spending_vin: vin as u32,
+                block_height: blockid.map(|b| b.height),
+            };
+            self.notify(url, &event);
+        }
+    }
+
+    // Replaces the existing target for the same transaction, if any
+    pub fn add_target(&self, txid: &Sha256dHash, target: ConfirmationTarget) {
+        let txid = full_hash(&txid[..]);
+        self.put(target_key(&txid), &target);
+        self.targets.lock().unwrap().insert(txid, target);
+    }
+
+    // Fire the notifications for the transactions that reached their confirmation target (these
+    // are then removed) or were reorged out of the best chain. Called after each index update.
+    pub fn check_confirmations(&self, chain: &ChainQuery) {
+        let mut targets = self.targets.lock().unwrap();
+        if targets.is_empty() {
+            return;
+        }
+        let best_height = chain.best_height();
+        let mut reached = vec![];
+        for (txid, target) in targets.iter_mut() {
+            let url: Uri = match target.url.parse() {
+                Ok(url) => url,
+                Err(_) => continue, // validated when registered
+            };
+            let blockid = chain.tx_confirming_block(&parse_hash(txid));
+            let event = match blockid {
+                Some(ref blockid) => {
+                    let confirmations = (best_height + 1 - blockid.height) as u32;
+                    if target.confirmed_in != Some(full_hash(&blockid.hash[..])) {
+                        target.confirmed_in = Some(full_hash(&blockid.hash[..]));
+                        self.put(target_key(txid), &*target);
+                    }
+                    if confirmations < target.confirmations {
+                        continue;
+                    }
+                    reached.push(*txid);
+                    ConfirmationEvent {
+                        txid: parse_hash(txid).to_string(),
+                        status: "confirmed",
+                        confirmations,
+                        block_hash: Some(blockid.hash.to_string()),
+                        block_height: Some(blockid.height),
+                    }
+                }
+                None if target.confirmed_in.is_some() => {
+                    // keep waiting, it may get confirmed again
+                    target.confirmed_in = None;
+                    self.put(target_key(txid), &*target);
+                    ConfirmationEvent {
+                        txid: parse_hash(txid).to_string(),
+                        status: "reorged",
+                        confirmations: 0,
+                        block_hash: None,
+                        block_height: None,
+                    }
+                }
+                None => continue,
+            };
+            self.notify(url, &event);
+        }
+        for txid in reached {
+            self.delete(target_key(&txid));
+            targets.remove(&txid);
+        }
+    }
+}
+
+// POSTs the JSON events to their webhook, one at a time (in order) from a dedicated thread
+fn start_notifier() -> mpsc::UnboundedSender<(Uri, String)> {
+    let (sender, receiver) = mpsc::unbounded::<(Uri, String)>();
+    thread::Builder::new()
+        .name("webhook".to_string())
+        .spawn(move || {
+            let client = Client::new();
+            rt::run(receiver.for_each(move |(url, body)| {
+                let req = Request::post(url)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap();
+                client.request(req).then(|res| {
+                    match res {
+                        Ok(resp) if !resp.status().is_success() => {
+                            warn!("webhook failed with status {}", resp.status())
+                        }
+                        Err(e) => warn!("webhook failed: {}", e),
+                        Ok(_) => (),
+                    }
+                    Ok(())
+                })
+            }));
+        })
+        .unwrap();
+    sender
+}